@@ -1,21 +1,124 @@
+use super::types::{Conversation, FileAttachment};
+
 /// Lệnh UI gửi xuống tầng mạng.
 #[derive(Debug, Clone)]
 pub enum NetworkCommand {
-    SendMessage(String),
-    /// Yêu cầu Peer đồng bộ tin nhắn (Offline-first logic)
+    /// Gửi tin nhắn vào một phòng chat đã tham gia
+    SendMessage {
+        room: String,
+        content: String,
+        /// Id của tin nhắn đang trả lời (nếu có)
+        reply_to: Option<String>,
+    },
+    /// Yêu cầu Peer đồng bộ tin nhắn (Offline-first logic), mỗi phòng đã
+    /// tham gia từ mốc riêng của nó
     /// - to_peer: ID của người muốn đồng bộ
-    /// - last_timestamp: Thời điểm cuối cùng mình nhận tin từ họ
     SyncRequest {
         to_peer: String,
-        last_timestamp: i64,
     },
     /// Connect to a peer manually by address
     /// - address: Multiaddr của peer (ví dụ: /ip4/192.168.1.1/tcp/9000/p2p/12D3KooW...)
     ConnectToPeer {
         address: String,
     },
-    /// Add a peer by PeerId into the friend list and check their status.
-    AddFriend {
+    /// Gửi tin nhắn riêng tới một người bạn qua giao thức direct message
+    /// (kết nối trực tiếp hoặc qua relay circuit).
+    SendDirect {
+        to_peer: String,
+        content: String,
+        reply_to: Option<String>,
+    },
+    /// Tham gia một phòng chat (subscribe topic gossipsub của phòng)
+    JoinRoom {
+        room: String,
+    },
+    /// Rời phòng chat (unsubscribe); tin nhắn đã lưu vẫn được giữ lại
+    LeaveRoom {
+        room: String,
+    },
+    /// Đánh dấu đã đọc các tin nhắn đang hiển thị trong một cuộc trò chuyện
+    /// (gửi read receipt nếu người dùng bật tính năng này)
+    MarkRead {
+        conversation: Conversation,
+        message_ids: Vec<String>,
+    },
+    /// Sửa nội dung một tin nhắn mình đã gửi
+    EditMessage {
+        conversation: Conversation,
+        message_id: String,
+        content: String,
+    },
+    /// Xóa (thu hồi) một tin nhắn mình đã gửi
+    DeleteMessage {
+        conversation: Conversation,
+        message_id: String,
+    },
+    /// Thả một emoji lên tin nhắn
+    AddReaction {
+        conversation: Conversation,
+        message_id: String,
+        emoji: String,
+    },
+    /// Gỡ emoji mình đã thả
+    RemoveReaction {
+        conversation: Conversation,
+        message_id: String,
+        emoji: String,
+    },
+    /// Chia sẻ một file trên máy vào cuộc trò chuyện
+    SendFile {
+        conversation: Conversation,
+        path: String,
+    },
+    /// Tải file được chia sẻ từ người gửi (tiếp tục nếu đã tải dở). File chia sẻ
+    /// trong phòng được tải song song từ mọi peer đang giữ bản sao.
+    DownloadFile {
+        conversation: Conversation,
+        peer_id: String,
+        attachment: FileAttachment,
+    },
+    /// Báo người khác trong cuộc trò chuyện là mình đang gõ (UI tự giới hạn tần suất)
+    Typing {
+        conversation: Conversation,
+    },
+    /// Bật/tắt gửi read receipt
+    SetReadReceipts(bool),
+    /// Gửi lời mời kết bạn (kèm lời nhắn) tới một PeerId. Chỉ khi cả hai bên
+    /// đồng ý mới nhắn tin riêng và thấy trạng thái online của nhau.
+    SendFriendRequest {
+        peer_id: String,
+        note: String,
+    },
+    /// Đồng ý lời mời kết bạn đã nhận
+    AcceptFriend {
+        peer_id: String,
+    },
+    /// Từ chối lời mời đã nhận, hoặc hủy lời mời mình đã gửi
+    DeclineFriend {
+        peer_id: String,
+    },
+    /// Hủy kết bạn: bên kia được báo và không còn đọc được trạng thái của mình
+    RemoveFriend {
+        peer_id: String,
+    },
+    /// Đổi tên gợi nhớ và ghi chú riêng về một người bạn (chỉ lưu trên máy)
+    UpdateFriend {
+        peer_id: String,
+        petname: String,
+        annotation: String,
+    },
+    /// Đổi hồ sơ của mình rồi báo cho các peer đang kết nối. `avatar_path`:
+    /// `None` giữ ảnh đại diện hiện tại, đường dẫn rỗng thì bỏ ảnh.
+    SetProfile {
+        display_name: String,
+        status: String,
+        avatar_path: Option<String>,
+    },
+    /// Chặn một peer: không kết nối, không nhận tin nhắn, hủy kết bạn nếu có
+    BlockPeer {
+        peer_id: String,
+    },
+    UnblockPeer {
         peer_id: String,
     },
 }
@@ -1,11 +1,124 @@
-use super::types::{ChatMessage, PeerStatus};
+use super::types::{
+    ChatMessage, Conversation, DeliveryStatus, MessageOp, PeerProfile, PeerStatus, Reactions,
+};
 
 /// Sự kiện từ tầng mạng gửi lên UI.
 #[derive(Debug, Clone)]
 pub enum NetworkEvent {
+    /// PeerId của chính mình, gửi một lần khi tầng mạng khởi động
+    LocalPeerId(String),
     MessageReceived(ChatMessage),
     HistorySynced(Vec<ChatMessage>),
+    /// Không tải được tin nhắn gốc của các câu trả lời sau nhiều lần thử
+    ThreadUnavailable {
+        parent_id: String,
+    },
+    /// Tin nhắn gossip bị loại (người gửi giả mạo, sai định dạng...);
+    /// `peer` là peer đã chuyển tiếp tin nhắn.
+    MessageRejected {
+        peer: String,
+        reason: String,
+    },
     PeerConnected(String),
     PeerDisconnected(String),
     FriendStatus(PeerStatus),
+    /// Lời mời kết bạn nhận được, đang chờ mình trả lời
+    FriendRequestReceived {
+        peer: String,
+        note: String,
+    },
+    /// Lời mời mình đã gửi, đang chờ bên kia trả lời
+    FriendRequestSent {
+        peer: String,
+        note: String,
+    },
+    /// Lời mời đã được trả lời (đồng ý, từ chối) hoặc bị hủy
+    FriendRequestClosed {
+        peer: String,
+    },
+    /// Tên gợi nhớ và ghi chú của một người bạn (khi khởi động và khi thay đổi)
+    FriendInfo {
+        peer: String,
+        petname: String,
+        annotation: String,
+    },
+    /// Không còn là bạn (mình hoặc bên kia đã hủy kết bạn)
+    FriendRemoved {
+        peer: String,
+    },
+    /// Hồ sơ mới (đã kiểm tra chữ ký) của một peer, hoặc của chính mình
+    ProfileUpdated(PeerProfile),
+    /// Không đổi được hồ sơ của mình (ví dụ ảnh đại diện không đọc được)
+    ProfileError(String),
+    /// Peer đã bị chặn (đã lưu và đã ngắt kết nối)
+    PeerBlocked {
+        peer: String,
+    },
+    PeerUnblocked {
+        peer: String,
+    },
+    /// Một tin nhắn vừa được tác giả sửa hoặc xóa (chữ ký đã được kiểm tra)
+    MessageOpApplied(MessageOp),
+    /// Reaction trên một tin nhắn thay đổi (danh sách đầy đủ sau thay đổi)
+    ReactionsUpdated {
+        message_id: String,
+        reactions: Reactions,
+    },
+    /// Tiến độ tải file (byte đã nhận / tổng, số peer đang cung cấp)
+    FileProgress {
+        file_id: String,
+        received: u64,
+        total: u64,
+        sources: usize,
+    },
+    /// File mình vừa chia sẻ, nằm tại `path` trên máy
+    FileShared {
+        file_id: String,
+        path: String,
+    },
+    /// File đã tải xong và được lưu tại `path`
+    FileCompleted {
+        file_id: String,
+        path: String,
+    },
+    /// Tải file thất bại; tiến độ vẫn được giữ để tải tiếp
+    FileFailed {
+        file_id: String,
+        reason: String,
+    },
+    /// Không chia sẻ được file trên máy
+    FileShareFailed {
+        path: String,
+        reason: String,
+    },
+    /// Tin nhắn của mình vừa được một peer xác nhận đã nhận/đã đọc
+    ReceiptUpdated {
+        message_id: String,
+        status: DeliveryStatus,
+    },
+    /// `peer` đang gõ trong `conversation` (tín hiệu tạm thời, không lưu)
+    Typing {
+        conversation: Conversation,
+        peer: String,
+    },
+    RoomJoined(String),
+    RoomLeft(String),
+    /// Tin nhắn riêng (gửi đi hoặc nhận về); `peer` là người còn lại trong cuộc trò chuyện.
+    DirectMessage {
+        peer: String,
+        message: ChatMessage,
+    },
+    DirectMessageFailed {
+        peer: String,
+        reason: String,
+    },
+    /// Không giải mã được tin nhắn riêng (mất session, khóa không khớp...)
+    DirectMessageUndecryptable {
+        peer: String,
+        reason: String,
+    },
+    /// Tin nhắn riêng không qua được bước xác thực: đã bị sửa trên đường truyền
+    DirectMessageTampered {
+        peer: String,
+    },
 }
@@ -4,4 +4,7 @@ pub mod types;
 
 pub use commands::NetworkCommand;
 pub use events::NetworkEvent;
-pub use types::{ChatMessage, PeerStatus};
+pub use types::{
+    ChatMessage, Conversation, DEFAULT_ROOM, DeliveryStatus, FileAttachment, MessageOp, OpAction,
+    PeerProfile, PeerStatus, Reactions, normalize_room_name,
+};
@@ -1,4 +1,11 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Phòng chat mặc định, mọi client đều tham gia (topic `rust-p2p-chat-global`).
+pub const DEFAULT_ROOM: &str = "global";
+
+/// Độ dài tối đa của tên phòng
+const MAX_ROOM_NAME_LEN: usize = 32;
 
 /// Domain model đại diện một tin nhắn chat.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -7,6 +14,147 @@ pub struct ChatMessage {
     pub sender: String,
     pub content: String,
     pub timestamp: i64,
+    /// Phòng chứa tin nhắn. Client cũ không gửi trường này nên mặc định là phòng chung;
+    /// tin nhắn riêng để trống.
+    #[serde(default = "default_room")]
+    pub room: String,
+    /// Nội dung đã được người gửi sửa
+    #[serde(default)]
+    pub edited: bool,
+    /// Tin nhắn đã bị người gửi xóa (chỉ còn lại "bia mộ", nội dung rỗng)
+    #[serde(default)]
+    pub deleted: bool,
+    /// Id của tin nhắn đang được trả lời (nếu có)
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// File người gửi chia sẻ kèm tin nhắn (nội dung là tên file)
+    #[serde(default)]
+    pub attachment: Option<FileAttachment>,
+}
+
+/// Thông tin một file được chia sẻ. `file_id` là hash của danh sách hash các
+/// chunk nên người nhận kiểm tra được từng chunk tải về.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileAttachment {
+    pub file_id: String,
+    pub name: String,
+    pub size: u64,
+    /// SHA-256 (hex) của toàn bộ file
+    pub sha256: String,
+}
+
+impl ChatMessage {
+    /// Áp dụng thao tác sửa/xóa lên bản sao trong bộ nhớ
+    pub fn apply_op(&mut self, action: &OpAction) {
+        match action {
+            OpAction::Edit { content } if !self.deleted => {
+                self.content = content.clone();
+                self.edited = true;
+            }
+            OpAction::Edit { .. } => {}
+            OpAction::Delete => {
+                self.content.clear();
+                self.deleted = true;
+            }
+        }
+    }
+}
+
+/// Reaction trên một tin nhắn: emoji -> các peer đã thả emoji đó
+pub type Reactions = BTreeMap<String, Vec<String>>;
+
+/// Thao tác sửa hoặc xóa một tin nhắn đã gửi. Chữ ký của tác giả gốc đi kèm
+/// nên thao tác có thể được peer khác chuyển tiếp (qua đồng bộ lịch sử) mà
+/// không bị giả mạo.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageOp {
+    pub target_id: String,
+    /// PeerId của người gửi tin nhắn gốc
+    pub author: String,
+    /// Phòng của tin nhắn gốc; rỗng với tin nhắn riêng
+    pub room: String,
+    pub timestamp: i64,
+    pub action: OpAction,
+    pub signature: Vec<u8>,
+}
+
+/// Hồ sơ một người dùng tự công bố: tên hiển thị, ảnh đại diện nhỏ (PNG) và
+/// dòng trạng thái. Được ký bằng khóa định danh nên peer khác có thể chuyển
+/// tiếp hay lưu lại mà không giả mạo được.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerProfile {
+    pub peer_id: String,
+    pub display_name: String,
+    /// Trạng thái tự do ("vắng mặt", "đang bận"...)
+    pub status: String,
+    /// Ảnh đại diện PNG, rỗng nếu chưa đặt
+    pub avatar: Vec<u8>,
+    /// Thời điểm sửa gần nhất; bản lớn hơn thay thế bản cũ
+    pub updated_at: i64,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OpAction {
+    Edit { content: String },
+    Delete,
+}
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+/// Chuẩn hóa tên phòng người dùng nhập: chữ thường, chỉ gồm chữ/số, `-` và `_`.
+/// Trả về `None` nếu tên không hợp lệ.
+pub fn normalize_room_name(name: &str) -> Option<String> {
+    let name = name.trim().trim_start_matches('#').to_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(name)
+}
+
+/// Cuộc trò chuyện đang được hiển thị: một phòng chat hoặc chat riêng với một bạn.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Room(String),
+    Direct(String),
+}
+
+impl Default for Conversation {
+    fn default() -> Self {
+        Conversation::Room(DEFAULT_ROOM.to_string())
+    }
+}
+
+/// Trạng thái gửi của tin nhắn do mình gửi, hiển thị bằng dấu tick.
+/// Thứ tự các biến thể là thứ tự tiến triển (Sent < Delivered < Read).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeliveryStatus {
+    Sent,
+    Delivered,
+    Read,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Read => "read",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "sent" => Some(DeliveryStatus::Sent),
+            "delivered" => Some(DeliveryStatus::Delivered),
+            "read" => Some(DeliveryStatus::Read),
+            _ => None,
+        }
+    }
 }
 
 /// Trạng thái của một peer trong danh sách bạn bè.
@@ -17,3 +165,26 @@ pub struct PeerStatus {
     pub message: String,
     pub checked_at: i64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_names_are_normalized() {
+        assert_eq!(
+            normalize_room_name(" #Rust-VN "),
+            Some("rust-vn".to_string())
+        );
+        assert_eq!(
+            normalize_room_name("dev_ops2"),
+            Some("dev_ops2".to_string())
+        );
+        assert_eq!(normalize_room_name("#"), None);
+        assert_eq!(normalize_room_name("phòng chat"), None);
+        assert_eq!(normalize_room_name("a/b"), None);
+        let longest = "a".repeat(MAX_ROOM_NAME_LEN);
+        assert_eq!(normalize_room_name(&longest), Some(longest.clone()));
+        assert_eq!(normalize_room_name(&format!("{longest}a")), None);
+    }
+}
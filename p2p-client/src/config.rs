@@ -1,11 +1,171 @@
+use std::env;
 use std::fs;
+use std::time::Duration;
 
-use serde_json;
+use libp2p::gossipsub;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 use crate::storage::ensure_data_dir;
 
 const BOOTSTRAP_FILE: &str = "data/bootstrap_nodes.json";
 const PLACEHOLDER_ADDR: &str = "/ip4/YOUR-NODE-MASTER-IP/tcp/4001/p2p/NODE-MASTER-PEERID";
+const PEER_SCORING_FILE: &str = "data/peer_scoring.json";
+
+/// Lịch kiểm tra lại trạng thái bạn bè.
+///
+/// Đọc từ biến môi trường (hoặc file `.env`), tính bằng giây:
+/// - `CLIENT_FRIEND_REFRESH_SECS`: khoảng cách giữa hai lần kiểm tra (mặc định 60)
+/// - `CLIENT_FRIEND_REFRESH_JITTER_SECS`: độ lệch ngẫu nhiên cộng thêm (mặc định 15)
+/// - `CLIENT_FRIEND_MAX_BACKOFF_SECS`: khoảng chờ tối đa với bạn không liên lạc được (mặc định 1800)
+#[derive(Debug, Clone)]
+pub struct FriendRefreshConfig {
+    pub interval: Duration,
+    pub jitter: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for FriendRefreshConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            jitter: Duration::from_secs(15),
+            max_backoff: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+impl FriendRefreshConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            interval: env_secs("CLIENT_FRIEND_REFRESH_SECS", default.interval),
+            jitter: env_secs("CLIENT_FRIEND_REFRESH_JITTER_SECS", default.jitter),
+            max_backoff: env_secs("CLIENT_FRIEND_MAX_BACKOFF_SECS", default.max_backoff),
+        }
+    }
+
+    /// Thời gian chờ tới lần kiểm tra tiếp theo sau `failures` lần liên tiếp
+    /// không liên lạc được: nhân đôi mỗi lần, không quá `max_backoff`.
+    pub fn next_delay(&self, failures: u32) -> Duration {
+        let backoff = self
+            .interval
+            .saturating_mul(1 << failures.min(16))
+            .min(self.max_backoff.max(self.interval));
+        let jitter = rand::thread_rng().gen_range(0..=self.jitter.as_millis() as u64);
+        backoff + Duration::from_millis(jitter)
+    }
+}
+
+/// Ngưỡng chấm điểm peer của gossipsub và cách tính uy tín.
+///
+/// Đọc từ `data/peer_scoring.json` (tạo với giá trị mặc định nếu chưa có);
+/// trường nào thiếu thì dùng mặc định. Điểm của một peer xuống dưới:
+/// - `gossip_threshold`: không trao đổi gossip với peer đó nữa
+/// - `publish_threshold`: không gửi tin mình đăng cho peer đó
+/// - `graylist_threshold`: bỏ qua mọi thứ peer đó gửi
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerScoringConfig {
+    pub gossip_threshold: f64,
+    pub publish_threshold: f64,
+    pub graylist_threshold: f64,
+    /// Điểm tối thiểu để tin danh sách peer (PX) một peer gợi ý khi prune
+    pub accept_px_threshold: f64,
+    /// Điểm trung vị của mesh dưới mức này thì ghép thêm peer điểm cao
+    pub opportunistic_graft_threshold: f64,
+    /// Trọng số của uy tín (tin nhắn bị từ chối, lưu qua các lần chạy) trong điểm gossipsub
+    pub reputation_weight: f64,
+    /// Số giờ để hình phạt đã lưu của một peer giảm còn một nửa
+    pub reputation_half_life_hours: f64,
+}
+
+impl Default for PeerScoringConfig {
+    fn default() -> Self {
+        let thresholds = gossipsub::PeerScoreThresholds::default();
+        Self {
+            gossip_threshold: thresholds.gossip_threshold,
+            publish_threshold: thresholds.publish_threshold,
+            graylist_threshold: thresholds.graylist_threshold,
+            accept_px_threshold: thresholds.accept_px_threshold,
+            opportunistic_graft_threshold: thresholds.opportunistic_graft_threshold,
+            reputation_weight: 10.0,
+            reputation_half_life_hours: 24.0,
+        }
+    }
+}
+
+impl PeerScoringConfig {
+    /// Đọc cấu hình từ file; file hỏng hoặc giá trị không hợp lệ thì dùng mặc định.
+    pub fn load() -> Self {
+        ensure_data_dir().ok();
+
+        let config = match fs::read_to_string(PEER_SCORING_FILE) {
+            Ok(content) => match serde_json::from_str::<Self>(&content) {
+                Ok(config) => config,
+                Err(err) => {
+                    log::warn!("Failed to parse {PEER_SCORING_FILE} ({err}), using defaults");
+                    return Self::default();
+                }
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let config = Self::default();
+                if let Err(err) = serde_json::to_string_pretty(&config)
+                    .map_err(std::io::Error::other)
+                    .and_then(|content| fs::write(PEER_SCORING_FILE, content))
+                {
+                    log::warn!("Unable to create {PEER_SCORING_FILE}: {err}");
+                }
+                return config;
+            }
+            Err(err) => {
+                log::warn!("Failed to read {PEER_SCORING_FILE} ({err}), using defaults");
+                return Self::default();
+            }
+        };
+        match config.validate() {
+            Ok(()) => config,
+            Err(err) => {
+                log::warn!("Invalid {PEER_SCORING_FILE} ({err}), using defaults");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn thresholds(&self) -> gossipsub::PeerScoreThresholds {
+        gossipsub::PeerScoreThresholds {
+            gossip_threshold: self.gossip_threshold,
+            publish_threshold: self.publish_threshold,
+            graylist_threshold: self.graylist_threshold,
+            accept_px_threshold: self.accept_px_threshold,
+            opportunistic_graft_threshold: self.opportunistic_graft_threshold,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.thresholds().validate()?;
+        if self.reputation_weight < 0.0 {
+            return Err("reputation_weight must be >= 0".to_string());
+        }
+        if self.reputation_half_life_hours <= 0.0 {
+            return Err("reputation_half_life_hours must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn env_secs(name: &str, default: Duration) -> Duration {
+    match env::var(name) {
+        Ok(value) => match value.trim().parse::<u64>() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(_) => {
+                log::warn!("Invalid {name} `{value}`, using {}s", default.as_secs());
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
 
 /// Load bootstrap nodes from JSON file
 pub fn load_bootstrap_nodes() -> Vec<String> {
@@ -43,3 +203,37 @@ fn create_placeholder_file() -> std::io::Result<()> {
     let content = serde_json::to_string_pretty(&default).unwrap_or_else(|_| "[]".to_string());
     fs::write(BOOTSTRAP_FILE, content)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn friend_refresh_backoff_doubles_up_to_the_cap() {
+        let config = FriendRefreshConfig {
+            interval: Duration::from_secs(60),
+            jitter: Duration::ZERO,
+            max_backoff: Duration::from_secs(30 * 60),
+        };
+        assert_eq!(config.next_delay(0), Duration::from_secs(60));
+        assert_eq!(config.next_delay(1), Duration::from_secs(120));
+        assert_eq!(config.next_delay(4), Duration::from_secs(960));
+        assert_eq!(config.next_delay(5), config.max_backoff);
+        assert_eq!(config.next_delay(u32::MAX), config.max_backoff);
+    }
+
+    #[test]
+    fn friend_refresh_backoff_stays_within_the_jitter() {
+        let config = FriendRefreshConfig {
+            interval: Duration::from_secs(60),
+            jitter: Duration::from_secs(15),
+            // Cap below the interval: the interval wins
+            max_backoff: Duration::from_secs(10),
+        };
+        for failures in [0, 3, 100] {
+            let delay = config.next_delay(failures);
+            assert!(delay >= config.interval);
+            assert!(delay <= config.interval + config.jitter);
+        }
+    }
+}
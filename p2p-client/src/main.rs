@@ -23,11 +23,17 @@ async fn main() -> Result<(), eframe::Error> {
     // Load bootstrap nodes from JSON file
     let bootstrap_nodes = config::load_bootstrap_nodes();
     let bootstrap_peers = parse_bootstrap_peers(&bootstrap_nodes);
+    let friend_refresh = config::FriendRefreshConfig::from_env();
+    let peer_scoring = config::PeerScoringConfig::load();
 
-    run_full_client(bootstrap_peers).await
+    run_full_client(bootstrap_peers, friend_refresh, peer_scoring).await
 }
 
-async fn run_full_client(bootstrap_peers: Vec<(PeerId, Multiaddr)>) -> Result<(), eframe::Error> {
+async fn run_full_client(
+    bootstrap_peers: Vec<(PeerId, Multiaddr)>,
+    friend_refresh: config::FriendRefreshConfig,
+    peer_scoring: config::PeerScoringConfig,
+) -> Result<(), eframe::Error> {
     // 1. Tạo các kênh giao tiếp (Channels)
     // UI -> Network
     let (cmd_tx, cmd_rx) = mpsc::channel(100);
@@ -37,7 +43,14 @@ async fn run_full_client(bootstrap_peers: Vec<(PeerId, Multiaddr)>) -> Result<()
     // 2. Khởi chạy Network Thread (Chạy ngầm)
     let bootstrap_clone = bootstrap_peers.clone();
     tokio::spawn(async move {
-        let client = P2PClient::new(event_tx, cmd_rx, bootstrap_clone, true);
+        let client = P2PClient::new(
+            event_tx,
+            cmd_rx,
+            bootstrap_clone,
+            friend_refresh,
+            peer_scoring,
+            true,
+        );
         if let Err(err) = client.run().await {
             log::error!("Network client terminated: {err}");
         }
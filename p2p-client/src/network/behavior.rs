@@ -1,8 +1,8 @@
-use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
 use std::error::Error;
-use std::hash::{Hash, Hasher};
 use std::time::Duration;
 
+use libp2p::allow_block_list::{self, BlockedPeers};
 use libp2p::autonat;
 use libp2p::dcutr;
 use libp2p::gossipsub::{self, IdentTopic};
@@ -12,6 +12,17 @@ use libp2p::ping;
 use libp2p::relay::client;
 use libp2p::swarm::NetworkBehaviour;
 use libp2p::{PeerId, identity};
+use sha2::{Digest, Sha256};
+
+use crate::config::PeerScoringConfig;
+
+use super::direct::{DirectBehaviour, DirectEvent, build_direct_behaviour};
+use super::envelope::MAX_ENVELOPE_SIZE;
+use super::files::{FileBehaviour, FileEvent, build_file_behaviour};
+use super::friends::{FriendBehaviour, FriendEvent, build_friend_behaviour};
+use super::mailbox::{MailboxBehaviour, MailboxEvent, build_mailbox_behaviour};
+use super::profile::{ProfileBehaviour, ProfileEvent, build_profile_behaviour};
+use super::sync::{SyncBehaviour, SyncEvent, build_sync_behaviour};
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "ChatBehaviorEvent")]
@@ -23,6 +34,14 @@ pub struct ChatBehavior {
     pub autonat: autonat::Behaviour,
     pub dcutr: dcutr::Behaviour,
     pub ping: ping::Behaviour,
+    pub sync: SyncBehaviour,
+    pub direct: DirectBehaviour,
+    pub files: FileBehaviour,
+    pub mailbox: MailboxBehaviour,
+    pub friends: FriendBehaviour,
+    pub profile: ProfileBehaviour,
+    /// Refuses every connection to and from blocked peers
+    pub block_list: allow_block_list::Behaviour<BlockedPeers>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -34,6 +53,18 @@ pub enum ChatBehaviorEvent {
     Autonat(autonat::Event),
     Dcutr(dcutr::Event),
     Ping(ping::Event),
+    Sync(SyncEvent),
+    Direct(DirectEvent),
+    Files(FileEvent),
+    Mailbox(MailboxEvent),
+    Friends(FriendEvent),
+    Profile(ProfileEvent),
+}
+
+impl From<Infallible> for ChatBehaviorEvent {
+    fn from(event: Infallible) -> Self {
+        match event {}
+    }
 }
 
 impl From<gossipsub::Event> for ChatBehaviorEvent {
@@ -78,20 +109,97 @@ impl From<ping::Event> for ChatBehaviorEvent {
     }
 }
 
+impl From<SyncEvent> for ChatBehaviorEvent {
+    fn from(event: SyncEvent) -> Self {
+        ChatBehaviorEvent::Sync(event)
+    }
+}
+
+impl From<DirectEvent> for ChatBehaviorEvent {
+    fn from(event: DirectEvent) -> Self {
+        ChatBehaviorEvent::Direct(event)
+    }
+}
+
+impl From<FileEvent> for ChatBehaviorEvent {
+    fn from(event: FileEvent) -> Self {
+        ChatBehaviorEvent::Files(event)
+    }
+}
+
+impl From<MailboxEvent> for ChatBehaviorEvent {
+    fn from(event: MailboxEvent) -> Self {
+        ChatBehaviorEvent::Mailbox(event)
+    }
+}
+
+impl From<FriendEvent> for ChatBehaviorEvent {
+    fn from(event: FriendEvent) -> Self {
+        ChatBehaviorEvent::Friends(event)
+    }
+}
+
+impl From<ProfileEvent> for ChatBehaviorEvent {
+    fn from(event: ProfileEvent) -> Self {
+        ChatBehaviorEvent::Profile(event)
+    }
+}
+
+/// Gossipsub topic of a chat room. The default room keeps the original
+/// `rust-p2p-chat-global` topic so older clients still see its messages.
+pub fn room_topic(room: &str) -> IdentTopic {
+    IdentTopic::new(format!("rust-p2p-chat-{room}"))
+}
+
+/// Scoring of peers in a room's mesh. Rooms are quiet, so a peer is not
+/// penalised for delivering few messages; what lowers its score is sending
+/// messages the client rejects, until it is pruned from the mesh and, past
+/// the graylist threshold, ignored altogether.
+pub fn room_score_params() -> gossipsub::TopicScoreParams {
+    gossipsub::TopicScoreParams {
+        topic_weight: 1.0,
+        // At most +36 for an hour in the mesh
+        time_in_mesh_weight: 0.01,
+        time_in_mesh_quantum: Duration::from_secs(1),
+        time_in_mesh_cap: 3600.0,
+        first_message_deliveries_weight: 1.0,
+        first_message_deliveries_decay: 0.9,
+        first_message_deliveries_cap: 20.0,
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        // Squared: a handful of rejected messages outweighs any credit earned
+        // in the mesh; the count halves about every minute
+        invalid_message_deliveries_weight: -10.0,
+        invalid_message_deliveries_decay: 0.99,
+        ..Default::default()
+    }
+}
+
+/// Build the network behaviour. Room topics are subscribed by the client
+/// once it knows which rooms were joined.
 pub fn build_behavior(
     local_key: &identity::Keypair,
     local_peer_id: PeerId,
     relay_behaviour: libp2p::relay::client::Behaviour,
-) -> Result<(ChatBehavior, IdentTopic), Box<dyn Error>> {
+    scoring: &PeerScoringConfig,
+) -> Result<ChatBehavior, Box<dyn Error>> {
+    // Content-addressed id: identical on every build and platform, so all peers
+    // agree on which messages they have already seen
     let message_id_fn = |message: &gossipsub::Message| {
-        let mut hasher = DefaultHasher::new();
-        message.data.hash(&mut hasher);
-        gossipsub::MessageId::from(hasher.finish().to_string())
+        let digest = Sha256::new()
+            .chain_update(message.topic.as_str())
+            .chain_update(&message.data)
+            .finalize();
+        gossipsub::MessageId::from(hex::encode(digest))
     };
 
     let gossipsub_config = gossipsub::ConfigBuilder::default()
         .heartbeat_interval(Duration::from_secs(5))
         .validation_mode(gossipsub::ValidationMode::Strict)
+        // Messages are only forwarded once the client has checked the sender
+        .validate_messages()
+        // Room for a full envelope plus gossipsub framing and signature
+        .max_transmit_size(MAX_ENVELOPE_SIZE + 1024)
         .message_id_fn(message_id_fn)
         .build()?;
 
@@ -99,9 +207,15 @@ pub fn build_behavior(
         gossipsub::MessageAuthenticity::Signed(local_key.clone()),
         gossipsub_config,
     )?;
-
-    let topic = gossipsub::IdentTopic::new("rust-p2p-chat-global");
-    gossipsub.subscribe(&topic)?;
+    // Topic weights are added per room by `room_score_params` on subscribe.
+    // Many clients share a relay's address, so colocation is not held against
+    // them. The application score is the reputation kept by `network::reputation`.
+    let score_params = gossipsub::PeerScoreParams {
+        ip_colocation_factor_weight: 0.0,
+        app_specific_weight: scoring.reputation_weight,
+        ..Default::default()
+    };
+    gossipsub.with_peer_score(score_params, scoring.thresholds())?;
 
     let store = MemoryStore::new(local_peer_id);
     let mut kad = kad::Behaviour::new(local_peer_id, store);
@@ -116,17 +230,27 @@ pub fn build_behavior(
     let autonat = autonat::Behaviour::new(local_peer_id, autonat::Config::default());
     let dcutr = dcutr::Behaviour::new(local_peer_id);
     let ping = ping::Behaviour::new(ping::Config::default());
+    let sync = build_sync_behaviour();
+    let direct = build_direct_behaviour();
+    let files = build_file_behaviour();
+    let mailbox = build_mailbox_behaviour();
+    let friends = build_friend_behaviour();
+    let profile = build_profile_behaviour();
 
-    Ok((
-        ChatBehavior {
-            gossipsub,
-            kad,
-            identify,
-            relay: relay_behaviour,
-            autonat,
-            dcutr,
-            ping,
-        },
-        topic,
-    ))
+    Ok(ChatBehavior {
+        gossipsub,
+        kad,
+        identify,
+        relay: relay_behaviour,
+        autonat,
+        dcutr,
+        ping,
+        sync,
+        direct,
+        files,
+        mailbox,
+        friends,
+        profile,
+        block_list: allow_block_list::Behaviour::default(),
+    })
 }
@@ -0,0 +1,168 @@
+//! Local cache of content-addressed blobs.
+//!
+//! Attachments shared in rooms are kept under [`BLOB_DIR`], named by their file
+//! id, while and after they are downloaded. Every verified chunk is served to
+//! whoever asks for that id, and the id is announced as a Kademlia provider
+//! record, so a file posted to a room can be fetched from every peer holding a
+//! copy instead of the original sender alone. Every blob on disk counts
+//! against [`BLOB_CACHE_LIMIT`]: partial blobs of downloads that failed or sat
+//! idle for [`PARTIAL_BLOB_IDLE`] are deleted, then the least recently used
+//! finished ones are dropped first.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use libp2p::{Swarm, kad};
+
+use crate::storage::client_db::ClientDatabase;
+use crate::storage::models::FileDownload;
+
+use super::behavior::ChatBehavior;
+
+/// Where blobs are written (partial ones included).
+pub const BLOB_DIR: &str = "data/blobs";
+
+/// Total size of the blobs kept on disk, partial ones included.
+pub const BLOB_CACHE_LIMIT: u64 = 512 * 1024 * 1024;
+
+/// A download whose blob was not written for this long is given up.
+pub const PARTIAL_BLOB_IDLE: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+pub fn blob_path(file_id: &str) -> PathBuf {
+    Path::new(BLOB_DIR).join(file_id)
+}
+
+/// Whether a blob exists and was last written more than [`PARTIAL_BLOB_IDLE`] ago.
+pub fn is_idle(file_id: &str) -> bool {
+    fs::metadata(blob_path(file_id))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|idle| idle > PARTIAL_BLOB_IDLE)
+}
+
+/// Size of a blob on disk, 0 if there is none.
+fn blob_size(file_id: &str) -> u64 {
+    fs::metadata(blob_path(file_id)).map_or(0, |metadata| metadata.len())
+}
+
+/// DHT key of a blob: the raw bytes of its id.
+pub fn provider_key(file_id: &str) -> Option<kad::RecordKey> {
+    hex::decode(file_id).ok().map(kad::RecordKey::from)
+}
+
+/// Announce that we can serve (part of) a blob.
+pub fn provide(swarm: &mut Swarm<ChatBehavior>, file_id: &str) {
+    let Some(key) = provider_key(file_id) else {
+        return;
+    };
+    match swarm.behaviour_mut().kad.start_providing(key) {
+        Ok(_) => log::debug!("Announced blob {file_id}"),
+        Err(err) => log::warn!("Failed to announce blob {file_id}: {err}"),
+    }
+}
+
+/// Announce every file we shared in a room and every blob we hold, once the
+/// DHT is reachable.
+pub fn announce_all(swarm: &mut Swarm<ChatBehavior>, db: Option<&ClientDatabase>) {
+    let Some(db) = db else {
+        return;
+    };
+    match db.get_shared_files() {
+        Ok(files) => {
+            for file in files.iter().filter(|file| file.public) {
+                provide(swarm, &file.attachment.file_id);
+            }
+        }
+        Err(err) => log::warn!("Failed to load shared files: {err}"),
+    }
+    match db.get_downloads() {
+        Ok(downloads) => {
+            for download in downloads {
+                let file_id = &download.attachment.file_id;
+                if download.public && download.chunk_hashes.is_some() && blob_path(file_id).exists()
+                {
+                    provide(swarm, file_id);
+                }
+            }
+        }
+        Err(err) => log::warn!("Failed to load downloads: {err}"),
+    }
+}
+
+/// Delete the partial blobs of downloads no longer running (`is_active` says
+/// which are), then drop the least recently used finished blobs until every
+/// blob on disk fits in [`BLOB_CACHE_LIMIT`]. The saved copies in the download
+/// directory are kept, and so are the blobs of running downloads.
+pub fn enforce_limit(
+    swarm: &mut Swarm<ChatBehavior>,
+    db: Option<&ClientDatabase>,
+    is_active: impl Fn(&str) -> bool,
+) {
+    let Some(db) = db else {
+        return;
+    };
+    let downloads = match db.get_downloads() {
+        Ok(downloads) => downloads,
+        Err(err) => {
+            log::warn!("Failed to load downloads: {err}");
+            return;
+        }
+    };
+
+    let mut total = 0;
+    let mut cached = Vec::new();
+    for download in downloads {
+        let file_id = &download.attachment.file_id;
+        let size = blob_size(file_id);
+        if size == 0 {
+            continue;
+        }
+        let finished = download.saved_path.is_some() && download.is_complete();
+        if is_active(file_id) {
+            total += size;
+        } else if finished && download.public {
+            total += size;
+            cached.push(download);
+        } else {
+            // Left behind by a failed or abandoned download
+            drop_blob(swarm, db, download);
+        }
+    }
+    cached.sort_by_key(|download| download.last_used);
+
+    for download in cached {
+        if total <= BLOB_CACHE_LIMIT {
+            break;
+        }
+        let size = blob_size(&download.attachment.file_id);
+        if drop_blob(swarm, db, download) {
+            total -= size;
+        }
+    }
+}
+
+/// Delete a blob we no longer keep and stop announcing it. Returns whether
+/// it was deleted.
+fn drop_blob(
+    swarm: &mut Swarm<ChatBehavior>,
+    db: &ClientDatabase,
+    mut download: FileDownload,
+) -> bool {
+    let file_id = download.attachment.file_id.clone();
+    if let Err(err) = fs::remove_file(blob_path(&file_id)) {
+        log::warn!("Failed to delete blob {file_id}: {err}");
+        return false;
+    }
+    // We no longer hold any chunk of it
+    download.received.fill(0);
+    if let Err(err) = db.save_download(&download) {
+        log::warn!("Failed to save dropped blob {file_id}: {err}");
+    }
+    if let Some(key) = provider_key(&file_id) {
+        swarm.behaviour_mut().kad.stop_providing(&key);
+    }
+    log::info!("Dropped blob {file_id} from the cache");
+    true
+}
@@ -3,8 +3,9 @@ use std::env;
 use std::error::Error;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use chrono::Utc;
 use futures::StreamExt;
@@ -12,21 +13,76 @@ use libp2p::gossipsub;
 use libp2p::identify;
 use libp2p::kad;
 use libp2p::multiaddr::Protocol;
+use libp2p::ping;
+use libp2p::request_response;
 use libp2p::swarm::{Config as SwarmConfig, SwarmEvent};
 use libp2p::{Multiaddr, PeerId, Swarm, identity};
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
-use crate::common::{ChatMessage, NetworkCommand, NetworkEvent, PeerStatus};
-use serde_json;
+use crate::common::{
+    ChatMessage, Conversation, DEFAULT_ROOM, DeliveryStatus, FileAttachment, MessageOp,
+    NetworkCommand, NetworkEvent, OpAction, PeerProfile, PeerStatus, normalize_room_name,
+};
+use crate::config::{FriendRefreshConfig, PeerScoringConfig};
+use crate::storage::client_db::ClientDatabase;
+use crate::storage::models::{Friend, FriendState, Message, SharedFile};
 
-use super::behavior::{ChatBehaviorEvent, build_behavior};
+use super::behavior::{ChatBehaviorEvent, build_behavior, room_score_params, room_topic};
+use super::blobs;
+use super::direct::{DirectEvent, DirectRequest, DirectResponse, DirectStatus};
+use super::e2e::{E2eError, E2eSessions, SealedMessage};
+use super::envelope::{
+    Envelope, EnvelopeError, MAX_RECEIPT_IDS, Payload, ReceiptKind, check_op_limits, content_id,
+    is_valid_reaction,
+};
+use super::files::{FileTransfers, prepare_file};
+use super::friends::{
+    FRIEND_REQUEST_INTERVAL, FriendEvent, FriendRequest, FriendResponse, MAX_FRIEND_NOTE_CHARS,
+    MAX_INCOMING_FRIEND_REQUESTS,
+};
+use super::mailbox::{
+    MAILBOX_PROTOCOL, MailboxEvent, MailboxItem, MailboxRequest, MailboxResponse,
+};
 use super::nat_traversal::NatTraversal;
+use super::ops::{sign_op, verify_op};
+use super::presence::{self, PRESENCE_KEY_LEN, PRESENCE_REPUBLISH_INTERVAL};
+use super::profile::{
+    PROFILE_PROTOCOL, PROFILE_REFRESH_INTERVAL, ProfileEvent, ProfileRequest, ProfileResponse,
+    load_avatar, sign_profile, verify_profile,
+};
+use super::reputation::{REPUTATION_SAVE_INTERVAL, Reputations};
+use super::sync::{
+    HistoryRequest, HistoryResponse, MAX_SYNC_MESSAGES, MAX_SYNC_ROOMS, SyncEvent, sync_page,
+};
 use super::transport::build_transport;
 
 const CLIENT_KEY_PATH: &str = "data/client_key.pk";
+/// Friend list of older versions, moved into the database on first start
 const FRIENDS_FILE: &str = "data/friends.json";
 const MAX_CONCURRENT_FRIEND_QUERIES: usize = 3;
+/// How often batched delivered receipts for room messages are sent
+const RECEIPT_FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+/// How often stalled downloads are retried (possibly over another relay)
+const FILE_RETRY_INTERVAL: Duration = Duration::from_secs(15);
+/// How often leftover blobs are deleted and the blob cache is trimmed
+const BLOB_PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How often the friend refresh schedule is looked at
+const FRIEND_SCHEDULER_TICK: Duration = Duration::from_secs(5);
+/// Longest petname and annotation kept for a friend, in characters
+const MAX_PETNAME_CHARS: usize = 64;
+const MAX_ANNOTATION_CHARS: usize = 1000;
+/// Tries at fetching the thread of a missing parent message before giving up
+const MAX_THREAD_ATTEMPTS: u32 = 4;
+/// Wait before fetching a thread again after a failed try; doubled every time
+const THREAD_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How often due thread fetches are retried
+const THREAD_RETRY_TICK: Duration = Duration::from_secs(5);
+/// Missing parent messages fetched at once; replies to others wait their turn
+const MAX_REQUESTED_THREADS: usize = 256;
+/// Messages one peer may publish in a room per [`RATE_WINDOW`]
+const RATE_LIMIT_MESSAGES: u32 = 50;
+const RATE_WINDOW: Duration = Duration::from_secs(10);
 
 pub struct P2PClient {
     event_sender: mpsc::Sender<NetworkEvent>,
@@ -34,14 +90,66 @@ pub struct P2PClient {
     bootstrap_peers: Vec<(PeerId, Multiaddr)>,
     enable_chat: bool,
     local_peer_id: Option<PeerId>,
+    /// Identity key, kept to sign edits and deletes of our own messages
+    local_key: Option<identity::Keypair>,
+    /// Mutually accepted friends: the only peers we exchange direct messages
+    /// and presence with
     friend_ids: HashSet<String>,
+    /// Friends and pending friend requests, as stored in the database
+    friends: HashMap<String, Friend>,
+    /// When each peer last sent us a friend request we kept
+    friend_requests_seen: HashMap<PeerId, Instant>,
+    /// Friend requests and answers awaiting the other side's response
+    friend_messages: HashMap<request_response::OutboundRequestId, PendingFriendMessage>,
+    /// Key our presence record is encrypted with, handed to accepted friends
+    presence_key: Vec<u8>,
     pending_friend_queries: HashMap<kad::QueryId, String>,
     friend_queue: VecDeque<String>,
+    /// When each friend is checked again
+    friend_schedule: HashMap<String, FriendSchedule>,
+    friend_refresh: FriendRefreshConfig,
+    /// Friends we are connected to but not yet heard a ping from (peer -> how
+    /// we are connected); they are reported online once the ping comes back
+    awaiting_ping: HashMap<PeerId, String>,
     bootstrap_completed: bool,
     auto_dial_query_id: Option<kad::QueryId>,
     dialed_peers: HashSet<PeerId>,
     peer_addresses: HashMap<PeerId, Vec<Multiaddr>>,
     nat_traversal: NatTraversal,
+    db: Option<ClientDatabase>,
+    /// Joined chat rooms, keyed by their gossipsub topic
+    rooms: HashMap<gossipsub::TopicHash, String>,
+    /// Outbound sync requests for a single thread (request -> parent message id)
+    thread_requests: HashMap<request_response::OutboundRequestId, String>,
+    /// Missing parent messages being fetched (parent message id -> fetch)
+    requested_threads: HashMap<String, ThreadFetch>,
+    /// Outbound direct messages awaiting acknowledgement
+    pending_direct: HashMap<request_response::OutboundRequestId, PendingDirect>,
+    /// Room messages by friends received since the last flush, to acknowledge
+    /// as delivered (author -> ids)
+    pending_delivered: HashMap<PeerId, Vec<String>>,
+    /// Whether read receipts are sent (user setting)
+    read_receipts: bool,
+    /// End-to-end ratchet sessions for direct messages (set up once the identity is loaded)
+    e2e: Option<E2eSessions>,
+    /// File downloads in progress
+    files: FileTransfers,
+    /// Configured nodemasters offering a mailbox for friends we cannot reach
+    mailbox_nodes: HashSet<PeerId>,
+    mailbox_requests: HashMap<request_response::OutboundRequestId, PendingMailbox>,
+    /// Latest verified profile of each peer, ours included
+    profiles: HashMap<String, PeerProfile>,
+    /// Peers that told identify they speak the profile protocol
+    profile_peers: HashSet<PeerId>,
+    /// When each peer's profile was last asked for
+    profile_checked: HashMap<PeerId, Instant>,
+    /// Peers the user blocked
+    blocked: HashSet<PeerId>,
+    /// Room messages recently published by each peer
+    rate_limiter: GossipRateLimiter,
+    peer_scoring: PeerScoringConfig,
+    /// Penalties of misbehaving peers, kept across restarts
+    reputations: Reputations,
 }
 
 impl P2PClient {
@@ -49,62 +157,332 @@ impl P2PClient {
         event_sender: mpsc::Sender<NetworkEvent>,
         command_receiver: mpsc::Receiver<NetworkCommand>,
         bootstrap_peers: Vec<(PeerId, Multiaddr)>,
+        friend_refresh: FriendRefreshConfig,
+        peer_scoring: PeerScoringConfig,
         enable_chat: bool,
     ) -> Self {
-        let friend_ids = load_friend_list_from_disk();
-        let friend_queue = friend_ids.iter().cloned().collect::<VecDeque<_>>();
         let bootstrap_peers_clone = bootstrap_peers.clone();
+        let db = match ClientDatabase::new() {
+            Ok(db) => Some(db),
+            Err(err) => {
+                log::error!("Failed to open client database: {err}");
+                None
+            }
+        };
+        let friends = load_friends(db.as_ref());
+        let friend_ids: HashSet<String> = friends
+            .values()
+            .filter(|friend| friend.state == FriendState::Accepted)
+            .map(|friend| friend.peer_id.clone())
+            .collect();
+        let friend_queue = friend_ids.iter().cloned().collect::<VecDeque<_>>();
+        let presence_key = load_presence_key(db.as_ref());
+        let read_receipts = db
+            .as_ref()
+            .and_then(|db| db.read_receipts_enabled().ok())
+            .unwrap_or(true);
+        let files = FileTransfers::new(db.as_ref());
+        let profiles = load_profiles(db.as_ref());
+        let blocked = load_blocked_peers(db.as_ref());
+        let reputations = Reputations::load(db.as_ref(), &peer_scoring);
         Self {
             event_sender,
             command_receiver,
             bootstrap_peers,
             enable_chat,
             local_peer_id: None,
+            local_key: None,
             friend_ids,
+            friends,
+            friend_requests_seen: HashMap::new(),
+            friend_messages: HashMap::new(),
+            presence_key,
             pending_friend_queries: HashMap::new(),
             friend_queue,
+            friend_schedule: HashMap::new(),
+            friend_refresh,
+            awaiting_ping: HashMap::new(),
             bootstrap_completed: false,
             auto_dial_query_id: None,
             dialed_peers: HashSet::new(),
             peer_addresses: HashMap::new(),
             nat_traversal: NatTraversal::new(bootstrap_peers_clone),
+            db,
+            rooms: HashMap::new(),
+            thread_requests: HashMap::new(),
+            requested_threads: HashMap::new(),
+            pending_direct: HashMap::new(),
+            pending_delivered: HashMap::new(),
+            read_receipts,
+            e2e: None,
+            files,
+            mailbox_nodes: HashSet::new(),
+            mailbox_requests: HashMap::new(),
+            profiles,
+            profile_peers: HashSet::new(),
+            profile_checked: HashMap::new(),
+            blocked,
+            rate_limiter: GossipRateLimiter::default(),
+            peer_scoring,
+            reputations,
         }
     }
 
-    async fn handle_add_friend(
+    async fn handle_send_friend_request(
         &mut self,
         peer_id: String,
+        note: String,
         swarm: &mut Swarm<super::behavior::ChatBehavior>,
     ) {
         let peer_id = peer_id.trim().to_string();
-        if peer_id.is_empty() {
+        let peer = match PeerId::from_str(&peer_id) {
+            Ok(peer) => peer,
+            Err(err) => {
+                log::warn!("Not sending a friend request to invalid PeerId {peer_id}: {err}");
+                return;
+            }
+        };
+        if Some(peer) == self.local_peer_id || self.blocked.contains(&peer) {
             return;
         }
+        match self.friends.get(&peer_id).map(|friend| friend.state) {
+            Some(FriendState::Accepted) => return,
+            // They asked first: asking back is accepting
+            Some(FriendState::Incoming) => {
+                self.handle_accept_friend(peer_id, swarm).await;
+                return;
+            }
+            _ => {}
+        }
+
+        let note: String = note.trim().chars().take(MAX_FRIEND_NOTE_CHARS).collect();
+        self.set_friend(Friend {
+            peer_id: peer_id.clone(),
+            state: FriendState::Outgoing,
+            note: note.clone(),
+            presence_key: None,
+            updated_at: Utc::now().timestamp(),
+            petname: String::new(),
+            annotation: String::new(),
+            key_delivered: false,
+        });
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::FriendRequestSent {
+                peer: peer_id,
+                note: note.clone(),
+            })
+            .await;
+        self.send_friend_message(peer, FriendRequest::Request { note }, swarm);
+    }
 
-        let was_new = self.friend_ids.insert(peer_id.clone());
-        if was_new {
-            self.persist_friend_list();
+    async fn handle_accept_friend(
+        &mut self,
+        peer_id: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let Ok(peer) = PeerId::from_str(&peer_id) else {
+            return;
+        };
+        let Some(friend) = self.friends.get(&peer_id).cloned() else {
+            return;
+        };
+        if friend.state != FriendState::Incoming {
+            return;
         }
+        self.set_friend(Friend {
+            state: FriendState::Accepted,
+            updated_at: Utc::now().timestamp(),
+            ..friend
+        });
+        self.friendship_started(&peer_id, swarm).await;
+        let presence_key = self.presence_key.clone();
+        self.send_friend_message(peer, FriendRequest::Accept { presence_key }, swarm);
+    }
 
-        match PeerId::from_str(&peer_id) {
-            Ok(_) => {
-                self.notify_friend_status(
-                    &peer_id,
-                    false,
-                    "Đang kiểm tra qua bootstrap node...",
-                )
-                .await;
-                self.enqueue_friend_check(&peer_id);
-                self.try_start_next_friend_queries(swarm);
-            }
+    /// Decline a request we received or withdraw one we sent.
+    async fn handle_decline_friend(
+        &mut self,
+        peer_id: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let Ok(peer) = PeerId::from_str(&peer_id) else {
+            return;
+        };
+        match self.friends.get(&peer_id).map(|friend| friend.state) {
+            Some(FriendState::Incoming | FriendState::Outgoing) => {}
+            _ => return,
+        }
+        self.forget_friend(&peer_id);
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::FriendRequestClosed { peer: peer_id })
+            .await;
+        // Best effort: a request they never hear back about just stays pending
+        self.send_friend_message(peer, FriendRequest::Decline, swarm);
+    }
+
+    /// End a friendship. The other side is told, and our presence key changes
+    /// so the records we publish from now on are unreadable to them.
+    async fn handle_remove_friend(
+        &mut self,
+        peer_id: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let Ok(peer) = PeerId::from_str(&peer_id) else {
+            return;
+        };
+        if self.friends.get(&peer_id).map(|friend| friend.state) != Some(FriendState::Accepted) {
+            return;
+        }
+        log::info!("Removing friend {peer_id}");
+        self.friendship_ended(&peer_id, swarm).await;
+        self.send_friend_message(peer, FriendRequest::Decline, swarm);
+    }
+
+    async fn handle_update_friend(&mut self, peer_id: String, petname: String, annotation: String) {
+        let Some(friend) = self.friends.get_mut(&peer_id) else {
+            return;
+        };
+        friend.petname = petname.trim().chars().take(MAX_PETNAME_CHARS).collect();
+        friend.annotation = annotation
+            .trim()
+            .chars()
+            .take(MAX_ANNOTATION_CHARS)
+            .collect();
+        if let Some(db) = &self.db
+            && let Err(err) = db.set_friend_names(&peer_id, &friend.petname, &friend.annotation)
+        {
+            log::warn!("Failed to save name of friend {peer_id}: {err}");
+        }
+        let event = NetworkEvent::FriendInfo {
+            peer: peer_id,
+            petname: friend.petname.clone(),
+            annotation: friend.annotation.clone(),
+        };
+        let _ = self.event_sender.send(event).await;
+    }
+
+    /// Block a peer: its connections are closed and refused, gossipsub drops
+    /// what it publishes, and a friendship or pending request with it ends.
+    async fn handle_block_peer(
+        &mut self,
+        peer_id: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let peer_id = peer_id.trim().to_string();
+        let peer = match PeerId::from_str(&peer_id) {
+            Ok(peer) => peer,
             Err(err) => {
-                self.notify_friend_status(
-                    &peer_id,
-                    false,
-                    format!("PeerId không hợp lệ: {err}"),
-                )
-                .await;
+                log::warn!("Not blocking invalid PeerId {peer_id}: {err}");
+                return;
+            }
+        };
+        if Some(peer) == self.local_peer_id || !self.blocked.insert(peer) {
+            return;
+        }
+        log::info!("Blocking {peer}");
+        if let Some(db) = &self.db
+            && let Err(err) = db.block_peer(&peer_id)
+        {
+            log::warn!("Failed to save block of {peer_id}: {err}");
+        }
+        swarm.behaviour_mut().gossipsub.blacklist_peer(&peer);
+        swarm.behaviour_mut().block_list.block_peer(peer);
+
+        match self.friends.get(&peer_id).map(|friend| friend.state) {
+            Some(FriendState::Accepted) => self.friendship_ended(&peer_id, swarm).await,
+            Some(FriendState::Incoming | FriendState::Outgoing) => {
+                self.forget_friend(&peer_id);
+                let _ = self
+                    .event_sender
+                    .send(NetworkEvent::FriendRequestClosed {
+                        peer: peer_id.clone(),
+                    })
+                    .await;
             }
+            None => {}
+        }
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::PeerBlocked { peer: peer_id })
+            .await;
+    }
+
+    async fn handle_unblock_peer(
+        &mut self,
+        peer_id: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let Ok(peer) = PeerId::from_str(&peer_id) else {
+            return;
+        };
+        if !self.blocked.remove(&peer) {
+            return;
+        }
+        log::info!("Unblocking {peer}");
+        if let Some(db) = &self.db
+            && let Err(err) = db.unblock_peer(&peer_id)
+        {
+            log::warn!("Failed to save unblock of {peer_id}: {err}");
+        }
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .remove_blacklisted_peer(&peer);
+        swarm.behaviour_mut().block_list.unblock_peer(peer);
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::PeerUnblocked { peer: peer_id })
+            .await;
+    }
+
+    async fn handle_set_profile(
+        &mut self,
+        display_name: String,
+        status: String,
+        avatar_path: Option<String>,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let (Some(local_key), Some(local_peer_id)) = (&self.local_key, self.local_peer_id) else {
+            return;
+        };
+        let previous = self.profiles.get(&local_peer_id.to_string());
+        let avatar = match avatar_path.as_deref().map(str::trim) {
+            None => previous
+                .map(|profile| profile.avatar.clone())
+                .unwrap_or_default(),
+            Some("") => Vec::new(),
+            Some(path) => match load_avatar(Path::new(path)) {
+                Ok(avatar) => avatar,
+                Err(err) => {
+                    let _ = self
+                        .event_sender
+                        .send(NetworkEvent::ProfileError(format!(
+                            "Không dùng được ảnh {path}: {err}"
+                        )))
+                        .await;
+                    return;
+                }
+            },
+        };
+        let Some(profile) = sign_profile(local_key, &display_name, &status, avatar, previous)
+        else {
+            return;
+        };
+        log::info!("Profile updated, sending it to connected peers");
+        self.store_profile(profile.clone()).await;
+        let connected: Vec<PeerId> = self
+            .profile_peers
+            .iter()
+            .filter(|peer| swarm.is_connected(peer))
+            .copied()
+            .collect();
+        for peer in connected {
+            swarm
+                .behaviour_mut()
+                .profile
+                .send_request(&peer, ProfileRequest::Update(profile.clone()));
         }
     }
 
@@ -112,13 +490,27 @@ impl P2PClient {
         
         let local_key = load_or_generate_local_key()?;
         let local_peer_id = PeerId::from(local_key.public());
-        self.local_peer_id = Some(local_peer_id.clone());
+        self.local_peer_id = Some(local_peer_id);
         log::info!("Local PeerID: {local_peer_id:?}");
+        self.e2e = E2eSessions::new(&local_key);
+        if self.e2e.is_none() {
+            log::warn!("Identity key is not ed25519; direct messages are disabled");
+        }
+        self.local_key = Some(local_key.clone());
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::LocalPeerId(local_peer_id.to_string()))
+            .await;
 
         // Build transport and get relay behaviour (they must be created together)
         let (transport, relay_behaviour) = build_transport(&local_key, local_peer_id)?;
         // Pass relay behaviour to build_behavior to ensure they're linked
-        let (behavior, topic) = build_behavior(&local_key, local_peer_id, relay_behaviour)?;
+        let behavior = build_behavior(
+            &local_key,
+            local_peer_id,
+            relay_behaviour,
+            &self.peer_scoring,
+        )?;
 
         let mut swarm = Swarm::new(
             transport,
@@ -127,6 +519,14 @@ impl P2PClient {
             SwarmConfig::with_tokio_executor(),
         );
 
+        for room in self.stored_rooms() {
+            self.subscribe_room(&room, &mut swarm);
+        }
+        for peer in &self.blocked {
+            swarm.behaviour_mut().gossipsub.blacklist_peer(peer);
+            swarm.behaviour_mut().block_list.block_peer(*peer);
+        }
+
         if let Some(public_addr) = client_public_addr_from_env() {
             log::info!("Announcing client public address: {}", public_addr);
             swarm.add_external_address(public_addr);
@@ -164,15 +564,22 @@ impl P2PClient {
 
         log::info!("Network event loop started");
         self.emit_initial_friend_placeholders().await;
-        self.enqueue_all_friend_checks();
-        self.try_start_next_friend_queries(&mut swarm);
+
+        let mut receipt_flush = tokio::time::interval(RECEIPT_FLUSH_INTERVAL);
+        let mut file_retry = tokio::time::interval(FILE_RETRY_INTERVAL);
+        let mut blob_prune = tokio::time::interval(BLOB_PRUNE_INTERVAL);
+        let mut presence_publish = tokio::time::interval(PRESENCE_REPUBLISH_INTERVAL);
+        // The first tick checks every friend right away
+        let mut friend_scheduler = tokio::time::interval(FRIEND_SCHEDULER_TICK);
+        let mut reputation_save = tokio::time::interval(REPUTATION_SAVE_INTERVAL);
+        let mut thread_retry = tokio::time::interval(THREAD_RETRY_TICK);
 
         loop {
             tokio::select! {
                 command = self.command_receiver.recv() => {
                     match command {
                         Some(command) => {
-                            self.handle_command(command, &mut swarm, &topic, local_peer_id).await;
+                            self.handle_command(command, &mut swarm, local_peer_id).await;
                         }
                         None => break,
                     }
@@ -180,9 +587,36 @@ impl P2PClient {
                 event = swarm.select_next_some() => {
                     self.handle_swarm_event(event, &mut swarm).await;
                 }
+                _ = receipt_flush.tick() => {
+                    self.flush_delivered_receipts(&mut swarm);
+                }
+                _ = file_retry.tick() => {
+                    self.files.resume_all(&mut swarm, &self.nat_traversal);
+                }
+                _ = blob_prune.tick() => {
+                    for event in self.files.prune(&mut swarm, self.db.as_ref()) {
+                        let _ = self.event_sender.send(event).await;
+                    }
+                }
+                _ = friend_scheduler.tick() => {
+                    self.refresh_friends(&mut swarm).await;
+                }
+                _ = presence_publish.tick() => {
+                    // Until bootstrap completes there is nobody to store it
+                    if self.bootstrap_completed {
+                        self.publish_presence(&mut swarm);
+                    }
+                }
+                _ = reputation_save.tick() => {
+                    self.reputations.save(&mut swarm, self.db.as_ref());
+                }
+                _ = thread_retry.tick() => {
+                    self.retry_thread_fetches(&mut swarm);
+                }
             }
         }
 
+        self.reputations.save(&mut swarm, self.db.as_ref());
         Ok(())
     }
 
@@ -190,54 +624,144 @@ impl P2PClient {
         &mut self,
         command: NetworkCommand,
         swarm: &mut Swarm<super::behavior::ChatBehavior>,
-        topic: &gossipsub::IdentTopic,
         local_peer_id: PeerId,
     ) {
         match command {
-            NetworkCommand::SendMessage(content) => {
+            NetworkCommand::SendMessage {
+                room,
+                content,
+                reply_to,
+            } => {
                 if !self.enable_chat {
                     log::warn!("Chat feature disabled; ignoring SendMessage command");
                     return;
                 }
                 let msg = ChatMessage {
-                    id: Uuid::new_v4().to_string(),
-                    sender: local_peer_id.to_string(),
-                    content: content.clone(),
-                    timestamp: Utc::now().timestamp(),
+                    reply_to,
+                    ..new_chat_message(local_peer_id, room, content)
                 };
-
-                match serde_json::to_vec(&msg) {
-                    Ok(json_bytes) => {
-                        if let Err(err) = swarm
-                            .behaviour_mut()
-                            .gossipsub
-                            .publish(topic.clone(), json_bytes)
-                        {
-                            log::warn!("Publish error: {err:?}");
-                        } else if let Err(err) = self
-                            .event_sender
-                            .send(NetworkEvent::MessageReceived(msg))
-                            .await
-                        {
-                            log::warn!("Failed to notify UI about self message: {err:?}");
+                self.send_room_message(msg, swarm).await;
+            }
+            NetworkCommand::SyncRequest { to_peer } => {
+                if !self.enable_chat {
+                    log::warn!("Chat feature disabled; ignoring SyncRequest command");
+                    return;
+                }
+                match PeerId::from_str(&to_peer) {
+                    Ok(peer) => {
+                        log::info!("Requesting history from {peer}");
+                        // One request per room, so a busy room cannot hide a quiet one
+                        for room in self.joined_rooms() {
+                            self.request_room_history(peer, room, swarm);
                         }
                     }
                     Err(err) => {
-                        log::warn!("Failed to serialize message: {err:?}");
+                        log::warn!("Invalid peer id for SyncRequest `{to_peer}`: {err}");
                     }
                 }
             }
-            NetworkCommand::SyncRequest {
+            NetworkCommand::JoinRoom { room } => {
+                if !self.enable_chat {
+                    log::warn!("Chat feature disabled; ignoring JoinRoom command");
+                    return;
+                }
+                self.handle_join_room(room, swarm).await;
+            }
+            NetworkCommand::LeaveRoom { room } => {
+                self.handle_leave_room(room, swarm).await;
+            }
+            NetworkCommand::MarkRead {
+                conversation,
+                message_ids,
+            } => {
+                self.handle_mark_read(conversation, message_ids, swarm);
+            }
+            NetworkCommand::EditMessage {
+                conversation,
+                message_id,
+                content,
+            } => {
+                if !self.enable_chat {
+                    log::warn!("Chat feature disabled; ignoring EditMessage command");
+                    return;
+                }
+                self.send_op(conversation, message_id, OpAction::Edit { content }, swarm)
+                    .await;
+            }
+            NetworkCommand::DeleteMessage {
+                conversation,
+                message_id,
+            } => {
+                if !self.enable_chat {
+                    log::warn!("Chat feature disabled; ignoring DeleteMessage command");
+                    return;
+                }
+                self.send_op(conversation, message_id, OpAction::Delete, swarm)
+                    .await;
+            }
+            NetworkCommand::AddReaction {
+                conversation,
+                message_id,
+                emoji,
+            } => {
+                if self.enable_chat {
+                    self.send_reaction(conversation, message_id, emoji, true, swarm)
+                        .await;
+                }
+            }
+            NetworkCommand::RemoveReaction {
+                conversation,
+                message_id,
+                emoji,
+            } => {
+                if self.enable_chat {
+                    self.send_reaction(conversation, message_id, emoji, false, swarm)
+                        .await;
+                }
+            }
+            NetworkCommand::SendFile { conversation, path } => {
+                if !self.enable_chat {
+                    log::warn!("Chat feature disabled; ignoring SendFile command");
+                    return;
+                }
+                self.send_file(conversation, path, swarm, local_peer_id)
+                    .await;
+            }
+            NetworkCommand::DownloadFile {
+                conversation,
+                peer_id,
+                attachment,
+            } => {
+                let public = matches!(conversation, Conversation::Room(_));
+                self.download_file(peer_id, attachment, public, swarm).await;
+            }
+            NetworkCommand::Typing { conversation } => {
+                if self.enable_chat {
+                    self.send_typing(conversation, swarm, local_peer_id);
+                }
+            }
+            NetworkCommand::SetReadReceipts(enabled) => {
+                self.read_receipts = enabled;
+                if let Some(db) = &self.db
+                    && let Err(err) = db.set_read_receipts_enabled(enabled)
+                {
+                    log::warn!("Failed to save read receipt setting: {err}");
+                }
+            }
+            NetworkCommand::SendDirect {
                 to_peer,
-                last_timestamp,
+                content,
+                reply_to,
             } => {
                 if !self.enable_chat {
-                    log::warn!("Chat feature disabled; ignoring SyncRequest command");
+                    log::warn!("Chat feature disabled; ignoring SendDirect command");
                     return;
                 }
-                log::warn!(
-                    "SyncRequest not implemented (to_peer={to_peer}, last_timestamp={last_timestamp})"
-                );
+                let msg = ChatMessage {
+                    reply_to,
+                    ..new_chat_message(local_peer_id, String::new(), content)
+                };
+                self.send_direct(to_peer, msg, swarm).await;
             }
             NetworkCommand::ConnectToPeer { address } => {
                 match address.parse::<Multiaddr>() {
@@ -267,8 +791,38 @@ impl P2PClient {
                     }
                 }
             }
-            NetworkCommand::AddFriend { peer_id } => {
-                self.handle_add_friend(peer_id, swarm).await;
+            NetworkCommand::SendFriendRequest { peer_id, note } => {
+                self.handle_send_friend_request(peer_id, note, swarm).await;
+            }
+            NetworkCommand::AcceptFriend { peer_id } => {
+                self.handle_accept_friend(peer_id, swarm).await;
+            }
+            NetworkCommand::DeclineFriend { peer_id } => {
+                self.handle_decline_friend(peer_id, swarm).await;
+            }
+            NetworkCommand::RemoveFriend { peer_id } => {
+                self.handle_remove_friend(peer_id, swarm).await;
+            }
+            NetworkCommand::UpdateFriend {
+                peer_id,
+                petname,
+                annotation,
+            } => {
+                self.handle_update_friend(peer_id, petname, annotation).await;
+            }
+            NetworkCommand::BlockPeer { peer_id } => {
+                self.handle_block_peer(peer_id, swarm).await;
+            }
+            NetworkCommand::UnblockPeer { peer_id } => {
+                self.handle_unblock_peer(peer_id, swarm).await;
+            }
+            NetworkCommand::SetProfile {
+                display_name,
+                status,
+                avatar_path,
+            } => {
+                self.handle_set_profile(display_name, status, avatar_path, swarm)
+                    .await;
             }
         }
     }
@@ -280,15 +834,12 @@ impl P2PClient {
     ) {
         match event {
             SwarmEvent::Behaviour(ChatBehaviorEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
                 message,
-                ..
             })) => {
-                if let Ok(chat_msg) = serde_json::from_slice::<ChatMessage>(&message.data) {
-                    let _ = self
-                        .event_sender
-                        .send(NetworkEvent::MessageReceived(chat_msg))
-                        .await;
-                }
+                self.handle_gossip_message(propagation_source, message_id, message, swarm)
+                    .await;
             }
             SwarmEvent::Behaviour(ChatBehaviorEvent::Identify(event)) => {
                 self.handle_identify_event(event, swarm).await;
@@ -307,6 +858,30 @@ impl P2PClient {
             }
             SwarmEvent::Behaviour(ChatBehaviorEvent::Ping(event)) => {
                 log::trace!("Ping event: {:?}", event);
+                self.handle_ping_event(event).await;
+            }
+            SwarmEvent::Behaviour(ChatBehaviorEvent::Sync(event)) => {
+                self.handle_sync_event(event, swarm).await;
+            }
+            SwarmEvent::Behaviour(ChatBehaviorEvent::Direct(event)) => {
+                self.handle_direct_event(event, swarm).await;
+            }
+            SwarmEvent::Behaviour(ChatBehaviorEvent::Mailbox(event)) => {
+                self.handle_mailbox_event(event, swarm).await;
+            }
+            SwarmEvent::Behaviour(ChatBehaviorEvent::Friends(event)) => {
+                self.handle_friend_event(event, swarm).await;
+            }
+            SwarmEvent::Behaviour(ChatBehaviorEvent::Profile(event)) => {
+                self.handle_profile_event(event, swarm).await;
+            }
+            SwarmEvent::Behaviour(ChatBehaviorEvent::Files(event)) => {
+                let events =
+                    self.files
+                        .handle_event(event, swarm, self.db.as_ref(), &self.nat_traversal);
+                for event in events {
+                    let _ = self.event_sender.send(event).await;
+                }
             }
             SwarmEvent::NewListenAddr { address, .. } => {
                 log::info!("Listening on {address:?}");
@@ -321,25 +896,39 @@ impl P2PClient {
                     log::info!("Connected to {} via relay", peer_id);
                 }
                 
+                // Penalties from earlier runs count from the start
+                self.reputations.apply(peer_id, swarm);
+
+                // Downloads from this peer continue over the new connection
+                self.files.resume_peer(peer_id, swarm, &self.nat_traversal);
+                // So does an unanswered friend request
+                self.deliver_friend_handshake(peer_id, swarm);
+
                 let peer_id_str = peer_id.to_string();
                 let _ = self
                     .event_sender
                     .send(NetworkEvent::PeerConnected(peer_id_str.clone()))
                     .await;
                 if self.friend_ids.contains(&peer_id_str) {
+                    // Online once the first ping on this connection comes back
+                    let via = if endpoint.is_relayed() {
+                        "Đã kết nối qua relay"
+                    } else {
+                        "Đã kết nối trực tiếp tới bạn"
+                    };
+                    self.awaiting_ping.insert(peer_id, via.to_string());
                     self.notify_friend_status(
                         &peer_id_str,
-                        true,
-                        if endpoint.is_relayed() {
-                            "Đã kết nối qua relay"
-                        } else {
-                            "Đã kết nối trực tiếp tới bạn"
-                        },
+                        false,
+                        format!("{via}, đang chờ phản hồi ping..."),
                     )
                     .await;
                 }
             }
             SwarmEvent::ConnectionClosed { peer_id, cause: _, .. } => {
+                if !swarm.is_connected(&peer_id) {
+                    self.awaiting_ping.remove(&peer_id);
+                }
                 let peer_id_str = peer_id.to_string();
                 let _ = self
                     .event_sender
@@ -354,28 +943,31 @@ impl P2PClient {
                     .await;
                 }
             }
-            SwarmEvent::Dialing { peer_id, .. } => {
-                if let Some(peer) = peer_id {
-                    log::debug!("Dialing peer {}", peer);
-                }
+            SwarmEvent::Dialing {
+                peer_id: Some(peer),
+                ..
+            } => {
+                log::debug!("Dialing peer {}", peer);
             }
-            SwarmEvent::OutgoingConnectionError { peer_id, error, connection_id: _ } => {
-                if let Some(peer) = peer_id {
-                    log::warn!("Outgoing connection error to {}: {:?}", peer, error);
-                    // Track failed direct connection for relay retry
-                    // Check if error is not transient (e.g., not a timeout that might succeed later)
-                    let should_retry = match &error {
-                        libp2p::swarm::DialError::Transport(_) => true,
-                        libp2p::swarm::DialError::NoAddresses => true,
-                        libp2p::swarm::DialError::Denied { .. } => false,
-                        libp2p::swarm::DialError::Aborted => false,
-                        _ => true, // Default to retry for other errors
-                    };
-                    if should_retry {
-                        self.nat_traversal.mark_failed_direct(peer);
-                        // Try relay connection if available
-                        self.nat_traversal.retry_with_relay(peer, swarm, &self.dialed_peers).await;
-                    }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer),
+                error,
+                connection_id: _,
+            } => {
+                log::warn!("Outgoing connection error to {}: {:?}", peer, error);
+                // Track failed direct connection for relay retry
+                // Check if error is not transient (e.g., not a timeout that might succeed later)
+                let should_retry = match &error {
+                    libp2p::swarm::DialError::Transport(_) => true,
+                    libp2p::swarm::DialError::NoAddresses => true,
+                    libp2p::swarm::DialError::Denied { .. } => false,
+                    libp2p::swarm::DialError::Aborted => false,
+                    _ => true, // Default to retry for other errors
+                };
+                if should_retry {
+                    self.nat_traversal.mark_failed_direct(peer);
+                    // Try relay connection if available
+                    self.nat_traversal.retry_with_relay(peer, swarm, &self.dialed_peers).await;
                 }
             }
             _ => {}
@@ -403,6 +995,21 @@ impl P2PClient {
                     .kad
                     .add_address(&peer_id, addr.clone());
             }
+
+            // Collect what friends left for us while we were offline. Only the
+            // configured nodemasters are trusted to keep and hand back our mail;
+            // any other peer could advertise the protocol and swallow it.
+            if info.protocols.contains(&MAILBOX_PROTOCOL) && self.is_bootstrap_peer(&peer_id) {
+                if self.mailbox_nodes.insert(peer_id) {
+                    log::info!("Nodemaster {peer_id} offers a mailbox");
+                }
+                self.fetch_mailbox(peer_id, swarm);
+            }
+
+            if info.protocols.contains(&PROFILE_PROTOCOL) {
+                self.profile_peers.insert(peer_id);
+                self.fetch_profile(peer_id, swarm);
+            }
         }
     }
 
@@ -412,7 +1019,9 @@ impl P2PClient {
         swarm: &mut Swarm<super::behavior::ChatBehavior>,
     ) {
         match event {
-            kad::Event::OutboundQueryProgressed { id, result, .. } => {
+            kad::Event::OutboundQueryProgressed {
+                id, result, step, ..
+            } => {
                 match result {
                     kad::QueryResult::Bootstrap(res) => {
                         match res {
@@ -423,6 +1032,8 @@ impl P2PClient {
                                 // When bootstrap completes, query DHT for peers to dial
                                 if num_remaining == 0 && !self.bootstrap_completed {
                                     self.bootstrap_completed = true;
+                                    blobs::announce_all(swarm, self.db.as_ref());
+                                    self.publish_presence(swarm);
                                     self.start_auto_dial_from_dht(swarm).await;
                                 }
                             }
@@ -431,20 +1042,53 @@ impl P2PClient {
                             }
                         }
                     }
-                    kad::QueryResult::GetClosestPeers(res) => {
-                        // Check if this is the auto-dial query
-                        if let Some(query_id) = self.auto_dial_query_id {
-                            if id == query_id {
-                                self.auto_dial_query_id = None;
-                                self.handle_auto_dial_result(res, swarm).await;
-                                return;
+                    // The only closest-peers query is the auto-dial one
+                    kad::QueryResult::GetClosestPeers(res)
+                        if self.auto_dial_query_id == Some(id) =>
+                    {
+                        self.auto_dial_query_id = None;
+                        self.handle_auto_dial_result(res, swarm).await;
+                    }
+                    kad::QueryResult::GetRecord(res) => {
+                        let Some(peer_id) = self.pending_friend_queries.get(&id).cloned() else {
+                            return;
+                        };
+                        let found = self.handle_presence_result(&peer_id, res, swarm).await;
+                        if found || step.last {
+                            self.pending_friend_queries.remove(&id);
+                            if let Some(mut query) = swarm.behaviour_mut().kad.query_mut(&id) {
+                                query.finish();
+                            }
+                            if !found {
+                                self.notify_friend_status(
+                                    &peer_id,
+                                    false,
+                                    "Không có bản ghi presence còn hạn (bạn đang offline)",
+                                )
+                                .await;
                             }
+                            self.try_start_next_friend_queries(swarm);
                         }
-                        // Otherwise handle friend queries
-                        if let Some(peer_id) = self.pending_friend_queries.remove(&id) {
-                            self.handle_friend_lookup_result(peer_id, res).await;
+                    }
+                    kad::QueryResult::PutRecord(res) => match res {
+                        Ok(_) => log::debug!("Presence record published"),
+                        Err(err) => log::debug!("Failed to publish presence record: {err}"),
+                    },
+                    kad::QueryResult::GetProviders(res) => {
+                        let events = self.files.handle_providers(
+                            id,
+                            res,
+                            step.last,
+                            swarm,
+                            &self.nat_traversal,
+                        );
+                        for event in events {
+                            let _ = self.event_sender.send(event).await;
                         }
                     }
+                    kad::QueryResult::StartProviding(Err(err)) => {
+                        log::debug!("Failed to publish provider record: {err}");
+                    }
                     _ => {}
                 }
             }
@@ -462,7 +1106,7 @@ impl P2PClient {
                     // Auto-dial if bootstrap completed and peer not yet dialed
                     if self.bootstrap_completed 
                         && !self.dialed_peers.contains(&peer)
-                        && !self.bootstrap_peers.iter().any(|(pid, _)| *pid == peer)
+                        && !self.is_bootstrap_peer(&peer)
                     {
                         self.try_dial_peer(peer, addr_vec, swarm);
                     }
@@ -472,274 +1116,2803 @@ impl P2PClient {
         }
     }
 
-
-    async fn notify_friend_status(
-        &self,
-        peer_id: &str,
-        online: bool,
-        message: impl Into<String>,
+    /// Validate a gossip message before gossipsub forwards it (manual validation mode).
+    async fn handle_gossip_message(
+        &mut self,
+        propagation_source: PeerId,
+        message_id: gossipsub::MessageId,
+        message: gossipsub::Message,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
     ) {
-        let status = PeerStatus {
-            peer_id: peer_id.to_string(),
-            online,
-            message: message.into(),
-            checked_at: Utc::now().timestamp(),
+        // Counted before decoding, so a flood costs as little as possible
+        let rate = message.source.map_or(RateVerdict::Allowed, |author| {
+            self.rate_limiter
+                .check(author, &message.topic, Instant::now())
+        });
+        let verdict = match (self.rooms.get(&message.topic), rate) {
+            // Not our room (e.g. just left it): drop without penalising anyone
+            (None, _) => Err((
+                gossipsub::MessageAcceptance::Ignore,
+                "not subscribed to topic".to_string(),
+            )),
+            (Some(room), RateVerdict::Allowed) => validate_gossip_message(&message, room),
+            // Ignored rather than rejected: rejecting would cost the neighbours
+            // that forwarded the flood their score, not its author
+            (Some(_), rate) => {
+                if let (RateVerdict::Flooding, Some(author)) = (rate, message.source) {
+                    log::warn!("{author} is flooding {}", message.topic);
+                    self.reputations.penalise(author, swarm);
+                }
+                Err((
+                    gossipsub::MessageAcceptance::Ignore,
+                    format!(
+                        "more than {RATE_LIMIT_MESSAGES} messages in {}s",
+                        RATE_WINDOW.as_secs()
+                    ),
+                ))
+            }
         };
-        if let Err(err) = self
-            .event_sender
-            .send(NetworkEvent::FriendStatus(status))
-            .await
+        let acceptance = match &verdict {
+            Ok(_) => gossipsub::MessageAcceptance::Accept,
+            Err((gossipsub::MessageAcceptance::Reject, _)) => gossipsub::MessageAcceptance::Reject,
+            Err(_) => gossipsub::MessageAcceptance::Ignore,
+        };
+
+        if !swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(&message_id, &propagation_source, acceptance)
         {
-            log::warn!("Failed to emit friend status event: {err}");
+            log::debug!("Message {message_id} expired before it was validated");
+        }
+
+        match verdict {
+            Ok(envelope) => {
+                self.handle_room_envelope(envelope, &message.data, propagation_source, swarm)
+                    .await
+            }
+            Err((gossipsub::MessageAcceptance::Reject, reason)) => {
+                log::warn!("Rejected gossip message from {propagation_source}: {reason}");
+                // The author is held responsible; gossipsub already penalises the forwarder.
+                // Kinds and wire versions we don't know are ignored, never rejected, so
+                // newer clients are not penalised here.
+                let author = message.source.unwrap_or(propagation_source);
+                self.reputations.penalise(author, swarm);
+                let _ = self
+                    .event_sender
+                    .send(NetworkEvent::MessageRejected {
+                        peer: propagation_source.to_string(),
+                        reason,
+                    })
+                    .await;
+            }
+            Err((_, reason)) => {
+                log::debug!("Ignored gossip message from {propagation_source}: {reason}");
+            }
         }
     }
 
-    async fn emit_initial_friend_placeholders(&self) {
-        for peer_id in &self.friend_ids {
-            self.notify_friend_status(
-                peer_id,
-                false,
-                "Đang chờ kiểm tra trạng thái qua bootstrap...",
-            )
-            .await;
+    /// `encoded` is the envelope as its author signed it; `source` is the peer
+    /// that forwarded it to us.
+    async fn handle_room_envelope(
+        &mut self,
+        envelope: Envelope,
+        encoded: &[u8],
+        source: PeerId,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        match &envelope.payload {
+            Payload::Receipt { target_ids, kind } => {
+                self.apply_receipt(&envelope.sender, target_ids, *kind)
+                    .await;
+                return;
+            }
+            Payload::Typing => {
+                let conversation = Conversation::Room(envelope.room);
+                self.notify_typing(conversation, envelope.sender).await;
+                return;
+            }
+            // Author, room and signature were checked during gossip validation
+            Payload::Op(op) => {
+                self.apply_op(op.clone()).await;
+                return;
+            }
+            Payload::Reaction { target_id, emoji } => {
+                self.apply_reaction(&envelope.sender, target_id, emoji, envelope.timestamp, true)
+                    .await;
+                return;
+            }
+            Payload::ReactionRemoved { target_id, emoji } => {
+                self.apply_reaction(
+                    &envelope.sender,
+                    target_id,
+                    emoji,
+                    envelope.timestamp,
+                    false,
+                )
+                .await;
+                return;
+            }
+            _ => {}
+        }
+        let id = envelope.id.clone();
+        let Some(mut chat_msg) = envelope.into_room_message(encoded) else {
+            log::debug!("Message {id} has a kind this client does not display yet");
+            return;
+        };
+        if !self.store_message(&chat_msg, encoded) {
+            log::debug!("Dropping duplicate message {}", chat_msg.id);
+            return;
+        }
+        self.apply_stored_op(&mut chat_msg);
+        self.request_missing_parent(&chat_msg, source, swarm);
+        if let Ok(sender) = PeerId::from_str(&chat_msg.sender) {
+            self.fetch_profile(sender, swarm);
+            // Acknowledged in batches by `flush_delivered_receipts`
+            if self.friend_ids.contains(&chat_msg.sender) {
+                self.pending_delivered
+                    .entry(sender)
+                    .or_default()
+                    .push(chat_msg.id.clone());
+            }
         }
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::MessageReceived(chat_msg))
+            .await;
     }
 
-    fn enqueue_all_friend_checks(&mut self) {
-        let all_ids: Vec<String> = self.friend_ids.iter().cloned().collect();
-        for peer_id in all_ids {
-            self.enqueue_friend_check(&peer_id);
+    async fn handle_sync_event(
+        &mut self,
+        event: SyncEvent,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        match event {
+            request_response::Event::Message { peer, message, .. } => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    let since = request.since;
+                    let response = self.serve_history(request);
+                    log::info!(
+                        "Serving {} history messages to {peer} (since {since})",
+                        response.messages.len()
+                    );
+                    if swarm
+                        .behaviour_mut()
+                        .sync
+                        .send_response(channel, response)
+                        .is_err()
+                    {
+                        log::warn!("Failed to send history response to {peer}: channel closed");
+                    }
+                }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    let thread = self.thread_requests.remove(&request_id);
+                    if let Some(parent_id) = &thread {
+                        log::debug!("Thread {parent_id} answered by {peer}");
+                    }
+                    let received = response.messages.len();
+                    log::info!(
+                        "Received {received} history messages and {} ops from {peer}",
+                        response.ops.len()
+                    );
+                    if received == 0 && response.ops.is_empty() {
+                        if let Some(parent_id) = thread {
+                            self.settle_thread_fetch(parent_id, false).await;
+                        }
+                        return;
+                    }
+
+                    // The peer holds more; continue after the newest message (several
+                    // messages can share its second). Thread requests are a single page.
+                    if thread.is_none()
+                        && response.more
+                        && let Some(encoded) = response.messages.last()
+                        && let Ok(last) = Envelope::decode(encoded)
+                    {
+                        swarm.behaviour_mut().sync.send_request(
+                            &peer,
+                            HistoryRequest {
+                                since: last.timestamp,
+                                after_id: Some(content_id(encoded)),
+                                rooms: vec![last.room],
+                                thread: None,
+                            },
+                        );
+                    }
+
+                    // Only keep rooms we are still in
+                    let joined = self.joined_rooms();
+                    let mut messages = Vec::new();
+                    let mut thread_found = false;
+                    for encoded in &response.messages {
+                        let mut message = match validate_synced_message(encoded, &joined) {
+                            Ok(message) => message,
+                            Err(reason) => {
+                                log::debug!("Dropping synced message from {peer}: {reason}");
+                                continue;
+                            }
+                        };
+                        // Ids are content ids, so only the parent itself has its id; a thread
+                        // response carries nothing but the parent and its replies
+                        if let Some(parent_id) = &thread {
+                            if *parent_id == message.id {
+                                thread_found = true;
+                            } else if message.reply_to.as_ref() != Some(parent_id) {
+                                log::debug!(
+                                    "Dropping message outside thread {parent_id} from {peer}"
+                                );
+                                continue;
+                            }
+                        }
+                        // Only pass on what we did not already have
+                        if self.is_blocked(&message.sender)
+                            || !self.store_message(&message, encoded)
+                        {
+                            continue;
+                        }
+                        self.apply_stored_op(&mut message);
+                        messages.push(message);
+                    }
+                    for message in &messages {
+                        self.request_missing_parent(message, peer, swarm);
+                    }
+                    if !messages.is_empty()
+                        && let Err(err) = self
+                            .event_sender
+                            .send(NetworkEvent::HistorySynced(messages))
+                            .await
+                    {
+                        log::warn!("Failed to emit history sync event: {err}");
+                    }
+
+                    // `apply_op` also drops ops by anyone but the sender of their message
+                    for op in response.ops {
+                        match validate_synced_op(&op, &joined) {
+                            Ok(()) => self.apply_op(op).await,
+                            Err(reason) => log::debug!(
+                                "Dropping synced op on {} from {peer}: {reason}",
+                                op.target_id
+                            ),
+                        }
+                    }
+
+                    if let Some(parent_id) = thread {
+                        self.settle_thread_fetch(parent_id, thread_found).await;
+                    }
+                }
+            },
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                log::debug!("History sync request to {peer} failed: {error}");
+                if let Some(parent_id) = self.thread_requests.remove(&request_id) {
+                    self.settle_thread_fetch(parent_id, false).await;
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log::debug!("History sync request from {peer} failed: {error}");
+            }
+            request_response::Event::ResponseSent { .. } => {}
         }
     }
 
-    fn enqueue_friend_check(&mut self, peer_id: &str) {
-        if self
-            .pending_friend_queries
-            .values()
-            .any(|running| running == peer_id)
+    async fn send_room_message(
+        &mut self,
+        mut msg: ChatMessage,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let encoded = match self.publish_envelope(&msg.room, &Envelope::from(&msg), swarm) {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                log::warn!("Failed to send message to room `{}`: {err}", msg.room);
+                return;
+            }
+        };
+        msg.id = content_id(&encoded);
+        self.store_message(&msg, &encoded);
+        if let Err(err) = self
+            .event_sender
+            .send(NetworkEvent::MessageReceived(msg))
+            .await
         {
+            log::warn!("Failed to notify UI about self message: {err:?}");
+        }
+    }
+
+    async fn send_direct(
+        &mut self,
+        to_peer: String,
+        msg: ChatMessage,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let to_peer = to_peer.trim().to_string();
+        if !self.friend_ids.contains(&to_peer) {
+            self.notify_direct_failure(&to_peer, "Chỉ có thể nhắn riêng cho bạn bè")
+                .await;
             return;
         }
-        if self.friend_queue.iter().any(|queued| queued == peer_id) {
+        let peer = match PeerId::from_str(&to_peer) {
+            Ok(peer) => peer,
+            Err(err) => {
+                self.notify_direct_failure(&to_peer, format!("PeerId không hợp lệ: {err}"))
+                    .await;
+                return;
+            }
+        };
+
+        if let Err(reason) =
+            self.dispatch_direct(peer, &Envelope::from(&msg), Some(msg.id.clone()), swarm)
+        {
+            self.notify_direct_failure(&to_peer, reason).await;
             return;
         }
-        self.friend_queue.push_back(peer_id.to_string());
+
+        self.store_direct_message(&to_peer, &msg);
+        if let Err(err) = self
+            .event_sender
+            .send(NetworkEvent::DirectMessage {
+                peer: to_peer,
+                message: msg,
+            })
+            .await
+        {
+            log::warn!("Failed to notify UI about direct message: {err:?}");
+        }
     }
 
-    fn try_start_next_friend_queries(
+    async fn handle_direct_event(
         &mut self,
+        event: DirectEvent,
         swarm: &mut Swarm<super::behavior::ChatBehavior>,
     ) {
-        while self.pending_friend_queries.len() < MAX_CONCURRENT_FRIEND_QUERIES {
-            let Some(peer_id) = self.friend_queue.pop_front() else {
-                break;
-            };
-            if !self.start_friend_lookup(&peer_id, swarm) {
-                // Invalid peer id, continue to next
-                continue;
+        match event {
+            request_response::Event::Message { peer, message, .. } => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    let (status, _) = self.receive_direct(peer, &request).await;
+                    if swarm
+                        .behaviour_mut()
+                        .direct
+                        .send_response(channel, DirectResponse { status })
+                        .is_err()
+                    {
+                        log::warn!("Failed to acknowledge direct message from {peer}");
+                    }
+                }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    let Some(pending) = self.pending_direct.remove(&request_id) else {
+                        return;
+                    };
+                    let reason = match response.status {
+                        DirectStatus::Delivered => {
+                            if let Some(message_id) = pending.message_id {
+                                self.apply_receipt(
+                                    &pending.peer,
+                                    &[message_id],
+                                    ReceiptKind::Delivered,
+                                )
+                                .await;
+                            }
+                            return;
+                        }
+                        DirectStatus::NotFriend => "Người nhận chưa thêm bạn vào danh sách bạn bè",
+                        DirectStatus::UnsupportedVersion => {
+                            "Người nhận dùng phiên bản giao thức khác, cần cập nhật ứng dụng"
+                        }
+                        DirectStatus::Undecryptable => "Người nhận không giải mã được tin nhắn",
+                    };
+                    // Receipts fail silently; only chat messages are worth reporting
+                    if pending.message_id.is_some() {
+                        self.notify_direct_failure(&pending.peer, reason).await;
+                    }
+                }
+            },
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                log::warn!("Direct message to {peer} failed: {error}");
+                let Some(pending) = self.pending_direct.remove(&request_id) else {
+                    return;
+                };
+                // Unreachable friend: leave the message in a mailbox instead. Only
+                // dial failures, where the friend surely did not get the message.
+                let pending = match error {
+                    request_response::OutboundFailure::DialFailure => {
+                        match self.deposit_in_mailbox(pending, swarm) {
+                            Ok(()) => return,
+                            Err(pending) => pending,
+                        }
+                    }
+                    _ => pending,
+                };
+                if pending.message_id.is_some() {
+                    self.notify_direct_failure(
+                        &pending.peer,
+                        format!("Gửi tin nhắn riêng thất bại: {error}"),
+                    )
+                    .await;
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log::debug!("Inbound direct message from {peer} failed: {error}");
             }
+            request_response::Event::ResponseSent { .. } => {}
         }
     }
 
-    fn start_friend_lookup(
+    /// Check that a direct message comes from a friend, open it and pass it on.
+    /// Returns the status to acknowledge it with and the id of the new chat
+    /// message it carried, if any.
+    async fn receive_direct(
         &mut self,
-        peer_id: &str,
+        peer: PeerId,
+        request: &DirectRequest,
+    ) -> (DirectStatus, Option<String>) {
+        let peer_id_str = peer.to_string();
+        if !self.enable_chat || !self.friend_ids.contains(&peer_id_str) {
+            log::warn!("Rejected direct message from non-friend {peer}");
+            return (DirectStatus::NotFriend, None);
+        }
+
+        let (status, event) = match self.open_direct(&peer, request) {
+            Ok(envelope) => {
+                let message_id = self.handle_direct_envelope(peer_id_str, envelope).await;
+                return (DirectStatus::Delivered, message_id);
+            }
+            Err(DirectOpenError::E2e(E2eError::Tampered)) => {
+                log::warn!("Direct message from {peer} failed authentication");
+                (
+                    DirectStatus::Undecryptable,
+                    NetworkEvent::DirectMessageTampered { peer: peer_id_str },
+                )
+            }
+            // Nothing the user could read was lost, so there is nothing to report
+            Err(DirectOpenError::Envelope(EnvelopeError::UnknownKind(kind))) => {
+                log::debug!("Direct message from {peer} has unknown kind {kind}");
+                return (DirectStatus::UnsupportedVersion, None);
+            }
+            Err(DirectOpenError::Envelope(err)) => {
+                log::warn!("Unreadable direct message from {peer}: {err}");
+                let status = match err {
+                    EnvelopeError::UnsupportedVersion(_) => DirectStatus::UnsupportedVersion,
+                    _ => DirectStatus::Undecryptable,
+                };
+                (
+                    status,
+                    NetworkEvent::DirectMessageUndecryptable {
+                        peer: peer_id_str,
+                        reason: err.to_string(),
+                    },
+                )
+            }
+            Err(DirectOpenError::E2e(E2eError::Undecryptable(reason))) => {
+                log::warn!("Could not decrypt direct message from {peer}: {reason}");
+                (
+                    DirectStatus::Undecryptable,
+                    NetworkEvent::DirectMessageUndecryptable {
+                        peer: peer_id_str,
+                        reason,
+                    },
+                )
+            }
+        };
+        if let Err(err) = self.event_sender.send(event).await {
+            log::warn!("Failed to emit direct message event: {err}");
+        }
+        (status, None)
+    }
+
+    /// Returns the id of the chat message carried, if it is new.
+    async fn handle_direct_envelope(
+        &mut self,
+        peer_id: String,
+        envelope: Envelope,
+    ) -> Option<String> {
+        match &envelope.payload {
+            Payload::Receipt { target_ids, kind } => {
+                self.apply_receipt(&peer_id, target_ids, *kind).await;
+                return None;
+            }
+            Payload::Typing => {
+                if !envelope.is_expired(Utc::now().timestamp()) {
+                    let conversation = Conversation::Direct(peer_id.clone());
+                    self.notify_typing(conversation, peer_id).await;
+                }
+                return None;
+            }
+            Payload::Op(op) => {
+                if op.author == peer_id && op.room.is_empty() && verify_op(op) {
+                    self.apply_op(op.clone()).await;
+                } else {
+                    log::warn!("Dropping forged op from {peer_id} on {}", op.target_id);
+                }
+                return None;
+            }
+            Payload::Reaction { target_id, emoji }
+            | Payload::ReactionRemoved { target_id, emoji } => {
+                let added = matches!(envelope.payload, Payload::Reaction { .. });
+                if is_valid_reaction(emoji) {
+                    self.apply_reaction(&peer_id, target_id, emoji, envelope.timestamp, added)
+                        .await;
+                }
+                return None;
+            }
+            _ => {}
+        }
+        let id = envelope.id.clone();
+        let Some(mut message) = envelope.into_chat_message() else {
+            log::debug!("Direct message {id} has a kind this client does not display yet");
+            return None;
+        };
+        if !self.store_direct_message(&peer_id, &message) {
+            log::debug!("Dropping duplicate direct message {id}");
+            return None;
+        }
+        self.apply_stored_op(&mut message);
+        if let Err(err) = self
+            .event_sender
+            .send(NetworkEvent::DirectMessage {
+                peer: peer_id,
+                message,
+            })
+            .await
+        {
+            log::warn!("Failed to emit direct message event: {err}");
+        }
+        Some(id)
+    }
+
+    /// Seal an envelope for a friend and send it, over a relay circuit when we
+    /// are not connected. `message_id` is set for chat messages so that the
+    /// acknowledgement becomes a delivered receipt.
+    fn dispatch_direct(
+        &mut self,
+        peer: PeerId,
+        envelope: &Envelope,
+        message_id: Option<String>,
         swarm: &mut Swarm<super::behavior::ChatBehavior>,
-    ) -> bool {
-        match PeerId::from_str(peer_id) {
-            Ok(target_peer) => {
-                let query_id = swarm.behaviour_mut().kad.get_closest_peers(target_peer);
-                self.pending_friend_queries
-                    .insert(query_id, peer_id.to_string());
-                true
+    ) -> Result<(), String> {
+        let sealed = self.seal_direct(&peer, envelope)?;
+        // Typing notices are stale by the time a mailbox would deliver them
+        let mailbox_copy = match envelope.payload {
+            Payload::Typing => None,
+            _ => Some(sealed.clone()),
+        };
+
+        // Known addresses come from Kademlia; relay circuits cover friends behind NAT
+        let relay_addrs = if swarm.is_connected(&peer) {
+            Vec::new()
+        } else {
+            self.nat_traversal.relay_circuit_addrs(&peer)
+        };
+        let request_id = swarm.behaviour_mut().direct.send_request_with_addresses(
+            &peer,
+            DirectRequest { sealed },
+            relay_addrs,
+        );
+        self.pending_direct.insert(
+            request_id,
+            PendingDirect {
+                peer: peer.to_string(),
+                message_id,
+                sealed: mailbox_copy,
+            },
+        );
+        Ok(())
+    }
+
+    fn seal_direct(&mut self, peer: &PeerId, envelope: &Envelope) -> Result<SealedMessage, String> {
+        let Some(e2e) = self.e2e.as_mut() else {
+            return Err("Chưa có khóa mã hóa đầu cuối".to_string());
+        };
+        let Some(local_key) = &self.local_key else {
+            return Err("Chưa có khóa định danh".to_string());
+        };
+        let plaintext = envelope
+            .encode(local_key)
+            .map_err(|err| format!("Không thể mã hóa tin nhắn: {err}"))?;
+        e2e.seal(peer, &plaintext, self.db.as_ref())
+            .map_err(|err| format!("Không thể mã hóa tin nhắn: {err}"))
+    }
+
+    /// Decrypt a direct message and check it really comes from `peer`.
+    fn open_direct(
+        &mut self,
+        peer: &PeerId,
+        request: &DirectRequest,
+    ) -> Result<Envelope, DirectOpenError> {
+        let Some(e2e) = self.e2e.as_mut() else {
+            return Err(DirectOpenError::E2e(E2eError::Undecryptable(
+                "end-to-end encryption unavailable".to_string(),
+            )));
+        };
+        let plaintext = e2e
+            .open(peer, &request.sealed, self.db.as_ref())
+            .map_err(DirectOpenError::E2e)?;
+        let mut envelope = Envelope::decode(&plaintext).map_err(DirectOpenError::Envelope)?;
+        // The ciphertext authenticates the sender, so a mismatch means a forged field
+        if envelope.sender != peer.to_string() {
+            return Err(DirectOpenError::E2e(E2eError::Tampered));
+        }
+        envelope
+            .check_limits()
+            .map_err(|reason| DirectOpenError::Envelope(EnvelopeError::Malformed(reason)))?;
+        envelope.room.clear();
+        Ok(envelope)
+    }
+
+    /// Leave a direct message that could not be delivered in a mailbox. Gives
+    /// the message back when there is no mailbox to use.
+    fn deposit_in_mailbox(
+        &mut self,
+        pending: PendingDirect,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) -> Result<(), PendingDirect> {
+        let Some(node) = self
+            .mailbox_nodes
+            .iter()
+            .find(|node| swarm.is_connected(node))
+            .copied()
+        else {
+            return Err(pending);
+        };
+        let Some(payload) = pending
+            .sealed
+            .as_ref()
+            .and_then(|sealed| postcard::to_allocvec(sealed).ok())
+        else {
+            return Err(pending);
+        };
+        let request = MailboxRequest::Deposit {
+            recipient: pending.peer.clone(),
+            payload,
+        };
+        let request_id = swarm.behaviour_mut().mailbox.send_request(&node, request);
+        log::info!(
+            "{} is unreachable, leaving the message in the mailbox of {node}",
+            pending.peer
+        );
+        self.mailbox_requests
+            .insert(request_id, PendingMailbox::Deposit(pending));
+        Ok(())
+    }
+
+    fn fetch_mailbox(&mut self, node: PeerId, swarm: &mut Swarm<super::behavior::ChatBehavior>) {
+        if self.e2e.is_none() {
+            return;
+        }
+        let request_id = swarm
+            .behaviour_mut()
+            .mailbox
+            .send_request(&node, MailboxRequest::Fetch);
+        self.mailbox_requests
+            .insert(request_id, PendingMailbox::Fetch);
+    }
+
+    async fn handle_mailbox_event(
+        &mut self,
+        event: MailboxEvent,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                let Some(pending) = self.mailbox_requests.remove(&request_id) else {
+                    return;
+                };
+                match (pending, response) {
+                    (PendingMailbox::Deposit(pending), MailboxResponse::Stored) => {
+                        log::info!("Message for {} is waiting in the mailbox", pending.peer);
+                    }
+                    (PendingMailbox::Deposit(pending), response) => {
+                        log::warn!("Mailbox of {peer} refused a message: {response:?}");
+                        let reason = match response {
+                            MailboxResponse::QuotaExceeded => {
+                                "Người nhận đang offline và hộp thư của họ đã đầy"
+                            }
+                            _ => "Người nhận đang offline và hộp thư không nhận tin nhắn này",
+                        };
+                        if pending.message_id.is_some() {
+                            self.notify_direct_failure(&pending.peer, reason).await;
+                        }
+                    }
+                    (PendingMailbox::Fetch, MailboxResponse::Messages { items, more }) => {
+                        self.drain_mailbox(peer, items, more, swarm).await;
+                    }
+                    (PendingMailbox::Ack { more: true }, MailboxResponse::Acked) => {
+                        self.fetch_mailbox(peer, swarm);
+                    }
+                    (PendingMailbox::Ack { .. }, MailboxResponse::Acked) => {}
+                    (_, response) => {
+                        log::warn!("Unexpected mailbox response from {peer}: {response:?}");
+                    }
+                }
             }
-            Err(err) => {
-                log::warn!("Không thể parse peer_id {peer_id}: {err}");
-                false
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                log::warn!("Mailbox request to {peer} failed: {error}");
+                if let Some(PendingMailbox::Deposit(pending)) =
+                    self.mailbox_requests.remove(&request_id)
+                    && pending.message_id.is_some()
+                {
+                    self.notify_direct_failure(
+                        &pending.peer,
+                        format!("Gửi tin nhắn riêng thất bại: {error}"),
+                    )
+                    .await;
+                }
             }
+            _ => {}
+        }
+    }
+
+    /// Read the messages friends left in a mailbox as if they had just been
+    /// sent, send delivered receipts back and acknowledge them to the node.
+    async fn drain_mailbox(
+        &mut self,
+        node: PeerId,
+        items: Vec<MailboxItem>,
+        more: bool,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        if items.is_empty() {
+            return;
         }
+        log::info!(
+            "Collecting {} message(s) from the mailbox of {node}",
+            items.len()
+        );
+
+        let mut delivered: HashMap<PeerId, Vec<String>> = HashMap::new();
+        let mut ids = Vec::with_capacity(items.len());
+        for item in items {
+            ids.push(item.id);
+            let Ok(sender) = PeerId::from_str(&item.sender) else {
+                continue;
+            };
+            let sealed = match postcard::from_bytes::<SealedMessage>(&item.payload) {
+                Ok(sealed) => sealed,
+                Err(err) => {
+                    log::warn!("Dropping unreadable mailbox message from {sender}: {err}");
+                    continue;
+                }
+            };
+            let (_, message_id) = self.receive_direct(sender, &DirectRequest { sealed }).await;
+            if let Some(message_id) = message_id {
+                delivered.entry(sender).or_default().push(message_id);
+            }
+        }
+
+        if let Some(local_peer_id) = self.local_peer_id {
+            for (sender, message_ids) in delivered {
+                for chunk in message_ids.chunks(MAX_RECEIPT_IDS) {
+                    let envelope = Envelope::receipt(
+                        local_peer_id.to_string(),
+                        String::new(),
+                        chunk.to_vec(),
+                        ReceiptKind::Delivered,
+                    );
+                    if let Err(err) = self.dispatch_direct(sender, &envelope, None, swarm) {
+                        log::debug!("Failed to send delivered receipts to {sender}: {err}");
+                    }
+                }
+            }
+        }
+
+        // Everything is acknowledged, unreadable messages included, so they do
+        // not come back on every connection
+        let request_id = swarm
+            .behaviour_mut()
+            .mailbox
+            .send_request(&node, MailboxRequest::Ack { ids });
+        self.mailbox_requests
+            .insert(request_id, PendingMailbox::Ack { more });
+    }
+
+    async fn notify_direct_failure(&self, peer_id: &str, reason: impl Into<String>) {
+        if let Err(err) = self
+            .event_sender
+            .send(NetworkEvent::DirectMessageFailed {
+                peer: peer_id.to_string(),
+                reason: reason.into(),
+            })
+            .await
+        {
+            log::warn!("Failed to emit direct message failure: {err}");
+        }
+    }
+
+    /// Rooms to subscribe at startup: the default room plus those stored in the database
+    fn stored_rooms(&self) -> Vec<String> {
+        let Some(db) = &self.db else {
+            return vec![DEFAULT_ROOM.to_string()];
+        };
+        db.get_rooms().unwrap_or_else(|err| {
+            log::warn!("Failed to load joined rooms: {err}");
+            vec![DEFAULT_ROOM.to_string()]
+        })
+    }
+
+    /// Ask `peer` for what we missed in `room`, from the room's own cursor.
+    fn request_room_history(
+        &self,
+        peer: PeerId,
+        room: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let since = self
+            .db
+            .as_ref()
+            .and_then(|db| db.sync_cursor(&room).ok())
+            .unwrap_or(0);
+        swarm.behaviour_mut().sync.send_request(
+            &peer,
+            HistoryRequest {
+                since,
+                after_id: None,
+                rooms: vec![room],
+                thread: None,
+            },
+        );
+    }
+
+    /// Whether `peer` is one of the configured bootstrap nodemasters.
+    fn is_bootstrap_peer(&self, peer: &PeerId) -> bool {
+        self.bootstrap_peers
+            .iter()
+            .any(|(bootstrap, _)| bootstrap == peer)
+    }
+
+    fn joined_rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<String> = self.rooms.values().cloned().collect();
+        rooms.sort();
+        rooms
+    }
+
+    fn subscribe_room(
+        &mut self,
+        room: &str,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) -> bool {
+        let topic = room_topic(room);
+        match swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+            Ok(_) => {
+                log::info!("Joined room `{room}`");
+                if let Err(err) = swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .set_topic_params(topic.clone(), room_score_params())
+                {
+                    log::warn!("Failed to set peer scoring for room `{room}`: {err}");
+                }
+                self.rooms.insert(topic.hash(), room.to_string());
+                true
+            }
+            Err(err) => {
+                log::warn!("Failed to subscribe to room `{room}`: {err:?}");
+                false
+            }
+        }
+    }
+
+    async fn handle_join_room(
+        &mut self,
+        room: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let Some(room) = normalize_room_name(&room) else {
+            log::warn!("Invalid room name `{room}`");
+            return;
+        };
+        if self.rooms.contains_key(&room_topic(&room).hash()) || !self.subscribe_room(&room, swarm)
+        {
+            return;
+        }
+
+        if let Some(db) = &self.db
+            && let Err(err) = db.insert_room(&room)
+        {
+            log::warn!("Failed to store room `{room}`: {err}");
+        }
+
+        // Catch up on the new room from everyone we are already connected to
+        let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+        for peer in peers {
+            self.request_room_history(peer, room.clone(), swarm);
+        }
+
+        if let Err(err) = self.event_sender.send(NetworkEvent::RoomJoined(room)).await {
+            log::warn!("Failed to emit room joined event: {err}");
+        }
+    }
+
+    async fn handle_leave_room(
+        &mut self,
+        room: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let Some(room) = normalize_room_name(&room) else {
+            log::warn!("Invalid room name `{room}`");
+            return;
+        };
+        if room == DEFAULT_ROOM {
+            log::warn!("The default room cannot be left");
+            return;
+        }
+        let topic = room_topic(&room);
+        if self.rooms.remove(&topic.hash()).is_none() {
+            return;
+        }
+        swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
+        if let Some(db) = &self.db
+            && let Err(err) = db.remove_room(&room)
+        {
+            log::warn!("Failed to forget room `{room}`: {err}");
+        }
+        log::info!("Left room `{room}`");
+
+        if let Err(err) = self.event_sender.send(NetworkEvent::RoomLeft(room)).await {
+            log::warn!("Failed to emit room left event: {err}");
+        }
+    }
+
+    /// Hash a local file, remember it as shared and offer it in the conversation.
+    async fn send_file(
+        &mut self,
+        conversation: Conversation,
+        path: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+        local_peer_id: PeerId,
+    ) {
+        let path = path.trim().to_string();
+        let source = PathBuf::from(&path);
+        let prepared = match tokio::task::spawn_blocking(move || prepare_file(&source)).await {
+            Ok(prepared) => prepared.map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        let public = matches!(conversation, Conversation::Room(_));
+        let shared = match prepared {
+            Ok(shared) => SharedFile { public, ..shared },
+            Err(reason) => {
+                log::warn!("Failed to share `{path}`: {reason}");
+                let _ = self
+                    .event_sender
+                    .send(NetworkEvent::FileShareFailed { path, reason })
+                    .await;
+                return;
+            }
+        };
+        if let Some(db) = &self.db
+            && let Err(err) = db.save_shared_file(&shared)
+        {
+            log::warn!("Failed to remember shared file `{path}`: {err}");
+            return;
+        }
+        // Others in the room can fetch it from anyone who downloaded it
+        if public {
+            blobs::provide(swarm, &shared.attachment.file_id);
+        }
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::FileShared {
+                file_id: shared.attachment.file_id.clone(),
+                path: shared.path.clone(),
+            })
+            .await;
+        log::info!(
+            "Sharing `{path}` as {} ({} bytes)",
+            shared.attachment.file_id,
+            shared.attachment.size
+        );
+
+        let name = shared.attachment.name.clone();
+        match conversation {
+            Conversation::Room(room) => {
+                let msg = ChatMessage {
+                    attachment: Some(shared.attachment),
+                    ..new_chat_message(local_peer_id, room, name)
+                };
+                self.send_room_message(msg, swarm).await;
+            }
+            Conversation::Direct(peer_id) => {
+                let msg = ChatMessage {
+                    attachment: Some(shared.attachment),
+                    ..new_chat_message(local_peer_id, String::new(), name)
+                };
+                self.send_direct(peer_id, msg, swarm).await;
+            }
+        }
+    }
+
+    /// Download an attachment from its sender; room attachments also come
+    /// from the other providers found in the DHT.
+    async fn download_file(
+        &mut self,
+        peer_id: String,
+        attachment: FileAttachment,
+        public: bool,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let file_id = attachment.file_id.clone();
+        let started = match PeerId::from_str(&peer_id) {
+            Ok(peer) => self
+                .files
+                .start(peer, attachment, public, self.db.as_ref())
+                .map(|saved| (peer, saved)),
+            Err(err) => Err(format!("PeerId không hợp lệ: {err}")),
+        };
+        let event = match started {
+            Ok((_, Some(path))) => NetworkEvent::FileCompleted { file_id, path },
+            Ok((peer, None)) => {
+                log::info!("Downloading {file_id} from {peer}");
+                self.files.resume(&file_id, swarm, &self.nat_traversal);
+                return;
+            }
+            Err(reason) => NetworkEvent::FileFailed { file_id, reason },
+        };
+        if let Err(err) = self.event_sender.send(event).await {
+            log::warn!("Failed to emit file event: {err}");
+        }
+    }
+
+    /// Ask `peer` for the thread of a room reply whose parent we do not have,
+    /// unless it is being fetched already.
+    fn request_missing_parent(
+        &mut self,
+        message: &ChatMessage,
+        peer: PeerId,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let Some(parent_id) = &message.reply_to else {
+            return;
+        };
+        if message.room.is_empty() || self.requested_threads.contains_key(parent_id) {
+            return;
+        }
+        if let Some(db) = &self.db
+            && !matches!(db.get_message_sender(parent_id), Ok(None))
+        {
+            return;
+        }
+        if self.requested_threads.len() >= MAX_REQUESTED_THREADS {
+            log::debug!("Too many threads being fetched, not fetching {parent_id}");
+            return;
+        }
+        log::info!("Fetching thread of missing message {parent_id} from {peer}");
+        self.requested_threads.insert(
+            parent_id.clone(),
+            ThreadFetch {
+                room: message.room.clone(),
+                peer,
+                attempts: 0,
+                retry_at: None,
+            },
+        );
+        self.send_thread_request(parent_id.clone(), swarm);
+    }
+
+    fn send_thread_request(
+        &mut self,
+        parent_id: String,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let Some(fetch) = self.requested_threads.get_mut(&parent_id) else {
+            return;
+        };
+        fetch.attempts += 1;
+        fetch.retry_at = None;
+        let request_id = swarm.behaviour_mut().sync.send_request(
+            &fetch.peer,
+            HistoryRequest {
+                since: 0,
+                after_id: None,
+                rooms: vec![fetch.room.clone()],
+                thread: Some(parent_id.clone()),
+            },
+        );
+        self.thread_requests.insert(request_id, parent_id);
+    }
+
+    /// Ask again for the threads whose retry is due.
+    fn retry_thread_fetches(&mut self, swarm: &mut Swarm<super::behavior::ChatBehavior>) {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .requested_threads
+            .iter()
+            .filter(|(_, fetch)| fetch.retry_at.is_some_and(|at| at <= now))
+            .map(|(parent_id, _)| parent_id.clone())
+            .collect();
+        for parent_id in due {
+            log::debug!("Fetching thread of missing message {parent_id} again");
+            self.send_thread_request(parent_id, swarm);
+        }
+    }
+
+    /// A thread fetch is over: forget it once the parent arrived, otherwise
+    /// retry later with a growing delay, and after [`MAX_THREAD_ATTEMPTS`] tell
+    /// the UI the parent is not coming.
+    async fn settle_thread_fetch(&mut self, parent_id: String, found: bool) {
+        let Some(fetch) = self.requested_threads.get_mut(&parent_id) else {
+            return;
+        };
+        if !found && fetch.attempts < MAX_THREAD_ATTEMPTS {
+            fetch.retry_at =
+                Some(Instant::now() + THREAD_RETRY_DELAY * (1 << (fetch.attempts - 1)));
+            return;
+        }
+        self.requested_threads.remove(&parent_id);
+        if found {
+            return;
+        }
+        log::info!("Giving up on fetching missing message {parent_id}");
+        if let Err(err) = self
+            .event_sender
+            .send(NetworkEvent::ThreadUnavailable { parent_id })
+            .await
+        {
+            log::warn!("Failed to emit thread event: {err}");
+        }
+    }
+
+    /// Sign and publish an envelope on the topic of a joined room. Returns the
+    /// envelope as published.
+    fn publish_envelope(
+        &mut self,
+        room: &str,
+        envelope: &Envelope,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) -> Result<Vec<u8>, String> {
+        let topic = room_topic(room);
+        if !self.rooms.contains_key(&topic.hash()) {
+            return Err("not a member of this room".to_string());
+        }
+        let Some(local_key) = &self.local_key else {
+            return Err("no identity key".to_string());
+        };
+        let bytes = envelope.encode(local_key).map_err(|err| err.to_string())?;
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(topic, bytes.clone())
+            .map(|_| bytes)
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    /// Acknowledge room messages received since the last flush, one direct
+    /// envelope per author. Only friends can be reached directly; publishing
+    /// the receipts in the room would have every member answer every message.
+    fn flush_delivered_receipts(&mut self, swarm: &mut Swarm<super::behavior::ChatBehavior>) {
+        let Some(local_peer_id) = self.local_peer_id else {
+            return;
+        };
+        for (author, message_ids) in std::mem::take(&mut self.pending_delivered) {
+            for chunk in message_ids.chunks(MAX_RECEIPT_IDS) {
+                let envelope = Envelope::receipt(
+                    local_peer_id.to_string(),
+                    String::new(),
+                    chunk.to_vec(),
+                    ReceiptKind::Delivered,
+                );
+                if let Err(err) = self.dispatch_direct(author, &envelope, None, swarm) {
+                    log::debug!("Failed to send delivered receipts to {author}: {err}");
+                }
+            }
+        }
+    }
+
+    /// Send a typing signal for a conversation. Room signals go over gossip,
+    /// direct ones over the encrypted direct protocol; neither is stored.
+    fn send_typing(
+        &mut self,
+        conversation: Conversation,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+        local_peer_id: PeerId,
+    ) {
+        match conversation {
+            Conversation::Room(room) => {
+                let envelope = Envelope::typing(local_peer_id.to_string(), room.clone());
+                if let Err(err) = self.publish_envelope(&room, &envelope, swarm) {
+                    log::debug!("Failed to publish typing signal in `{room}`: {err}");
+                }
+            }
+            Conversation::Direct(peer_id) => {
+                // Only friends accept direct traffic, and only if they are reachable now
+                let Ok(peer) = PeerId::from_str(&peer_id) else {
+                    return;
+                };
+                if !self.friend_ids.contains(&peer_id) || !swarm.is_connected(&peer) {
+                    return;
+                }
+                let envelope = Envelope::typing(local_peer_id.to_string(), String::new());
+                if let Err(err) = self.dispatch_direct(peer, &envelope, None, swarm) {
+                    log::debug!("Failed to send typing signal to {peer}: {err}");
+                }
+            }
+        }
+    }
+
+    async fn notify_typing(&self, conversation: Conversation, peer: String) {
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::Typing { conversation, peer })
+            .await;
+    }
+
+    /// Record that the user has seen these messages and, if read receipts are
+    /// on, tell their authors.
+    fn handle_mark_read(
+        &mut self,
+        conversation: Conversation,
+        message_ids: Vec<String>,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let (Some(db), Some(local_peer_id)) = (&self.db, self.local_peer_id) else {
+            return;
+        };
+        let local = local_peer_id.to_string();
+        // Marks are kept even with receipts off, so turning them on later does
+        // not announce old messages
+        let fresh: Vec<String> = message_ids
+            .into_iter()
+            .filter(|id| {
+                db.record_receipt(id, &local, DeliveryStatus::Read)
+                    .unwrap_or(false)
+            })
+            .collect();
+        if fresh.is_empty() || !self.read_receipts {
+            return;
+        }
+
+        for chunk in fresh.chunks(MAX_RECEIPT_IDS) {
+            match &conversation {
+                Conversation::Room(room) => {
+                    let envelope = Envelope::receipt(
+                        local.clone(),
+                        room.clone(),
+                        chunk.to_vec(),
+                        ReceiptKind::Read,
+                    );
+                    if let Err(err) = self.publish_envelope(room, &envelope, swarm) {
+                        log::debug!("Failed to publish read receipts in `{room}`: {err}");
+                    }
+                }
+                Conversation::Direct(peer_id) => {
+                    let Ok(peer) = PeerId::from_str(peer_id) else {
+                        return;
+                    };
+                    if !self.friend_ids.contains(peer_id) {
+                        return;
+                    }
+                    let envelope = Envelope::receipt(
+                        local.clone(),
+                        String::new(),
+                        chunk.to_vec(),
+                        ReceiptKind::Read,
+                    );
+                    if let Err(err) = self.dispatch_direct(peer, &envelope, None, swarm) {
+                        log::debug!("Failed to send read receipts to {peer}: {err}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Record receipts from `from` for our own messages and report progress to the UI.
+    async fn apply_receipt(&self, from: &str, target_ids: &[String], kind: ReceiptKind) {
+        let (Some(db), Some(local_peer_id)) = (&self.db, self.local_peer_id) else {
+            return;
+        };
+        let local = local_peer_id.to_string();
+        let status = DeliveryStatus::from(kind);
+        let mut updated = Vec::new();
+        for message_id in target_ids.iter().take(MAX_RECEIPT_IDS) {
+            // Receipts for other people's messages are none of our business
+            match db.get_message_sender(message_id) {
+                Ok(Some(sender)) if sender == local => {}
+                Ok(_) => continue,
+                Err(err) => {
+                    log::warn!("Failed to look up message {message_id}: {err}");
+                    continue;
+                }
+            }
+            match db.record_receipt(message_id, from, status) {
+                Ok(true) => updated.push(message_id.clone()),
+                Ok(false) => {}
+                Err(err) => log::warn!("Failed to store receipt for {message_id}: {err}"),
+            }
+        }
+
+        for message_id in updated {
+            if let Err(err) = self
+                .event_sender
+                .send(NetworkEvent::ReceiptUpdated { message_id, status })
+                .await
+            {
+                log::warn!("Failed to emit receipt event: {err}");
+            }
+        }
+    }
+
+    /// Edit or delete one of our own messages and tell the others in the conversation.
+    async fn send_op(
+        &mut self,
+        conversation: Conversation,
+        message_id: String,
+        action: OpAction,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let (Some(local_key), Some(local_peer_id)) = (&self.local_key, self.local_peer_id) else {
+            return;
+        };
+        let local = local_peer_id.to_string();
+        if let Some(db) = &self.db {
+            match db.get_message_sender(&message_id) {
+                Ok(Some(sender)) if sender == local => {}
+                Ok(_) => {
+                    log::warn!("Refusing to change message {message_id}: not ours");
+                    return;
+                }
+                Err(err) => {
+                    log::warn!("Failed to look up message {message_id}: {err}");
+                    return;
+                }
+            }
+        }
+        let room = match &conversation {
+            Conversation::Room(room) => room.clone(),
+            Conversation::Direct(_) => String::new(),
+        };
+        let Some(op) = sign_op(local_key, message_id, room, action) else {
+            return;
+        };
+
+        let envelope = Envelope::new(local.clone(), op.room.clone(), Payload::Op(op.clone()));
+        let sent = match &conversation {
+            Conversation::Room(room) => self.publish_envelope(room, &envelope, swarm).map(drop),
+            Conversation::Direct(peer_id) => match PeerId::from_str(peer_id) {
+                Ok(peer) if self.friend_ids.contains(peer_id) => {
+                    self.dispatch_direct(peer, &envelope, None, swarm)
+                }
+                Ok(_) => Err("not a friend".to_string()),
+                Err(err) => Err(err.to_string()),
+            },
+        };
+        // Applied locally even if nobody is reachable; room peers catch up through sync
+        if let Err(err) = sent {
+            log::warn!("Failed to send op on {}: {err}", op.target_id);
+        }
+        self.apply_op(op).await;
+    }
+
+    /// Store a verified op and, if it is news, apply it to the stored message
+    /// and report it to the UI. Ops on a known message signed by anyone but
+    /// its sender are dropped.
+    async fn apply_op(&self, op: MessageOp) {
+        if let Some(db) = &self.db {
+            match db.get_message_sender(&op.target_id) {
+                Ok(Some(sender)) if sender != op.author => {
+                    log::warn!(
+                        "Dropping op on {} by {}, not its sender",
+                        op.target_id,
+                        op.author
+                    );
+                    return;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("Failed to look up sender of {}: {err}", op.target_id);
+                    return;
+                }
+            }
+            match db.save_op(&op) {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(err) => {
+                    log::warn!("Failed to store op on {}: {err}", op.target_id);
+                    return;
+                }
+            }
+            // The target may not have arrived yet; `apply_stored_op` catches it up later
+            if let Err(err) = db.apply_op(&op) {
+                log::warn!("Failed to apply op on {}: {err}", op.target_id);
+            }
+        }
+        if let Err(err) = self
+            .event_sender
+            .send(NetworkEvent::MessageOpApplied(op))
+            .await
+        {
+            log::warn!("Failed to emit message op event: {err}");
+        }
+    }
+
+    /// Apply an op that arrived before the message it targets.
+    fn apply_stored_op(&self, message: &mut ChatMessage) {
+        let Some(db) = &self.db else {
+            return;
+        };
+        let op = match db.get_op(&message.id, &message.sender) {
+            Ok(Some(op)) => op,
+            Ok(None) => return,
+            Err(err) => {
+                log::warn!("Failed to look up op on {}: {err}", message.id);
+                return;
+            }
+        };
+        message.apply_op(&op.action);
+        if let Err(err) = db.apply_op(&op) {
+            log::warn!("Failed to apply op on {}: {err}", message.id);
+        }
+    }
+
+    /// Add or take back one of our reactions and tell the others in the conversation.
+    async fn send_reaction(
+        &mut self,
+        conversation: Conversation,
+        message_id: String,
+        emoji: String,
+        added: bool,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let Some(local_peer_id) = self.local_peer_id else {
+            return;
+        };
+        if !is_valid_reaction(&emoji) {
+            log::warn!("Invalid reaction `{emoji}`");
+            return;
+        }
+        let local = local_peer_id.to_string();
+        let payload = if added {
+            Payload::Reaction {
+                target_id: message_id.clone(),
+                emoji: emoji.clone(),
+            }
+        } else {
+            Payload::ReactionRemoved {
+                target_id: message_id.clone(),
+                emoji: emoji.clone(),
+            }
+        };
+        let sent = match &conversation {
+            Conversation::Room(room) => {
+                let envelope = Envelope::new(local.clone(), room.clone(), payload);
+                self.publish_envelope(room, &envelope, swarm)
+                    .map(|_| envelope.timestamp)
+            }
+            Conversation::Direct(peer_id) => {
+                let envelope = Envelope::new(local.clone(), String::new(), payload);
+                match PeerId::from_str(peer_id) {
+                    Ok(peer) if self.friend_ids.contains(peer_id) => self
+                        .dispatch_direct(peer, &envelope, None, swarm)
+                        .map(|_| envelope.timestamp),
+                    Ok(_) => Err("not a friend".to_string()),
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+        };
+        match sent {
+            Ok(timestamp) => {
+                self.apply_reaction(&local, &message_id, &emoji, timestamp, added)
+                    .await
+            }
+            Err(err) => log::warn!("Failed to send reaction on {message_id}: {err}"),
+        }
+    }
+
+    /// Record a reaction change from `peer_id` and report the message's new
+    /// reactions to the UI if anything changed.
+    async fn apply_reaction(
+        &self,
+        peer_id: &str,
+        message_id: &str,
+        emoji: &str,
+        timestamp: i64,
+        added: bool,
+    ) {
+        let Some(db) = &self.db else {
+            return;
+        };
+        match db.set_reaction(message_id, peer_id, emoji, timestamp, !added) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(err) => {
+                log::warn!("Failed to store reaction on {message_id}: {err}");
+                return;
+            }
+        }
+        let reactions = match db.get_reactions(message_id) {
+            Ok(reactions) => reactions,
+            Err(err) => {
+                log::warn!("Failed to load reactions on {message_id}: {err}");
+                return;
+            }
+        };
+        if let Err(err) = self
+            .event_sender
+            .send(NetworkEvent::ReactionsUpdated {
+                message_id: message_id.to_string(),
+                reactions,
+            })
+            .await
+        {
+            log::warn!("Failed to emit reaction event: {err}");
+        }
+    }
+
+    /// Persist a direct message; returns `false` if it was already known.
+    fn store_direct_message(&self, peer_id: &str, message: &ChatMessage) -> bool {
+        let Some(db) = &self.db else {
+            return true;
+        };
+        db.insert_direct_message(peer_id, &Message::from(message))
+            .unwrap_or_else(|err| {
+                log::warn!("Failed to store direct message {}: {err}", message.id);
+                true
+            })
+    }
+
+    /// Persist a room message with the envelope it arrived in; returns `false`
+    /// if it was already known. Without a working database every message
+    /// counts as new.
+    fn store_message(&self, message: &ChatMessage, encoded: &[u8]) -> bool {
+        let Some(db) = &self.db else {
+            return true;
+        };
+        let message = Message {
+            envelope: Some(encoded.to_vec()),
+            ..Message::from(message)
+        };
+        db.insert_message(&message).unwrap_or_else(|err| {
+            log::warn!("Failed to store message {}: {err}", message.id);
+            true
+        })
+    }
+
+    /// Answer a history request from the database.
+    fn serve_history(&self, request: HistoryRequest) -> HistoryResponse {
+        let mut response = HistoryResponse {
+            messages: Vec::new(),
+            ops: Vec::new(),
+            more: false,
+        };
+        if !self.enable_chat {
+            return response;
+        }
+        if request.rooms.len() > MAX_SYNC_ROOMS {
+            log::debug!(
+                "Not serving history of {} rooms in one request",
+                request.rooms.len()
+            );
+            return response;
+        }
+        let rooms = if request.rooms.is_empty() {
+            vec![DEFAULT_ROOM.to_string()]
+        } else {
+            request.rooms
+        };
+        if let Some(parent_id) = &request.thread {
+            (response.messages, _) = self.load_thread(parent_id, &rooms);
+            return response;
+        }
+        let (messages, page_end) = self.load_history_since(
+            request.since,
+            request.after_id.as_deref().unwrap_or_default(),
+            &rooms,
+        );
+        // Ops made up to the last message of the page; later ones come with
+        // the next page, so no page repeats them
+        response.ops = self.load_ops_since(request.since, page_end, &rooms);
+        response.messages = messages;
+        response.more = page_end.is_some();
+        response
+    }
+
+    /// Signed envelopes of the messages to serve for a history request, and
+    /// the time of the last one if the page is full.
+    fn load_history_since(
+        &self,
+        since: i64,
+        after_id: &str,
+        rooms: &[String],
+    ) -> (Vec<Vec<u8>>, Option<i64>) {
+        let Some(db) = &self.db else {
+            return (Vec::new(), None);
+        };
+        match db.get_messages_after(since, after_id, rooms, MAX_SYNC_MESSAGES) {
+            Ok(messages) => sync_page(messages),
+            Err(err) => {
+                log::warn!("Failed to load history for sync: {err}");
+                (Vec::new(), None)
+            }
+        }
+    }
+
+    fn load_thread(&self, parent_id: &str, rooms: &[String]) -> (Vec<Vec<u8>>, Option<i64>) {
+        let Some(db) = &self.db else {
+            return (Vec::new(), None);
+        };
+        match db.get_thread(parent_id, rooms, MAX_SYNC_MESSAGES) {
+            Ok(messages) => sync_page(messages),
+            Err(err) => {
+                log::warn!("Failed to load thread {parent_id} for sync: {err}");
+                (Vec::new(), None)
+            }
+        }
+    }
+
+    /// Ops made after `since` and, if given, no later than `until`.
+    fn load_ops_since(&self, since: i64, until: Option<i64>, rooms: &[String]) -> Vec<MessageOp> {
+        let Some(db) = &self.db else {
+            return Vec::new();
+        };
+        match db.get_ops_after(since, until, rooms, MAX_SYNC_MESSAGES) {
+            Ok(ops) => ops,
+            Err(err) => {
+                log::warn!("Failed to load message ops for sync: {err}");
+                Vec::new()
+            }
+        }
+    }
+
+    async fn notify_friend_status(
+        &self,
+        peer_id: &str,
+        online: bool,
+        message: impl Into<String>,
+    ) {
+        let status = PeerStatus {
+            peer_id: peer_id.to_string(),
+            online,
+            message: message.into(),
+            checked_at: Utc::now().timestamp(),
+        };
+        if let Err(err) = self
+            .event_sender
+            .send(NetworkEvent::FriendStatus(status))
+            .await
+        {
+            log::warn!("Failed to emit friend status event: {err}");
+        }
+    }
+
+    async fn emit_initial_friend_placeholders(&self) {
+        for friend in self.friends.values() {
+            let peer = friend.peer_id.clone();
+            if !friend.petname.is_empty() || !friend.annotation.is_empty() {
+                let info = NetworkEvent::FriendInfo {
+                    peer: peer.clone(),
+                    petname: friend.petname.clone(),
+                    annotation: friend.annotation.clone(),
+                };
+                let _ = self.event_sender.send(info).await;
+            }
+            let note = friend.note.clone();
+            let event = match friend.state {
+                FriendState::Accepted => {
+                    self.notify_friend_status(
+                        &friend.peer_id,
+                        false,
+                        "Đang chờ kiểm tra trạng thái qua bootstrap...",
+                    )
+                    .await;
+                    continue;
+                }
+                FriendState::Incoming => NetworkEvent::FriendRequestReceived { peer, note },
+                FriendState::Outgoing => NetworkEvent::FriendRequestSent { peer, note },
+            };
+            let _ = self.event_sender.send(event).await;
+        }
+    }
+
+    /// Check again the friends whose turn has come, and send again friend
+    /// requests nobody answered yet. Connected friends only need the next
+    /// ping; the others are looked up in the DHT and dialed.
+    async fn refresh_friends(&mut self, swarm: &mut Swarm<super::behavior::ChatBehavior>) {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .friends
+            .values()
+            .filter(|friend| friend.state != FriendState::Incoming)
+            .map(|friend| &friend.peer_id)
+            .filter(|peer_id| {
+                self.friend_schedule
+                    .get(*peer_id)
+                    .is_none_or(|schedule| schedule.next_check <= now)
+            })
+            .cloned()
+            .collect();
+        for peer_id in due {
+            let Ok(peer) = PeerId::from_str(&peer_id) else {
+                continue;
+            };
+            self.plan_friend_check(&peer_id);
+            self.deliver_friend_handshake(peer, swarm);
+            if !self.friend_ids.contains(&peer_id) {
+                continue;
+            }
+            if swarm.is_connected(&peer) {
+                self.awaiting_ping
+                    .entry(peer)
+                    .or_insert_with(|| "Đã kết nối".to_string());
+            } else if self
+                .friends
+                .get(&peer_id)
+                .is_some_and(|friend| friend.presence_key.is_some())
+            {
+                self.enqueue_friend_check(&peer_id);
+            } else {
+                self.notify_friend_status(
+                    &peer_id,
+                    false,
+                    "Chưa nhận được khóa presence, chờ bạn kết nối lại",
+                )
+                .await;
+            }
+        }
+        self.try_start_next_friend_queries(swarm);
+    }
+
+    /// Schedule the check after the one starting now. The friend counts as
+    /// unreachable until a ping comes back, so every check that does not end
+    /// with one doubles the wait (see [`FriendRefreshConfig::next_delay`]).
+    fn plan_friend_check(&mut self, peer_id: &str) {
+        let schedule = self
+            .friend_schedule
+            .entry(peer_id.to_string())
+            .or_insert(FriendSchedule {
+                next_check: Instant::now(),
+                failures: 0,
+            });
+        schedule.next_check = Instant::now() + self.friend_refresh.next_delay(schedule.failures);
+        schedule.failures = schedule.failures.saturating_add(1);
+        if schedule.failures > 1 {
+            log::debug!(
+                "Friend {peer_id} unreachable {} time(s) in a row, next check in {:?}",
+                schedule.failures - 1,
+                schedule.next_check - Instant::now()
+            );
+        }
+    }
+
+    /// A ping came back from the friend: back to the normal interval.
+    fn mark_friend_reachable(&mut self, peer: &PeerId) {
+        let peer_id = peer.to_string();
+        if !self.friend_ids.contains(&peer_id) {
+            return;
+        }
+        self.friend_schedule.insert(
+            peer_id,
+            FriendSchedule {
+                next_check: Instant::now() + self.friend_refresh.next_delay(0),
+                failures: 0,
+            },
+        );
+    }
+
+    fn enqueue_friend_check(&mut self, peer_id: &str) {
+        if self
+            .pending_friend_queries
+            .values()
+            .any(|running| running == peer_id)
+        {
+            return;
+        }
+        if self.friend_queue.iter().any(|queued| queued == peer_id) {
+            return;
+        }
+        self.friend_queue.push_back(peer_id.to_string());
+    }
+
+    fn try_start_next_friend_queries(
+        &mut self,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        while self.pending_friend_queries.len() < MAX_CONCURRENT_FRIEND_QUERIES {
+            let Some(peer_id) = self.friend_queue.pop_front() else {
+                break;
+            };
+            if !self.start_friend_lookup(&peer_id, swarm) {
+                // Invalid peer id, continue to next
+                continue;
+            }
+        }
+    }
+
+    fn start_friend_lookup(
+        &mut self,
+        peer_id: &str,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) -> bool {
+        match PeerId::from_str(peer_id) {
+            Ok(target_peer) => {
+                let query_id = swarm
+                    .behaviour_mut()
+                    .kad
+                    .get_record(presence::record_key(&target_peer));
+                self.pending_friend_queries
+                    .insert(query_id, peer_id.to_string());
+                true
+            }
+            Err(err) => {
+                log::warn!("Không thể parse peer_id {peer_id}: {err}");
+                false
+            }
+        }
+    }
+
+    /// Returns whether a valid presence record of the friend was found, in
+    /// which case the friend is dialed (or pinged if already connected).
+    async fn handle_presence_result(
+        &mut self,
+        peer_id: &str,
+        result: Result<kad::GetRecordOk, kad::GetRecordError>,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) -> bool {
+        let Ok(target) = PeerId::from_str(peer_id) else {
+            return false;
+        };
+        let Some(presence_key) = self
+            .friends
+            .get(peer_id)
+            .and_then(|friend| friend.presence_key.clone())
+        else {
+            return false;
+        };
+        let record = match result {
+            Ok(kad::GetRecordOk::FoundRecord(found)) => found,
+            Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => return false,
+            Err(err) => {
+                log::debug!("Presence lookup for {peer_id} failed: {err}");
+                return false;
+            }
+        };
+        let Some(addresses) = presence::verify(&record.record, &target, &presence_key) else {
+            log::warn!(
+                "Ignoring invalid or expired presence record of {peer_id} from {:?}",
+                record.peer
+            );
+            return false;
+        };
+
+        for address in &addresses {
+            swarm
+                .behaviour_mut()
+                .kad
+                .add_address(&target, address.clone());
+        }
+        if !addresses.is_empty() {
+            self.peer_addresses.insert(target, addresses.clone());
+        }
+
+        if swarm.is_connected(&target) {
+            // Confirmed (with a fresh round-trip time) by the next ping
+            self.awaiting_ping
+                .entry(target)
+                .or_insert_with(|| "Đã kết nối".to_string());
+            return true;
+        }
+        self.notify_friend_status(peer_id, false, "Tìm thấy presence, đang kết nối...")
+            .await;
+        if !self.try_dial_peer(target, addresses, swarm) {
+            self.nat_traversal
+                .retry_with_relay(target, swarm, &self.dialed_peers)
+                .await;
+        }
+        true
+    }
+
+    async fn handle_ping_event(&mut self, event: ping::Event) {
+        let Some(via) = self.awaiting_ping.remove(&event.peer) else {
+            return;
+        };
+        let peer_id = event.peer.to_string();
+        match event.result {
+            Ok(rtt) => {
+                self.mark_friend_reachable(&event.peer);
+                self.notify_friend_status(
+                    &peer_id,
+                    true,
+                    format!("{via} (ping {} ms)", rtt.as_millis()),
+                )
+                .await;
+            }
+            Err(err) => {
+                self.notify_friend_status(&peer_id, false, format!("Không phản hồi ping: {err}"))
+                    .await;
+            }
+        }
+    }
+
+    fn publish_presence(&mut self, swarm: &mut Swarm<super::behavior::ChatBehavior>) {
+        if let Some(local_key) = &self.local_key {
+            presence::publish(swarm, local_key, &self.presence_key);
+        }
+    }
+
+    fn try_dial_peer(
+        &mut self,
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) -> bool {
+        for addr in addresses {
+            // Construct full multiaddr with peer_id if not already present
+            let mut full_addr = addr.clone();
+            if !full_addr.iter().any(|p| matches!(p, Protocol::P2p(_))) {
+                full_addr = full_addr.with(Protocol::P2p(peer_id));
+            }
+
+            match swarm.dial(full_addr.clone()) {
+                Ok(()) => {
+                    // Don't insert into dialed_peers here - only when connection is actually established
+                    // This allows relay fallback to retry if direct connection fails
+                    log::info!("Auto-dialing peer {} at {}", peer_id, full_addr);
+                    return true;
+                }
+                Err(err) => {
+                    log::debug!("Failed to dial {} at {}: {}", peer_id, full_addr, err);
+                    // Try next address
+                }
+            }
+        }
+        false
+    }
+
+    async fn start_auto_dial_from_dht(
+        &mut self,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        // Query DHT for closest peers using local peer id as target
+        // This will return peers that are close to us in the DHT
+        if let Some(local_peer_id) = self.local_peer_id {
+            log::info!("Bootstrap completed, querying DHT for peers to dial...");
+            let query_id = swarm.behaviour_mut().kad.get_closest_peers(local_peer_id);
+            self.auto_dial_query_id = Some(query_id);
+        }
+    }
+
+    async fn handle_auto_dial_result(
+        &mut self,
+        result: Result<kad::GetClosestPeersOk, kad::GetClosestPeersError>,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        match result {
+            Ok(kad::GetClosestPeersOk { peers, .. }) => {
+                log::info!("Found {} peers from DHT, attempting to dial...", peers.len());
+                
+                // Track bootstrap peer IDs to avoid dialing them again
+                let bootstrap_peer_ids: HashSet<PeerId> = self
+                    .bootstrap_peers
+                    .iter()
+                    .map(|(peer_id, _)| *peer_id)
+                    .collect();
+
+                let mut dialed_count = 0;
+                let mut skipped_count = 0;
+
+                for peer_record in peers {
+                    let peer_id = peer_record.peer_id;
+                    
+                    // Skip if already dialed or is a bootstrap peer
+                    if self.dialed_peers.contains(&peer_id) || bootstrap_peer_ids.contains(&peer_id) {
+                        skipped_count += 1;
+                        continue;
+                    }
+
+                    // Get addresses for this peer from stored addresses
+                    // Note: addresses should be available from RoutingUpdated events
+                    let addresses = self.peer_addresses.get(&peer_id).cloned().unwrap_or_default();
+
+                    if addresses.is_empty() {
+                        // If no addresses stored yet, the peer might not be in routing table
+                        // We'll skip for now and let future RoutingUpdated events populate addresses
+                        // Then we can retry dialing in a future auto-dial cycle
+                        log::debug!("No addresses stored for peer {} yet, skipping (will retry when addresses discovered)", peer_id);
+                        skipped_count += 1;
+                        continue;
+                    }
+
+                    // Try to dial using available addresses
+                    let num_addresses = addresses.len();
+                    if self.try_dial_peer(peer_id, addresses, swarm) {
+                        dialed_count += 1;
+                    } else {
+                        log::debug!("Could not dial peer {} with any of {} addresses", peer_id, num_addresses);
+                    }
+                }
+
+                log::info!(
+                    "Auto-dial completed: {} dialed, {} skipped (already dialed or bootstrap)",
+                    dialed_count,
+                    skipped_count
+                );
+            }
+            Err(kad::GetClosestPeersError::Timeout { peers, .. }) => {
+                log::warn!(
+                    "Timeout querying DHT for peers, but found {} peers",
+                    peers.len()
+                );
+                // Still try to dial the peers we found before timeout
+                if !peers.is_empty() {
+                    let bootstrap_peer_ids: HashSet<PeerId> = self
+                        .bootstrap_peers
+                        .iter()
+                        .map(|(peer_id, _)| *peer_id)
+                        .collect();
+
+                    for peer_record in peers {
+                        let peer_id = peer_record.peer_id;
+                        if self.dialed_peers.contains(&peer_id) || bootstrap_peer_ids.contains(&peer_id) {
+                            continue;
+                        }
+
+                        // Try to get addresses and dial
+                        let addresses = self.peer_addresses.get(&peer_id).cloned().unwrap_or_default();
+
+                        for addr in addresses {
+                            let mut full_addr = addr.clone();
+                            if !full_addr.iter().any(|p| matches!(p, Protocol::P2p(_))) {
+                                full_addr = full_addr.with(Protocol::P2p(peer_id));
+                            }
+
+                            if swarm.dial(full_addr.clone()).is_ok() {
+                                // Don't insert into dialed_peers here - only when connection is actually established
+                                // This allows relay fallback to retry if direct connection fails
+                                log::info!("Dialing peer {} at {}", peer_id, full_addr);
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_blocked(&self, peer_id: &str) -> bool {
+        PeerId::from_str(peer_id).is_ok_and(|peer| self.blocked.contains(&peer))
+    }
+
+    /// Ask `peer` for its profile, unless we did recently.
+    fn fetch_profile(&mut self, peer: PeerId, swarm: &mut Swarm<super::behavior::ChatBehavior>) {
+        if Some(peer) == self.local_peer_id
+            || self
+                .profile_checked
+                .get(&peer)
+                .is_some_and(|checked| checked.elapsed() < PROFILE_REFRESH_INTERVAL)
+        {
+            return;
+        }
+        self.profile_checked.insert(peer, Instant::now());
+        let known = self
+            .profiles
+            .get(&peer.to_string())
+            .map_or(0, |profile| profile.updated_at);
+        let relay_addrs = if swarm.is_connected(&peer) {
+            Vec::new()
+        } else {
+            self.nat_traversal.relay_circuit_addrs(&peer)
+        };
+        swarm.behaviour_mut().profile.send_request_with_addresses(
+            &peer,
+            ProfileRequest::Get { known },
+            relay_addrs,
+        );
+    }
+
+    async fn handle_profile_event(
+        &mut self,
+        event: ProfileEvent,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+                ..
+            } => {
+                let response = match request {
+                    ProfileRequest::Get { known } => {
+                        let own = self
+                            .local_peer_id
+                            .and_then(|local| self.profiles.get(&local.to_string()));
+                        match own {
+                            None => ProfileResponse::NoProfile,
+                            Some(profile) if profile.updated_at <= known => {
+                                ProfileResponse::Unchanged
+                            }
+                            Some(profile) => ProfileResponse::Profile(profile.clone()),
+                        }
+                    }
+                    ProfileRequest::Update(profile) => {
+                        self.accept_profile(peer, profile).await;
+                        ProfileResponse::Done
+                    }
+                };
+                if swarm
+                    .behaviour_mut()
+                    .profile
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    log::debug!("Failed to answer profile request from {peer}");
+                }
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { response, .. },
+                ..
+            } => {
+                if let ProfileResponse::Profile(profile) = response {
+                    self.accept_profile(peer, profile).await;
+                }
+            }
+            request_response::Event::OutboundFailure { peer, error, .. } => {
+                log::debug!("Profile request to {peer} failed: {error}");
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log::debug!("Profile request from {peer} failed: {error}");
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Keep a profile `peer` sent about itself if it checks out and is newer.
+    async fn accept_profile(&mut self, peer: PeerId, profile: PeerProfile) {
+        if !verify_profile(&profile, &peer) {
+            log::warn!("Ignoring invalid profile from {peer}");
+            return;
+        }
+        if self
+            .profiles
+            .get(&profile.peer_id)
+            .is_some_and(|known| known.updated_at >= profile.updated_at)
+        {
+            return;
+        }
+        log::debug!("New profile of {peer}: {}", profile.display_name);
+        self.store_profile(profile).await;
+    }
+
+    async fn store_profile(&mut self, profile: PeerProfile) {
+        if let Some(db) = &self.db
+            && let Err(err) = db.save_profile(&profile)
+        {
+            log::warn!("Failed to save profile of {}: {err}", profile.peer_id);
+        }
+        self.profiles
+            .insert(profile.peer_id.clone(), profile.clone());
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::ProfileUpdated(profile))
+            .await;
+    }
+
+    async fn handle_friend_event(
+        &mut self,
+        event: FriendEvent,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+                ..
+            } => {
+                let response = self.handle_friend_request(peer, request, swarm).await;
+                if swarm
+                    .behaviour_mut()
+                    .friends
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    log::warn!("Failed to answer friend request from {peer}");
+                }
+            }
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                let Some(pending) = self.friend_messages.remove(&request_id) else {
+                    return;
+                };
+                match response {
+                    FriendResponse::Accepted { presence_key } => {
+                        if !self.friendship_confirmed(peer, presence_key, swarm).await {
+                            return;
+                        }
+                        if pending.was_request {
+                            // They accepted earlier or asked too: now they need our key
+                            let presence_key = self.presence_key.clone();
+                            self.send_friend_message(
+                                peer,
+                                FriendRequest::Accept { presence_key },
+                                swarm,
+                            );
+                        } else {
+                            self.mark_key_delivered(&peer.to_string());
+                        }
+                    }
+                    FriendResponse::Pending => {
+                        log::info!("Friend request to {peer} is waiting for their answer");
+                    }
+                    FriendResponse::Done => {}
+                    FriendResponse::NotRequested => {
+                        log::debug!("{peer} has no friend request from us to answer");
+                    }
+                }
+            }
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                // Sent again on the next connection or refresh
+                self.friend_messages.remove(&request_id);
+                log::debug!("Friend request to {peer} failed: {error}");
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log::debug!("Inbound friend request from {peer} failed: {error}");
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    async fn handle_friend_request(
+        &mut self,
+        peer: PeerId,
+        request: FriendRequest,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) -> FriendResponse {
+        let peer_id = peer.to_string();
+        let state = self.friends.get(&peer_id).map(|friend| friend.state);
+        match request {
+            FriendRequest::Request { note } => {
+                let note: String = note.trim().chars().take(MAX_FRIEND_NOTE_CHARS).collect();
+                match state {
+                    Some(FriendState::Accepted) => {}
+                    // Both asked: that is a yes from each side
+                    Some(FriendState::Outgoing) => {
+                        if let Some(friend) = self.friends.get(&peer_id).cloned() {
+                            self.set_friend(Friend {
+                                state: FriendState::Accepted,
+                                updated_at: Utc::now().timestamp(),
+                                ..friend
+                            });
+                            self.friendship_started(&peer_id, swarm).await;
+                        }
+                    }
+                    Some(FriendState::Incoming) | None => {
+                        if !self.note_friend_request(peer) {
+                            log::debug!("Ignoring repeated friend request from {peer}");
+                            return FriendResponse::Pending;
+                        }
+                        if state.is_none() {
+                            self.make_room_for_friend_request().await;
+                        }
+                        log::info!("Friend request from {peer}");
+                        self.set_friend(Friend {
+                            peer_id: peer_id.clone(),
+                            state: FriendState::Incoming,
+                            note: note.clone(),
+                            presence_key: None,
+                            updated_at: Utc::now().timestamp(),
+                            petname: String::new(),
+                            annotation: String::new(),
+                            key_delivered: false,
+                        });
+                        let _ = self
+                            .event_sender
+                            .send(NetworkEvent::FriendRequestReceived {
+                                peer: peer_id,
+                                note,
+                            })
+                            .await;
+                        return FriendResponse::Pending;
+                    }
+                }
+                // Our key goes back with the answer
+                self.mark_key_delivered(&peer_id);
+                FriendResponse::Accepted {
+                    presence_key: self.presence_key.clone(),
+                }
+            }
+            FriendRequest::Accept { presence_key } => {
+                if self.friendship_confirmed(peer, presence_key, swarm).await {
+                    self.mark_key_delivered(&peer_id);
+                    FriendResponse::Accepted {
+                        presence_key: self.presence_key.clone(),
+                    }
+                } else {
+                    FriendResponse::NotRequested
+                }
+            }
+            FriendRequest::Decline => {
+                match state {
+                    Some(FriendState::Outgoing | FriendState::Incoming) => {
+                        log::info!("{peer} declined or withdrew the friend request");
+                        self.forget_friend(&peer_id);
+                        let _ = self
+                            .event_sender
+                            .send(NetworkEvent::FriendRequestClosed { peer: peer_id })
+                            .await;
+                    }
+                    Some(FriendState::Accepted) => {
+                        log::info!("{peer} removed us from their friends");
+                        self.friendship_ended(&peer_id, swarm).await;
+                    }
+                    None => {}
+                }
+                FriendResponse::Done
+            }
+        }
+    }
+
+    /// `peer` accepted our request (or confirms an accepted friendship) and
+    /// sent its presence key. Returns `false` if we never asked.
+    async fn friendship_confirmed(
+        &mut self,
+        peer: PeerId,
+        presence_key: Vec<u8>,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) -> bool {
+        let peer_id = peer.to_string();
+        let Some(friend) = self.friends.get(&peer_id).cloned() else {
+            return false;
+        };
+        let was_outgoing = match friend.state {
+            FriendState::Outgoing => true,
+            FriendState::Accepted => false,
+            FriendState::Incoming => return false,
+        };
+        let presence_key = (presence_key.len() == PRESENCE_KEY_LEN).then_some(presence_key);
+        self.set_friend(Friend {
+            state: FriendState::Accepted,
+            presence_key: presence_key.or(friend.presence_key.clone()),
+            updated_at: Utc::now().timestamp(),
+            ..friend
+        });
+        if was_outgoing {
+            log::info!("{peer} accepted our friend request");
+            self.friendship_started(&peer_id, swarm).await;
+        }
+        true
+    }
+
+    /// A friend request was accepted on either side.
+    async fn friendship_started(
+        &mut self,
+        peer_id: &str,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
+    ) {
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::FriendRequestClosed {
+                peer: peer_id.to_string(),
+            })
+            .await;
+        self.notify_friend_status(peer_id, false, "Đã kết bạn, đang kiểm tra trạng thái...")
+            .await;
+        // The next refresh tick checks them right away
+        self.friend_schedule.remove(peer_id);
+        self.refresh_friends(swarm).await;
     }
 
-    async fn handle_friend_lookup_result(
-        &self,
-        peer_id: String,
-        result: Result<kad::GetClosestPeersOk, kad::GetClosestPeersError>,
+    /// A friendship was ended on either side.
+    async fn friendship_ended(
+        &mut self,
+        peer_id: &str,
+        swarm: &mut Swarm<super::behavior::ChatBehavior>,
     ) {
-        match result {
-            Ok(kad::GetClosestPeersOk { peers, .. }) => {
-                let target = PeerId::from_str(&peer_id).ok();
-                let found = target
-                    .as_ref()
-                    .map(|target_peer| {
-                        peers
-                            .iter()
-                            .any(|peer| peer.peer_id == *target_peer)
-                    })
-                    .unwrap_or(false);
-                let message = if found {
-                    format!("Đã tìm thấy trong DHT ({} peers gần nhất)", peers.len())
-                } else if peers.is_empty() {
-                    "Không tìm thấy peer trong DHT".to_string()
-                } else {
-                    format!(
-                        "Không tìm thấy, nhưng có {} peers gần nhất trả về",
-                        peers.len()
-                    )
-                };
-                self.notify_friend_status(&peer_id, found, message).await;
+        self.forget_friend(peer_id);
+        if let Ok(peer) = PeerId::from_str(peer_id) {
+            self.awaiting_ping.remove(&peer);
+        }
+        let _ = self
+            .event_sender
+            .send(NetworkEvent::FriendRemoved {
+                peer: peer_id.to_string(),
+            })
+            .await;
+        self.rotate_presence_key(swarm);
+    }
+
+    /// Replace our presence key and publish a record only the remaining
+    /// friends can open, once they receive the new key.
+    fn rotate_presence_key(&mut self, swarm: &mut Swarm<super::behavior::ChatBehavior>) {
+        self.presence_key = presence::generate_key();
+        if let Some(db) = &self.db {
+            if let Err(err) = db.set_presence_key(&self.presence_key) {
+                log::warn!("Failed to save presence key: {err}");
             }
-            Err(kad::GetClosestPeersError::Timeout { peers, .. }) => {
-                let message = format!(
-                    "Timeout khi truy vấn bootstrap ({} peers gần nhất)",
-                    peers.len()
-                );
-                self.notify_friend_status(&peer_id, false, message).await;
+            if let Err(err) = db.reset_friend_keys_delivered() {
+                log::warn!("Failed to reset presence key delivery: {err}");
             }
         }
+        for friend in self.friends.values_mut() {
+            friend.key_delivered = false;
+        }
+        self.publish_presence(swarm);
+        // Friends we are connected to get the new key now, the others on
+        // their next connection or refresh
+        let connected: Vec<PeerId> = self
+            .friend_ids
+            .iter()
+            .filter_map(|peer_id| PeerId::from_str(peer_id).ok())
+            .filter(|peer| swarm.is_connected(peer))
+            .collect();
+        for peer in connected {
+            self.deliver_friend_handshake(peer, swarm);
+        }
     }
 
-    fn try_dial_peer(
+    fn mark_key_delivered(&mut self, peer_id: &str) {
+        let Some(friend) = self.friends.get(peer_id).cloned() else {
+            return;
+        };
+        if friend.state == FriendState::Accepted && !friend.key_delivered {
+            self.set_friend(Friend {
+                key_delivered: true,
+                ..friend
+            });
+        }
+    }
+
+    /// Send what the other side still has to hear from us: our request while
+    /// it is unanswered, our presence key while we lack theirs or they have
+    /// not confirmed our current one.
+    fn deliver_friend_handshake(
         &mut self,
-        peer_id: PeerId,
-        addresses: Vec<Multiaddr>,
+        peer: PeerId,
         swarm: &mut Swarm<super::behavior::ChatBehavior>,
-    ) -> bool {
-        for addr in addresses {
-            // Construct full multiaddr with peer_id if not already present
-            let mut full_addr = addr.clone();
-            if !full_addr.iter().any(|p| matches!(p, Protocol::P2p(_))) {
-                full_addr = full_addr.with(Protocol::P2p(peer_id));
-            }
-
-            match swarm.dial(full_addr.clone()) {
-                Ok(()) => {
-                    // Don't insert into dialed_peers here - only when connection is actually established
-                    // This allows relay fallback to retry if direct connection fails
-                    log::info!("Auto-dialing peer {} at {}", peer_id, full_addr);
-                    return true;
-                }
-                Err(err) => {
-                    log::debug!("Failed to dial {} at {}: {}", peer_id, full_addr, err);
-                    // Try next address
+    ) {
+        let Some(friend) = self.friends.get(&peer.to_string()) else {
+            return;
+        };
+        let request = match friend.state {
+            FriendState::Outgoing => FriendRequest::Request {
+                note: friend.note.clone(),
+            },
+            FriendState::Accepted if friend.presence_key.is_none() || !friend.key_delivered => {
+                FriendRequest::Accept {
+                    presence_key: self.presence_key.clone(),
                 }
             }
+            _ => return,
+        };
+        if self
+            .friend_messages
+            .values()
+            .any(|pending| pending.peer == peer)
+        {
+            return;
         }
-        false
+        self.send_friend_message(peer, request, swarm);
     }
 
-    async fn start_auto_dial_from_dht(
+    fn send_friend_message(
         &mut self,
+        peer: PeerId,
+        request: FriendRequest,
         swarm: &mut Swarm<super::behavior::ChatBehavior>,
     ) {
-        // Query DHT for closest peers using local peer id as target
-        // This will return peers that are close to us in the DHT
-        if let Some(local_peer_id) = self.local_peer_id {
-            log::info!("Bootstrap completed, querying DHT for peers to dial...");
-            let query_id = swarm.behaviour_mut().kad.get_closest_peers(local_peer_id);
-            self.auto_dial_query_id = Some(query_id);
+        let was_request = matches!(request, FriendRequest::Request { .. });
+        // Same routes as direct messages: known addresses, then relay circuits
+        let relay_addrs = if swarm.is_connected(&peer) {
+            Vec::new()
+        } else {
+            self.nat_traversal.relay_circuit_addrs(&peer)
+        };
+        let request_id =
+            swarm
+                .behaviour_mut()
+                .friends
+                .send_request_with_addresses(&peer, request, relay_addrs);
+        self.friend_messages
+            .insert(request_id, PendingFriendMessage { peer, was_request });
+    }
+
+    /// Store a friend (or request) and keep the accepted set in step.
+    fn set_friend(&mut self, friend: Friend) {
+        if let Some(db) = &self.db
+            && let Err(err) = db.save_friend(&friend)
+        {
+            log::warn!("Failed to save friend {}: {err}", friend.peer_id);
+        }
+        if friend.state == FriendState::Accepted {
+            self.friend_ids.insert(friend.peer_id.clone());
+        } else {
+            self.friend_ids.remove(&friend.peer_id);
         }
+        // What the database kept (an earlier presence key included)
+        let friend = match &self.db {
+            Some(db) => db
+                .get_friend(&friend.peer_id)
+                .ok()
+                .flatten()
+                .unwrap_or(friend),
+            None => friend,
+        };
+        self.friends.insert(friend.peer_id.clone(), friend);
     }
 
-    async fn handle_auto_dial_result(
-        &mut self,
-        result: Result<kad::GetClosestPeersOk, kad::GetClosestPeersError>,
-        swarm: &mut Swarm<super::behavior::ChatBehavior>,
-    ) {
-        match result {
-            Ok(kad::GetClosestPeersOk { peers, .. }) => {
-                log::info!("Found {} peers from DHT, attempting to dial...", peers.len());
-                
-                // Track bootstrap peer IDs to avoid dialing them again
-                let bootstrap_peer_ids: HashSet<PeerId> = self
-                    .bootstrap_peers
-                    .iter()
-                    .map(|(peer_id, _)| *peer_id)
-                    .collect();
+    /// Note a friend request from `peer`; `false` if it sent one too recently.
+    fn note_friend_request(&mut self, peer: PeerId) -> bool {
+        let now = Instant::now();
+        self.friend_requests_seen
+            .retain(|_, at| now.duration_since(*at) < FRIEND_REQUEST_INTERVAL);
+        if self.friend_requests_seen.contains_key(&peer) {
+            return false;
+        }
+        self.friend_requests_seen.insert(peer, now);
+        true
+    }
 
-                let mut dialed_count = 0;
-                let mut skipped_count = 0;
+    /// Drop the oldest pending friend requests so a new one fits.
+    async fn make_room_for_friend_request(&mut self) {
+        let mut incoming: Vec<(i64, String)> = self
+            .friends
+            .values()
+            .filter(|friend| friend.state == FriendState::Incoming)
+            .map(|friend| (friend.updated_at, friend.peer_id.clone()))
+            .collect();
+        if incoming.len() < MAX_INCOMING_FRIEND_REQUESTS {
+            return;
+        }
+        incoming.sort();
+        let excess = incoming.len() + 1 - MAX_INCOMING_FRIEND_REQUESTS;
+        for (_, peer_id) in incoming.into_iter().take(excess) {
+            log::info!("Dropping the friend request from {peer_id} to make room");
+            self.forget_friend(&peer_id);
+            let _ = self
+                .event_sender
+                .send(NetworkEvent::FriendRequestClosed { peer: peer_id })
+                .await;
+        }
+    }
 
-                for peer_record in peers {
-                    let peer_id = peer_record.peer_id;
-                    
-                    // Skip if already dialed or is a bootstrap peer
-                    if self.dialed_peers.contains(&peer_id) || bootstrap_peer_ids.contains(&peer_id) {
-                        skipped_count += 1;
-                        continue;
-                    }
+    fn forget_friend(&mut self, peer_id: &str) {
+        if let Some(db) = &self.db
+            && let Err(err) = db.remove_friend(peer_id)
+        {
+            log::warn!("Failed to remove friend {peer_id}: {err}");
+        }
+        self.friends.remove(peer_id);
+        self.friend_ids.remove(peer_id);
+        self.friend_schedule.remove(peer_id);
+    }
+}
 
-                    // Get addresses for this peer from stored addresses
-                    // Note: addresses should be available from RoutingUpdated events
-                    let addresses = self.peer_addresses.get(&peer_id).cloned().unwrap_or_default();
+/// Fetch of a missing parent message, asked for because replies to it arrived.
+struct ThreadFetch {
+    room: String,
+    /// Peer the first reply came from, likely to hold the parent
+    peer: PeerId,
+    attempts: u32,
+    /// When to ask again after a failed try; `None` while a request is out
+    retry_at: Option<Instant>,
+}
 
-                    if addresses.is_empty() {
-                        // If no addresses stored yet, the peer might not be in routing table
-                        // We'll skip for now and let future RoutingUpdated events populate addresses
-                        // Then we can retry dialing in a future auto-dial cycle
-                        log::debug!("No addresses stored for peer {} yet, skipping (will retry when addresses discovered)", peer_id);
-                        skipped_count += 1;
-                        continue;
-                    }
+/// A direct request awaiting the receiver's acknowledgement.
+struct PendingDirect {
+    peer: String,
+    /// Id of the chat message carried, `None` for receipts
+    message_id: Option<String>,
+    /// What was sent, kept to leave in a mailbox if the friend is unreachable
+    sealed: Option<SealedMessage>,
+}
 
-                    // Try to dial using available addresses
-                    let num_addresses = addresses.len();
-                    if self.try_dial_peer(peer_id, addresses, swarm) {
-                        dialed_count += 1;
-                    } else {
-                        log::debug!("Could not dial peer {} with any of {} addresses", peer_id, num_addresses);
-                    }
-                }
+/// A friend request or answer awaiting the other side's response.
+struct PendingFriendMessage {
+    peer: PeerId,
+    /// A request (not an answer): an `Accepted` reply means they still need our key
+    was_request: bool,
+}
 
-                log::info!(
-                    "Auto-dial completed: {} dialed, {} skipped (already dialed or bootstrap)",
-                    dialed_count,
-                    skipped_count
-                );
-            }
-            Err(kad::GetClosestPeersError::Timeout { peers, .. }) => {
-                log::warn!(
-                    "Timeout querying DHT for peers, but found {} peers",
-                    peers.len()
-                );
-                // Still try to dial the peers we found before timeout
-                if !peers.is_empty() {
-                    let bootstrap_peer_ids: HashSet<PeerId> = self
-                        .bootstrap_peers
-                        .iter()
-                        .map(|(peer_id, _)| *peer_id)
-                        .collect();
+/// When a friend is checked again.
+struct FriendSchedule {
+    next_check: Instant,
+    /// Checks in a row that did not reach the friend
+    failures: u32,
+}
 
-                    for peer_record in peers {
-                        let peer_id = peer_record.peer_id;
-                        if self.dialed_peers.contains(&peer_id) || bootstrap_peer_ids.contains(&peer_id) {
-                            continue;
-                        }
+/// A request to a nodemaster mailbox waiting for its answer.
+enum PendingMailbox {
+    Deposit(PendingDirect),
+    Fetch,
+    /// `more`: fetch again once the acknowledgement is in
+    Ack {
+        more: bool,
+    },
+}
 
-                        // Try to get addresses and dial
-                        let addresses = self.peer_addresses.get(&peer_id).cloned().unwrap_or_default();
+/// Why an incoming direct message could not be read.
+enum DirectOpenError {
+    E2e(E2eError),
+    Envelope(EnvelopeError),
+}
 
-                        for addr in addresses {
-                            let mut full_addr = addr.clone();
-                            if !full_addr.iter().any(|p| matches!(p, Protocol::P2p(_))) {
-                                full_addr = full_addr.with(Protocol::P2p(peer_id));
-                            }
+/// A new chat message from us, without reply or attachment.
+fn new_chat_message(sender: PeerId, room: String, content: String) -> ChatMessage {
+    ChatMessage {
+        id: Uuid::new_v4().to_string(),
+        sender: sender.to_string(),
+        content,
+        timestamp: Utc::now().timestamp(),
+        room,
+        edited: false,
+        deleted: false,
+        reply_to: None,
+        attachment: None,
+    }
+}
 
-                            if swarm.dial(full_addr.clone()).is_ok() {
-                                // Don't insert into dialed_peers here - only when connection is actually established
-                                // This allows relay fallback to retry if direct connection fails
-                                log::info!("Dialing peer {} at {}", peer_id, full_addr);
-                                break;
-                            }
-                        }
-                    }
-                }
-            }
+/// Decode a gossip message and check that its claimed sender is the peer that
+/// signed it. `room` is the room of the topic it arrived on. On failure the
+/// verdict to report to gossipsub comes with the reason.
+fn validate_gossip_message(
+    message: &gossipsub::Message,
+    room: &str,
+) -> Result<Envelope, (gossipsub::MessageAcceptance, String)> {
+    let reject = |reason: String| (gossipsub::MessageAcceptance::Reject, reason);
+    let Some(source) = message.source else {
+        return Err(reject("unsigned message".to_string()));
+    };
+    let envelope = Envelope::decode(&message.data).map_err(|err| match err {
+        // A newer client is not misbehaving; just don't forward what we can't check
+        EnvelopeError::UnsupportedVersion(_) | EnvelopeError::UnknownKind(_) => {
+            (gossipsub::MessageAcceptance::Ignore, err.to_string())
         }
+        _ => reject(err.to_string()),
+    })?;
+    if envelope.sender != source.to_string() {
+        return Err(reject(format!(
+            "sender `{}` does not match signing peer {source}",
+            envelope.sender
+        )));
+    }
+    envelope.check_limits().map_err(reject)?;
+    // Only the author may change a message, and the signature must prove it
+    if let Payload::Op(op) = &envelope.payload
+        && (op.author != envelope.sender || op.room != room || !verify_op(op))
+    {
+        return Err(reject(format!("forged op on message {}", op.target_id)));
+    }
+    if let Payload::Reaction { emoji, .. } | Payload::ReactionRemoved { emoji, .. } =
+        &envelope.payload
+        && !is_valid_reaction(emoji)
+    {
+        return Err(reject("invalid reaction".to_string()));
+    }
+    // Stale typing signals are neither shown nor forwarded
+    if envelope.is_expired(Utc::now().timestamp()) {
+        return Err((
+            gossipsub::MessageAcceptance::Ignore,
+            "expired ephemeral message".to_string(),
+        ));
+    }
+    // The signed room is what sync hands on later, so it must be the topic's
+    if envelope.room != room {
+        return Err(reject(format!(
+            "room `{}` does not match the topic",
+            envelope.room
+        )));
+    }
+    Ok(envelope)
+}
+
+/// Decode a message served by history sync and check it as if it had arrived
+/// over gossip: the peer serving it is not its author, so only the author's
+/// signature vouches for it. `joined` are the rooms we are in.
+fn validate_synced_message(encoded: &[u8], joined: &[String]) -> Result<ChatMessage, String> {
+    let envelope = Envelope::decode(encoded).map_err(|err| err.to_string())?;
+    envelope.check_limits()?;
+    if !joined.contains(&envelope.room) {
+        return Err(format!("not in room `{}`", envelope.room));
     }
+    let id = envelope.id.clone();
+    envelope
+        .into_room_message(encoded)
+        .ok_or_else(|| format!("message {id} is not a chat message"))
+}
+
+/// Check an op served by history sync: it is relayed by whoever served it, so
+/// only its author's signature counts.
+fn validate_synced_op(op: &MessageOp, joined: &[String]) -> Result<(), String> {
+    check_op_limits(op)?;
+    if !joined.contains(&op.room) {
+        return Err(format!("not in room `{}`", op.room));
+    }
+    if !verify_op(op) {
+        return Err(format!("not signed by {}", op.author));
+    }
+    Ok(())
+}
+
+/// Counts the messages each peer published in each room over a fixed window.
+#[derive(Default)]
+struct GossipRateLimiter {
+    /// (author, topic) -> start of the current window and messages seen in it
+    windows: HashMap<(PeerId, gossipsub::TopicHash), (Instant, u32)>,
+    last_pruned: Option<Instant>,
+}
+
+/// What to do with a room message, going by how many its author published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RateVerdict {
+    Allowed,
+    /// Past the limit: drop it without forwarding
+    Limited,
+    /// The author just passed twice the limit in this window and is
+    /// flooding; penalise it once, and drop the message
+    Flooding,
+}
 
-    fn persist_friend_list(&self) {
-        if let Err(err) = write_friend_list(&self.friend_ids) {
-            log::warn!("Failed to persist friend list: {err}");
+impl GossipRateLimiter {
+    /// Count a message published by `author` on `topic`. The margin before
+    /// [`RateVerdict::Flooding`] absorbs messages the network delayed and
+    /// delivered in a burst.
+    fn check(&mut self, author: PeerId, topic: &gossipsub::TopicHash, now: Instant) -> RateVerdict {
+        if self
+            .last_pruned
+            .is_none_or(|at| now.duration_since(at) >= RATE_WINDOW)
+        {
+            self.windows
+                .retain(|_, (started, _)| now.duration_since(*started) < RATE_WINDOW);
+            self.last_pruned = Some(now);
+        }
+        let (started, count) = self
+            .windows
+            .entry((author, topic.clone()))
+            .or_insert((now, 0));
+        if now.duration_since(*started) >= RATE_WINDOW {
+            *started = now;
+            *count = 0;
+        }
+        *count = count.saturating_add(1);
+        if *count <= RATE_LIMIT_MESSAGES {
+            RateVerdict::Allowed
+        } else if *count == 2 * RATE_LIMIT_MESSAGES + 1 {
+            RateVerdict::Flooding
+        } else {
+            RateVerdict::Limited
         }
     }
 }
@@ -769,6 +3942,51 @@ fn load_or_generate_local_key() -> Result<identity::Keypair, Box<dyn Error>> {
     }
 }
 
+/// Friends from the database. Entries of the old `friends.json` list become
+/// outgoing requests, so the handshake runs once with each of them.
+fn load_friends(db: Option<&ClientDatabase>) -> HashMap<String, Friend> {
+    let Some(db) = db else {
+        return HashMap::new();
+    };
+    let legacy = load_friend_list_from_disk();
+    if !legacy.is_empty() {
+        let known = db.get_friends().unwrap_or_default();
+        for peer_id in legacy {
+            if known.iter().any(|friend| friend.peer_id == peer_id)
+                || PeerId::from_str(&peer_id).is_err()
+            {
+                continue;
+            }
+            let friend = Friend {
+                peer_id,
+                state: FriendState::Outgoing,
+                note: String::new(),
+                presence_key: None,
+                updated_at: Utc::now().timestamp(),
+                petname: String::new(),
+                annotation: String::new(),
+                key_delivered: false,
+            };
+            if let Err(err) = db.save_friend(&friend) {
+                log::warn!("Failed to migrate friend {}: {err}", friend.peer_id);
+            }
+        }
+        if let Err(err) = fs::rename(FRIENDS_FILE, format!("{FRIENDS_FILE}.migrated")) {
+            log::warn!("Failed to retire {FRIENDS_FILE}: {err}");
+        }
+    }
+    match db.get_friends() {
+        Ok(friends) => friends
+            .into_iter()
+            .map(|friend| (friend.peer_id.clone(), friend))
+            .collect(),
+        Err(err) => {
+            log::warn!("Failed to load friends: {err}");
+            HashMap::new()
+        }
+    }
+}
+
 fn load_friend_list_from_disk() -> HashSet<String> {
     match fs::read_to_string(FRIENDS_FILE) {
         Ok(content) => match serde_json::from_str::<Vec<String>>(&content) {
@@ -786,16 +4004,53 @@ fn load_friend_list_from_disk() -> HashSet<String> {
     }
 }
 
-fn write_friend_list(friends: &HashSet<String>) -> io::Result<()> {
-    let mut entries: Vec<String> = friends.iter().cloned().collect();
-    entries.sort();
+fn load_blocked_peers(db: Option<&ClientDatabase>) -> HashSet<PeerId> {
+    let Some(db) = db else {
+        return HashSet::new();
+    };
+    match db.get_blocked_peers() {
+        Ok(peers) => peers
+            .iter()
+            .filter_map(|peer_id| PeerId::from_str(peer_id).ok())
+            .collect(),
+        Err(err) => {
+            log::warn!("Failed to load blocked peers: {err}");
+            HashSet::new()
+        }
+    }
+}
 
-    if let Some(parent) = Path::new(FRIENDS_FILE).parent() {
-        fs::create_dir_all(parent)?;
+fn load_profiles(db: Option<&ClientDatabase>) -> HashMap<String, PeerProfile> {
+    let Some(db) = db else {
+        return HashMap::new();
+    };
+    match db.get_profiles() {
+        Ok(profiles) => profiles
+            .into_iter()
+            .map(|profile| (profile.peer_id.clone(), profile))
+            .collect(),
+        Err(err) => {
+            log::warn!("Failed to load profiles: {err}");
+            HashMap::new()
+        }
     }
+}
 
-    let payload = serde_json::to_string_pretty(&entries)?;
-    fs::write(FRIENDS_FILE, payload)
+/// Our presence key, created on first start. Without a database it only lasts
+/// for this run.
+fn load_presence_key(db: Option<&ClientDatabase>) -> Vec<u8> {
+    if let Some(key) = db.and_then(|db| db.get_presence_key().ok().flatten())
+        && key.len() == PRESENCE_KEY_LEN
+    {
+        return key;
+    }
+    let key = presence::generate_key();
+    if let Some(db) = db
+        && let Err(err) = db.set_presence_key(&key)
+    {
+        log::warn!("Failed to save presence key: {err}");
+    }
+    key
 }
 
 fn client_public_addr_from_env() -> Option<Multiaddr> {
@@ -810,3 +4065,130 @@ fn client_public_addr_from_env() -> Option<Multiaddr> {
         Err(_) => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::envelope::EPHEMERAL_TTL_SECS;
+
+    #[test]
+    fn rate_limiter_ignores_past_the_limit_and_flags_a_flood_once() {
+        let mut limiter = GossipRateLimiter::default();
+        let (author, other) = (PeerId::random(), PeerId::random());
+        let topic = room_topic("general").hash();
+        let start = Instant::now();
+
+        let verdicts: Vec<RateVerdict> = (0..3 * RATE_LIMIT_MESSAGES)
+            .map(|_| limiter.check(author, &topic, start))
+            .collect();
+        let limit = RATE_LIMIT_MESSAGES as usize;
+        assert!(verdicts[..limit].iter().all(|v| *v == RateVerdict::Allowed));
+        assert!(
+            verdicts[limit..2 * limit]
+                .iter()
+                .all(|v| *v == RateVerdict::Limited)
+        );
+        assert_eq!(verdicts[2 * limit], RateVerdict::Flooding);
+        assert!(
+            verdicts[2 * limit + 1..]
+                .iter()
+                .all(|v| *v == RateVerdict::Limited)
+        );
+
+        // Other authors and other rooms have windows of their own
+        assert_eq!(limiter.check(other, &topic, start), RateVerdict::Allowed);
+        let elsewhere = room_topic("elsewhere").hash();
+        assert_eq!(
+            limiter.check(author, &elsewhere, start),
+            RateVerdict::Allowed
+        );
+
+        // A new window starts afresh
+        let later = start + RATE_WINDOW;
+        assert_eq!(limiter.check(author, &topic, later), RateVerdict::Allowed);
+    }
+
+    fn gossip(source: Option<PeerId>, data: Vec<u8>, room: &str) -> gossipsub::Message {
+        gossipsub::Message {
+            source,
+            data,
+            sequence_number: None,
+            topic: room_topic(room).hash(),
+        }
+    }
+
+    fn text(local_key: &identity::Keypair, room: &str) -> Vec<u8> {
+        Envelope::new(
+            local_key.public().to_peer_id().to_string(),
+            room.to_string(),
+            Payload::Text {
+                content: "hi".to_string(),
+            },
+        )
+        .encode(local_key)
+        .unwrap()
+    }
+
+    fn verdict(result: Result<Envelope, (gossipsub::MessageAcceptance, String)>) -> String {
+        match result {
+            Ok(_) => "accept".to_string(),
+            Err((acceptance, _)) => format!("{acceptance:?}"),
+        }
+    }
+
+    #[test]
+    fn gossip_must_come_from_its_sender() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let author = local_key.public().to_peer_id();
+        let data = text(&local_key, "general");
+
+        let message = gossip(Some(author), data.clone(), "general");
+        assert_eq!(
+            verdict(validate_gossip_message(&message, "general")),
+            "accept"
+        );
+
+        let unsigned = gossip(None, data.clone(), "general");
+        assert_eq!(
+            verdict(validate_gossip_message(&unsigned, "general")),
+            "Reject"
+        );
+
+        // Republished under someone else's gossipsub identity
+        let relayed = gossip(Some(PeerId::random()), data, "general");
+        assert_eq!(
+            verdict(validate_gossip_message(&relayed, "general")),
+            "Reject"
+        );
+    }
+
+    #[test]
+    fn gossip_must_stay_in_its_room() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let author = local_key.public().to_peer_id();
+        let message = gossip(Some(author), text(&local_key, "general"), "elsewhere");
+        assert_eq!(
+            verdict(validate_gossip_message(&message, "elsewhere")),
+            "Reject"
+        );
+    }
+
+    #[test]
+    fn stale_typing_signal_is_ignored() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let author = local_key.public().to_peer_id();
+        let mut typing = Envelope::typing(author.to_string(), "general".to_string());
+        let fresh = gossip(Some(author), typing.encode(&local_key).unwrap(), "general");
+        assert_eq!(
+            verdict(validate_gossip_message(&fresh, "general")),
+            "accept"
+        );
+
+        typing.timestamp -= EPHEMERAL_TTL_SECS + 1;
+        let stale = gossip(Some(author), typing.encode(&local_key).unwrap(), "general");
+        assert_eq!(
+            verdict(validate_gossip_message(&stale, "general")),
+            "Ignore"
+        );
+    }
+}
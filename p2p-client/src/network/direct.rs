@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use libp2p::StreamProtocol;
+use libp2p::request_response::{self, ProtocolSupport};
+use serde::{Deserialize, Serialize};
+
+use super::e2e::SealedMessage;
+
+/// Protocol used for one-to-one messages between friends.
+pub const DIRECT_PROTOCOL: StreamProtocol = StreamProtocol::new("/rust-p2p-chat/dm/1.0.0");
+
+/// A wire envelope (see `network::envelope`) encrypted for the receiving
+/// friend (see `network::e2e`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectRequest {
+    pub sealed: SealedMessage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirectStatus {
+    Delivered,
+    /// The receiver does not have the sender in its friend list
+    NotFriend,
+    /// The receiver could not decrypt or authenticate the payload
+    Undecryptable,
+    /// The payload uses a wire envelope version the receiver does not know
+    UnsupportedVersion,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectResponse {
+    pub status: DirectStatus,
+}
+
+pub type DirectBehaviour = request_response::cbor::Behaviour<DirectRequest, DirectResponse>;
+pub type DirectEvent = request_response::Event<DirectRequest, DirectResponse>;
+
+pub fn build_direct_behaviour() -> DirectBehaviour {
+    // Relayed circuits are slower to set up, so give them more headroom than sync
+    let config = request_response::Config::default().with_request_timeout(Duration::from_secs(60));
+    request_response::cbor::Behaviour::new([(DIRECT_PROTOCOL, ProtocolSupport::Full)], config)
+}
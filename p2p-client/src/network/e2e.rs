@@ -0,0 +1,699 @@
+//! End-to-end encryption for direct messages.
+//!
+//! Each pair of friends shares a root secret derived from their ed25519
+//! identity keys (converted to X25519), so only the two key holders can read
+//! a conversation. On top of that root a Double Ratchet provides forward
+//! secrecy: every reply rotates the DH ratchet key and every message uses a
+//! fresh key from a symmetric chain.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use libp2p::{PeerId, identity};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{PublicKey as X25519Public, StaticSecret};
+
+use crate::storage::client_db::ClientDatabase;
+
+const ROOT_SALT: &[u8] = b"rust-p2p-chat/e2e/root/v1";
+const RATCHET_INFO: &[u8] = b"rust-p2p-chat/e2e/ratchet/v1";
+/// Most message keys we derive ahead for out-of-order delivery in one chain.
+const MAX_SKIP: u32 = 64;
+/// Most skipped message keys kept per session.
+const MAX_STORED_SKIPPED: usize = 256;
+/// Fresh-chain messages remembered per session so a replay is refused.
+const MAX_SEEN_FRESH: usize = 256;
+/// Multihash code for identity hashes, used by PeerIds that embed an ed25519 key.
+const IDENTITY_MULTIHASH: u64 = 0x00;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+pub enum E2eError {
+    /// No usable key for this message: unknown peer key, lost session, replay...
+    Undecryptable(String),
+    /// The key was available but authentication failed, so the header or
+    /// ciphertext was modified in transit.
+    Tampered,
+}
+
+impl fmt::Display for E2eError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            E2eError::Undecryptable(reason) => write!(f, "undecryptable message: {reason}"),
+            E2eError::Tampered => write!(f, "message failed authentication"),
+        }
+    }
+}
+
+/// Ratchet header sent in clear next to the ciphertext (authenticated as AEAD data).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    /// Sender's current ratchet public key
+    pub dh: [u8; 32],
+    /// Length of the sender's previous sending chain
+    pub pn: u32,
+    /// Message number in the current sending chain
+    pub n: u32,
+    /// The sending chain was started against the receiver's identity key,
+    /// i.e. the sender opened a new session (first contact or lost state).
+    pub fresh: bool,
+}
+
+impl RatchetHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(41);
+        bytes.extend_from_slice(&self.dh);
+        bytes.extend_from_slice(&self.pn.to_be_bytes());
+        bytes.extend_from_slice(&self.n.to_be_bytes());
+        bytes.push(self.fresh as u8);
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedMessage {
+    pub header: RatchetHeader,
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    dh: [u8; 32],
+    n: u32,
+    key: [u8; 32],
+}
+
+/// Double Ratchet state for one friend.
+#[derive(Clone, Serialize, Deserialize)]
+struct Session {
+    root_key: [u8; 32],
+    dhs_secret: [u8; 32],
+    dhs_public: [u8; 32],
+    dhr: [u8; 32],
+    cks: Option<[u8; 32]>,
+    ckr: Option<[u8; 32]>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    /// Our sending chain was started against the peer's identity key and the
+    /// peer has not answered yet.
+    fresh: bool,
+    skipped: Vec<SkippedKey>,
+    /// Ratchet keys of every fresh chain from the peer we read from, latest
+    /// last. Never forgotten (32 bytes per new session of the peer): a fresh
+    /// chain missing here is new and resets the session, so a replayed old
+    /// one must always be recognised.
+    adopted_fresh: Vec<[u8; 32]>,
+    /// Ratchet key and number of every fresh-chain message read. Such messages
+    /// can be read without the ratchet state, so this is what stops a replay.
+    #[serde(default)]
+    seen_fresh: Vec<([u8; 32], u32)>,
+}
+
+impl Session {
+    /// Both sides start from the same state: identity keys on both ends of the
+    /// ratchet and the static-static root secret.
+    fn initial(root_key: [u8; 32], own_static: &StaticSecret, peer_static: [u8; 32]) -> Self {
+        Self {
+            root_key,
+            dhs_secret: own_static.to_bytes(),
+            dhs_public: X25519Public::from(own_static).to_bytes(),
+            dhr: peer_static,
+            cks: None,
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            fresh: false,
+            skipped: Vec::new(),
+            adopted_fresh: Vec::new(),
+            seen_fresh: Vec::new(),
+        }
+    }
+
+    /// Note that the message with `header` was read, if it is from a fresh chain.
+    fn remember_fresh(&mut self, header: &RatchetHeader) {
+        if !header.fresh {
+            return;
+        }
+        self.seen_fresh.push((header.dh, header.n));
+        if self.seen_fresh.len() > MAX_SEEN_FRESH {
+            let excess = self.seen_fresh.len() - MAX_SEEN_FRESH;
+            self.seen_fresh.drain(..excess);
+        }
+    }
+
+    fn skip_receiving_keys(&mut self, until: u32) -> Result<(), E2eError> {
+        let Some(mut ckr) = self.ckr else {
+            return Ok(());
+        };
+        if until > self.nr.saturating_add(MAX_SKIP) {
+            return Err(E2eError::Undecryptable(
+                "too many skipped messages".to_string(),
+            ));
+        }
+        while self.nr < until {
+            let (next, key) = kdf_ck(&ckr);
+            self.skipped.push(SkippedKey {
+                dh: self.dhr,
+                n: self.nr,
+                key,
+            });
+            ckr = next;
+            self.nr += 1;
+        }
+        self.ckr = Some(ckr);
+        if self.skipped.len() > MAX_STORED_SKIPPED {
+            let excess = self.skipped.len() - MAX_STORED_SKIPPED;
+            self.skipped.drain(..excess);
+        }
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, their_ratchet: [u8; 32]) {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dhr = their_ratchet;
+
+        let own = StaticSecret::from(self.dhs_secret);
+        let dh = own.diffie_hellman(&X25519Public::from(self.dhr));
+        let (root_key, ckr) = kdf_rk(&self.root_key, dh.as_bytes());
+        self.root_key = root_key;
+        self.ckr = Some(ckr);
+
+        let next = StaticSecret::random_from_rng(OsRng);
+        let dh = next.diffie_hellman(&X25519Public::from(self.dhr));
+        let (root_key, cks) = kdf_rk(&self.root_key, dh.as_bytes());
+        self.root_key = root_key;
+        self.cks = Some(cks);
+        self.dhs_secret = next.to_bytes();
+        self.dhs_public = X25519Public::from(&next).to_bytes();
+        self.fresh = false;
+    }
+
+    fn next_receiving_key(&mut self) -> Option<[u8; 32]> {
+        let (next, key) = kdf_ck(&self.ckr?);
+        self.ckr = Some(next);
+        self.nr += 1;
+        Some(key)
+    }
+}
+
+/// Holds the local X25519 identity and the ratchet session with every friend.
+pub struct E2eSessions {
+    local_peer_id: PeerId,
+    static_secret: StaticSecret,
+    sessions: HashMap<PeerId, Session>,
+}
+
+impl E2eSessions {
+    /// Returns `None` when the identity is not an ed25519 key.
+    pub fn new(local_key: &identity::Keypair) -> Option<Self> {
+        let local_peer_id = local_key.public().to_peer_id();
+        let keypair = local_key.clone().try_into_ed25519().ok()?;
+        // Same conversion as RFC 8032 key expansion: the X25519 scalar is the
+        // (clamped) lower half of SHA-512(seed).
+        let digest = Sha512::digest(keypair.secret().as_ref());
+        let mut scalar = [0u8; 32];
+        scalar.copy_from_slice(&digest[..32]);
+
+        Some(Self {
+            local_peer_id,
+            static_secret: StaticSecret::from(scalar),
+            sessions: HashMap::new(),
+        })
+    }
+
+    /// Encrypt `plaintext` for `peer`, advancing the sending chain.
+    pub fn seal(
+        &mut self,
+        peer: &PeerId,
+        plaintext: &[u8],
+        db: Option<&ClientDatabase>,
+    ) -> Result<SealedMessage, E2eError> {
+        let peer_static = peer_static_key(peer)?;
+        let mut session = self.load_session(peer, peer_static, db);
+
+        if session.cks.is_none() {
+            let next = StaticSecret::random_from_rng(OsRng);
+            let dh = next.diffie_hellman(&X25519Public::from(session.dhr));
+            let (root_key, cks) = kdf_rk(&session.root_key, dh.as_bytes());
+            session.root_key = root_key;
+            session.cks = Some(cks);
+            session.dhs_secret = next.to_bytes();
+            session.dhs_public = X25519Public::from(&next).to_bytes();
+            session.pn = session.ns;
+            session.ns = 0;
+            session.fresh = session.dhr == peer_static;
+        }
+
+        let Some(cks) = session.cks else {
+            return Err(E2eError::Undecryptable("no sending chain".to_string()));
+        };
+        let (next, key) = kdf_ck(&cks);
+        let header = RatchetHeader {
+            dh: session.dhs_public,
+            pn: session.pn,
+            n: session.ns,
+            fresh: session.fresh,
+        };
+        session.cks = Some(next);
+        session.ns += 1;
+
+        let aad = associated_data(&self.local_peer_id, peer, &header);
+        let ciphertext = aead_seal(&key, plaintext, &aad);
+        self.store_session(peer, session, db);
+
+        Ok(SealedMessage { header, ciphertext })
+    }
+
+    /// Decrypt a message from `peer`. The session only changes when the
+    /// message authenticates, so tampered input can't desynchronise it.
+    pub fn open(
+        &mut self,
+        peer: &PeerId,
+        sealed: &SealedMessage,
+        db: Option<&ClientDatabase>,
+    ) -> Result<Vec<u8>, E2eError> {
+        let peer_static = peer_static_key(peer)?;
+        let header = &sealed.header;
+        let aad = associated_data(peer, &self.local_peer_id, header);
+        let current = self.load_session(peer, peer_static, db);
+
+        // Out-of-order message from a chain we already advanced past
+        if let Some(pos) = current
+            .skipped
+            .iter()
+            .position(|skipped| skipped.dh == header.dh && skipped.n == header.n)
+        {
+            let mut session = current;
+            let key = session.skipped.remove(pos).key;
+            let plaintext =
+                aead_open(&key, &sealed.ciphertext, &aad).map_err(|_| E2eError::Tampered)?;
+            session.remember_fresh(header);
+            self.store_session(peer, session, db);
+            return Ok(plaintext);
+        }
+
+        // Next message in the current receiving chain
+        if current.ckr.is_some() && header.dh == current.dhr {
+            if header.n < current.nr {
+                return Err(E2eError::Undecryptable(
+                    "message key already used".to_string(),
+                ));
+            }
+            let mut session = current;
+            session.skip_receiving_keys(header.n)?;
+            let key = session
+                .next_receiving_key()
+                .ok_or_else(|| E2eError::Undecryptable("no receiving chain".to_string()))?;
+            let plaintext =
+                aead_open(&key, &sealed.ciphertext, &aad).map_err(|_| E2eError::Tampered)?;
+            session.remember_fresh(header);
+            self.store_session(peer, session, db);
+            return Ok(plaintext);
+        }
+
+        let mut session = current;
+        if header.fresh {
+            if session.seen_fresh.contains(&(header.dh, header.n)) {
+                return Err(E2eError::Undecryptable(
+                    "message key already used".to_string(),
+                ));
+            }
+            let root_key = self.root_key(peer, peer_static);
+            // Both sides opened a session at the same time. The lower PeerId
+            // keeps its own chain and only reads this one; the other side
+            // drops its chain when our first message arrives. Late messages
+            // from a chain we already adopted are read the same way.
+            let simultaneous = session.fresh && self.local_peer_id.to_bytes() < peer.to_bytes();
+            if simultaneous || session.adopted_fresh.last() == Some(&header.dh) {
+                let plaintext = self.open_fresh_statelessly(root_key, sealed, &aad)?;
+                if !session.adopted_fresh.contains(&header.dh) {
+                    session.adopted_fresh.push(header.dh);
+                }
+                session.remember_fresh(header);
+                self.store_session(peer, session, db);
+                return Ok(plaintext);
+            }
+            // A chain the peer has since replaced is old, and must not reset
+            // the session
+            if session.adopted_fresh.contains(&header.dh) {
+                return Err(E2eError::Undecryptable(
+                    "message from a superseded session".to_string(),
+                ));
+            }
+            let mut adopted_fresh = std::mem::take(&mut session.adopted_fresh);
+            adopted_fresh.push(header.dh);
+            let seen_fresh = std::mem::take(&mut session.seen_fresh);
+            session = Session::initial(root_key, &self.static_secret, peer_static);
+            session.adopted_fresh = adopted_fresh;
+            session.seen_fresh = seen_fresh;
+        }
+
+        session.skip_receiving_keys(header.pn)?;
+        session.dh_ratchet(header.dh);
+        session.skip_receiving_keys(header.n)?;
+        let key = session
+            .next_receiving_key()
+            .ok_or_else(|| E2eError::Undecryptable("no receiving chain".to_string()))?;
+        // The key came from a ratchet step the header asked for, so a failure
+        // means the header or ciphertext was altered
+        let plaintext =
+            aead_open(&key, &sealed.ciphertext, &aad).map_err(|_| E2eError::Tampered)?;
+        session.remember_fresh(header);
+        self.store_session(peer, session, db);
+        Ok(plaintext)
+    }
+
+    fn open_fresh_statelessly(
+        &self,
+        root_key: [u8; 32],
+        sealed: &SealedMessage,
+        aad: &[u8],
+    ) -> Result<Vec<u8>, E2eError> {
+        let header = &sealed.header;
+        if header.n > MAX_SKIP {
+            return Err(E2eError::Undecryptable(
+                "too many skipped messages".to_string(),
+            ));
+        }
+        let dh = self
+            .static_secret
+            .diffie_hellman(&X25519Public::from(header.dh));
+        let (_, mut chain) = kdf_rk(&root_key, dh.as_bytes());
+        for _ in 0..header.n {
+            chain = kdf_ck(&chain).0;
+        }
+        let (_, key) = kdf_ck(&chain);
+        aead_open(&key, &sealed.ciphertext, aad).map_err(|_| E2eError::Tampered)
+    }
+
+    fn load_session(
+        &mut self,
+        peer: &PeerId,
+        peer_static: [u8; 32],
+        db: Option<&ClientDatabase>,
+    ) -> Session {
+        if let Some(session) = self.sessions.get(peer) {
+            return session.clone();
+        }
+
+        let stored = db.and_then(|db| match db.get_e2e_session(&peer.to_string()) {
+            Ok(state) => state,
+            Err(err) => {
+                log::warn!("Failed to load E2E session for {peer}: {err}");
+                None
+            }
+        });
+        if let Some(state) = stored {
+            match serde_json::from_str::<Session>(&state) {
+                Ok(session) => return session,
+                Err(err) => log::warn!("Discarding corrupt E2E session for {peer}: {err}"),
+            }
+        }
+
+        let root_key = self.root_key(peer, peer_static);
+        Session::initial(root_key, &self.static_secret, peer_static)
+    }
+
+    fn store_session(&mut self, peer: &PeerId, session: Session, db: Option<&ClientDatabase>) {
+        if let Some(db) = db {
+            match serde_json::to_string(&session) {
+                Ok(state) => {
+                    if let Err(err) = db.save_e2e_session(&peer.to_string(), &state) {
+                        log::warn!("Failed to persist E2E session for {peer}: {err}");
+                    }
+                }
+                Err(err) => log::warn!("Failed to serialize E2E session for {peer}: {err}"),
+            }
+        }
+        self.sessions.insert(*peer, session);
+    }
+
+    /// Root secret shared by the two identities, bound to both PeerIds.
+    fn root_key(&self, peer: &PeerId, peer_static: [u8; 32]) -> [u8; 32] {
+        let dh = self
+            .static_secret
+            .diffie_hellman(&X25519Public::from(peer_static));
+        let mut ids = [self.local_peer_id.to_bytes(), peer.to_bytes()];
+        ids.sort();
+        let info = ids.concat();
+
+        let mut root_key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(ROOT_SALT), dh.as_bytes())
+            .expand(&info, &mut root_key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        root_key
+    }
+}
+
+/// Public key embedded in a PeerId. Ed25519 peer ids are short enough to be
+/// stored inline (identity multihash); other key types only have a hash.
+pub fn embedded_public_key(peer: &PeerId) -> Option<identity::PublicKey> {
+    let multihash = peer.as_ref();
+    if multihash.code() != IDENTITY_MULTIHASH {
+        return None;
+    }
+    identity::PublicKey::try_decode_protobuf(multihash.digest()).ok()
+}
+
+/// X25519 form of the ed25519 key embedded in `peer`.
+fn peer_static_key(peer: &PeerId) -> Result<[u8; 32], E2eError> {
+    let public = embedded_public_key(peer)
+        .ok_or_else(|| {
+            E2eError::Undecryptable("peer id does not embed its public key".to_string())
+        })?
+        .try_into_ed25519()
+        .ok()
+        .ok_or_else(|| E2eError::Undecryptable("peer key is not ed25519".to_string()))?;
+    let point = CompressedEdwardsY(public.to_bytes())
+        .decompress()
+        .ok_or_else(|| E2eError::Undecryptable("invalid peer key".to_string()))?;
+    Ok(point.to_montgomery().to_bytes())
+}
+
+fn kdf_rk(root_key: &[u8; 32], dh_output: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut output = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root_key), dh_output)
+        .expand(RATCHET_INFO, &mut output)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+    let mut next_root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    next_root.copy_from_slice(&output[..32]);
+    chain.copy_from_slice(&output[32..]);
+    (next_root, chain)
+}
+
+/// Returns (next chain key, message key).
+fn kdf_ck(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let derive = |label: u8| {
+        let mut mac =
+            <HmacSha256 as Mac>::new_from_slice(chain_key).expect("HMAC accepts any key length");
+        mac.update(&[label]);
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&mac.finalize().into_bytes());
+        out
+    };
+    (derive(0x02), derive(0x01))
+}
+
+fn associated_data(sender: &PeerId, recipient: &PeerId, header: &RatchetHeader) -> Vec<u8> {
+    let mut aad = sender.to_bytes();
+    aad.extend_from_slice(&recipient.to_bytes());
+    aad.extend_from_slice(&header.to_bytes());
+    aad
+}
+
+// Every message key is used exactly once, so a fixed nonce is safe here.
+fn aead_seal(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(
+            Nonce::from_slice(&[0u8; 12]),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("ChaCha20Poly1305 encryption does not fail for in-memory buffers")
+}
+
+fn aead_open(key: &[u8; 32], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, ()> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            Nonce::from_slice(&[0u8; 12]),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Party {
+        peer: PeerId,
+        key: identity::Keypair,
+        sessions: E2eSessions,
+    }
+
+    impl Party {
+        fn new() -> Self {
+            let key = identity::Keypair::generate_ed25519();
+            Self {
+                peer: key.public().to_peer_id(),
+                sessions: E2eSessions::new(&key).unwrap(),
+                key,
+            }
+        }
+
+        /// The same identity after losing every session.
+        fn restarted(&self) -> Self {
+            Self {
+                peer: self.peer,
+                sessions: E2eSessions::new(&self.key).unwrap(),
+                key: self.key.clone(),
+            }
+        }
+
+        fn seal(&mut self, to: &Party, text: &str) -> SealedMessage {
+            self.sessions.seal(&to.peer, text.as_bytes(), None).unwrap()
+        }
+
+        fn open(&mut self, from: &Party, sealed: &SealedMessage) -> Result<String, E2eError> {
+            self.sessions
+                .open(&from.peer, sealed, None)
+                .map(|plaintext| String::from_utf8(plaintext).unwrap())
+        }
+    }
+
+    fn tampered(sealed: &SealedMessage) -> SealedMessage {
+        let mut sealed = sealed.clone();
+        sealed.ciphertext[0] ^= 1;
+        sealed
+    }
+
+    #[test]
+    fn messages_in_order_and_replies() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        for text in ["one", "two"] {
+            let sealed = alice.seal(&bob, text);
+            assert_eq!(bob.open(&alice, &sealed).unwrap(), text);
+        }
+        let reply = bob.seal(&alice, "three");
+        assert_eq!(alice.open(&bob, &reply).unwrap(), "three");
+        let next = alice.seal(&bob, "four");
+        assert!(!next.header.fresh);
+        assert_eq!(bob.open(&alice, &next).unwrap(), "four");
+    }
+
+    #[test]
+    fn messages_out_of_order() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let sealed: Vec<_> = ["zero", "one", "two"]
+            .iter()
+            .map(|text| alice.seal(&bob, text))
+            .collect();
+        assert_eq!(bob.open(&alice, &sealed[2]).unwrap(), "two");
+        assert_eq!(bob.open(&alice, &sealed[0]).unwrap(), "zero");
+        assert_eq!(bob.open(&alice, &sealed[1]).unwrap(), "one");
+    }
+
+    #[test]
+    fn replays_are_refused() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let first = alice.seal(&bob, "first");
+        assert!(first.header.fresh);
+        bob.open(&alice, &first).unwrap();
+        assert!(bob.open(&alice, &first).is_err());
+
+        // Once both sides moved on to new chains, the fresh chain is only
+        // remembered, and its messages must still not be read twice
+        let reply = bob.seal(&alice, "reply");
+        alice.open(&bob, &reply).unwrap();
+        let next = alice.seal(&bob, "next");
+        bob.open(&alice, &next).unwrap();
+        assert!(bob.open(&alice, &first).is_err());
+        assert!(bob.open(&alice, &next).is_err());
+        assert!(alice.open(&bob, &reply).is_err());
+
+        let later = alice.seal(&bob, "later");
+        assert_eq!(bob.open(&alice, &later).unwrap(), "later");
+    }
+
+    #[test]
+    fn tampered_messages_are_reported_and_leave_the_session_alone() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let first = alice.seal(&bob, "first");
+        assert!(matches!(
+            bob.open(&alice, &tampered(&first)),
+            Err(E2eError::Tampered)
+        ));
+        assert_eq!(bob.open(&alice, &first).unwrap(), "first");
+
+        // Also after a ratchet step
+        let reply = bob.seal(&alice, "reply");
+        alice.open(&bob, &reply).unwrap();
+        let next = alice.seal(&bob, "next");
+        assert!(matches!(
+            bob.open(&alice, &tampered(&next)),
+            Err(E2eError::Tampered)
+        ));
+        assert_eq!(bob.open(&alice, &next).unwrap(), "next");
+    }
+
+    #[test]
+    fn lost_session_is_reset_once() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let old = alice.seal(&bob, "old");
+        bob.open(&alice, &old).unwrap();
+        let reply = bob.seal(&alice, "reply");
+        alice.open(&bob, &reply).unwrap();
+
+        let mut alice = alice.restarted();
+        let fresh = alice.seal(&bob, "fresh");
+        assert!(fresh.header.fresh);
+        assert_eq!(bob.open(&alice, &fresh).unwrap(), "fresh");
+        let answer = bob.seal(&alice, "answer");
+        assert_eq!(alice.open(&bob, &answer).unwrap(), "answer");
+
+        // Replaying either session's first message must not reset it again
+        assert!(bob.open(&alice, &old).is_err());
+        assert!(bob.open(&alice, &fresh).is_err());
+        let after = alice.seal(&bob, "after");
+        assert_eq!(bob.open(&alice, &after).unwrap(), "after");
+    }
+
+    #[test]
+    fn old_fresh_chain_never_resets_the_session() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let first = alice.seal(&bob, "first");
+        bob.open(&alice, &first).unwrap();
+        for _ in 0..16 {
+            alice = alice.restarted();
+            let fresh = alice.seal(&bob, "fresh");
+            bob.open(&alice, &fresh).unwrap();
+        }
+        // Long enough for `first` to leave the replay window as well
+        for _ in 0..MAX_SEEN_FRESH {
+            let more = alice.seal(&bob, "more");
+            bob.open(&alice, &more).unwrap();
+        }
+
+        assert!(bob.open(&alice, &first).is_err());
+        let next = alice.seal(&bob, "next");
+        assert_eq!(bob.open(&alice, &next).unwrap(), "next");
+    }
+}
@@ -0,0 +1,643 @@
+//! Versioned wire format shared by gossipsub and the direct message protocol.
+//!
+//! An encoded envelope is one version byte followed by the postcard encoding
+//! of the shared metadata, a kind tag and the kind's fields as an opaque body.
+//! The version byte is read before anything else, so a peer can tell "newer
+//! protocol than mine" apart from garbage and ignore the message instead of
+//! penalising the sender. Likewise a kind added after the receiver was built
+//! still decodes far enough to be recognised as [`EnvelopeError::UnknownKind`].
+//!
+//! Every envelope is signed with the sender's identity key, so a stored copy
+//! can be handed on (history sync) and still be checked by whoever gets it.
+//! A room message is known by the [`content_id`] of its envelope rather than
+//! the id its sender picked, so nobody can claim another message's id first.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::Utc;
+use libp2p::{PeerId, identity};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::common::{ChatMessage, DeliveryStatus, FileAttachment, MessageOp, OpAction};
+
+use super::e2e::embedded_public_key;
+
+/// Current wire format version.
+///
+/// 2: edits became signed [`Payload::Op`]s, replacing the unsigned `Edit`.
+/// 3: the payload is a kind tag and an opaque body.
+/// 4: envelopes are signed by their sender.
+/// 5: room messages are referred to by their [`content_id`].
+pub const WIRE_VERSION: u8 = 5;
+
+const ENVELOPE_SIGNING_DOMAIN: &str = "rust-p2p-chat/envelope/v1";
+
+/// Largest encoded envelope we send or accept (version byte included).
+pub const MAX_ENVELOPE_SIZE: usize = 64 * 1024;
+
+/// Longest reaction accepted, in bytes (one emoji, modifiers included).
+pub const MAX_REACTION_LEN: usize = 32;
+
+/// Longest message or file id accepted, in bytes (UUIDs and hex digests).
+pub const MAX_ID_LEN: usize = 64;
+
+/// Longest file name accepted in a file offer, in bytes.
+pub const MAX_FILE_NAME_LEN: usize = 1024;
+
+/// Most message ids acknowledged by one receipt envelope.
+pub const MAX_RECEIPT_IDS: usize = 256;
+
+/// Seconds an envelope may be dated ahead of our clock. Later ones are refused,
+/// as a message from the future would move the history sync cursor past
+/// everything sent in between.
+pub const MAX_CLOCK_SKEW_SECS: i64 = 5 * 60;
+
+/// Seconds an ephemeral envelope (typing signal) stays meaningful. Older ones
+/// are dropped instead of shown or forwarded.
+pub const EPHEMERAL_TTL_SECS: i64 = 6;
+
+/// A chat payload together with the metadata every kind shares.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub id: String,
+    pub sender: String,
+    pub timestamp: i64,
+    /// Room of the message; empty for direct messages.
+    pub room: String,
+    pub payload: Payload,
+}
+
+/// Message kinds. A new kind gets a new tag in [`Payload::kind`]; tags are
+/// never reused, and changing the fields of an existing kind requires bumping
+/// [`WIRE_VERSION`].
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Text {
+        content: String,
+    },
+    /// Informational notice (join/leave, client notices...). Notices are
+    /// made locally; one sent by a peer is never shown, so nobody can pass
+    /// their words off as the client's.
+    System {
+        content: String,
+    },
+    /// Edit or delete an earlier message; only valid from its author
+    Op(MessageOp),
+    /// React to a message with an emoji
+    Reaction {
+        target_id: String,
+        emoji: String,
+    },
+    /// Announce a file the sender is willing to transfer
+    FileOffer {
+        file_id: String,
+        name: String,
+        size: u64,
+        /// Hex-encoded SHA-256 of the whole file
+        sha256: String,
+    },
+    /// Acknowledge messages written by the receiver of this envelope
+    Receipt {
+        target_ids: Vec<String>,
+        kind: ReceiptKind,
+    },
+    /// The sender is typing in this conversation (ephemeral, never stored)
+    Typing,
+    /// Take back an earlier reaction of the sender
+    ReactionRemoved {
+        target_id: String,
+        emoji: String,
+    },
+    /// A chat message answering an earlier one
+    Reply {
+        content: String,
+        reply_to: String,
+    },
+}
+
+impl Payload {
+    /// Wire tag of this kind.
+    pub fn kind(&self) -> u16 {
+        match self {
+            Payload::Text { .. } => 0,
+            Payload::System { .. } => 1,
+            Payload::Op(_) => 2,
+            Payload::Reaction { .. } => 3,
+            Payload::FileOffer { .. } => 4,
+            Payload::Receipt { .. } => 5,
+            Payload::Typing => 6,
+            Payload::ReactionRemoved { .. } => 7,
+            Payload::Reply { .. } => 8,
+        }
+    }
+
+    /// The fields of this kind, encoded on their own.
+    fn encode_body(&self) -> postcard::Result<Vec<u8>> {
+        match self {
+            Payload::Text { content } | Payload::System { content } => {
+                postcard::to_allocvec(content)
+            }
+            Payload::Op(op) => postcard::to_allocvec(op),
+            Payload::Reaction { target_id, emoji }
+            | Payload::ReactionRemoved { target_id, emoji } => {
+                postcard::to_allocvec(&(target_id, emoji))
+            }
+            Payload::FileOffer {
+                file_id,
+                name,
+                size,
+                sha256,
+            } => postcard::to_allocvec(&(file_id, name, size, sha256)),
+            Payload::Receipt { target_ids, kind } => postcard::to_allocvec(&(target_ids, kind)),
+            Payload::Typing => Ok(Vec::new()),
+            Payload::Reply { content, reply_to } => postcard::to_allocvec(&(content, reply_to)),
+        }
+    }
+
+    /// The payload of kind `kind` encoded in `body`; `None` for a kind this
+    /// client does not know.
+    fn decode_body(kind: u16, body: &[u8]) -> postcard::Result<Option<Self>> {
+        let payload = match kind {
+            0 => Payload::Text {
+                content: postcard::from_bytes(body)?,
+            },
+            1 => Payload::System {
+                content: postcard::from_bytes(body)?,
+            },
+            2 => Payload::Op(postcard::from_bytes(body)?),
+            3 | 7 => {
+                let (target_id, emoji) = postcard::from_bytes(body)?;
+                if kind == 3 {
+                    Payload::Reaction { target_id, emoji }
+                } else {
+                    Payload::ReactionRemoved { target_id, emoji }
+                }
+            }
+            4 => {
+                let (file_id, name, size, sha256) = postcard::from_bytes(body)?;
+                Payload::FileOffer {
+                    file_id,
+                    name,
+                    size,
+                    sha256,
+                }
+            }
+            5 => {
+                let (target_ids, kind) = postcard::from_bytes(body)?;
+                Payload::Receipt { target_ids, kind }
+            }
+            6 => Payload::Typing,
+            8 => {
+                let (content, reply_to) = postcard::from_bytes(body)?;
+                Payload::Reply { content, reply_to }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(payload))
+    }
+}
+
+/// [`Envelope`] as it travels: the payload stays opaque until its kind is known.
+#[derive(Serialize, Deserialize)]
+struct WireEnvelope {
+    id: String,
+    sender: String,
+    timestamp: i64,
+    room: String,
+    kind: u16,
+    body: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl WireEnvelope {
+    /// Every field except the signature, in a fixed order and encoding.
+    fn signing_bytes(&self) -> postcard::Result<Vec<u8>> {
+        postcard::to_allocvec(&(
+            ENVELOPE_SIGNING_DOMAIN,
+            &self.id,
+            &self.sender,
+            self.timestamp,
+            &self.room,
+            self.kind,
+            &self.body,
+        ))
+    }
+
+    /// Whether the signature was made by the key embedded in the sender's id.
+    fn is_signed_by_sender(&self) -> bool {
+        let Some(public_key) = PeerId::from_str(&self.sender)
+            .ok()
+            .and_then(|sender| embedded_public_key(&sender))
+        else {
+            return false;
+        };
+        self.signing_bytes()
+            .is_ok_and(|bytes| public_key.verify(&bytes, &self.signature))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+impl From<ReceiptKind> for DeliveryStatus {
+    fn from(kind: ReceiptKind) -> Self {
+        match kind {
+            ReceiptKind::Delivered => DeliveryStatus::Delivered,
+            ReceiptKind::Read => DeliveryStatus::Read,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    Empty,
+    TooLarge(usize),
+    /// Sent by a client speaking another wire version
+    UnsupportedVersion(u8),
+    /// A message kind added after this client was built
+    UnknownKind(u16),
+    /// Not signed by the peer it claims to come from
+    BadSignature,
+    Malformed(String),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Empty => write!(f, "empty envelope"),
+            EnvelopeError::TooLarge(size) => {
+                write!(f, "envelope of {size} bytes exceeds {MAX_ENVELOPE_SIZE}")
+            }
+            EnvelopeError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported wire version {version} (ours is {WIRE_VERSION})"
+                )
+            }
+            EnvelopeError::UnknownKind(kind) => write!(f, "unknown message kind {kind}"),
+            EnvelopeError::BadSignature => write!(f, "envelope not signed by its sender"),
+            EnvelopeError::Malformed(reason) => write!(f, "malformed envelope: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+impl Envelope {
+    /// A new envelope from `sender` in `room` (empty for direct messages).
+    pub fn new(sender: String, room: String, payload: Payload) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            sender,
+            timestamp: Utc::now().timestamp(),
+            room,
+            payload,
+        }
+    }
+
+    pub fn receipt(
+        sender: String,
+        room: String,
+        target_ids: Vec<String>,
+        kind: ReceiptKind,
+    ) -> Self {
+        Self::new(sender, room, Payload::Receipt { target_ids, kind })
+    }
+
+    pub fn typing(sender: String, room: String) -> Self {
+        Self::new(sender, room, Payload::Typing)
+    }
+
+    /// Whether this is an ephemeral envelope past its lifetime at `now`.
+    pub fn is_expired(&self, now: i64) -> bool {
+        matches!(self.payload, Payload::Typing) && now - self.timestamp > EPHEMERAL_TTL_SECS
+    }
+
+    /// Encode and sign with `local_key`, the key of [`Envelope::sender`].
+    pub fn encode(&self, local_key: &identity::Keypair) -> Result<Vec<u8>, EnvelopeError> {
+        let malformed = |err: postcard::Error| EnvelopeError::Malformed(err.to_string());
+        let mut wire = WireEnvelope {
+            id: self.id.clone(),
+            sender: self.sender.clone(),
+            timestamp: self.timestamp,
+            room: self.room.clone(),
+            kind: self.payload.kind(),
+            body: self.payload.encode_body().map_err(malformed)?,
+            signature: Vec::new(),
+        };
+        wire.signature = local_key
+            .sign(&wire.signing_bytes().map_err(malformed)?)
+            .map_err(|err| EnvelopeError::Malformed(err.to_string()))?;
+        let mut bytes = vec![WIRE_VERSION];
+        bytes.extend_from_slice(&postcard::to_allocvec(&wire).map_err(malformed)?);
+        if bytes.len() > MAX_ENVELOPE_SIZE {
+            return Err(EnvelopeError::TooLarge(bytes.len()));
+        }
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        if bytes.len() > MAX_ENVELOPE_SIZE {
+            return Err(EnvelopeError::TooLarge(bytes.len()));
+        }
+        let (&version, body) = bytes.split_first().ok_or(EnvelopeError::Empty)?;
+        if version != WIRE_VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        let malformed = |err: postcard::Error| EnvelopeError::Malformed(err.to_string());
+        let wire: WireEnvelope = postcard::from_bytes(body).map_err(malformed)?;
+        if !wire.is_signed_by_sender() {
+            return Err(EnvelopeError::BadSignature);
+        }
+        let payload = Payload::decode_body(wire.kind, &wire.body)
+            .map_err(malformed)?
+            .ok_or(EnvelopeError::UnknownKind(wire.kind))?;
+        Ok(Self {
+            id: wire.id,
+            sender: wire.sender,
+            timestamp: wire.timestamp,
+            room: wire.room,
+            payload,
+        })
+    }
+
+    /// Why a field of this envelope is out of bounds, if one is. The size of
+    /// the whole envelope is checked by [`Envelope::decode`].
+    pub fn check_limits(&self) -> Result<(), String> {
+        check_id("id", &self.id)?;
+        let ahead = self.timestamp.saturating_sub(Utc::now().timestamp());
+        if ahead > MAX_CLOCK_SKEW_SECS {
+            return Err(format!("dated {ahead}s in the future"));
+        }
+        match &self.payload {
+            Payload::Op(op) => check_op_limits(op),
+            Payload::Reaction { target_id, .. } | Payload::ReactionRemoved { target_id, .. } => {
+                check_id("reaction target", target_id)
+            }
+            Payload::Reply { reply_to, .. } => check_id("reply target", reply_to),
+            Payload::FileOffer {
+                file_id,
+                name,
+                sha256,
+                ..
+            } => {
+                check_id("file id", file_id)?;
+                if name.trim().is_empty() || name.len() > MAX_FILE_NAME_LEN {
+                    return Err(format!("file name of {} bytes", name.len()));
+                }
+                if sha256.len() != 64 || !sha256.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                    return Err("file digest is not SHA-256 hex".to_string());
+                }
+                Ok(())
+            }
+            Payload::Receipt { target_ids, .. } => {
+                if target_ids.len() > MAX_RECEIPT_IDS {
+                    return Err(format!("receipt for {} messages", target_ids.len()));
+                }
+                target_ids
+                    .iter()
+                    .try_for_each(|id| check_id("receipt target", id))
+            }
+            Payload::Text { .. } | Payload::System { .. } | Payload::Typing => Ok(()),
+        }
+    }
+
+    /// The room message carried by `encoded`, this envelope as its sender
+    /// signed it, known by its [`content_id`].
+    pub fn into_room_message(self, encoded: &[u8]) -> Option<ChatMessage> {
+        let mut message = self.into_chat_message()?;
+        message.id = content_id(encoded);
+        Some(message)
+    }
+
+    /// The chat line shown for this envelope, if its kind is displayed as one.
+    /// A [`Payload::System`] notice from a peer is not.
+    pub fn into_chat_message(self) -> Option<ChatMessage> {
+        let (content, reply_to, attachment) = match self.payload {
+            Payload::Text { content } => (content, None, None),
+            Payload::Reply { content, reply_to } => (content, Some(reply_to), None),
+            Payload::FileOffer {
+                file_id,
+                name,
+                size,
+                sha256,
+            } => (
+                name.clone(),
+                None,
+                Some(FileAttachment {
+                    file_id,
+                    name,
+                    size,
+                    sha256,
+                }),
+            ),
+            _ => return None,
+        };
+        Some(ChatMessage {
+            id: self.id,
+            sender: self.sender,
+            content,
+            timestamp: self.timestamp,
+            room: self.room,
+            edited: false,
+            deleted: false,
+            reply_to,
+            attachment,
+        })
+    }
+}
+
+/// Id of the room message in `encoded`, a signed envelope: the hex SHA-256 of
+/// the envelope. Everyone derives the same id from the same bytes, and only
+/// the sender can make an envelope with this id.
+pub fn content_id(encoded: &[u8]) -> String {
+    hex::encode(Sha256::digest(encoded))
+}
+
+/// Why a field of `op` is out of bounds, if one is. Ops served by history
+/// sync come without an envelope, so the bounds are checked on their own.
+pub fn check_op_limits(op: &MessageOp) -> Result<(), String> {
+    check_id("op target", &op.target_id)?;
+    match &op.action {
+        OpAction::Edit { content } if content.len() > MAX_ENVELOPE_SIZE => {
+            Err(format!("edit of {} bytes", content.len()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_id(field: &str, id: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > MAX_ID_LEN {
+        Err(format!("{field} of {} bytes", id.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether `emoji` is acceptable as a reaction: a short, visible string.
+pub fn is_valid_reaction(emoji: &str) -> bool {
+    !emoji.trim().is_empty() && emoji.len() <= MAX_REACTION_LEN
+}
+
+impl From<&ChatMessage> for Envelope {
+    fn from(message: &ChatMessage) -> Self {
+        Self {
+            id: message.id.clone(),
+            sender: message.sender.clone(),
+            timestamp: message.timestamp,
+            room: message.room.clone(),
+            payload: match (&message.attachment, &message.reply_to) {
+                (Some(attachment), _) => Payload::FileOffer {
+                    file_id: attachment.file_id.clone(),
+                    name: attachment.name.clone(),
+                    size: attachment.size,
+                    sha256: attachment.sha256.clone(),
+                },
+                (None, Some(reply_to)) => Payload::Reply {
+                    content: message.content.clone(),
+                    reply_to: reply_to.clone(),
+                },
+                (None, None) => Payload::Text {
+                    content: message.content.clone(),
+                },
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::client_db::ClientDatabase;
+    use crate::storage::models::Message;
+
+    fn signed(local_key: &identity::Keypair, mut wire: WireEnvelope) -> Vec<u8> {
+        wire.signature = local_key.sign(&wire.signing_bytes().unwrap()).unwrap();
+        let mut bytes = vec![WIRE_VERSION];
+        bytes.extend_from_slice(&postcard::to_allocvec(&wire).unwrap());
+        bytes
+    }
+
+    #[test]
+    fn payload_round_trips() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let envelope = Envelope::new(
+            local_key.public().to_peer_id().to_string(),
+            "general".to_string(),
+            Payload::Reply {
+                content: "hi".to_string(),
+                reply_to: "parent".to_string(),
+            },
+        );
+        let decoded = Envelope::decode(&envelope.encode(&local_key).unwrap()).unwrap();
+        assert_eq!(decoded.id, envelope.id);
+        assert!(matches!(
+            decoded.payload,
+            Payload::Reply { content, reply_to } if content == "hi" && reply_to == "parent"
+        ));
+    }
+
+    #[test]
+    fn unknown_kind_is_told_apart_from_garbage() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let wire = WireEnvelope {
+            id: "id".to_string(),
+            sender: local_key.public().to_peer_id().to_string(),
+            timestamp: 0,
+            room: String::new(),
+            kind: u16::MAX,
+            body: vec![1, 2, 3],
+            signature: Vec::new(),
+        };
+        let mut bytes = signed(&local_key, wire);
+        assert!(matches!(
+            Envelope::decode(&bytes),
+            Err(EnvelopeError::UnknownKind(kind)) if kind == u16::MAX
+        ));
+
+        bytes.truncate(bytes.len() - 2);
+        assert!(matches!(
+            Envelope::decode(&bytes),
+            Err(EnvelopeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn two_senders_using_one_id_are_two_messages() {
+        let db = ClientDatabase::with_path(":memory:").unwrap();
+        let mut stored = Vec::new();
+        for local_key in [
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        ] {
+            let mut envelope = Envelope::new(
+                local_key.public().to_peer_id().to_string(),
+                "general".to_string(),
+                Payload::Text {
+                    content: "hi".to_string(),
+                },
+            );
+            envelope.id = "same".to_string();
+            let encoded = envelope.encode(&local_key).unwrap();
+            let message = Envelope::decode(&encoded)
+                .unwrap()
+                .into_room_message(&encoded)
+                .unwrap();
+            assert_eq!(message.id, content_id(&encoded));
+            let message = Message {
+                envelope: Some(encoded),
+                ..Message::from(&message)
+            };
+            assert!(db.insert_message(&message).unwrap());
+            assert!(!db.insert_message(&message).unwrap());
+            stored.push(message.id);
+        }
+        assert_ne!(stored[0], stored[1]);
+    }
+
+    #[test]
+    fn envelope_from_the_future_is_refused() {
+        let mut envelope = Envelope::typing("peer".to_string(), "general".to_string());
+        envelope.timestamp += MAX_CLOCK_SKEW_SECS / 2;
+        assert!(envelope.check_limits().is_ok());
+        envelope.timestamp += MAX_CLOCK_SKEW_SECS;
+        assert!(envelope.check_limits().is_err());
+    }
+
+    #[test]
+    fn system_notice_from_a_peer_is_not_shown() {
+        let envelope = Envelope::new(
+            "peer".to_string(),
+            "general".to_string(),
+            Payload::System {
+                content: "Bạn đã bị mời ra khỏi phòng".to_string(),
+            },
+        );
+        assert!(envelope.into_chat_message().is_none());
+    }
+
+    #[test]
+    fn envelope_signed_by_someone_else_is_refused() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let victim = identity::Keypair::generate_ed25519();
+        let wire = WireEnvelope {
+            id: "id".to_string(),
+            sender: victim.public().to_peer_id().to_string(),
+            timestamp: 0,
+            room: "general".to_string(),
+            kind: Payload::Typing.kind(),
+            body: Vec::new(),
+            signature: Vec::new(),
+        };
+        assert!(matches!(
+            Envelope::decode(&signed(&local_key, wire)),
+            Err(EnvelopeError::BadSignature)
+        ));
+    }
+}
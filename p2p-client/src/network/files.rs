@@ -0,0 +1,1070 @@
+//! Chunked, resumable file transfer between peers.
+//!
+//! A shared file is cut into fixed-size chunks. Its id is the SHA-256 of the
+//! chunk size and every chunk hash (the manifest), so a downloader holding only
+//! the offer can check the manifest, then each chunk against it, and finally
+//! the whole file against the offered SHA-256. Progress is stored per chunk, so
+//! a download continues where it stopped after a disconnect, a relay switch or
+//! a restart.
+//!
+//! Since chunks are checked against the id alone, any peer may serve them.
+//! Files shared in rooms are looked up in the DHT and their chunks requested in
+//! parallel from every provider found, besides the sender (see [`super::blobs`]).
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use libp2p::request_response::{self, OutboundRequestId, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol, Swarm, kad};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::common::{FileAttachment, NetworkEvent};
+use crate::storage::client_db::ClientDatabase;
+use crate::storage::models::{FileDownload, SharedFile};
+
+use super::behavior::ChatBehavior;
+use super::blobs::{self, blob_path};
+use super::bytes;
+use super::nat_traversal::NatTraversal;
+
+/// Protocol used to fetch file manifests and chunks from the peer offering them.
+pub const FILE_PROTOCOL: StreamProtocol = StreamProtocol::new("/rust-p2p-chat/file/1.0.0");
+
+/// Chunk size used for files we share.
+pub const CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Largest chunk size accepted in a manifest.
+const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// Largest file we share or download.
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Where finished downloads are saved.
+pub const DOWNLOAD_DIR: &str = "data/downloads";
+
+/// Chunk requests kept in flight per download and provider.
+const DOWNLOAD_WINDOW: usize = 4;
+
+/// Providers fetched from at once per download.
+const MAX_SOURCES: usize = 8;
+
+/// Minimum time between two DHT provider lookups of the same file.
+const PROVIDER_QUERY_INTERVAL: Duration = Duration::from_secs(60);
+
+const HASH_LEN: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileRequest {
+    Manifest { file_id: String },
+    Chunk { file_id: String, index: u32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileResponse {
+    Manifest {
+        chunk_size: u32,
+        /// SHA-256 of every chunk, concatenated
+        #[serde(with = "bytes")]
+        chunk_hashes: Vec<u8>,
+    },
+    Chunk {
+        index: u32,
+        #[serde(with = "bytes")]
+        data: Vec<u8>,
+    },
+    /// Unknown file, or the file changed on disk since it was offered
+    NotFound,
+}
+
+pub type FileBehaviour = request_response::cbor::Behaviour<FileRequest, FileResponse>;
+pub type FileEvent = request_response::Event<FileRequest, FileResponse>;
+
+pub fn build_file_behaviour() -> FileBehaviour {
+    // Chunks often travel over relay circuits, so allow as much time as direct messages
+    let config = request_response::Config::default().with_request_timeout(Duration::from_secs(60));
+    request_response::cbor::Behaviour::new([(FILE_PROTOCOL, ProtocolSupport::Full)], config)
+}
+
+/// Hash a file chunk by chunk so it can be offered to others.
+pub fn prepare_file(path: &Path) -> io::Result<SharedFile> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file"));
+    }
+    if metadata.len() > MAX_FILE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("file is larger than {MAX_FILE_SIZE} bytes"),
+        ));
+    }
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "file name is not UTF-8"))?;
+
+    let mut file = File::open(path)?;
+    let mut whole = Sha256::new();
+    let mut chunk_hashes = Vec::new();
+    let mut buffer = vec![0; CHUNK_SIZE as usize];
+    let mut size = 0u64;
+    loop {
+        let read = read_full(&mut file, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        whole.update(&buffer[..read]);
+        chunk_hashes.extend_from_slice(&Sha256::digest(&buffer[..read]));
+        size += read as u64;
+    }
+
+    Ok(SharedFile {
+        attachment: FileAttachment {
+            file_id: manifest_id(CHUNK_SIZE, &chunk_hashes),
+            name,
+            size,
+            sha256: hex::encode(whole.finalize()),
+        },
+        path: path.to_string_lossy().into_owned(),
+        chunk_hashes,
+        public: false,
+    })
+}
+
+/// Answer a request for one of the files we shared, or for a blob shared in a
+/// room that we downloaded (partly or fully).
+pub fn serve(db: Option<&ClientDatabase>, request: &FileRequest) -> FileResponse {
+    let file_id = match request {
+        FileRequest::Manifest { file_id } | FileRequest::Chunk { file_id, .. } => file_id,
+    };
+    let Some(db) = db else {
+        return FileResponse::NotFound;
+    };
+    match db.get_shared_file(file_id) {
+        Ok(Some(shared)) => {
+            return serve_chunks(
+                request,
+                Path::new(&shared.path),
+                CHUNK_SIZE,
+                &shared.chunk_hashes,
+            );
+        }
+        Ok(None) => {}
+        Err(err) => {
+            log::warn!("Failed to look up shared file {file_id}: {err}");
+            return FileResponse::NotFound;
+        }
+    }
+    let download = match db.get_download(file_id) {
+        Ok(Some(download)) if download.public => download,
+        Ok(_) => return FileResponse::NotFound,
+        Err(err) => {
+            log::warn!("Failed to look up blob {file_id}: {err}");
+            return FileResponse::NotFound;
+        }
+    };
+    let Some(chunk_hashes) = &download.chunk_hashes else {
+        return FileResponse::NotFound;
+    };
+    match request {
+        FileRequest::Manifest { .. } => {
+            if download.is_complete()
+                && let Err(err) = db.touch_download(file_id, Utc::now().timestamp())
+            {
+                log::warn!("Failed to mark blob {file_id} as used: {err}");
+            }
+        }
+        // Only chunks we verified ourselves
+        FileRequest::Chunk { index, .. } if !download.has_chunk(*index) => {
+            return FileResponse::NotFound;
+        }
+        FileRequest::Chunk { .. } => {}
+    }
+    serve_chunks(
+        request,
+        &blob_path(file_id),
+        download.chunk_size,
+        chunk_hashes,
+    )
+}
+
+fn serve_chunks(
+    request: &FileRequest,
+    path: &Path,
+    chunk_size: u32,
+    chunk_hashes: &[u8],
+) -> FileResponse {
+    match request {
+        FileRequest::Manifest { .. } => FileResponse::Manifest {
+            chunk_size,
+            chunk_hashes: chunk_hashes.to_vec(),
+        },
+        FileRequest::Chunk { index, .. } => {
+            match read_chunk(path, chunk_size, chunk_hashes, *index) {
+                Ok(Some(data)) => FileResponse::Chunk {
+                    index: *index,
+                    data,
+                },
+                Ok(None) => FileResponse::NotFound,
+                Err(err) => {
+                    log::warn!("Failed to read chunk {index} of {}: {err}", path.display());
+                    FileResponse::NotFound
+                }
+            }
+        }
+    }
+}
+
+/// A download being worked on in this session.
+struct Download {
+    record: FileDownload,
+    /// Peers serving the file, the sender first
+    providers: Vec<PeerId>,
+    /// Providers we could not reach, left alone until the next retry
+    stalled: HashSet<PeerId>,
+    /// Providers that answered they do not have (that part of) the file
+    lacking: HashSet<PeerId>,
+    /// Chunk index -> provider it was requested from
+    in_flight: HashMap<u32, PeerId>,
+    /// Provider the manifest was requested from
+    manifest_from: Option<PeerId>,
+    /// Running DHT lookup for more providers
+    provider_query: Option<kad::QueryId>,
+    last_provider_query: Option<Instant>,
+    /// Last progress sent to the UI, in percent
+    reported_percent: u64,
+}
+
+/// Downloads in progress and the requests they are waiting on.
+pub struct FileTransfers {
+    downloads: HashMap<String, Download>,
+    /// Outbound request -> (file id, chunk index; `None` for the manifest)
+    requests: HashMap<OutboundRequestId, (String, Option<u32>)>,
+    /// DHT provider lookup -> file id
+    provider_queries: HashMap<kad::QueryId, String>,
+}
+
+impl FileTransfers {
+    /// Pick up the unfinished downloads of an earlier session.
+    pub fn new(db: Option<&ClientDatabase>) -> Self {
+        let mut downloads = HashMap::new();
+        let stored = db.map(|db| db.get_downloads()).unwrap_or(Ok(Vec::new()));
+        match stored {
+            Ok(stored) => {
+                for record in stored {
+                    if record.saved_path.is_some() {
+                        continue;
+                    }
+                    let Ok(peer) = PeerId::from_str(&record.peer_id) else {
+                        continue;
+                    };
+                    downloads.insert(
+                        record.attachment.file_id.clone(),
+                        Download::new(record, peer),
+                    );
+                }
+            }
+            Err(err) => log::warn!("Failed to load unfinished downloads: {err}"),
+        }
+        Self {
+            downloads,
+            requests: HashMap::new(),
+            provider_queries: HashMap::new(),
+        }
+    }
+
+    /// Start (or resume) downloading a file offered by `peer`; `public` files
+    /// (shared in a room) are also fetched from other providers. Returns the
+    /// saved path if the file was already downloaded.
+    pub fn start(
+        &mut self,
+        peer: PeerId,
+        attachment: FileAttachment,
+        public: bool,
+        db: Option<&ClientDatabase>,
+    ) -> Result<Option<String>, String> {
+        if attachment.file_id.len() != HASH_LEN * 2
+            || !attachment.file_id.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Err("Mã file không hợp lệ".to_string());
+        }
+        if attachment.size > MAX_FILE_SIZE {
+            return Err(format!("File lớn hơn giới hạn {MAX_FILE_SIZE} byte"));
+        }
+        if let Some(download) = self.downloads.get_mut(&attachment.file_id) {
+            download.add_provider(peer);
+            download.stalled.remove(&peer);
+            download.lacking.remove(&peer);
+            download.record.public |= public;
+            return Ok(None);
+        }
+
+        let stored = match db.map(|db| db.get_download(&attachment.file_id)) {
+            Some(Ok(stored)) => stored,
+            Some(Err(err)) => return Err(format!("Không đọc được tiến trình tải: {err}")),
+            None => None,
+        };
+        let record = match stored {
+            Some(record) => {
+                if let Some(path) = &record.saved_path
+                    && Path::new(path).exists()
+                {
+                    return Ok(Some(path.clone()));
+                }
+                FileDownload {
+                    peer_id: peer.to_string(),
+                    saved_path: None,
+                    public: record.public || public,
+                    ..record
+                }
+            }
+            None => FileDownload {
+                attachment,
+                peer_id: peer.to_string(),
+                chunk_size: 0,
+                chunk_hashes: None,
+                received: Vec::new(),
+                saved_path: None,
+                public,
+                last_used: 0,
+            },
+        };
+        let mut download = Download::new(record, peer);
+        if download.is_complete() {
+            // The blob is still cached from an earlier download
+            let path = download.finish()?;
+            download.record.saved_path = Some(path.clone());
+            save_record(db, &download.record);
+            return Ok(Some(path));
+        }
+        save_record(db, &download.record);
+        self.downloads
+            .insert(download.record.attachment.file_id.clone(), download);
+        Ok(None)
+    }
+
+    /// Look for more providers of a download and send its next requests.
+    pub fn resume(
+        &mut self,
+        file_id: &str,
+        swarm: &mut Swarm<ChatBehavior>,
+        nat_traversal: &NatTraversal,
+    ) {
+        self.find_providers(file_id, swarm);
+        self.pump(file_id, swarm, nat_traversal);
+    }
+
+    /// Give every provider another chance and keep every download fed with
+    /// requests (after a reconnect or a failure).
+    pub fn resume_all(&mut self, swarm: &mut Swarm<ChatBehavior>, nat_traversal: &NatTraversal) {
+        let file_ids: Vec<String> = self.downloads.keys().cloned().collect();
+        for file_id in file_ids {
+            if let Some(download) = self.downloads.get_mut(&file_id) {
+                download.stalled.clear();
+                download.lacking.clear();
+            }
+            self.resume(&file_id, swarm, nat_traversal);
+        }
+    }
+
+    /// Resume the downloads served by a peer we just connected to.
+    pub fn resume_peer(
+        &mut self,
+        peer: PeerId,
+        swarm: &mut Swarm<ChatBehavior>,
+        nat_traversal: &NatTraversal,
+    ) {
+        let mut file_ids = Vec::new();
+        for (file_id, download) in &mut self.downloads {
+            if download.providers.contains(&peer) {
+                download.stalled.remove(&peer);
+                file_ids.push(file_id.clone());
+            }
+        }
+        for file_id in file_ids {
+            self.pump(&file_id, swarm, nat_traversal);
+        }
+    }
+
+    /// Add the providers found by a DHT lookup. Returns the events to report
+    /// to the UI; nothing if the lookup is not ours.
+    pub fn handle_providers(
+        &mut self,
+        query: kad::QueryId,
+        result: Result<kad::GetProvidersOk, kad::GetProvidersError>,
+        last: bool,
+        swarm: &mut Swarm<ChatBehavior>,
+        nat_traversal: &NatTraversal,
+    ) -> Vec<NetworkEvent> {
+        let Some(file_id) = self.provider_queries.get(&query).cloned() else {
+            return Vec::new();
+        };
+        if last {
+            self.provider_queries.remove(&query);
+        }
+        let Some(download) = self.downloads.get_mut(&file_id) else {
+            return Vec::new();
+        };
+        if last {
+            download.provider_query = None;
+        }
+        match result {
+            Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                let local_peer_id = *swarm.local_peer_id();
+                for peer in providers.into_iter().filter(|peer| *peer != local_peer_id) {
+                    download.add_provider(peer);
+                }
+                log::debug!(
+                    "{} provider(s) known for {file_id}",
+                    download.providers.len()
+                );
+            }
+            Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+            Err(err) => log::debug!("Provider lookup for {file_id} failed: {err}"),
+        }
+        self.pump(&file_id, swarm, nat_traversal);
+        self.fail_if_stuck(&file_id).into_iter().collect()
+    }
+
+    /// Serve incoming requests and advance downloads. Returns the events to
+    /// report to the UI.
+    pub fn handle_event(
+        &mut self,
+        event: FileEvent,
+        swarm: &mut Swarm<ChatBehavior>,
+        db: Option<&ClientDatabase>,
+        nat_traversal: &NatTraversal,
+    ) -> Vec<NetworkEvent> {
+        match event {
+            request_response::Event::Message { peer, message, .. } => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    let response = serve(db, &request);
+                    if swarm
+                        .behaviour_mut()
+                        .files
+                        .send_response(channel, response)
+                        .is_err()
+                    {
+                        log::debug!("Failed to answer file request from {peer}");
+                    }
+                    Vec::new()
+                }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    let Some((file_id, index)) = self.requests.remove(&request_id) else {
+                        return Vec::new();
+                    };
+                    let events = self.handle_response(peer, &file_id, index, response, swarm, db);
+                    self.pump(&file_id, swarm, nat_traversal);
+                    events
+                }
+            },
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                // The peer is retried when it reconnects or on the next retry
+                // tick; other providers take over its chunks meanwhile
+                log::debug!("File request to {peer} failed: {error}");
+                let Some((file_id, index)) = self.requests.remove(&request_id) else {
+                    return Vec::new();
+                };
+                if let Some(download) = self.downloads.get_mut(&file_id) {
+                    download.release(index);
+                    download.stalled.insert(peer);
+                }
+                self.pump(&file_id, swarm, nat_traversal);
+                Vec::new()
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log::debug!("File request from {peer} failed: {error}");
+                Vec::new()
+            }
+            request_response::Event::ResponseSent { .. } => Vec::new(),
+        }
+    }
+
+    fn handle_response(
+        &mut self,
+        peer: PeerId,
+        file_id: &str,
+        index: Option<u32>,
+        response: FileResponse,
+        swarm: &mut Swarm<ChatBehavior>,
+        db: Option<&ClientDatabase>,
+    ) -> Vec<NetworkEvent> {
+        let Some(download) = self.downloads.get_mut(file_id) else {
+            return Vec::new();
+        };
+        download.release(index);
+
+        let result = match (index, response) {
+            (_, FileResponse::NotFound) => {
+                download.lacking.insert(peer);
+                return self.fail_if_stuck(file_id).into_iter().collect();
+            }
+            (
+                None,
+                FileResponse::Manifest {
+                    chunk_size,
+                    chunk_hashes,
+                },
+            ) => download.accept_manifest(chunk_size, chunk_hashes),
+            (Some(expected), FileResponse::Chunk { index, data }) if index == expected => {
+                match download.verify_chunk(index, &data) {
+                    Ok(offset) => {
+                        if let Err(err) = write_blob(file_id, offset, &data) {
+                            let reason = format!("Không ghi được file tạm: {err}");
+                            return vec![self.fail(file_id, reason)];
+                        }
+                        download.record.received[index as usize] = 1;
+                        Ok(())
+                    }
+                    Err(reason) => Err(reason),
+                }
+            }
+            _ => Err("Phản hồi không khớp với yêu cầu".to_string()),
+        };
+        if let Err(reason) = result {
+            // The data does not match the file id: stop asking this provider
+            log::warn!("Dropping provider {peer} of {file_id}: {reason}");
+            download.remove_provider(&peer);
+            if download.providers.is_empty() && download.provider_query.is_none() {
+                return vec![self.fail(file_id, reason)];
+            }
+            return Vec::new();
+        }
+        save_record(db, &download.record);
+        // Chunks we verified can be served to others from now on
+        if index.is_none() && download.record.public {
+            blobs::provide(swarm, file_id);
+        }
+
+        if !download.is_complete() {
+            return download.progress_event().into_iter().collect();
+        }
+        match download.finish() {
+            Ok(path) => {
+                download.record.saved_path = Some(path.clone());
+                download.record.last_used = Utc::now().timestamp();
+                save_record(db, &download.record);
+                self.downloads.remove(file_id);
+                blobs::enforce_limit(swarm, db, |id| self.downloads.contains_key(id));
+                vec![NetworkEvent::FileCompleted {
+                    file_id: file_id.to_string(),
+                    path,
+                }]
+            }
+            Err(reason) => {
+                // Start over: the chunks matched their hashes but not the whole file
+                download.record.received.fill(0);
+                save_record(db, &download.record);
+                vec![self.fail(file_id, reason)]
+            }
+        }
+    }
+
+    /// Give up once every provider lacks the file and nothing is left to wait for.
+    fn fail_if_stuck(&mut self, file_id: &str) -> Option<NetworkEvent> {
+        let download = self.downloads.get(file_id)?;
+        let stuck = download.provider_query.is_none()
+            && download.manifest_from.is_none()
+            && download.in_flight.is_empty()
+            && download
+                .providers
+                .iter()
+                .all(|peer| download.lacking.contains(peer));
+        stuck.then(|| self.fail(file_id, "Không còn ai chia sẻ file này".to_string()))
+    }
+
+    /// Give up downloads whose blob sat idle for [`blobs::PARTIAL_BLOB_IDLE`],
+    /// then delete the blobs of downloads no longer running and keep the rest
+    /// within the cache limit. Returns the events to report to the UI.
+    pub fn prune(
+        &mut self,
+        swarm: &mut Swarm<ChatBehavior>,
+        db: Option<&ClientDatabase>,
+    ) -> Vec<NetworkEvent> {
+        let idle: Vec<String> = self
+            .downloads
+            .keys()
+            .filter(|file_id| blobs::is_idle(file_id))
+            .cloned()
+            .collect();
+        let events = idle
+            .into_iter()
+            .map(|file_id| self.fail(&file_id, "Quá lâu không tải thêm được".to_string()))
+            .collect();
+        blobs::enforce_limit(swarm, db, |id| self.downloads.contains_key(id));
+        events
+    }
+
+    /// Drop a download from this session; its partial blob is deleted by the
+    /// next [`FileTransfers::prune`].
+    fn fail(&mut self, file_id: &str, reason: String) -> NetworkEvent {
+        log::warn!("Download of {file_id} failed: {reason}");
+        self.downloads.remove(file_id);
+        self.requests.retain(|_, (id, _)| id != file_id);
+        self.provider_queries.retain(|_, id| id != file_id);
+        NetworkEvent::FileFailed {
+            file_id: file_id.to_string(),
+            reason,
+        }
+    }
+
+    /// Ask the DHT for more providers of a public download, unless enough are
+    /// known or a lookup ran recently.
+    fn find_providers(&mut self, file_id: &str, swarm: &mut Swarm<ChatBehavior>) {
+        let Some(download) = self.downloads.get_mut(file_id) else {
+            return;
+        };
+        let recent = download
+            .last_provider_query
+            .is_some_and(|at| at.elapsed() < PROVIDER_QUERY_INTERVAL);
+        if !download.record.public
+            || download.provider_query.is_some()
+            || recent
+            || download.usable_providers().count() >= MAX_SOURCES
+        {
+            return;
+        }
+        let Some(key) = blobs::provider_key(file_id) else {
+            return;
+        };
+        let query = swarm.behaviour_mut().kad.get_providers(key);
+        download.provider_query = Some(query);
+        download.last_provider_query = Some(Instant::now());
+        self.provider_queries.insert(query, file_id.to_string());
+    }
+
+    /// Send the next requests of a download, spread over its providers up to
+    /// the window size of each.
+    fn pump(
+        &mut self,
+        file_id: &str,
+        swarm: &mut Swarm<ChatBehavior>,
+        nat_traversal: &NatTraversal,
+    ) {
+        let Some(download) = self.downloads.get_mut(file_id) else {
+            return;
+        };
+        let sources: Vec<PeerId> = download.usable_providers().take(MAX_SOURCES).collect();
+        let Some(first) = sources.first().copied() else {
+            return;
+        };
+
+        if download.record.chunk_hashes.is_none() {
+            if download.manifest_from.is_none() {
+                download.manifest_from = Some(first);
+                let request = FileRequest::Manifest {
+                    file_id: file_id.to_string(),
+                };
+                let request_id = send_request(swarm, nat_traversal, &first, request);
+                self.requests
+                    .insert(request_id, (file_id.to_string(), None));
+            }
+            return;
+        }
+
+        let mut missing = (0..download.record.received.len() as u32)
+            .filter(|index| {
+                !download.record.has_chunk(*index) && !download.in_flight.contains_key(index)
+            })
+            .take(sources.len() * DOWNLOAD_WINDOW)
+            .collect::<Vec<_>>()
+            .into_iter();
+        for peer in sources {
+            let busy = download
+                .in_flight
+                .values()
+                .filter(|asked| **asked == peer)
+                .count();
+            for _ in busy..DOWNLOAD_WINDOW {
+                let Some(index) = missing.next() else {
+                    return;
+                };
+                download.in_flight.insert(index, peer);
+                let request = FileRequest::Chunk {
+                    file_id: file_id.to_string(),
+                    index,
+                };
+                let request_id = send_request(swarm, nat_traversal, &peer, request);
+                self.requests
+                    .insert(request_id, (file_id.to_string(), Some(index)));
+            }
+        }
+    }
+}
+
+impl Download {
+    fn new(mut record: FileDownload, peer: PeerId) -> Self {
+        // Chunks only count while the blob holding them is there
+        if !blob_path(&record.attachment.file_id).exists() {
+            record.received.fill(0);
+        }
+        Self {
+            record,
+            providers: vec![peer],
+            stalled: HashSet::new(),
+            lacking: HashSet::new(),
+            in_flight: HashMap::new(),
+            manifest_from: None,
+            provider_query: None,
+            last_provider_query: None,
+            reported_percent: 0,
+        }
+    }
+
+    fn add_provider(&mut self, peer: PeerId) {
+        if !self.providers.contains(&peer) {
+            self.providers.push(peer);
+        }
+    }
+
+    fn remove_provider(&mut self, peer: &PeerId) {
+        self.providers.retain(|provider| provider != peer);
+        self.stalled.remove(peer);
+        self.lacking.remove(peer);
+    }
+
+    fn usable_providers(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.providers
+            .iter()
+            .copied()
+            .filter(|peer| !self.stalled.contains(peer) && !self.lacking.contains(peer))
+    }
+
+    /// Forget the request for a chunk (`None`: the manifest) once answered.
+    fn release(&mut self, index: Option<u32>) {
+        match index {
+            Some(index) => {
+                self.in_flight.remove(&index);
+            }
+            None => self.manifest_from = None,
+        }
+    }
+
+    fn accept_manifest(&mut self, chunk_size: u32, chunk_hashes: Vec<u8>) -> Result<(), String> {
+        // Another provider answered first
+        if self.record.chunk_hashes.is_some() {
+            return Ok(());
+        }
+        let size = self.record.attachment.size;
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(format!("Kích thước chunk không hợp lệ: {chunk_size}"));
+        }
+        let chunks = size.div_ceil(chunk_size as u64) as usize;
+        if chunk_hashes.len() != chunks * HASH_LEN
+            || manifest_id(chunk_size, &chunk_hashes) != self.record.attachment.file_id
+        {
+            return Err("Danh sách chunk không khớp với mã file".to_string());
+        }
+        self.record.chunk_size = chunk_size;
+        self.record.chunk_hashes = Some(chunk_hashes);
+        self.record.received = vec![0; chunks];
+        Ok(())
+    }
+
+    /// Check a chunk against the manifest; returns where it goes in the file.
+    fn verify_chunk(&self, index: u32, data: &[u8]) -> Result<u64, String> {
+        let Some(chunk_hashes) = &self.record.chunk_hashes else {
+            return Err("Nhận chunk trước danh sách chunk".to_string());
+        };
+        let start = index as usize * HASH_LEN;
+        let Some(expected) = chunk_hashes.get(start..start + HASH_LEN) else {
+            return Err(format!("Chunk {index} nằm ngoài file"));
+        };
+        let offset = index as u64 * self.record.chunk_size as u64;
+        let expected_len =
+            (self.record.attachment.size - offset).min(self.record.chunk_size as u64);
+        if data.len() as u64 != expected_len || Sha256::digest(data).as_slice() != expected {
+            return Err(format!("Chunk {index} sai hash"));
+        }
+        Ok(offset)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.record.is_complete()
+    }
+
+    /// Progress for the UI, only when the whole percentage moved.
+    fn progress_event(&mut self) -> Option<NetworkEvent> {
+        let total = self.record.attachment.size;
+        let received = self.record.received_bytes();
+        let percent = (received * 100).checked_div(total).unwrap_or(100);
+        if percent == self.reported_percent {
+            return None;
+        }
+        self.reported_percent = percent;
+        Some(NetworkEvent::FileProgress {
+            file_id: self.record.attachment.file_id.clone(),
+            received,
+            total,
+            sources: self.usable_providers().count(),
+        })
+    }
+
+    /// Check the whole blob and save it under its name. Public blobs stay in
+    /// the cache for others; private ones are moved out of it.
+    fn finish(&self) -> Result<String, String> {
+        let file_id = &self.record.attachment.file_id;
+        let blob = blob_path(file_id);
+        // Empty files have no chunk, so nothing was written yet
+        if !blob.exists() {
+            write_blob(file_id, 0, &[]).map_err(|err| format!("Không ghi được file tạm: {err}"))?;
+        }
+        let digest = hash_file(&blob).map_err(|err| format!("Không đọc được file tạm: {err}"))?;
+        if digest != self.record.attachment.sha256 {
+            let _ = fs::remove_file(&blob);
+            return Err("File tải về không khớp SHA-256".to_string());
+        }
+        fs::create_dir_all(DOWNLOAD_DIR).map_err(|err| format!("Không lưu được file: {err}"))?;
+        let target = unique_download_path(&self.record.attachment.name, file_id);
+        let saved = if self.record.public {
+            fs::copy(&blob, &target).map(|_| ())
+        } else {
+            fs::rename(&blob, &target)
+        };
+        saved.map_err(|err| format!("Không lưu được file: {err}"))?;
+        Ok(target.to_string_lossy().into_owned())
+    }
+}
+
+/// Send a file request, over relay circuits when we are not (or no longer)
+/// connected to `peer`.
+fn send_request(
+    swarm: &mut Swarm<ChatBehavior>,
+    nat_traversal: &NatTraversal,
+    peer: &PeerId,
+    request: FileRequest,
+) -> OutboundRequestId {
+    let addresses = if swarm.is_connected(peer) {
+        Vec::new()
+    } else {
+        nat_traversal.relay_circuit_addrs(peer)
+    };
+    swarm
+        .behaviour_mut()
+        .files
+        .send_request_with_addresses(peer, request, addresses)
+}
+
+fn save_record(db: Option<&ClientDatabase>, record: &FileDownload) {
+    if let Some(db) = db
+        && let Err(err) = db.save_download(record)
+    {
+        log::warn!(
+            "Failed to save download progress of {}: {err}",
+            record.attachment.file_id
+        );
+    }
+}
+
+/// Id of a file: SHA-256 (hex) of the chunk size and the chunk hashes.
+fn manifest_id(chunk_size: u32, chunk_hashes: &[u8]) -> String {
+    let digest = Sha256::new()
+        .chain_update(chunk_size.to_le_bytes())
+        .chain_update(chunk_hashes)
+        .finalize();
+    hex::encode(digest)
+}
+
+fn read_chunk(
+    path: &Path,
+    chunk_size: u32,
+    chunk_hashes: &[u8],
+    index: u32,
+) -> io::Result<Option<Vec<u8>>> {
+    let start = index as usize * HASH_LEN;
+    let Some(expected) = chunk_hashes.get(start..start + HASH_LEN) else {
+        return Ok(None);
+    };
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(index as u64 * chunk_size as u64))?;
+    let mut buffer = vec![0; chunk_size as usize];
+    let read = read_full(&mut file, &mut buffer)?;
+    buffer.truncate(read);
+    // The file was changed (or evicted) after it was offered
+    if Sha256::digest(&buffer).as_slice() != expected {
+        return Ok(None);
+    }
+    Ok(Some(buffer))
+}
+
+/// Fill `buffer` unless the end of the file comes first; returns the bytes read.
+fn read_full(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn write_blob(file_id: &str, offset: u64, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(blobs::BLOB_DIR)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(blob_path(file_id))?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
+}
+
+/// A path in the download directory for `name`, never overwriting a file.
+/// Only the last path component of the offered name is used.
+fn unique_download_path(name: &str, file_id: &str) -> PathBuf {
+    let name = Path::new(name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.starts_with('.'))
+        .unwrap_or(file_id);
+    let candidate = Path::new(DOWNLOAD_DIR).join(name);
+    if !candidate.exists() {
+        return candidate;
+    }
+    let path = Path::new(name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let extension = path.extension().and_then(|e| e.to_str());
+    (1..)
+        .map(|n| {
+            let file_name = match extension {
+                Some(extension) => format!("{stem} ({n}).{extension}"),
+                None => format!("{stem} ({n})"),
+            };
+            Path::new(DOWNLOAD_DIR).join(file_name)
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap_or(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Chunk hashes of `data` cut into `chunk_size` chunks.
+    fn chunk_hashes(data: &[u8], chunk_size: u32) -> Vec<u8> {
+        data.chunks(chunk_size as usize)
+            .flat_map(|chunk| Sha256::digest(chunk).to_vec())
+            .collect()
+    }
+
+    /// A download of `data` that has not fetched its manifest yet.
+    fn download(data: &[u8], chunk_size: u32) -> Download {
+        let record = FileDownload {
+            attachment: FileAttachment {
+                file_id: manifest_id(chunk_size, &chunk_hashes(data, chunk_size)),
+                name: "file.bin".to_string(),
+                size: data.len() as u64,
+                sha256: hex::encode(Sha256::digest(data)),
+            },
+            peer_id: String::new(),
+            chunk_size: 0,
+            chunk_hashes: None,
+            received: Vec::new(),
+            saved_path: None,
+            public: false,
+            last_used: 0,
+        };
+        Download::new(record, PeerId::random())
+    }
+
+    #[test]
+    fn manifest_id_covers_chunk_size_and_hashes() {
+        let hashes = chunk_hashes(b"hello world", 4);
+        let id = manifest_id(4, &hashes);
+        assert_eq!(id.len(), 64);
+        assert_eq!(id, manifest_id(4, &hashes));
+        assert_ne!(id, manifest_id(8, &hashes));
+        assert_ne!(id, manifest_id(4, &hashes[HASH_LEN..]));
+    }
+
+    #[test]
+    fn manifest_must_match_the_file_id() {
+        let data = b"hello world";
+        let hashes = chunk_hashes(data, 4);
+
+        let mut bad = download(data, 4);
+        assert!(bad.accept_manifest(0, hashes.clone()).is_err());
+        assert!(
+            bad.accept_manifest(MAX_CHUNK_SIZE + 1, hashes.clone())
+                .is_err()
+        );
+        assert!(bad.accept_manifest(4, hashes[HASH_LEN..].to_vec()).is_err());
+        let mut swapped = hashes.clone();
+        swapped.rotate_left(HASH_LEN);
+        assert!(bad.accept_manifest(4, swapped).is_err());
+        assert!(bad.record.chunk_hashes.is_none());
+
+        let mut good = download(data, 4);
+        good.accept_manifest(4, hashes.clone()).unwrap();
+        assert_eq!(good.record.received, [0, 0, 0]);
+        // A later answer from another provider changes nothing
+        good.accept_manifest(8, Vec::new()).unwrap();
+        assert_eq!(good.record.chunk_size, 4);
+    }
+
+    #[test]
+    fn chunks_are_checked_against_the_manifest() {
+        let data = b"hello world";
+        let mut download = download(data, 4);
+        assert!(download.verify_chunk(0, b"hell").is_err());
+        download.accept_manifest(4, chunk_hashes(data, 4)).unwrap();
+
+        assert_eq!(download.verify_chunk(0, b"hell").unwrap(), 0);
+        assert_eq!(download.verify_chunk(1, b"o wo").unwrap(), 4);
+        // The last chunk is shorter, and must be exactly that short
+        assert_eq!(download.verify_chunk(2, b"rld").unwrap(), 8);
+        assert!(download.verify_chunk(2, b"rld\0").is_err());
+        // Wrong length, wrong hash, outside the file
+        assert!(download.verify_chunk(0, b"hel").is_err());
+        assert!(download.verify_chunk(0, b"HELL").is_err());
+        assert!(download.verify_chunk(3, b"").is_err());
+    }
+
+    #[test]
+    fn empty_file_has_no_chunks() {
+        let mut download = download(b"", CHUNK_SIZE);
+        download.accept_manifest(CHUNK_SIZE, Vec::new()).unwrap();
+        assert!(download.record.received.is_empty());
+        assert!(download.is_complete());
+        assert!(download.verify_chunk(0, b"").is_err());
+    }
+
+    #[test]
+    fn download_path_stays_in_the_download_directory() {
+        let file_id = "f".repeat(64);
+        for (name, expected) in [
+            ("../../etc/passwd-p2p-test", "passwd-p2p-test"),
+            ("/tmp/report-p2p-test.pdf", "report-p2p-test.pdf"),
+            ("..", file_id.as_str()),
+            (".bashrc", file_id.as_str()),
+            ("", file_id.as_str()),
+        ] {
+            let path = unique_download_path(name, &file_id);
+            assert_eq!(path, Path::new(DOWNLOAD_DIR).join(expected), "{name}");
+        }
+    }
+}
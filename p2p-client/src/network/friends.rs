@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use libp2p::StreamProtocol;
+use libp2p::request_response::{self, ProtocolSupport};
+use serde::{Deserialize, Serialize};
+
+/// Protocol used to ask someone to be friends and to answer such a request.
+/// The sender is the authenticated peer of the connection, so requests cannot
+/// be forged on someone else's behalf.
+pub const FRIEND_PROTOCOL: StreamProtocol = StreamProtocol::new("/rust-p2p-chat/friend/1.0.0");
+
+/// Longest note accepted with a friend request, in characters.
+pub const MAX_FRIEND_NOTE_CHARS: usize = 280;
+
+/// Friend requests kept waiting for an answer; the oldest makes way for a new one.
+pub const MAX_INCOMING_FRIEND_REQUESTS: usize = 50;
+
+/// A peer's repeated friend requests are ignored within this interval.
+pub const FRIEND_REQUEST_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FriendRequest {
+    /// Ask to become friends
+    Request { note: String },
+    /// Accept the request the receiver sent us, with the key our presence
+    /// record is encrypted with
+    Accept { presence_key: Vec<u8> },
+    /// Decline the receiver's request, or withdraw ours
+    Decline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FriendResponse {
+    /// Request kept until the user answers it
+    Pending,
+    /// We are friends (both sides asked, or the receiver already accepted),
+    /// with the receiver's presence key
+    Accepted { presence_key: Vec<u8> },
+    /// Answer taken into account
+    Done,
+    /// An answer to a request the receiver never sent
+    NotRequested,
+}
+
+pub type FriendBehaviour = request_response::cbor::Behaviour<FriendRequest, FriendResponse>;
+pub type FriendEvent = request_response::Event<FriendRequest, FriendResponse>;
+
+pub fn build_friend_behaviour() -> FriendBehaviour {
+    // Requests usually go through a relay circuit, like direct messages
+    let config = request_response::Config::default().with_request_timeout(Duration::from_secs(60));
+    request_response::cbor::Behaviour::new([(FRIEND_PROTOCOL, ProtocolSupport::Full)], config)
+}
@@ -0,0 +1,54 @@
+use libp2p::StreamProtocol;
+use libp2p::request_response::{self, ProtocolSupport};
+use serde::{Deserialize, Serialize};
+
+/// Store-and-forward protocol offered by nodemasters started with `NODE_MAILBOX=1`.
+/// Direct messages to a friend we cannot reach are left there, still end-to-end
+/// encrypted, and collected by the friend when it connects.
+pub const MAILBOX_PROTOCOL: StreamProtocol = StreamProtocol::new("/rust-p2p-chat/mailbox/1.0.0");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MailboxRequest {
+    /// Keep an end-to-end-encrypted message (a postcard-encoded `SealedMessage`)
+    /// for an offline recipient
+    Deposit { recipient: String, payload: Vec<u8> },
+    /// Ask for the messages waiting for us
+    Fetch,
+    /// Delete fetched messages once we have them
+    Ack { ids: Vec<u64> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MailboxResponse {
+    Stored,
+    /// The recipient's mailbox is full
+    QuotaExceeded,
+    TooLarge,
+    InvalidRecipient,
+    Messages {
+        items: Vec<MailboxItem>,
+        /// More messages are waiting after these
+        more: bool,
+    },
+    Acked,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxItem {
+    pub id: u64,
+    /// PeerId of the friend who left the message (checked again by decryption)
+    pub sender: String,
+    pub payload: Vec<u8>,
+    pub deposited_at: i64,
+}
+
+pub type MailboxBehaviour = request_response::cbor::Behaviour<MailboxRequest, MailboxResponse>;
+pub type MailboxEvent = request_response::Event<MailboxRequest, MailboxResponse>;
+
+pub fn build_mailbox_behaviour() -> MailboxBehaviour {
+    // Clients only use mailboxes, they never host one
+    request_response::cbor::Behaviour::new(
+        [(MAILBOX_PROTOCOL, ProtocolSupport::Outbound)],
+        request_response::Config::default(),
+    )
+}
@@ -1,6 +1,19 @@
 pub mod behavior;
+pub mod blobs;
+pub mod bytes;
 pub mod client;
+pub mod direct;
+pub mod e2e;
+pub mod envelope;
+pub mod files;
+pub mod friends;
+pub mod mailbox;
 pub mod nat_traversal;
+pub mod ops;
+pub mod presence;
+pub mod profile;
+pub mod reputation;
+pub mod sync;
 pub mod transport;
 
 pub use client::P2PClient;
@@ -192,6 +192,18 @@ impl NatTraversal {
         }
     }
 
+    /// Relay circuit addresses through which `peer_id` may be reachable
+    pub fn relay_circuit_addrs(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.relay_peers
+            .iter()
+            .filter_map(|relay_id| {
+                format!("/p2p/{}/p2p-circuit/p2p/{}", relay_id, peer_id)
+                    .parse::<Multiaddr>()
+                    .ok()
+            })
+            .collect()
+    }
+
     /// Mark a peer as having failed direct connection
     pub fn mark_failed_direct(&mut self, peer_id: PeerId) {
         self.failed_direct_connections.insert(peer_id);
@@ -202,10 +214,5 @@ impl NatTraversal {
         self.failed_direct_connections.remove(peer_id);
         self.pending_relay_retries.remove(peer_id);
     }
-
-    /// Check if a peer has failed direct connection
-    pub fn has_failed_direct(&self, peer_id: &PeerId) -> bool {
-        self.failed_direct_connections.contains(peer_id)
-    }
 }
 
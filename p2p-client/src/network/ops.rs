@@ -0,0 +1,67 @@
+//! Signing and verification of message edit/delete operations.
+//!
+//! An op is signed with the author's identity key. Ed25519 PeerIds embed the
+//! public key, so anyone holding the op can check it came from the author of
+//! the original message, no matter which peer relayed it.
+
+use std::str::FromStr;
+
+use chrono::Utc;
+use libp2p::{PeerId, identity};
+
+use crate::common::{MessageOp, OpAction};
+
+use super::e2e::embedded_public_key;
+
+const OP_SIGNING_DOMAIN: &str = "rust-p2p-chat/message-op/v1";
+
+/// Create an op on one of our own messages, signed with our identity key.
+pub fn sign_op(
+    local_key: &identity::Keypair,
+    target_id: String,
+    room: String,
+    action: OpAction,
+) -> Option<MessageOp> {
+    let mut op = MessageOp {
+        target_id,
+        author: local_key.public().to_peer_id().to_string(),
+        room,
+        timestamp: Utc::now().timestamp(),
+        action,
+        signature: Vec::new(),
+    };
+    match local_key.sign(&signing_bytes(&op)?) {
+        Ok(signature) => {
+            op.signature = signature;
+            Some(op)
+        }
+        Err(err) => {
+            log::warn!("Failed to sign message op: {err}");
+            None
+        }
+    }
+}
+
+/// Check that `op` was signed by its claimed author.
+pub fn verify_op(op: &MessageOp) -> bool {
+    let Ok(author) = PeerId::from_str(&op.author) else {
+        return false;
+    };
+    let Some(public_key) = embedded_public_key(&author) else {
+        return false;
+    };
+    signing_bytes(op).is_some_and(|bytes| public_key.verify(&bytes, &op.signature))
+}
+
+/// Every field except the signature, in a fixed order and encoding.
+fn signing_bytes(op: &MessageOp) -> Option<Vec<u8>> {
+    postcard::to_allocvec(&(
+        OP_SIGNING_DOMAIN,
+        &op.target_id,
+        &op.author,
+        &op.room,
+        op.timestamp,
+        &op.action,
+    ))
+    .ok()
+}
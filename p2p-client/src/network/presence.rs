@@ -0,0 +1,256 @@
+//! Signed presence records in the DHT.
+//!
+//! Every client periodically puts a short-lived record under a key derived from
+//! its PeerId, listing the addresses it can be reached at. The record is signed
+//! with the identity key, so a friend check only trusts addresses the friend
+//! published itself, and the expiry inside the signed part tells a stale record
+//! from a live client. The addresses are encrypted with a presence key that is
+//! only handed to accepted friends (see `network::friends`), so nobody else
+//! learns where we are.
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chrono::Utc;
+use libp2p::{Multiaddr, PeerId, Swarm, identity, kad};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+
+use super::behavior::ChatBehavior;
+use super::e2e::embedded_public_key;
+
+const PRESENCE_SIGNING_DOMAIN: &str = "rust-p2p-chat/presence/v1";
+const PRESENCE_KEY_PREFIX: &[u8] = b"/rust-p2p-chat/presence/";
+
+/// How long a published record is trusted.
+pub const PRESENCE_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// How often our record is published again, well within [`PRESENCE_TTL`].
+pub const PRESENCE_REPUBLISH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Most addresses listed in one record.
+const MAX_PRESENCE_ADDRESSES: usize = 16;
+
+pub const PRESENCE_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PresenceRecord {
+    peer_id: String,
+    /// Nonce followed by the encrypted, postcard-encoded address list
+    sealed_addresses: Vec<u8>,
+    published_at: i64,
+    expires_at: i64,
+    signature: Vec<u8>,
+}
+
+/// A new random presence key.
+pub fn generate_key() -> Vec<u8> {
+    let mut key = vec![0u8; PRESENCE_KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// DHT key of a peer's presence record.
+pub fn record_key(peer: &PeerId) -> kad::RecordKey {
+    let mut key = PRESENCE_KEY_PREFIX.to_vec();
+    key.extend_from_slice(&peer.to_bytes());
+    kad::RecordKey::new(&key)
+}
+
+/// Publish a fresh record with the addresses we currently listen on or were
+/// observed at (relay circuits included), readable with `presence_key`.
+pub fn publish(
+    swarm: &mut Swarm<ChatBehavior>,
+    local_key: &identity::Keypair,
+    presence_key: &[u8],
+) {
+    let mut addresses: Vec<String> = Vec::new();
+    for address in swarm.external_addresses().chain(swarm.listeners()) {
+        let address = address.to_string();
+        if !addresses.contains(&address) && addresses.len() < MAX_PRESENCE_ADDRESSES {
+            addresses.push(address);
+        }
+    }
+
+    let address_count = addresses.len();
+    let Some(record) = signed_record(local_key, presence_key, &addresses, Utc::now().timestamp())
+    else {
+        return;
+    };
+    match swarm
+        .behaviour_mut()
+        .kad
+        .put_record(record, kad::Quorum::One)
+    {
+        Ok(_) => log::debug!("Publishing presence with {address_count} address(es)"),
+        Err(err) => log::warn!("Failed to store presence record: {err}"),
+    }
+}
+
+/// Our presence record listing `addresses`, published at `now` (unix seconds).
+fn signed_record(
+    local_key: &identity::Keypair,
+    presence_key: &[u8],
+    addresses: &[String],
+    now: i64,
+) -> Option<kad::Record> {
+    let peer = local_key.public().to_peer_id();
+    let Some(sealed_addresses) = seal_addresses(presence_key, &peer, addresses) else {
+        log::warn!("Failed to encrypt presence record");
+        return None;
+    };
+    let mut presence = PresenceRecord {
+        peer_id: peer.to_string(),
+        sealed_addresses,
+        published_at: now,
+        expires_at: now + PRESENCE_TTL.as_secs() as i64,
+        signature: Vec::new(),
+    };
+    let bytes = signing_bytes(&presence)?;
+    presence.signature = match local_key.sign(&bytes) {
+        Ok(signature) => signature,
+        Err(err) => {
+            log::warn!("Failed to sign presence record: {err}");
+            return None;
+        }
+    };
+    let value = match postcard::to_allocvec(&presence) {
+        Ok(value) => value,
+        Err(err) => {
+            log::warn!("Failed to encode presence record: {err}");
+            return None;
+        }
+    };
+
+    let mut record = kad::Record::new(record_key(&peer), value);
+    record.expires = Some(Instant::now() + PRESENCE_TTL);
+    Some(record)
+}
+
+/// Addresses from `peer`'s presence record, if it is signed by `peer`, has
+/// not expired and opens with the presence key `peer` gave us.
+pub fn verify(record: &kad::Record, peer: &PeerId, presence_key: &[u8]) -> Option<Vec<Multiaddr>> {
+    if record.key != record_key(peer) {
+        return None;
+    }
+    let presence: PresenceRecord = postcard::from_bytes(&record.value).ok()?;
+    if PeerId::from_str(&presence.peer_id).ok()? != *peer
+        || presence.expires_at <= Utc::now().timestamp()
+    {
+        return None;
+    }
+    let public_key = embedded_public_key(peer)?;
+    if !signing_bytes(&presence).is_some_and(|bytes| public_key.verify(&bytes, &presence.signature))
+    {
+        return None;
+    }
+    let addresses = open_addresses(presence_key, peer, &presence.sealed_addresses)?;
+    Some(
+        addresses
+            .iter()
+            .filter_map(|address| address.parse().ok())
+            .collect(),
+    )
+}
+
+fn seal_addresses(presence_key: &[u8], peer: &PeerId, addresses: &[String]) -> Option<Vec<u8>> {
+    if presence_key.len() != PRESENCE_KEY_LEN {
+        return None;
+    }
+    let plaintext = postcard::to_allocvec(addresses).ok()?;
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(presence_key))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &plaintext,
+                aad: &peer.to_bytes(),
+            },
+        )
+        .ok()?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Some(sealed)
+}
+
+fn open_addresses(presence_key: &[u8], peer: &PeerId, sealed: &[u8]) -> Option<Vec<String>> {
+    if presence_key.len() != PRESENCE_KEY_LEN || sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let plaintext = ChaCha20Poly1305::new(Key::from_slice(presence_key))
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &peer.to_bytes(),
+            },
+        )
+        .ok()?;
+    postcard::from_bytes(&plaintext).ok()
+}
+
+/// Every field except the signature, in a fixed order and encoding.
+fn signing_bytes(presence: &PresenceRecord) -> Option<Vec<u8>> {
+    postcard::to_allocvec(&(
+        PRESENCE_SIGNING_DOMAIN,
+        &presence.peer_id,
+        &presence.sealed_addresses,
+        presence.published_at,
+        presence.expires_at,
+    ))
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "/ip4/192.0.2.1/tcp/4001";
+
+    fn record(local_key: &identity::Keypair, presence_key: &[u8], now: i64) -> kad::Record {
+        signed_record(local_key, presence_key, &[ADDRESS.to_string()], now).unwrap()
+    }
+
+    #[test]
+    fn record_opens_only_for_its_peer_and_key() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let peer = local_key.public().to_peer_id();
+        let presence_key = generate_key();
+        let record = record(&local_key, &presence_key, Utc::now().timestamp());
+
+        let addresses = verify(&record, &peer, &presence_key).unwrap();
+        assert_eq!(addresses, vec![ADDRESS.parse::<Multiaddr>().unwrap()]);
+        assert!(verify(&record, &peer, &generate_key()).is_none());
+        let other = identity::Keypair::generate_ed25519().public().to_peer_id();
+        assert!(verify(&record, &other, &presence_key).is_none());
+    }
+
+    #[test]
+    fn tampered_record_is_refused() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let peer = local_key.public().to_peer_id();
+        let presence_key = generate_key();
+        let mut record = record(&local_key, &presence_key, Utc::now().timestamp());
+
+        let mut presence: PresenceRecord = postcard::from_bytes(&record.value).unwrap();
+        presence.expires_at += PRESENCE_TTL.as_secs() as i64;
+        record.value = postcard::to_allocvec(&presence).unwrap();
+        assert!(verify(&record, &peer, &presence_key).is_none());
+    }
+
+    #[test]
+    fn expired_record_is_refused() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let peer = local_key.public().to_peer_id();
+        let presence_key = generate_key();
+        let published_at = Utc::now().timestamp() - PRESENCE_TTL.as_secs() as i64 - 1;
+        let record = record(&local_key, &presence_key, published_at);
+        assert!(verify(&record, &peer, &presence_key).is_none());
+    }
+}
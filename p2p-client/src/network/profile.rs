@@ -0,0 +1,209 @@
+//! User profiles: display name, avatar and status text.
+//!
+//! Each client signs its own profile with its identity key. Peers ask for it
+//! when they meet (identify tells which peers speak the protocol) and cache the
+//! answer; a client that changes its profile pushes the new one to everybody
+//! connected. `updated_at` orders versions, so a cached copy only asks for an
+//! update and an older profile never replaces a newer one.
+
+use std::io::Cursor;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::Utc;
+use image::{ImageFormat, ImageReader, Limits};
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol, identity};
+use serde::{Deserialize, Serialize};
+
+use crate::common::PeerProfile;
+
+use super::e2e::embedded_public_key;
+
+pub const PROFILE_PROTOCOL: StreamProtocol = StreamProtocol::new("/rust-p2p-chat/profile/1.0.0");
+
+const PROFILE_SIGNING_DOMAIN: &str = "rust-p2p-chat/profile/v1";
+
+pub const MAX_DISPLAY_NAME_CHARS: usize = 64;
+pub const MAX_STATUS_CHARS: usize = 140;
+
+/// Largest avatar accepted, PNG-encoded.
+pub const MAX_AVATAR_BYTES: usize = 48 * 1024;
+
+/// Longest side of an avatar, in pixels.
+const AVATAR_SIZE: u32 = 64;
+
+/// Limits when reading a picture to turn into an avatar.
+const MAX_SOURCE_DIMENSION: u32 = 16_384;
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// How long a fetched profile is trusted before asking the peer again.
+pub const PROFILE_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ProfileRequest {
+    /// Ask for the receiver's profile; `known` is the `updated_at` of the copy
+    /// we have (0 if none)
+    Get { known: i64 },
+    /// The sender changed its profile
+    Update(PeerProfile),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ProfileResponse {
+    Profile(PeerProfile),
+    /// The copy the requester has is current
+    Unchanged,
+    /// The receiver has not set a profile
+    NoProfile,
+    /// Update taken into account
+    Done,
+}
+
+pub type ProfileBehaviour = request_response::cbor::Behaviour<ProfileRequest, ProfileResponse>;
+pub type ProfileEvent = request_response::Event<ProfileRequest, ProfileResponse>;
+
+pub fn build_profile_behaviour() -> ProfileBehaviour {
+    request_response::cbor::Behaviour::new(
+        [(PROFILE_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// Our profile with the given fields, signed with our identity key. The
+/// version is newer than `previous` even when changed twice in one second.
+pub fn sign_profile(
+    local_key: &identity::Keypair,
+    display_name: &str,
+    status: &str,
+    avatar: Vec<u8>,
+    previous: Option<&PeerProfile>,
+) -> Option<PeerProfile> {
+    let updated_at = previous.map_or(0, |profile| profile.updated_at + 1);
+    let mut profile = PeerProfile {
+        peer_id: local_key.public().to_peer_id().to_string(),
+        display_name: display_name
+            .trim()
+            .chars()
+            .take(MAX_DISPLAY_NAME_CHARS)
+            .collect(),
+        status: status.trim().chars().take(MAX_STATUS_CHARS).collect(),
+        avatar,
+        updated_at: updated_at.max(Utc::now().timestamp()),
+        signature: Vec::new(),
+    };
+    match local_key.sign(&signing_bytes(&profile)?) {
+        Ok(signature) => {
+            profile.signature = signature;
+            Some(profile)
+        }
+        Err(err) => {
+            log::warn!("Failed to sign profile: {err}");
+            None
+        }
+    }
+}
+
+/// Check that `profile` belongs to `peer`, is signed by it and fits the limits.
+pub fn verify_profile(profile: &PeerProfile, peer: &PeerId) -> bool {
+    if PeerId::from_str(&profile.peer_id).ok() != Some(*peer)
+        || profile.display_name.chars().count() > MAX_DISPLAY_NAME_CHARS
+        || profile.status.chars().count() > MAX_STATUS_CHARS
+        || profile.avatar.len() > MAX_AVATAR_BYTES
+    {
+        return false;
+    }
+    let Some(public_key) = embedded_public_key(peer) else {
+        return false;
+    };
+    signing_bytes(profile).is_some_and(|bytes| public_key.verify(&bytes, &profile.signature))
+}
+
+/// Turn the picture at `path` (PNG or JPEG) into a small PNG avatar.
+pub fn load_avatar(path: &Path) -> Result<Vec<u8>, String> {
+    let mut reader = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|err| err.to_string())?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+    let avatar = reader
+        .decode()
+        .map_err(|err| err.to_string())?
+        .thumbnail(AVATAR_SIZE, AVATAR_SIZE);
+
+    let mut png = Vec::new();
+    avatar
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|err| err.to_string())?;
+    if png.len() > MAX_AVATAR_BYTES {
+        return Err(format!("avatar is {} bytes after resizing", png.len()));
+    }
+    Ok(png)
+}
+
+/// Every field except the signature, in a fixed order and encoding.
+fn signing_bytes(profile: &PeerProfile) -> Option<Vec<u8>> {
+    postcard::to_allocvec(&(
+        PROFILE_SIGNING_DOMAIN,
+        &profile.peer_id,
+        &profile.display_name,
+        &profile.status,
+        &profile.avatar,
+        profile.updated_at,
+    ))
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_profile_verifies_only_for_its_peer() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let peer = local_key.public().to_peer_id();
+        let profile = sign_profile(&local_key, " Alice ", "online", Vec::new(), None).unwrap();
+        assert_eq!(profile.display_name, "Alice");
+        assert!(verify_profile(&profile, &peer));
+
+        let other = identity::Keypair::generate_ed25519().public().to_peer_id();
+        assert!(!verify_profile(&profile, &other));
+        let mut tampered = profile.clone();
+        tampered.status = "away".to_string();
+        assert!(!verify_profile(&tampered, &peer));
+
+        let next = sign_profile(&local_key, "Alice", "away", Vec::new(), Some(&profile)).unwrap();
+        assert!(next.updated_at > profile.updated_at);
+    }
+
+    #[test]
+    fn oversized_avatar_is_refused() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let peer = local_key.public().to_peer_id();
+        let avatar = vec![0; MAX_AVATAR_BYTES + 1];
+        let profile = sign_profile(&local_key, "Alice", "", avatar, None).unwrap();
+        assert!(!verify_profile(&profile, &peer));
+    }
+
+    #[test]
+    fn avatar_is_shrunk_to_fit() {
+        let path = std::env::temp_dir().join(format!("avatar-{}.png", std::process::id()));
+        image::RgbImage::from_fn(640, 320, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(&path)
+            .unwrap();
+        let avatar = load_avatar(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let avatar = avatar.unwrap();
+        assert!(avatar.len() <= MAX_AVATAR_BYTES);
+        let decoded = image::load_from_memory(&avatar).unwrap();
+        assert_eq!(
+            (decoded.width(), decoded.height()),
+            (AVATAR_SIZE, AVATAR_SIZE / 2)
+        );
+    }
+}
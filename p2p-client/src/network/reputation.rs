@@ -0,0 +1,274 @@
+//! Reputation of the peers we exchange room messages with, kept across restarts.
+//!
+//! Gossipsub only scores peers while it runs, so a peer that flooded a room
+//! yesterday would start with a clean slate after a restart. Two penalties are
+//! kept for every misbehaving peer: the part of its gossipsub score it earned
+//! itself (sampled periodically, the worst value is kept) and an
+//! application-level reputation lowered for every message we reject. Both fade
+//! with the configured half-life. The reputation is handed to gossipsub as the
+//! peer's application-specific score whenever it connects; the sampled score
+//! only joins it in a later run, as until then gossipsub still counts it.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::Utc;
+use libp2p::{PeerId, Swarm};
+
+use crate::config::PeerScoringConfig;
+use crate::storage::client_db::ClientDatabase;
+use crate::storage::models::PeerReputation;
+
+use super::behavior::ChatBehavior;
+
+/// How often scores are sampled and saved.
+pub const REPUTATION_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reputation lost for every message we reject.
+const REJECTED_MESSAGE_PENALTY: f64 = 1.0;
+
+/// Penalties that faded below this are forgotten.
+const NEGLIGIBLE_PENALTY: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy)]
+struct Standing {
+    /// Worst score the peer earned itself, saved for the next run
+    score: f64,
+    /// Part of `score` earned in an earlier run, which gossipsub forgot
+    carried: f64,
+    reputation: f64,
+    updated_at: i64,
+    /// Application score last handed to gossipsub
+    applied: f64,
+}
+
+impl Standing {
+    fn new(now: i64) -> Self {
+        Self {
+            score: 0.0,
+            carried: 0.0,
+            reputation: 0.0,
+            updated_at: now,
+            applied: 0.0,
+        }
+    }
+
+    fn is_negligible(&self) -> bool {
+        self.score.abs() < NEGLIGIBLE_PENALTY && self.reputation.abs() < NEGLIGIBLE_PENALTY
+    }
+}
+
+pub struct Reputations {
+    /// Weight of the application score in gossipsub, to tell our part of a
+    /// peer's score from the part it earned itself
+    weight: f64,
+    half_life_secs: f64,
+    standings: HashMap<PeerId, Standing>,
+    /// Faded since the last save, to delete from the database
+    forgotten: Vec<PeerId>,
+}
+
+impl Reputations {
+    fn new(config: &PeerScoringConfig) -> Self {
+        Self {
+            weight: config.reputation_weight,
+            half_life_secs: config.reputation_half_life_hours * 3600.0,
+            standings: HashMap::new(),
+            forgotten: Vec::new(),
+        }
+    }
+
+    /// Penalties saved by an earlier run.
+    pub fn load(db: Option<&ClientDatabase>, config: &PeerScoringConfig) -> Self {
+        let mut reputations = Self::new(config);
+        let Some(db) = db else {
+            return reputations;
+        };
+        let entries = db.get_peer_reputations().unwrap_or_else(|err| {
+            log::warn!("Failed to load peer reputations: {err}");
+            Vec::new()
+        });
+        let now = Utc::now().timestamp();
+        for entry in entries {
+            let Ok(peer) = PeerId::from_str(&entry.peer_id) else {
+                continue;
+            };
+            let mut standing = Standing {
+                score: entry.score,
+                carried: entry.score,
+                reputation: entry.reputation,
+                updated_at: entry.updated_at,
+                applied: 0.0,
+            };
+            fade(&mut standing, now, reputations.half_life_secs);
+            if standing.is_negligible() {
+                reputations.forgotten.push(peer);
+            } else {
+                reputations.standings.insert(peer, standing);
+            }
+        }
+        if !reputations.standings.is_empty() {
+            log::info!(
+                "Loaded penalties of {} peer(s)",
+                reputations.standings.len()
+            );
+        }
+        reputations
+    }
+
+    /// Hand the penalties held against `peer` to gossipsub; called once it
+    /// is connected, as gossipsub forgets application scores of unknown peers.
+    pub fn apply(&mut self, peer: PeerId, swarm: &mut Swarm<ChatBehavior>) {
+        if let Some(score) = self.refresh(peer, Utc::now().timestamp()) {
+            swarm
+                .behaviour_mut()
+                .gossipsub
+                .set_application_score(&peer, score);
+        }
+    }
+
+    /// `peer` sent a message we rejected.
+    pub fn penalise(&mut self, peer: PeerId, swarm: &mut Swarm<ChatBehavior>) {
+        self.note_rejection(peer, Utc::now().timestamp());
+        self.apply(peer, swarm);
+    }
+
+    /// Sample the scores of connected peers and save every penalty still held.
+    pub fn save(&mut self, swarm: &mut Swarm<ChatBehavior>, db: Option<&ClientDatabase>) {
+        let now = Utc::now().timestamp();
+        let connected: Vec<PeerId> = swarm.connected_peers().copied().collect();
+        for peer in &connected {
+            if let Some(live) = swarm.behaviour().gossipsub.peer_score(peer) {
+                self.sample(*peer, live, now);
+            }
+        }
+
+        let half_life_secs = self.half_life_secs;
+        self.standings.retain(|peer, standing| {
+            fade(standing, now, half_life_secs);
+            if standing.is_negligible() {
+                self.forgotten.push(*peer);
+                false
+            } else {
+                true
+            }
+        });
+        for peer in connected {
+            self.apply(peer, swarm);
+        }
+
+        let Some(db) = db else {
+            return;
+        };
+        for peer in self.forgotten.drain(..) {
+            if let Err(err) = db.remove_peer_reputation(&peer.to_string()) {
+                log::warn!("Failed to forget reputation of {peer}: {err}");
+            }
+        }
+        for (peer, standing) in &self.standings {
+            let entry = PeerReputation {
+                peer_id: peer.to_string(),
+                score: standing.score,
+                reputation: standing.reputation,
+                updated_at: standing.updated_at,
+            };
+            if let Err(err) = db.save_peer_reputation(&entry) {
+                log::warn!("Failed to save reputation of {peer}: {err}");
+            }
+        }
+    }
+
+    fn note_rejection(&mut self, peer: PeerId, now: i64) {
+        let mut standing = self
+            .standings
+            .get(&peer)
+            .copied()
+            .unwrap_or_else(|| Standing::new(now));
+        fade(&mut standing, now, self.half_life_secs);
+        standing.reputation -= REJECTED_MESSAGE_PENALTY;
+        self.standings.insert(peer, standing);
+    }
+
+    /// Keep the worst score `peer` earned itself, out of its `live` gossipsub
+    /// score (which includes the application score we handed it).
+    fn sample(&mut self, peer: PeerId, live: f64, now: i64) {
+        let applied = self.standings.get(&peer).map_or(0.0, |s| s.applied);
+        let own = live - self.weight * applied;
+        if own > -NEGLIGIBLE_PENALTY && !self.standings.contains_key(&peer) {
+            return;
+        }
+        let standing = self
+            .standings
+            .entry(peer)
+            .or_insert_with(|| Standing::new(now));
+        standing.score = standing.score.min(own);
+    }
+
+    /// Fade the penalties held against `peer` and work out the application
+    /// score to hand to gossipsub, if there are any.
+    fn refresh(&mut self, peer: PeerId, now: i64) -> Option<f64> {
+        let standing = self.standings.get_mut(&peer)?;
+        fade(standing, now, self.half_life_secs);
+        standing.applied = if self.weight > 0.0 {
+            standing.reputation + standing.carried / self.weight
+        } else {
+            standing.reputation
+        };
+        Some(standing.applied)
+    }
+}
+
+/// Halve the penalties of `standing` for every half-life since it was updated.
+fn fade(standing: &mut Standing, now: i64, half_life_secs: f64) {
+    let elapsed = (now - standing.updated_at).max(0) as f64;
+    let factor = 0.5f64.powf(elapsed / half_life_secs);
+    standing.score *= factor;
+    standing.carried *= factor;
+    standing.reputation *= factor;
+    standing.updated_at = now;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reputations() -> Reputations {
+        Reputations::new(&PeerScoringConfig::default())
+    }
+
+    #[test]
+    fn sampled_score_is_not_counted_twice() {
+        let mut reputations = reputations();
+        let peer = PeerId::random();
+        let weight = reputations.weight;
+
+        reputations.note_rejection(peer, 0);
+        let applied = reputations.refresh(peer, 0).unwrap();
+        assert_eq!(applied, -REJECTED_MESSAGE_PENALTY);
+
+        // Gossipsub holds its own penalty on top of the one we handed it
+        let own = -40.0;
+        reputations.sample(peer, own + weight * applied, 0);
+        assert_eq!(reputations.standings[&peer].score, own);
+        assert_eq!(reputations.refresh(peer, 0), Some(applied));
+    }
+
+    #[test]
+    fn score_from_an_earlier_run_is_handed_back() {
+        let mut reputations = reputations();
+        let peer = PeerId::random();
+        let weight = reputations.weight;
+        let half_life = reputations.half_life_secs as i64;
+        let mut standing = Standing::new(0);
+        standing.score = -40.0;
+        standing.carried = -40.0;
+        reputations.standings.insert(peer, standing);
+
+        assert_eq!(reputations.refresh(peer, 0), Some(-40.0 / weight));
+        // Gossipsub forgot the old penalty, so the live score is ours alone
+        reputations.sample(peer, -40.0, 0);
+        assert_eq!(reputations.standings[&peer].score, -40.0);
+        assert_eq!(reputations.refresh(peer, half_life), Some(-20.0 / weight));
+    }
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use libp2p::StreamProtocol;
+use libp2p::request_response::{self, ProtocolSupport};
+use serde::{Deserialize, Serialize};
+
+use crate::common::MessageOp;
+use crate::storage::models::Message;
+
+use super::bytes;
+
+/// Protocol used to pull chat history from another peer.
+pub const SYNC_PROTOCOL: StreamProtocol = StreamProtocol::new("/rust-p2p-chat/sync/3.0.0");
+
+/// Maximum number of messages returned in a single sync response.
+pub const MAX_SYNC_MESSAGES: usize = 500;
+
+/// Encoded envelopes returned in a single sync response stop once they reach
+/// this many bytes, well below the 10 MB the codec accepts.
+pub const MAX_SYNC_BYTES: usize = 4 * 1024 * 1024;
+
+/// Most rooms one history request may ask for.
+pub const MAX_SYNC_ROOMS: usize = 32;
+
+/// Ask a peer for the messages in the given rooms from `since` on, oldest
+/// first (by timestamp, then id).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRequest {
+    pub since: i64,
+    /// Id of the last message of the previous page, sent at `since`: only
+    /// messages after it are returned. Without it `since` is inclusive.
+    #[serde(default)]
+    pub after_id: Option<String>,
+    /// Rooms the requester has joined; empty means the default room only.
+    /// Requests for more than [`MAX_SYNC_ROOMS`] are not served.
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// Ask only for this message and its replies instead (`since` is ignored).
+    #[serde(default)]
+    pub thread: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryResponse {
+    /// Messages as their authors signed them (encoded envelopes), so the
+    /// peer serving them cannot alter or invent any
+    #[serde(with = "bytes::list")]
+    pub messages: Vec<Vec<u8>>,
+    /// Signed edits/deletes made after `since` and up to the last message of
+    /// the page (or since then, on the last page), so late joiners see them too
+    #[serde(default)]
+    pub ops: Vec<MessageOp>,
+    /// The page is full: ask again after its last message
+    #[serde(default)]
+    pub more: bool,
+}
+
+/// Envelopes of `messages` (oldest first, at most [`MAX_SYNC_MESSAGES`]) that
+/// fit in one response, and the time of the last one served if more are left.
+pub fn sync_page(messages: Vec<Message>) -> (Vec<Vec<u8>>, Option<i64>) {
+    let mut full = messages.len() >= MAX_SYNC_MESSAGES;
+    let mut page = Vec::new();
+    let mut bytes = 0;
+    let mut last = 0;
+    for message in messages {
+        let Some(envelope) = message.envelope else {
+            continue;
+        };
+        if !page.is_empty() && bytes + envelope.len() > MAX_SYNC_BYTES {
+            full = true;
+            break;
+        }
+        bytes += envelope.len();
+        last = message.timestamp;
+        page.push(envelope);
+    }
+    (page, full.then_some(last))
+}
+
+pub type SyncBehaviour = request_response::cbor::Behaviour<HistoryRequest, HistoryResponse>;
+pub type SyncEvent = request_response::Event<HistoryRequest, HistoryResponse>;
+
+pub fn build_sync_behaviour() -> SyncBehaviour {
+    let config = request_response::Config::default().with_request_timeout(Duration::from_secs(30));
+    request_response::cbor::Behaviour::new([(SYNC_PROTOCOL, ProtocolSupport::Full)], config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(timestamp: i64, size: usize) -> Message {
+        Message {
+            id: timestamp.to_string(),
+            sender: "peer".to_string(),
+            content: String::new(),
+            timestamp,
+            room: "general".to_string(),
+            edited: false,
+            deleted: false,
+            created_at: timestamp,
+            reply_to: None,
+            attachment: None,
+            envelope: Some(vec![0; size]),
+        }
+    }
+
+    #[test]
+    fn page_stops_at_the_byte_limit() {
+        let size = MAX_SYNC_BYTES / 3;
+        let (page, end) = sync_page((1..=5).map(|t| message(t, size)).collect());
+        assert_eq!(page.len(), 3);
+        assert_eq!(end, Some(3));
+
+        let (page, end) = sync_page((1..=5).map(|t| message(t, 10)).collect());
+        assert_eq!(page.len(), 5);
+        assert_eq!(end, None);
+
+        let (page, end) = sync_page(
+            (1..=MAX_SYNC_MESSAGES as i64)
+                .map(|t| message(t, 10))
+                .collect(),
+        );
+        assert_eq!(page.len(), MAX_SYNC_MESSAGES);
+        assert_eq!(end, Some(MAX_SYNC_MESSAGES as i64));
+    }
+}
@@ -6,6 +6,7 @@ use libp2p::core::upgrade::Version;
 use libp2p::relay::client;
 use libp2p::{PeerId, Transport, identity, noise, tcp, yamux};
 
+#[allow(clippy::type_complexity)]
 pub fn build_transport(
     local_key: &identity::Keypair,
     local_peer_id: PeerId,
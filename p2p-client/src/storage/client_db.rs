@@ -1,8 +1,18 @@
-use rusqlite::{OptionalExtension, Result as SqlResult, params};
+use rusqlite::types::Value;
+use rusqlite::{Connection, OptionalExtension, Result as SqlResult, params, params_from_iter};
+use std::collections::HashMap;
 use std::path::Path;
 
 use super::database::Database;
-use super::models::{Identity, Message, Peer};
+use super::models::{FileDownload, Friend, FriendState, Message, PeerReputation, SharedFile};
+use crate::common::{
+    DEFAULT_ROOM, DeliveryStatus, FileAttachment, MessageOp, OpAction, PeerProfile, Reactions,
+};
+
+/// Settings key: whether read receipts are sent ("1"/"0", on by default)
+const SETTING_READ_RECEIPTS: &str = "read_receipts";
+/// Settings key: key our presence record is encrypted with (hex)
+const SETTING_PRESENCE_KEY: &str = "presence_key";
 
 /// Database for client mode (messages, peers, identity)
 pub struct ClientDatabase {
@@ -33,10 +43,232 @@ impl ClientDatabase {
                 sender TEXT NOT NULL,
                 content TEXT NOT NULL,
                 timestamp INTEGER NOT NULL,
+                room TEXT NOT NULL DEFAULT 'global',
+                edited INTEGER NOT NULL DEFAULT 0,
+                deleted INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            )",
+            [],
+        )?;
+        // Databases created by older versions lack these columns
+        ensure_column(&conn, "messages", "room", "TEXT NOT NULL DEFAULT 'global'")?;
+        ensure_column(&conn, "messages", "edited", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "messages", "deleted", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "messages", "reply_to", "TEXT")?;
+        ensure_column(&conn, "messages", "attachment", "TEXT")?;
+        ensure_column(&conn, "messages", "envelope", "BLOB")?;
+
+        // Joined chat rooms (the default room is always joined and not stored)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS rooms (
+                name TEXT PRIMARY KEY,
+                joined_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            )",
+            [],
+        )?;
+
+        // Delivery/read receipts: one row per (message, peer), status only moves forward.
+        // Rows with our own peer id record messages we have already marked as read.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_receipts (
+                message_id TEXT NOT NULL,
+                peer_id TEXT NOT NULL,
+                status TEXT NOT NULL,
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                PRIMARY KEY (message_id, peer_id)
+            )",
+            [],
+        )?;
+
+        // Key/value user settings
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // Direct messages table (kept apart from `messages` so history sync never serves them)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS direct_messages (
+                id TEXT PRIMARY KEY,
+                peer_id TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                content TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                edited INTEGER NOT NULL DEFAULT 0,
+                deleted INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                reply_to TEXT,
+                attachment TEXT
+            )",
+            [],
+        )?;
+        ensure_column(
+            &conn,
+            "direct_messages",
+            "edited",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        ensure_column(
+            &conn,
+            "direct_messages",
+            "deleted",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        ensure_column(&conn, "direct_messages", "reply_to", "TEXT")?;
+        ensure_column(&conn, "direct_messages", "attachment", "TEXT")?;
+
+        // Edit/delete operations, the winning one per message and author. Kept apart
+        // from the messages so an op that arrives before its message can be applied
+        // later; only the op of the message's sender is ever applied, so an op
+        // someone else signed for the same id cannot shadow it.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_ops (
+                target_id TEXT NOT NULL,
+                author TEXT NOT NULL,
+                room TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                action TEXT NOT NULL,
+                content TEXT,
+                signature BLOB NOT NULL,
+                PRIMARY KEY (target_id, author)
+            )",
+            [],
+        )?;
+
+        // Emoji reactions: one row per (message, peer, emoji). Removing a reaction
+        // keeps the row with `removed = 1`, so the newest add/remove wins whatever
+        // order they arrive in.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_reactions (
+                message_id TEXT NOT NULL,
+                peer_id TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                removed INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (message_id, peer_id, emoji)
+            )",
+            [],
+        )?;
+
+        // Files we offered to others, served chunk by chunk from their original path
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS shared_files (
+                file_id TEXT PRIMARY KEY,
+                path TEXT NOT NULL,
+                name TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                sha256 TEXT NOT NULL,
+                chunk_hashes BLOB NOT NULL,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            )",
+            [],
+        )?;
+
+        ensure_column(
+            &conn,
+            "shared_files",
+            "public",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+
+        // Files being downloaded; `received` is one byte per chunk (1 = verified and
+        // written), so an interrupted download resumes where it stopped
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS file_downloads (
+                file_id TEXT PRIMARY KEY,
+                peer_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                sha256 TEXT NOT NULL,
+                chunk_size INTEGER NOT NULL DEFAULT 0,
+                chunk_hashes BLOB,
+                received BLOB NOT NULL,
+                saved_path TEXT,
                 created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
             )",
             [],
         )?;
+        ensure_column(
+            &conn,
+            "file_downloads",
+            "public",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        ensure_column(
+            &conn,
+            "file_downloads",
+            "last_used",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+
+        // End-to-end ratchet sessions (one serialized state per friend)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS e2e_sessions (
+                peer_id TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            )",
+            [],
+        )?;
+
+        // Friends and pending friend requests (both directions)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS friends (
+                peer_id TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                note TEXT NOT NULL DEFAULT '',
+                presence_key BLOB,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            )",
+            [],
+        )?;
+        // Our own name and notes for a friend, never sent to anyone
+        ensure_column(&conn, "friends", "petname", "TEXT NOT NULL DEFAULT ''")?;
+        ensure_column(&conn, "friends", "annotation", "TEXT NOT NULL DEFAULT ''")?;
+        // The friend confirmed it has our current presence key
+        ensure_column(
+            &conn,
+            "friends",
+            "key_delivered",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+
+        // Peers the user blocked: no connections, no messages
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS blocked_peers (
+                peer_id TEXT PRIMARY KEY,
+                blocked_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            )",
+            [],
+        )?;
+
+        // Penalties of misbehaving peers, kept across restarts
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS peer_reputation (
+                peer_id TEXT PRIMARY KEY,
+                score REAL NOT NULL,
+                reputation REAL NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // Signed profiles: ours and the latest one seen from each peer
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS profiles (
+                peer_id TEXT PRIMARY KEY,
+                display_name TEXT NOT NULL,
+                status TEXT NOT NULL,
+                avatar BLOB NOT NULL,
+                updated_at INTEGER NOT NULL,
+                signature BLOB NOT NULL
+            )",
+            [],
+        )?;
 
         // Peers table
         conn.execute(
@@ -66,58 +298,136 @@ impl ClientDatabase {
             "CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp)",
             [],
         )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_messages_room ON messages(room, timestamp)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_message_ops_room ON message_ops(room, timestamp)",
+            [],
+        )?;
         conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_peers_last_seen ON peers(last_seen)",
             [],
         )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_direct_messages_peer ON direct_messages(peer_id, timestamp)",
+            [],
+        )?;
 
         Ok(())
     }
 
     // ========== Messages ==========
 
-    /// Insert a new message
-    pub fn insert_message(&self, message: &Message) -> SqlResult<()> {
+    /// Insert a new message. Returns `false` if a message with the same id
+    /// was already stored (gossip replay or history sync overlap). Room
+    /// message ids are content ids, so the same id is the same envelope.
+    pub fn insert_message(&self, message: &Message) -> SqlResult<bool> {
         let conn = self.db.connection();
-        conn.execute(
-            "INSERT OR IGNORE INTO messages (id, sender, content, timestamp, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO messages (id, sender, content, timestamp, room, edited, deleted, created_at, reply_to, attachment, envelope)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
             params![
                 message.id,
                 message.sender,
                 message.content,
                 message.timestamp,
-                message.created_at
+                message.room,
+                message.edited,
+                message.deleted,
+                message.created_at,
+                message.reply_to,
+                message
+                    .attachment
+                    .as_ref()
+                    .and_then(|attachment| serde_json::to_string(attachment).ok()),
+                message.envelope
             ],
         )?;
-        Ok(())
+        Ok(inserted > 0)
     }
 
-    /// Get messages with pagination
-    pub fn get_messages(
-        &self,
-        limit: Option<usize>,
-        offset: Option<usize>,
-    ) -> SqlResult<Vec<Message>> {
+    /// Get the most recent messages, oldest first
+    pub fn get_recent_messages(&self, limit: usize) -> SqlResult<Vec<Message>> {
         let conn = self.db.connection();
-        let limit = limit.unwrap_or(100);
-        let offset = offset.unwrap_or(0);
-
         let mut stmt = conn.prepare(
-            "SELECT id, sender, content, timestamp, created_at 
+            "SELECT id, sender, content, timestamp, room, edited, deleted, created_at, reply_to, attachment, envelope 
              FROM messages 
-             ORDER BY timestamp ASC 
-             LIMIT ?1 OFFSET ?2",
+             ORDER BY timestamp DESC 
+             LIMIT ?1",
         )?;
 
+        let mut messages = stmt
+            .query_map(params![limit], |row| {
+                Ok(Message {
+                    id: row.get(0)?,
+                    sender: row.get(1)?,
+                    content: row.get(2)?,
+                    timestamp: row.get(3)?,
+                    room: row.get(4)?,
+                    edited: row.get(5)?,
+                    deleted: row.get(6)?,
+                    created_at: row.get(7)?,
+                    reply_to: row.get(8)?,
+                    attachment: attachment_from_row(row, 9)?,
+                    envelope: row.get(10)?,
+                })
+            })?
+            .collect::<SqlResult<Vec<_>>>()?;
+
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Get up to `limit` messages with a signed envelope in the given rooms that
+    /// come after the `(timestamp, after_id)` cursor, ordered by timestamp then id.
+    /// An empty `after_id` includes every message sent at `timestamp`.
+    pub fn get_messages_after(
+        &self,
+        timestamp: i64,
+        after_id: &str,
+        rooms: &[String],
+        limit: usize,
+    ) -> SqlResult<Vec<Message>> {
+        if rooms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let conn = self.db.connection();
+        let placeholders = (0..rooms.len())
+            .map(|i| format!("?{}", i + 4))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, sender, content, timestamp, room, edited, deleted, created_at, reply_to, attachment, envelope 
+             FROM messages 
+             WHERE (timestamp > ?1 OR (timestamp = ?1 AND id > ?2)) AND room IN ({placeholders}) 
+               AND envelope IS NOT NULL 
+             ORDER BY timestamp ASC, id ASC 
+             LIMIT ?3"
+        ))?;
+
+        let values = [
+            Value::from(timestamp),
+            Value::from(after_id.to_string()),
+            Value::from(limit as i64),
+        ]
+        .into_iter()
+        .chain(rooms.iter().cloned().map(Value::from));
         let messages = stmt
-            .query_map(params![limit, offset], |row| {
+            .query_map(params_from_iter(values), |row| {
                 Ok(Message {
                     id: row.get(0)?,
                     sender: row.get(1)?,
                     content: row.get(2)?,
                     timestamp: row.get(3)?,
-                    created_at: row.get(4)?,
+                    room: row.get(4)?,
+                    edited: row.get(5)?,
+                    deleted: row.get(6)?,
+                    created_at: row.get(7)?,
+                    reply_to: row.get(8)?,
+                    attachment: attachment_from_row(row, 9)?,
+                    envelope: row.get(10)?,
                 })
             })?
             .collect::<SqlResult<Vec<_>>>()?;
@@ -125,24 +435,52 @@ impl ClientDatabase {
         Ok(messages)
     }
 
-    /// Get messages after a timestamp
-    pub fn get_messages_after(&self, timestamp: i64) -> SqlResult<Vec<Message>> {
+    /// A message and up to `limit - 1` replies to it, limited to the given rooms
+    /// and to messages with a signed envelope
+    pub fn get_thread(
+        &self,
+        parent_id: &str,
+        rooms: &[String],
+        limit: usize,
+    ) -> SqlResult<Vec<Message>> {
+        if rooms.is_empty() {
+            return Ok(Vec::new());
+        }
         let conn = self.db.connection();
-        let mut stmt = conn.prepare(
-            "SELECT id, sender, content, timestamp, created_at 
+        let placeholders = (0..rooms.len())
+            .map(|i| format!("?{}", i + 3))
+            .collect::<Vec<_>>()
+            .join(", ");
+        // The parent first, whatever its timestamp
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, sender, content, timestamp, room, edited, deleted, created_at, reply_to, attachment, envelope 
              FROM messages 
-             WHERE timestamp > ?1 
-             ORDER BY timestamp ASC",
-        )?;
+             WHERE (id = ?1 OR reply_to = ?1) AND room IN ({placeholders}) 
+               AND envelope IS NOT NULL 
+             ORDER BY id != ?1, timestamp ASC, id ASC 
+             LIMIT ?2"
+        ))?;
 
+        let values = [
+            Value::from(parent_id.to_string()),
+            Value::from(limit as i64),
+        ]
+        .into_iter()
+        .chain(rooms.iter().cloned().map(Value::from));
         let messages = stmt
-            .query_map(params![timestamp], |row| {
+            .query_map(params_from_iter(values), |row| {
                 Ok(Message {
                     id: row.get(0)?,
                     sender: row.get(1)?,
                     content: row.get(2)?,
                     timestamp: row.get(3)?,
-                    created_at: row.get(4)?,
+                    room: row.get(4)?,
+                    edited: row.get(5)?,
+                    deleted: row.get(6)?,
+                    created_at: row.get(7)?,
+                    reply_to: row.get(8)?,
+                    attachment: attachment_from_row(row, 9)?,
+                    envelope: row.get(10)?,
                 })
             })?
             .collect::<SqlResult<Vec<_>>>()?;
@@ -150,101 +488,1048 @@ impl ClientDatabase {
         Ok(messages)
     }
 
-    /// Get message count
-    pub fn message_count(&self) -> SqlResult<usize> {
+    /// Where to resume history sync of a room: the time of the newest stored
+    /// message, but never later than when we stored it, so a message dated in
+    /// the future cannot hide older ones (0 if empty)
+    pub fn sync_cursor(&self, room: &str) -> SqlResult<i64> {
         let conn = self.db.connection();
-        let count: i64 = conn.query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))?;
-        Ok(count as usize)
+        conn.query_row(
+            "SELECT COALESCE(MAX(MIN(timestamp, created_at)), 0) FROM messages WHERE room = ?1",
+            params![room],
+            |row| row.get(0),
+        )
     }
 
-    // ========== Peers ==========
+    // ========== Rooms ==========
 
-    /// Upsert a peer
-    pub fn upsert_peer(&self, peer: &Peer) -> SqlResult<()> {
+    /// Remember a joined room
+    pub fn insert_room(&self, name: &str) -> SqlResult<()> {
         let conn = self.db.connection();
         conn.execute(
-            "INSERT OR REPLACE INTO peers (peer_id, last_seen, first_seen, address, is_bootstrap)
-             VALUES (?1, ?2, COALESCE((SELECT first_seen FROM peers WHERE peer_id = ?1), strftime('%s', 'now')), ?3, ?4)",
+            "INSERT OR IGNORE INTO rooms (name) VALUES (?1)",
+            params![name],
+        )?;
+        Ok(())
+    }
+
+    /// Forget a room (its messages are kept)
+    pub fn remove_room(&self, name: &str) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute("DELETE FROM rooms WHERE name = ?1", params![name])?;
+        Ok(())
+    }
+
+    /// Get joined rooms, always starting with the default room
+    pub fn get_rooms(&self) -> SqlResult<Vec<String>> {
+        let conn = self.db.connection();
+        let mut stmt = conn.prepare("SELECT name FROM rooms ORDER BY joined_at ASC, name ASC")?;
+        let stored = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<SqlResult<Vec<_>>>()?;
+
+        let mut rooms = vec![DEFAULT_ROOM.to_string()];
+        rooms.extend(stored.into_iter().filter(|name| name != DEFAULT_ROOM));
+        Ok(rooms)
+    }
+
+    // ========== Direct messages ==========
+
+    /// Insert a direct message exchanged with `peer_id`. Returns `false` if it was already stored.
+    pub fn insert_direct_message(&self, peer_id: &str, message: &Message) -> SqlResult<bool> {
+        let conn = self.db.connection();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO direct_messages (id, peer_id, sender, content, timestamp, edited, deleted, created_at, reply_to, attachment)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
             params![
-                peer.peer_id,
-                peer.last_seen,
-                peer.address,
-                if peer.is_bootstrap { 1 } else { 0 }
+                message.id,
+                peer_id,
+                message.sender,
+                message.content,
+                message.timestamp,
+                message.edited,
+                message.deleted,
+                message.created_at,
+                message.reply_to,
+                message
+                    .attachment
+                    .as_ref()
+                    .and_then(|attachment| serde_json::to_string(attachment).ok())
             ],
         )?;
-        Ok(())
+        Ok(inserted > 0)
     }
 
-    /// Update peer last_seen
-    pub fn update_peer_last_seen(&self, peer_id: &str, last_seen: i64) -> SqlResult<()> {
+    /// Get the most recent direct messages across all conversations, oldest first
+    pub fn get_recent_direct_messages(&self, limit: usize) -> SqlResult<Vec<(String, Message)>> {
+        let conn = self.db.connection();
+        let mut stmt = conn.prepare(
+            "SELECT peer_id, id, sender, content, timestamp, edited, deleted, created_at, reply_to, attachment 
+             FROM direct_messages 
+             ORDER BY timestamp DESC 
+             LIMIT ?1",
+        )?;
+
+        let mut messages = stmt
+            .query_map(params![limit], |row| {
+                Ok((
+                    row.get(0)?,
+                    Message {
+                        id: row.get(1)?,
+                        sender: row.get(2)?,
+                        content: row.get(3)?,
+                        timestamp: row.get(4)?,
+                        room: String::new(),
+                        edited: row.get(5)?,
+                        deleted: row.get(6)?,
+                        created_at: row.get(7)?,
+                        reply_to: row.get(8)?,
+                        attachment: attachment_from_row(row, 9)?,
+                        envelope: None,
+                    },
+                ))
+            })?
+            .collect::<SqlResult<Vec<_>>>()?;
+
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Sender of a stored room or direct message
+    pub fn get_message_sender(&self, message_id: &str) -> SqlResult<Option<String>> {
+        let conn = self.db.connection();
+        conn.query_row(
+            "SELECT sender FROM messages WHERE id = ?1
+             UNION ALL
+             SELECT sender FROM direct_messages WHERE id = ?1
+             LIMIT 1",
+            params![message_id],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    // ========== Edit / delete ops ==========
+
+    /// Store an op if it wins over the one its author already has stored for the
+    /// message: a delete is final, otherwise the newest edit wins. Returns whether
+    /// it was stored.
+    pub fn save_op(&self, op: &MessageOp) -> SqlResult<bool> {
+        if let Some(current) = self.get_op(&op.target_id, &op.author)? {
+            let newer = match (&current.action, &op.action) {
+                (OpAction::Delete, _) => false,
+                (_, OpAction::Delete) => true,
+                _ => op.timestamp > current.timestamp,
+            };
+            if !newer {
+                return Ok(false);
+            }
+        }
+
+        let (action, content) = match &op.action {
+            OpAction::Edit { content } => ("edit", Some(content.as_str())),
+            OpAction::Delete => ("delete", None),
+        };
         let conn = self.db.connection();
         conn.execute(
-            "UPDATE peers SET last_seen = ?1 WHERE peer_id = ?2",
-            params![last_seen, peer_id],
+            "INSERT OR REPLACE INTO message_ops (target_id, author, room, timestamp, action, content, signature)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                op.target_id,
+                op.author,
+                op.room,
+                op.timestamp,
+                action,
+                content,
+                op.signature
+            ],
         )?;
-        Ok(())
+        Ok(true)
+    }
+
+    /// Get the op `author` stored for a message
+    pub fn get_op(&self, target_id: &str, author: &str) -> SqlResult<Option<MessageOp>> {
+        let conn = self.db.connection();
+        conn.query_row(
+            "SELECT target_id, author, room, timestamp, action, content, signature 
+             FROM message_ops 
+             WHERE target_id = ?1 AND author = ?2",
+            params![target_id, author],
+            op_from_row,
+        )
+        .optional()
     }
 
-    /// Get all peers
-    pub fn get_all_peers(&self) -> SqlResult<Vec<Peer>> {
+    /// Get up to `limit` ops newer than a timestamp (and no newer than `until`)
+    /// in the given rooms, oldest first
+    pub fn get_ops_after(
+        &self,
+        timestamp: i64,
+        until: Option<i64>,
+        rooms: &[String],
+        limit: usize,
+    ) -> SqlResult<Vec<MessageOp>> {
+        if rooms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let conn = self.db.connection();
+        let placeholders = (0..rooms.len())
+            .map(|i| format!("?{}", i + 4))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT target_id, author, room, timestamp, action, content, signature 
+             FROM message_ops 
+             WHERE timestamp > ?1 AND (?3 IS NULL OR timestamp <= ?3) AND room IN ({placeholders}) 
+             ORDER BY timestamp ASC 
+             LIMIT ?2"
+        ))?;
+
+        let values = [
+            Value::from(timestamp),
+            Value::from(limit as i64),
+            Value::from(until),
+        ]
+        .into_iter()
+        .chain(rooms.iter().cloned().map(Value::from));
+        let ops = stmt
+            .query_map(params_from_iter(values), op_from_row)?
+            .collect::<SqlResult<Vec<_>>>()?;
+
+        Ok(ops)
+    }
+
+    /// Apply an op to the stored message it targets, in either message table.
+    /// Only messages written by `op.author` are touched. Returns whether a message changed.
+    pub fn apply_op(&self, op: &MessageOp) -> SqlResult<bool> {
+        let conn = self.db.connection();
+        let mut changed = 0;
+        for table in ["messages", "direct_messages"] {
+            changed += match &op.action {
+                OpAction::Edit { content } => conn.execute(
+                    &format!(
+                        "UPDATE {table} SET content = ?1, edited = 1 
+                         WHERE id = ?2 AND sender = ?3 AND deleted = 0"
+                    ),
+                    params![content, op.target_id, op.author],
+                )?,
+                OpAction::Delete => conn.execute(
+                    &format!(
+                        "UPDATE {table} SET content = '', deleted = 1 
+                         WHERE id = ?1 AND sender = ?2"
+                    ),
+                    params![op.target_id, op.author],
+                )?,
+            };
+        }
+        Ok(changed > 0)
+    }
+
+    // ========== Receipts ==========
+
+    /// Record a receipt from `peer_id`. Returns `false` if it does not advance
+    /// the stored status (duplicate, or "delivered" after "read").
+    pub fn record_receipt(
+        &self,
+        message_id: &str,
+        peer_id: &str,
+        status: DeliveryStatus,
+    ) -> SqlResult<bool> {
+        let conn = self.db.connection();
+        let current: Option<String> = conn
+            .query_row(
+                "SELECT status FROM message_receipts WHERE message_id = ?1 AND peer_id = ?2",
+                params![message_id, peer_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if current
+            .as_deref()
+            .and_then(DeliveryStatus::parse)
+            .is_some_and(|current| current >= status)
+        {
+            return Ok(false);
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO message_receipts (message_id, peer_id, status, updated_at)
+             VALUES (?1, ?2, ?3, strftime('%s', 'now'))",
+            params![message_id, peer_id, status.as_str()],
+        )?;
+        Ok(true)
+    }
+
+    /// Best status reported by any peer for each message sent by `sender`
+    pub fn get_delivery_statuses(
+        &self,
+        sender: &str,
+    ) -> SqlResult<HashMap<String, DeliveryStatus>> {
         let conn = self.db.connection();
         let mut stmt = conn.prepare(
-            "SELECT peer_id, last_seen, first_seen, address, is_bootstrap 
-             FROM peers 
-             ORDER BY last_seen DESC",
+            "SELECT r.message_id, r.status 
+             FROM message_receipts r 
+             WHERE r.peer_id != ?1 AND (
+                 r.message_id IN (SELECT id FROM messages WHERE sender = ?1)
+                 OR r.message_id IN (SELECT id FROM direct_messages WHERE sender = ?1)
+             )",
+        )?;
+
+        let rows = stmt
+            .query_map(params![sender], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<SqlResult<Vec<_>>>()?;
+
+        let mut best: HashMap<String, DeliveryStatus> = HashMap::new();
+        for (message_id, status) in rows {
+            if let Some(status) = DeliveryStatus::parse(&status) {
+                let entry = best.entry(message_id).or_insert(status);
+                *entry = (*entry).max(status);
+            }
+        }
+        Ok(best)
+    }
+
+    // ========== Reactions ==========
+
+    /// Record that `peer_id` added (or removed) `emoji` on a message at `timestamp`.
+    /// Returns `false` if a newer add/remove from the same peer is already stored.
+    pub fn set_reaction(
+        &self,
+        message_id: &str,
+        peer_id: &str,
+        emoji: &str,
+        timestamp: i64,
+        removed: bool,
+    ) -> SqlResult<bool> {
+        let conn = self.db.connection();
+        let changed = conn.execute(
+            "INSERT INTO message_reactions (message_id, peer_id, emoji, timestamp, removed)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (message_id, peer_id, emoji) DO UPDATE
+             SET timestamp = excluded.timestamp, removed = excluded.removed
+             WHERE excluded.timestamp >= message_reactions.timestamp
+               AND excluded.removed != message_reactions.removed",
+            params![message_id, peer_id, emoji, timestamp, removed as i32],
         )?;
+        Ok(changed > 0)
+    }
 
-        let peers = stmt
+    /// Current reactions on one message
+    pub fn get_reactions(&self, message_id: &str) -> SqlResult<Reactions> {
+        let conn = self.db.connection();
+        let mut stmt = conn.prepare(
+            "SELECT emoji, peer_id FROM message_reactions 
+             WHERE message_id = ?1 AND removed = 0 
+             ORDER BY timestamp ASC",
+        )?;
+        let rows = stmt
+            .query_map(params![message_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<SqlResult<Vec<_>>>()?;
+
+        let mut reactions = Reactions::new();
+        for (emoji, peer_id) in rows {
+            reactions.entry(emoji).or_default().push(peer_id);
+        }
+        Ok(reactions)
+    }
+
+    /// Current reactions on every message that has any
+    pub fn get_all_reactions(&self) -> SqlResult<HashMap<String, Reactions>> {
+        let conn = self.db.connection();
+        let mut stmt = conn.prepare(
+            "SELECT message_id, emoji, peer_id FROM message_reactions 
+             WHERE removed = 0 
+             ORDER BY timestamp ASC",
+        )?;
+        let rows = stmt
             .query_map([], |row| {
-                Ok(Peer {
-                    peer_id: row.get(0)?,
-                    last_seen: row.get(1)?,
-                    first_seen: row.get(2)?,
-                    address: row.get(3)?,
-                    is_bootstrap: row.get::<_, i64>(4)? != 0,
-                })
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
             })?
             .collect::<SqlResult<Vec<_>>>()?;
 
-        Ok(peers)
+        let mut all: HashMap<String, Reactions> = HashMap::new();
+        for (message_id, emoji, peer_id) in rows {
+            all.entry(message_id)
+                .or_default()
+                .entry(emoji)
+                .or_default()
+                .push(peer_id);
+        }
+        Ok(all)
+    }
+
+    // ========== Files ==========
+
+    /// Remember a shared file. A file once shared in a room stays public.
+    pub fn save_shared_file(&self, file: &SharedFile) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "INSERT INTO shared_files (file_id, path, name, size, sha256, chunk_hashes, public)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (file_id) DO UPDATE
+             SET path = excluded.path, name = excluded.name,
+                 public = MAX(shared_files.public, excluded.public)",
+            params![
+                file.attachment.file_id,
+                file.path,
+                file.attachment.name,
+                file.attachment.size as i64,
+                file.attachment.sha256,
+                file.chunk_hashes,
+                file.public
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_shared_file(&self, file_id: &str) -> SqlResult<Option<SharedFile>> {
+        let conn = self.db.connection();
+        conn.query_row(
+            "SELECT file_id, path, name, size, sha256, chunk_hashes, public FROM shared_files WHERE file_id = ?1",
+            params![file_id],
+            shared_file_from_row,
+        )
+        .optional()
     }
 
-    /// Remove a peer
-    pub fn remove_peer(&self, peer_id: &str) -> SqlResult<()> {
+    pub fn get_shared_files(&self) -> SqlResult<Vec<SharedFile>> {
         let conn = self.db.connection();
-        conn.execute("DELETE FROM peers WHERE peer_id = ?1", params![peer_id])?;
+        let mut stmt = conn.prepare(
+            "SELECT file_id, path, name, size, sha256, chunk_hashes, public FROM shared_files",
+        )?;
+        stmt.query_map([], shared_file_from_row)?
+            .collect::<SqlResult<Vec<_>>>()
+    }
+
+    /// Create or update the stored progress of a download
+    pub fn save_download(&self, download: &FileDownload) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "INSERT INTO file_downloads (file_id, peer_id, name, size, sha256, chunk_size, chunk_hashes, received, saved_path, public, last_used)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+             ON CONFLICT (file_id) DO UPDATE
+             SET peer_id = excluded.peer_id, chunk_size = excluded.chunk_size,
+                 chunk_hashes = excluded.chunk_hashes, received = excluded.received,
+                 saved_path = excluded.saved_path, public = excluded.public,
+                 last_used = excluded.last_used",
+            params![
+                download.attachment.file_id,
+                download.peer_id,
+                download.attachment.name,
+                download.attachment.size as i64,
+                download.attachment.sha256,
+                download.chunk_size,
+                download.chunk_hashes,
+                download.received,
+                download.saved_path,
+                download.public,
+                download.last_used
+            ],
+        )?;
         Ok(())
     }
 
-    // ========== Identity ==========
+    pub fn get_download(&self, file_id: &str) -> SqlResult<Option<FileDownload>> {
+        let conn = self.db.connection();
+        conn.query_row(
+            "SELECT file_id, peer_id, name, size, sha256, chunk_size, chunk_hashes, received, saved_path, public, last_used 
+             FROM file_downloads WHERE file_id = ?1",
+            params![file_id],
+            download_from_row,
+        )
+        .optional()
+    }
 
-    /// Save identity (replace if exists)
-    pub fn save_identity(&self, identity: &Identity) -> SqlResult<()> {
+    /// All downloads, finished ones included
+    pub fn get_downloads(&self) -> SqlResult<Vec<FileDownload>> {
+        let conn = self.db.connection();
+        let mut stmt = conn.prepare(
+            "SELECT file_id, peer_id, name, size, sha256, chunk_size, chunk_hashes, received, saved_path, public, last_used 
+             FROM file_downloads 
+             ORDER BY created_at ASC",
+        )?;
+        stmt.query_map([], download_from_row)?
+            .collect::<SqlResult<Vec<_>>>()
+    }
+
+    /// Mark a cached blob as used, for least-recently-used eviction
+    pub fn touch_download(&self, file_id: &str, timestamp: i64) -> SqlResult<()> {
         let conn = self.db.connection();
         conn.execute(
-            "INSERT OR REPLACE INTO identity (id, peer_id, keypair_encrypted, created_at)
-             VALUES (1, ?1, ?2, COALESCE((SELECT created_at FROM identity WHERE id = 1), strftime('%s', 'now')))",
-            params![identity.peer_id, identity.keypair_encrypted],
+            "UPDATE file_downloads SET last_used = ?2 WHERE file_id = ?1",
+            params![file_id, timestamp],
         )?;
         Ok(())
     }
 
-    /// Get identity
-    pub fn get_identity(&self) -> SqlResult<Option<Identity>> {
+    // ========== Settings ==========
+
+    pub fn read_receipts_enabled(&self) -> SqlResult<bool> {
+        Ok(self
+            .get_setting(SETTING_READ_RECEIPTS)?
+            .is_none_or(|value| value == "1"))
+    }
+
+    pub fn set_read_receipts_enabled(&self, enabled: bool) -> SqlResult<()> {
+        self.set_setting(SETTING_READ_RECEIPTS, if enabled { "1" } else { "0" })
+    }
+
+    fn get_setting(&self, key: &str) -> SqlResult<Option<String>> {
         let conn = self.db.connection();
-        let mut stmt = conn
-            .prepare("SELECT peer_id, keypair_encrypted, created_at FROM identity WHERE id = 1")?;
+        conn.query_row(
+            "SELECT value FROM settings WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )
+        .optional()
+    }
 
-        let identity = stmt
-            .query_row([], |row| {
-                Ok(Identity {
-                    peer_id: row.get(0)?,
-                    keypair_encrypted: row.get(1)?,
-                    created_at: row.get(2)?,
-                })
+    fn set_setting(&self, key: &str, value: &str) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_presence_key(&self) -> SqlResult<Option<Vec<u8>>> {
+        Ok(self
+            .get_setting(SETTING_PRESENCE_KEY)?
+            .and_then(|value| hex::decode(value).ok()))
+    }
+
+    pub fn set_presence_key(&self, key: &[u8]) -> SqlResult<()> {
+        self.set_setting(SETTING_PRESENCE_KEY, &hex::encode(key))
+    }
+
+    // ========== Friends ==========
+
+    /// Friends and pending requests, oldest first
+    pub fn get_friends(&self) -> SqlResult<Vec<Friend>> {
+        let conn = self.db.connection();
+        let mut stmt = conn.prepare(
+            "SELECT peer_id, state, note, presence_key, updated_at, petname, annotation, key_delivered 
+             FROM friends 
+             ORDER BY created_at ASC",
+        )?;
+        stmt.query_map([], friend_from_row)?
+            .filter_map(|friend| friend.transpose())
+            .collect::<SqlResult<Vec<_>>>()
+    }
+
+    pub fn get_friend(&self, peer_id: &str) -> SqlResult<Option<Friend>> {
+        let conn = self.db.connection();
+        conn.query_row(
+            "SELECT peer_id, state, note, presence_key, updated_at, petname, annotation, key_delivered 
+             FROM friends WHERE peer_id = ?1",
+            params![peer_id],
+            friend_from_row,
+        )
+        .optional()
+        .map(Option::flatten)
+    }
+
+    /// Create or update a friend. A known presence key is kept when the new
+    /// value has none; the petname and annotation only change through
+    /// [`Self::set_friend_names`].
+    pub fn save_friend(&self, friend: &Friend) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "INSERT INTO friends (peer_id, state, note, presence_key, updated_at, key_delivered)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (peer_id) DO UPDATE
+             SET state = excluded.state, note = excluded.note,
+                 presence_key = COALESCE(excluded.presence_key, friends.presence_key),
+                 updated_at = excluded.updated_at,
+                 key_delivered = excluded.key_delivered",
+            params![
+                friend.peer_id,
+                friend.state.as_str(),
+                friend.note,
+                friend.presence_key,
+                friend.updated_at,
+                friend.key_delivered
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_friend_names(
+        &self,
+        peer_id: &str,
+        petname: &str,
+        annotation: &str,
+    ) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "UPDATE friends SET petname = ?2, annotation = ?3 WHERE peer_id = ?1",
+            params![peer_id, petname, annotation],
+        )?;
+        Ok(())
+    }
+
+    /// Our presence key changed: every friend has to receive the new one
+    pub fn reset_friend_keys_delivered(&self) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute("UPDATE friends SET key_delivered = 0", [])?;
+        Ok(())
+    }
+
+    pub fn remove_friend(&self, peer_id: &str) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute("DELETE FROM friends WHERE peer_id = ?1", params![peer_id])?;
+        Ok(())
+    }
+
+    // ========== Blocked peers ==========
+
+    pub fn get_blocked_peers(&self) -> SqlResult<Vec<String>> {
+        let conn = self.db.connection();
+        let mut stmt = conn.prepare("SELECT peer_id FROM blocked_peers ORDER BY blocked_at ASC")?;
+        stmt.query_map([], |row| row.get::<_, String>(0))?.collect()
+    }
+
+    pub fn block_peer(&self, peer_id: &str) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "INSERT OR IGNORE INTO blocked_peers (peer_id) VALUES (?1)",
+            params![peer_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn unblock_peer(&self, peer_id: &str) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "DELETE FROM blocked_peers WHERE peer_id = ?1",
+            params![peer_id],
+        )?;
+        Ok(())
+    }
+
+    // ========== Peer reputation ==========
+
+    pub fn get_peer_reputations(&self) -> SqlResult<Vec<PeerReputation>> {
+        let conn = self.db.connection();
+        let mut stmt = conn.prepare(
+            "SELECT peer_id, score, reputation, updated_at 
+             FROM peer_reputation",
+        )?;
+        stmt.query_map([], |row| {
+            Ok(PeerReputation {
+                peer_id: row.get(0)?,
+                score: row.get(1)?,
+                reputation: row.get(2)?,
+                updated_at: row.get(3)?,
             })
-            .optional()?;
+        })?
+        .collect()
+    }
+
+    pub fn save_peer_reputation(&self, entry: &PeerReputation) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "INSERT OR REPLACE INTO peer_reputation (peer_id, score, reputation, updated_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                entry.peer_id,
+                entry.score,
+                entry.reputation,
+                entry.updated_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_peer_reputation(&self, peer_id: &str) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "DELETE FROM peer_reputation WHERE peer_id = ?1",
+            params![peer_id],
+        )?;
+        Ok(())
+    }
+
+    // ========== Profiles ==========
+
+    pub fn get_profiles(&self) -> SqlResult<Vec<PeerProfile>> {
+        let conn = self.db.connection();
+        let mut stmt = conn.prepare(
+            "SELECT peer_id, display_name, status, avatar, updated_at, signature 
+             FROM profiles",
+        )?;
+        stmt.query_map([], profile_from_row)?.collect()
+    }
+
+    /// Store a profile unless a newer one is already known
+    pub fn save_profile(&self, profile: &PeerProfile) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "INSERT INTO profiles (peer_id, display_name, status, avatar, updated_at, signature)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (peer_id) DO UPDATE
+             SET display_name = excluded.display_name, status = excluded.status,
+                 avatar = excluded.avatar, updated_at = excluded.updated_at,
+                 signature = excluded.signature
+             WHERE excluded.updated_at > profiles.updated_at",
+            params![
+                profile.peer_id,
+                profile.display_name,
+                profile.status,
+                profile.avatar,
+                profile.updated_at,
+                profile.signature
+            ],
+        )?;
+        Ok(())
+    }
+
+    // ========== E2E sessions ==========
+
+    /// Save the serialized ratchet session for a peer
+    pub fn save_e2e_session(&self, peer_id: &str, state: &str) -> SqlResult<()> {
+        let conn = self.db.connection();
+        conn.execute(
+            "INSERT OR REPLACE INTO e2e_sessions (peer_id, state, updated_at)
+             VALUES (?1, ?2, strftime('%s', 'now'))",
+            params![peer_id, state],
+        )?;
+        Ok(())
+    }
+
+    /// Get the serialized ratchet session for a peer
+    pub fn get_e2e_session(&self, peer_id: &str) -> SqlResult<Option<String>> {
+        let conn = self.db.connection();
+        conn.query_row(
+            "SELECT state FROM e2e_sessions WHERE peer_id = ?1",
+            params![peer_id],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+}
+
+/// Add a column to an existing table if it is missing (schema upgrade for old databases)
+fn ensure_column(conn: &Connection, table: &str, column: &str, definition: &str) -> SqlResult<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<SqlResult<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// Attachment stored as JSON in column `index` (NULL or unreadable -> none)
+fn attachment_from_row(row: &rusqlite::Row<'_>, index: usize) -> SqlResult<Option<FileAttachment>> {
+    let json: Option<String> = row.get(index)?;
+    Ok(json.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+fn download_from_row(row: &rusqlite::Row<'_>) -> SqlResult<FileDownload> {
+    Ok(FileDownload {
+        attachment: FileAttachment {
+            file_id: row.get(0)?,
+            name: row.get(2)?,
+            size: row.get::<_, i64>(3)? as u64,
+            sha256: row.get(4)?,
+        },
+        peer_id: row.get(1)?,
+        chunk_size: row.get(5)?,
+        chunk_hashes: row.get(6)?,
+        received: row.get(7)?,
+        saved_path: row.get(8)?,
+        public: row.get(9)?,
+        last_used: row.get(10)?,
+    })
+}
+
+fn shared_file_from_row(row: &rusqlite::Row<'_>) -> SqlResult<SharedFile> {
+    Ok(SharedFile {
+        attachment: FileAttachment {
+            file_id: row.get(0)?,
+            name: row.get(2)?,
+            size: row.get::<_, i64>(3)? as u64,
+            sha256: row.get(4)?,
+        },
+        path: row.get(1)?,
+        chunk_hashes: row.get(5)?,
+        public: row.get(6)?,
+    })
+}
+
+fn profile_from_row(row: &rusqlite::Row<'_>) -> SqlResult<PeerProfile> {
+    Ok(PeerProfile {
+        peer_id: row.get(0)?,
+        display_name: row.get(1)?,
+        status: row.get(2)?,
+        avatar: row.get(3)?,
+        updated_at: row.get(4)?,
+        signature: row.get(5)?,
+    })
+}
+
+/// A row with an unknown state (from a newer version) reads as none
+fn friend_from_row(row: &rusqlite::Row<'_>) -> SqlResult<Option<Friend>> {
+    let state: String = row.get(1)?;
+    let Some(state) = FriendState::parse(&state) else {
+        return Ok(None);
+    };
+    Ok(Some(Friend {
+        peer_id: row.get(0)?,
+        state,
+        note: row.get(2)?,
+        presence_key: row.get(3)?,
+        updated_at: row.get(4)?,
+        petname: row.get(5)?,
+        annotation: row.get(6)?,
+        key_delivered: row.get(7)?,
+    }))
+}
+
+fn op_from_row(row: &rusqlite::Row<'_>) -> SqlResult<MessageOp> {
+    let action: String = row.get(4)?;
+    let action = match action.as_str() {
+        "delete" => OpAction::Delete,
+        _ => OpAction::Edit {
+            content: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+        },
+    };
+    Ok(MessageOp {
+        target_id: row.get(0)?,
+        author: row.get(1)?,
+        room: row.get(2)?,
+        timestamp: row.get(3)?,
+        action,
+        signature: row.get(6)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, timestamp: i64) -> Message {
+        Message {
+            id: id.to_string(),
+            sender: "peer".to_string(),
+            content: id.to_string(),
+            timestamp,
+            room: DEFAULT_ROOM.to_string(),
+            edited: false,
+            deleted: false,
+            created_at: timestamp,
+            reply_to: None,
+            attachment: None,
+            envelope: Some(id.as_bytes().to_vec()),
+        }
+    }
+
+    #[test]
+    fn sync_cursor_is_per_room_and_ignores_future_dates() {
+        let db = ClientDatabase::with_path(":memory:").unwrap();
+        db.insert_message(&message("a", 10)).unwrap();
+        db.insert_message(&Message {
+            timestamp: 1_000_000,
+            ..message("b", 20)
+        })
+        .unwrap();
+        db.insert_message(&Message {
+            room: "quiet".to_string(),
+            ..message("c", 5)
+        })
+        .unwrap();
+
+        assert_eq!(db.sync_cursor(DEFAULT_ROOM).unwrap(), 20);
+        assert_eq!(db.sync_cursor("quiet").unwrap(), 5);
+        assert_eq!(db.sync_cursor("empty").unwrap(), 0);
+    }
+
+    #[test]
+    fn history_pages_do_not_skip_messages_sharing_a_second() {
+        let db = ClientDatabase::with_path(":memory:").unwrap();
+        for (id, timestamp) in [("a", 10), ("b", 20), ("c", 20), ("d", 20), ("e", 30)] {
+            db.insert_message(&message(id, timestamp)).unwrap();
+        }
+        let rooms = [DEFAULT_ROOM.to_string()];
+
+        let mut seen = Vec::new();
+        let (mut since, mut after_id) = (0, String::new());
+        loop {
+            let page = db.get_messages_after(since, &after_id, &rooms, 2).unwrap();
+            seen.extend(page.iter().map(|message| message.id.clone()));
+            match page.last() {
+                Some(last) if page.len() == 2 => {
+                    since = last.timestamp;
+                    after_id = last.id.clone();
+                }
+                _ => break,
+            }
+        }
+        assert_eq!(seen, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn history_without_cursor_id_includes_its_second() {
+        let db = ClientDatabase::with_path(":memory:").unwrap();
+        for (id, timestamp) in [("a", 10), ("b", 20), ("c", 30)] {
+            db.insert_message(&message(id, timestamp)).unwrap();
+        }
+        let rooms = [DEFAULT_ROOM.to_string()];
+        let ids: Vec<String> = db
+            .get_messages_after(20, "", &rooms, 10)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.id)
+            .collect();
+        assert_eq!(ids, ["b", "c"]);
+    }
+
+    #[test]
+    fn messages_without_envelope_are_not_served() {
+        let db = ClientDatabase::with_path(":memory:").unwrap();
+        db.insert_message(&message("a", 10)).unwrap();
+        let unsigned = Message {
+            envelope: None,
+            ..message("b", 20)
+        };
+        db.insert_message(&unsigned).unwrap();
+        let rooms = [DEFAULT_ROOM.to_string()];
+        let ids: Vec<String> = db
+            .get_messages_after(0, "", &rooms, 10)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.id)
+            .collect();
+        assert_eq!(ids, ["a"]);
+    }
+
+    fn op(author: &str, timestamp: i64, action: OpAction) -> MessageOp {
+        MessageOp {
+            target_id: "a".to_string(),
+            author: author.to_string(),
+            room: DEFAULT_ROOM.to_string(),
+            timestamp,
+            action,
+            signature: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn delete_by_another_peer_does_not_shadow_the_senders_ops() {
+        let db = ClientDatabase::with_path(":memory:").unwrap();
+        db.insert_message(&message("a", 10)).unwrap();
+
+        assert!(db.save_op(&op("mallory", 20, OpAction::Delete)).unwrap());
+        let edit = OpAction::Edit {
+            content: "fixed".to_string(),
+        };
+        assert!(db.save_op(&op("peer", 30, edit)).unwrap());
+
+        let stored = db.get_op("a", "peer").unwrap().unwrap();
+        assert!(matches!(stored.action, OpAction::Edit { .. }));
+
+        // The sender's own delete is still final
+        assert!(db.save_op(&op("peer", 40, OpAction::Delete)).unwrap());
+        let edit = OpAction::Edit {
+            content: "again".to_string(),
+        };
+        assert!(!db.save_op(&op("peer", 50, edit)).unwrap());
+    }
+
+    #[test]
+    fn ops_are_served_once_across_pages() {
+        let db = ClientDatabase::with_path(":memory:").unwrap();
+        db.insert_message(&message("a", 10)).unwrap();
+        for (author, timestamp) in [("peer", 20), ("other", 30)] {
+            db.save_op(&op(author, timestamp, OpAction::Delete))
+                .unwrap();
+        }
+        let rooms = [DEFAULT_ROOM.to_string()];
+        let timestamps = |until| {
+            db.get_ops_after(10, until, &rooms, 10)
+                .unwrap()
+                .iter()
+                .map(|op| op.timestamp)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(timestamps(Some(20)), [20]);
+        assert_eq!(timestamps(None), [20, 30]);
+        assert_eq!(
+            db.get_ops_after(20, None, &rooms, 10).unwrap()[0].timestamp,
+            30
+        );
+    }
+
+    #[test]
+    fn reactions_apply_in_timestamp_order() {
+        let db = ClientDatabase::with_path(":memory:").unwrap();
+        let react = |timestamp, removed| {
+            db.set_reaction("message", "peer", "👍", timestamp, removed)
+                .unwrap()
+        };
+        assert!(react(10, false));
+        // A removal older than the add arrives late
+        assert!(!react(5, true));
+        assert!(!react(10, false));
+        assert_eq!(db.get_reactions("message").unwrap()["👍"], vec!["peer"]);
+
+        assert!(react(20, true));
+        // The add it replaced arrives again
+        assert!(!react(15, false));
+        assert!(db.get_reactions("message").unwrap().is_empty());
+        assert!(db.get_all_reactions().unwrap().is_empty());
+
+        assert!(react(30, false));
+        assert_eq!(
+            db.get_all_reactions().unwrap()["message"]["👍"],
+            vec!["peer"]
+        );
+    }
+
+    #[test]
+    fn direct_messages_are_stored_once_and_oldest_first() {
+        let db = ClientDatabase::with_path(":memory:").unwrap();
+        assert!(
+            db.insert_direct_message("alice", &message("a2", 20))
+                .unwrap()
+        );
+        assert!(db.insert_direct_message("bob", &message("b1", 15)).unwrap());
+        assert!(
+            db.insert_direct_message("alice", &message("a1", 10))
+                .unwrap()
+        );
+        // Retried delivery of a message we already have
+        assert!(
+            !db.insert_direct_message("alice", &message("a1", 10))
+                .unwrap()
+        );
 
-        Ok(identity)
+        let recent: Vec<(String, String)> = db
+            .get_recent_direct_messages(2)
+            .unwrap()
+            .into_iter()
+            .map(|(peer_id, message)| (peer_id, message.id))
+            .collect();
+        assert_eq!(
+            recent,
+            vec![
+                ("bob".to_string(), "b1".to_string()),
+                ("alice".to_string(), "a2".to_string())
+            ]
+        );
     }
 }
@@ -1,27 +1,26 @@
 use rusqlite::{Connection, Result as SqlResult};
 use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
 
 /// Base database connection wrapper
+///
+/// The connection sits behind a mutex so the database can be shared with
+/// async tasks that require `Sync` state.
 pub struct Database {
-    conn: Connection,
+    conn: Mutex<Connection>,
 }
 
 impl Database {
     pub fn new<P: AsRef<Path>>(path: P) -> SqlResult<Self> {
         let conn = Connection::open(path)?;
-        Ok(Self { conn })
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
     }
 
-    pub fn in_memory() -> SqlResult<Self> {
-        let conn = Connection::open_in_memory()?;
-        Ok(Self { conn })
-    }
-
-    pub fn connection(&self) -> &Connection {
-        &self.conn
-    }
-
-    pub fn connection_mut(&mut self) -> &mut Connection {
-        &mut self.conn
+    pub fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.conn
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
     }
 }
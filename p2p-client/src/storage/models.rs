@@ -1,13 +1,6 @@
-use serde::{Deserialize, Serialize};
+use chrono::Utc;
 
-/// Bootstrap node entry (for server mode)
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BootstrapNode {
-    pub address: String,
-    pub peer_id: Option<String>,
-    pub added_at: i64,
-    pub last_verified: Option<i64>,
-}
+use crate::common::{ChatMessage, FileAttachment};
 
 /// Chat message (for client mode)
 #[derive(Debug, Clone)]
@@ -16,23 +9,157 @@ pub struct Message {
     pub sender: String,
     pub content: String,
     pub timestamp: i64,
+    pub room: String,
+    pub edited: bool,
+    pub deleted: bool,
     pub created_at: i64,
+    pub reply_to: Option<String>,
+    pub attachment: Option<FileAttachment>,
+    /// Signed wire envelope a room message arrived in; only messages that
+    /// have one are served to peers syncing history
+    pub envelope: Option<Vec<u8>>,
+}
+
+impl From<Message> for ChatMessage {
+    fn from(message: Message) -> Self {
+        Self {
+            id: message.id,
+            sender: message.sender,
+            content: message.content,
+            timestamp: message.timestamp,
+            room: message.room,
+            edited: message.edited,
+            deleted: message.deleted,
+            reply_to: message.reply_to,
+            attachment: message.attachment,
+        }
+    }
+}
+
+impl From<&ChatMessage> for Message {
+    fn from(message: &ChatMessage) -> Self {
+        Self {
+            id: message.id.clone(),
+            sender: message.sender.clone(),
+            content: message.content.clone(),
+            timestamp: message.timestamp,
+            room: message.room.clone(),
+            edited: message.edited,
+            deleted: message.deleted,
+            created_at: Utc::now().timestamp(),
+            reply_to: message.reply_to.clone(),
+            attachment: message.attachment.clone(),
+            envelope: None,
+        }
+    }
+}
+
+/// A file we offered, served from `path` (for client mode)
+#[derive(Debug, Clone)]
+pub struct SharedFile {
+    pub attachment: FileAttachment,
+    pub path: String,
+    /// SHA-256 of every chunk, concatenated
+    pub chunk_hashes: Vec<u8>,
+    /// Shared in a room: announced as a provider record in the DHT
+    pub public: bool,
+}
+
+/// Progress of a file download (for client mode)
+#[derive(Debug, Clone)]
+pub struct FileDownload {
+    pub attachment: FileAttachment,
+    /// Peer serving the file
+    pub peer_id: String,
+    /// Chunk size and hashes once the manifest has been fetched and verified
+    pub chunk_size: u32,
+    pub chunk_hashes: Option<Vec<u8>>,
+    /// One byte per chunk, 1 once the chunk is verified and written
+    pub received: Vec<u8>,
+    /// Where the finished file was saved
+    pub saved_path: Option<String>,
+    /// Shared in a room: the blob is cached, served to others and announced
+    pub public: bool,
+    /// Last time the cached blob was saved or served (unix seconds)
+    pub last_used: i64,
+}
+
+impl FileDownload {
+    /// Every chunk is verified and written
+    pub fn is_complete(&self) -> bool {
+        self.chunk_hashes.is_some() && self.received.iter().all(|&done| done == 1)
+    }
+
+    pub fn has_chunk(&self, index: u32) -> bool {
+        self.received.get(index as usize) == Some(&1)
+    }
+
+    /// Bytes verified and written so far
+    pub fn received_bytes(&self) -> u64 {
+        if self.saved_path.is_some() {
+            return self.attachment.size;
+        }
+        let chunks = self.received.iter().filter(|&&done| done == 1).count() as u64;
+        (chunks * self.chunk_size as u64).min(self.attachment.size)
+    }
 }
 
-/// Known peer (for client mode)
+/// Where a friendship stands (for client mode)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FriendState {
+    /// We asked, waiting for their answer
+    Outgoing,
+    /// They asked, waiting for the user's answer
+    Incoming,
+    /// Both sides accepted
+    Accepted,
+}
+
+impl FriendState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FriendState::Outgoing => "outgoing",
+            FriendState::Incoming => "incoming",
+            FriendState::Accepted => "accepted",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "outgoing" => Some(FriendState::Outgoing),
+            "incoming" => Some(FriendState::Incoming),
+            "accepted" => Some(FriendState::Accepted),
+            _ => None,
+        }
+    }
+}
+
+/// A friend or a pending friend request (for client mode)
 #[derive(Debug, Clone)]
-pub struct Peer {
+pub struct Friend {
     pub peer_id: String,
-    pub last_seen: Option<i64>,
-    pub first_seen: i64,
-    pub address: Option<String>,
-    pub is_bootstrap: bool,
+    pub state: FriendState,
+    /// Note sent with the request
+    pub note: String,
+    /// Key the friend encrypts its presence record with, once they accepted
+    pub presence_key: Option<Vec<u8>>,
+    pub updated_at: i64,
+    /// Name we gave the friend, shown instead of the PeerId (local only)
+    pub petname: String,
+    /// Private notes about the friend (local only)
+    pub annotation: String,
+    /// The friend confirmed it has our current presence key
+    pub key_delivered: bool,
 }
 
-/// Identity information (for both server and client)
+/// Penalties held against a peer (for client mode). Both values are zero or
+/// negative and fade with time from `updated_at`.
 #[derive(Debug, Clone)]
-pub struct Identity {
+pub struct PeerReputation {
     pub peer_id: String,
-    pub keypair_encrypted: Option<Vec<u8>>,
-    pub created_at: i64,
+    /// Gossipsub score the peer earned itself, our reputation part excluded
+    pub score: f64,
+    /// Application-level reputation: lowered for every message we reject
+    pub reputation: f64,
+    pub updated_at: i64,
 }
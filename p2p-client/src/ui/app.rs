@@ -1,16 +1,28 @@
 use eframe::egui;
 use tokio::sync::mpsc;
 
-use crate::common::{NetworkCommand, NetworkEvent};
+use std::collections::HashMap;
+
+use crate::common::{
+    ChatMessage, Conversation, DeliveryStatus, FileAttachment, NetworkCommand, NetworkEvent,
+};
+use crate::storage::client_db::ClientDatabase;
 
 use super::components::{
-    chat_area, debug_panel, input_bar,
+    chat_area::{self, MessageAction},
+    debug_panel,
+    image_preview::ImagePreviews,
+    input_bar,
     sidebar::{self, SidebarActions},
 };
-use super::state::AppState;
+use super::state::{AppState, FileTransfer};
+
+/// Số tin nhắn gần nhất được nạp lại từ database khi khởi động
+const HISTORY_LOAD_LIMIT: usize = 500;
 
 pub struct ChatApp {
     state: AppState,
+    previews: ImagePreviews,
     command_sender: mpsc::Sender<NetworkCommand>,
     event_receiver: mpsc::Receiver<NetworkEvent>,
 }
@@ -21,8 +33,37 @@ impl ChatApp {
         command_sender: mpsc::Sender<NetworkCommand>,
         event_receiver: mpsc::Receiver<NetworkEvent>,
     ) -> Self {
+        let mut state = AppState::new();
+        match ClientDatabase::new() {
+            Ok(db) => {
+                state.read_receipts = db.read_receipts_enabled().unwrap_or(true);
+                state.reactions = db.get_all_reactions().unwrap_or_else(|err| {
+                    log::warn!("Failed to load reactions: {err}");
+                    HashMap::new()
+                });
+                state.transfers = load_stored_downloads(&db);
+                state.load_rooms(load_stored_rooms(&db));
+                state.load_blocked(db.get_blocked_peers().unwrap_or_else(|err| {
+                    log::warn!("Failed to load blocked peers: {err}");
+                    Vec::new()
+                }));
+                state.load_history(load_stored_history(&db));
+                state.load_direct_history(load_stored_direct_history(&db));
+                state.load_profiles(db.get_profiles().unwrap_or_else(|err| {
+                    log::warn!("Failed to load profiles: {err}");
+                    Vec::new()
+                }));
+            }
+            Err(err) => log::warn!("Failed to open client database for history: {err}"),
+        }
+        let mut previews = ImagePreviews::new();
+        for profile in state.profiles.values() {
+            previews.set_avatar(&profile.peer_id, &profile.avatar);
+        }
+
         Self {
-            state: AppState::new(),
+            state,
+            previews,
             command_sender,
             event_receiver,
         }
@@ -31,21 +72,228 @@ impl ChatApp {
     fn handle_network_events(&mut self) {
         while let Ok(event) = self.event_receiver.try_recv() {
             match event {
+                NetworkEvent::LocalPeerId(peer_id) => {
+                    self.state.delivery = load_stored_delivery_statuses(&peer_id);
+                    self.state.local_peer_id = Some(peer_id);
+                    self.state.fill_profile_inputs();
+                }
+                NetworkEvent::Typing { conversation, peer } => {
+                    self.state.set_typing(conversation, peer)
+                }
+                NetworkEvent::ReceiptUpdated { message_id, status } => {
+                    self.state.update_delivery(message_id, status)
+                }
                 NetworkEvent::MessageReceived(message) => self.state.push_message(message),
+                NetworkEvent::MessageOpApplied(op) => self.state.apply_op(&op),
+                NetworkEvent::FileProgress {
+                    file_id,
+                    received,
+                    total,
+                    sources,
+                } => {
+                    self.state.transfers.insert(
+                        file_id,
+                        FileTransfer::InProgress {
+                            received,
+                            total,
+                            sources,
+                        },
+                    );
+                }
+                NetworkEvent::FileShared { file_id, path } => {
+                    self.state
+                        .transfers
+                        .insert(file_id, FileTransfer::Completed(path));
+                }
+                NetworkEvent::FileCompleted { file_id, path } => {
+                    self.state.add_debug_event(
+                        "FILE_SAVED".to_string(),
+                        None,
+                        format!("Đã lưu file vào {path}"),
+                    );
+                    self.state
+                        .transfers
+                        .insert(file_id, FileTransfer::Completed(path));
+                }
+                NetworkEvent::FileFailed { file_id, reason } => {
+                    self.state.add_debug_event(
+                        "FILE_FAILED".to_string(),
+                        None,
+                        format!("Tải file {file_id} thất bại: {reason}"),
+                    );
+                    self.state
+                        .transfers
+                        .insert(file_id, FileTransfer::Failed(reason));
+                }
+                NetworkEvent::FileShareFailed { path, reason } => self.state.add_debug_event(
+                    "FILE_SHARE_FAILED".to_string(),
+                    None,
+                    format!("Không chia sẻ được {path}: {reason}"),
+                ),
+                NetworkEvent::ReactionsUpdated {
+                    message_id,
+                    reactions,
+                } => self.state.update_reactions(message_id, reactions),
                 NetworkEvent::HistorySynced(history) => self.state.push_history(history),
-                NetworkEvent::PeerConnected(peer_id) => self.state.add_peer(peer_id),
+                NetworkEvent::ThreadUnavailable { parent_id } => {
+                    self.state.missing_parents.insert(parent_id);
+                }
+                NetworkEvent::MessageRejected { peer, reason } => {
+                    self.state
+                        .add_debug_event("MESSAGE_REJECTED".to_string(), Some(peer), reason)
+                }
+                NetworkEvent::PeerConnected(peer_id) => {
+                    // Pull whatever we missed the first time a peer shows up
+                    if !self.state.peers.contains(&peer_id) {
+                        self.request_sync(peer_id.clone());
+                    }
+                    self.state.add_peer(peer_id);
+                }
                 NetworkEvent::PeerDisconnected(peer_id) => self.state.remove_peer(&peer_id),
                 NetworkEvent::FriendStatus(status) => self.state.upsert_friend_status(status),
+                NetworkEvent::FriendRequestReceived { peer, note } => {
+                    self.state.add_friend_request(peer, note, true)
+                }
+                NetworkEvent::FriendRequestSent { peer, note } => {
+                    self.state.add_friend_request(peer, note, false)
+                }
+                NetworkEvent::FriendRequestClosed { peer } => {
+                    self.state.close_friend_request(&peer)
+                }
+                NetworkEvent::FriendInfo {
+                    peer,
+                    petname,
+                    annotation,
+                } => self.state.set_friend_info(peer, petname, annotation),
+                NetworkEvent::FriendRemoved { peer } => self.state.remove_friend(&peer),
+                NetworkEvent::ProfileUpdated(profile) => {
+                    self.previews.set_avatar(&profile.peer_id, &profile.avatar);
+                    self.state.set_profile(profile);
+                }
+                NetworkEvent::PeerBlocked { peer } => self.state.block_peer(peer),
+                NetworkEvent::PeerUnblocked { peer } => self.state.unblock_peer(&peer),
+                NetworkEvent::ProfileError(reason) => {
+                    self.state.profile_error = Some(reason.clone());
+                    self.state
+                        .add_debug_event("PROFILE_ERROR".to_string(), None, reason);
+                }
+                NetworkEvent::RoomJoined(room) => self.state.join_room(room),
+                NetworkEvent::RoomLeft(room) => self.state.leave_room(&room),
+                NetworkEvent::DirectMessage { peer, message } => {
+                    self.state.push_direct_message(peer, message)
+                }
+                NetworkEvent::DirectMessageFailed { peer, reason } => self.state.add_debug_event(
+                    "DIRECT_MESSAGE_FAILED".to_string(),
+                    Some(peer),
+                    reason,
+                ),
+                NetworkEvent::DirectMessageUndecryptable { peer, reason } => {
+                    self.state.add_debug_event(
+                        "E2E_UNDECRYPTABLE".to_string(),
+                        Some(peer),
+                        format!("Không giải mã được tin nhắn riêng: {reason}"),
+                    )
+                }
+                NetworkEvent::DirectMessageTampered { peer } => self.state.add_debug_event(
+                    "E2E_TAMPERED".to_string(),
+                    Some(peer),
+                    "Tin nhắn riêng đã bị sửa đổi trên đường truyền".to_string(),
+                ),
             }
         }
     }
 
-    fn send_command(&mut self, payload: String) {
+    fn send_command(&mut self, payload: String, reply_to: Option<String>) {
+        let command = match &self.state.active_conversation {
+            Conversation::Room(room) => NetworkCommand::SendMessage {
+                room: room.clone(),
+                content: payload,
+                reply_to,
+            },
+            Conversation::Direct(peer_id) => NetworkCommand::SendDirect {
+                to_peer: peer_id.clone(),
+                content: payload,
+                reply_to,
+            },
+        };
+        if let Err(err) = self.command_sender.try_send(command) {
+            log::warn!("Failed to send command to network: {err}");
+        }
+    }
+
+    /// Gửi nội dung mới cho tin nhắn đang sửa
+    fn send_edit(&mut self, conversation: Conversation, message_id: String, content: String) {
+        if let Err(err) = self.command_sender.try_send(NetworkCommand::EditMessage {
+            conversation,
+            message_id,
+            content,
+        }) {
+            log::warn!("Failed to send edit command: {err}");
+        }
+    }
+
+    fn delete_message(&mut self, message_id: String) {
+        if let Err(err) = self.command_sender.try_send(NetworkCommand::DeleteMessage {
+            conversation: self.state.active_conversation.clone(),
+            message_id,
+        }) {
+            log::warn!("Failed to send delete command: {err}");
+        }
+    }
+
+    fn send_reaction(&mut self, message_id: String, emoji: String, add: bool) {
+        let conversation = self.state.active_conversation.clone();
+        let command = if add {
+            NetworkCommand::AddReaction {
+                conversation,
+                message_id,
+                emoji,
+            }
+        } else {
+            NetworkCommand::RemoveReaction {
+                conversation,
+                message_id,
+                emoji,
+            }
+        };
+        if let Err(err) = self.command_sender.try_send(command) {
+            log::warn!("Failed to send reaction command: {err}");
+        }
+    }
+
+    fn send_file(&mut self, path: String) {
+        if let Err(err) = self.command_sender.try_send(NetworkCommand::SendFile {
+            conversation: self.state.active_conversation.clone(),
+            path,
+        }) {
+            log::warn!("Failed to send file command: {err}");
+        }
+    }
+
+    fn download_file(&mut self, peer_id: String, attachment: FileAttachment) {
+        self.state.transfers.insert(
+            attachment.file_id.clone(),
+            FileTransfer::InProgress {
+                received: 0,
+                total: attachment.size,
+                sources: 1,
+            },
+        );
+        if let Err(err) = self.command_sender.try_send(NetworkCommand::DownloadFile {
+            conversation: self.state.active_conversation.clone(),
+            peer_id,
+            attachment,
+        }) {
+            log::warn!("Failed to send download command: {err}");
+        }
+    }
+
+    fn request_sync(&mut self, to_peer: String) {
         if let Err(err) = self
             .command_sender
-            .try_send(NetworkCommand::SendMessage(payload))
+            .try_send(NetworkCommand::SyncRequest { to_peer })
         {
-            log::warn!("Failed to send command to network: {err}");
+            log::warn!("Failed to send sync command to network: {err}");
         }
     }
 
@@ -58,12 +306,135 @@ impl ChatApp {
         }
     }
 
-    fn add_friend(&mut self, peer_id: String) {
+    /// Báo tầng mạng những tin nhắn người dùng vừa nhìn thấy (chỉ khi cửa sổ đang focus)
+    fn mark_active_conversation_read(&mut self) {
+        let message_ids = self.state.take_unread_in_active();
+        if message_ids.is_empty() {
+            return;
+        }
+        if let Err(err) = self.command_sender.try_send(NetworkCommand::MarkRead {
+            conversation: self.state.active_conversation.clone(),
+            message_ids,
+        }) {
+            log::warn!("Failed to send mark-read command: {err}");
+        }
+    }
+
+    fn send_room_command(&mut self, command: NetworkCommand) {
+        if let Err(err) = self.command_sender.try_send(command) {
+            log::warn!("Failed to send room command: {err}");
+        }
+    }
+
+    fn send_typing(&mut self) {
+        if let Err(err) = self.command_sender.try_send(NetworkCommand::Typing {
+            conversation: self.state.active_conversation.clone(),
+        }) {
+            log::debug!("Failed to send typing command: {err}");
+        }
+    }
+
+    fn set_read_receipts(&mut self, enabled: bool) {
         if let Err(err) = self
             .command_sender
-            .try_send(NetworkCommand::AddFriend { peer_id })
+            .try_send(NetworkCommand::SetReadReceipts(enabled))
         {
-            log::warn!("Failed to send add-friend command: {err}");
+            log::warn!("Failed to send read receipt setting: {err}");
+        }
+    }
+
+    fn set_profile(&mut self, display_name: String, status: String, avatar_path: Option<String>) {
+        if let Err(err) = self.command_sender.try_send(NetworkCommand::SetProfile {
+            display_name,
+            status,
+            avatar_path,
+        }) {
+            log::warn!("Failed to send profile: {err}");
+        }
+    }
+
+    fn set_blocked(&mut self, peer_id: String, blocked: bool) {
+        let command = if blocked {
+            NetworkCommand::BlockPeer { peer_id }
+        } else {
+            NetworkCommand::UnblockPeer { peer_id }
+        };
+        if let Err(err) = self.command_sender.try_send(command) {
+            log::warn!("Failed to send block command: {err}");
+        }
+    }
+
+    fn send_friend_command(&mut self, command: NetworkCommand) {
+        if let Err(err) = self.command_sender.try_send(command) {
+            log::warn!("Failed to send friend command: {err}");
+        }
+    }
+}
+
+fn load_stored_rooms(db: &ClientDatabase) -> Vec<String> {
+    db.get_rooms().unwrap_or_else(|err| {
+        log::warn!("Failed to load joined rooms: {err}");
+        Vec::new()
+    })
+}
+
+fn load_stored_delivery_statuses(local_peer_id: &str) -> HashMap<String, DeliveryStatus> {
+    let statuses = ClientDatabase::new().and_then(|db| db.get_delivery_statuses(local_peer_id));
+    statuses.unwrap_or_else(|err| {
+        log::warn!("Failed to load message receipts: {err}");
+        HashMap::new()
+    })
+}
+
+/// Downloads plus the files we shared ourselves (available locally)
+fn load_stored_downloads(db: &ClientDatabase) -> HashMap<String, FileTransfer> {
+    let shared = db.get_shared_files().unwrap_or_else(|err| {
+        log::warn!("Failed to load shared files: {err}");
+        Vec::new()
+    });
+    let downloads = db.get_downloads().unwrap_or_else(|err| {
+        log::warn!("Failed to load downloads: {err}");
+        Vec::new()
+    });
+    let shared = shared
+        .into_iter()
+        .map(|file| (file.attachment.file_id, FileTransfer::Completed(file.path)));
+    downloads
+        .into_iter()
+        .map(|download| {
+            let transfer = match &download.saved_path {
+                Some(path) => FileTransfer::Completed(path.clone()),
+                None => FileTransfer::InProgress {
+                    received: download.received_bytes(),
+                    total: download.attachment.size,
+                    sources: 0,
+                },
+            };
+            (download.attachment.file_id, transfer)
+        })
+        .chain(shared)
+        .collect()
+}
+
+fn load_stored_history(db: &ClientDatabase) -> Vec<ChatMessage> {
+    match db.get_recent_messages(HISTORY_LOAD_LIMIT) {
+        Ok(messages) => messages.into_iter().map(ChatMessage::from).collect(),
+        Err(err) => {
+            log::warn!("Failed to load stored messages: {err}");
+            Vec::new()
+        }
+    }
+}
+
+fn load_stored_direct_history(db: &ClientDatabase) -> Vec<(String, ChatMessage)> {
+    match db.get_recent_direct_messages(HISTORY_LOAD_LIMIT) {
+        Ok(messages) => messages
+            .into_iter()
+            .map(|(peer_id, message)| (peer_id, ChatMessage::from(message)))
+            .collect(),
+        Err(err) => {
+            log::warn!("Failed to load stored direct messages: {err}");
+            Vec::new()
         }
     }
 }
@@ -71,17 +442,52 @@ impl ChatApp {
 impl eframe::App for ChatApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         self.handle_network_events();
+        self.previews.poll(ctx);
 
         egui::SidePanel::left("peer_sidebar")
             .resizable(true)
             .default_width(200.0)
             .show(ctx, |ui| {
-                let actions: SidebarActions = sidebar::render(ui, &mut self.state);
+                let actions: SidebarActions = sidebar::render(ui, &mut self.state, &self.previews);
                 if let Some(address) = actions.connect_address {
                     self.connect_to_peer(address);
                 }
-                if let Some(peer_id) = actions.friend_peer_id {
-                    self.add_friend(peer_id);
+                if let Some((peer_id, note)) = actions.friend_request {
+                    self.send_friend_command(NetworkCommand::SendFriendRequest { peer_id, note });
+                }
+                if let Some(peer_id) = actions.accept_friend {
+                    self.send_friend_command(NetworkCommand::AcceptFriend { peer_id });
+                }
+                if let Some(peer_id) = actions.decline_friend {
+                    self.send_friend_command(NetworkCommand::DeclineFriend { peer_id });
+                }
+                if let Some(peer_id) = actions.remove_friend {
+                    self.send_friend_command(NetworkCommand::RemoveFriend { peer_id });
+                }
+                if let Some(peer_id) = actions.block_peer {
+                    self.set_blocked(peer_id, true);
+                }
+                if let Some(peer_id) = actions.unblock_peer {
+                    self.set_blocked(peer_id, false);
+                }
+                if let Some((display_name, status, avatar_path)) = actions.set_profile {
+                    self.set_profile(display_name, status, avatar_path);
+                }
+                if let Some(editor) = actions.update_friend {
+                    self.send_friend_command(NetworkCommand::UpdateFriend {
+                        peer_id: editor.peer_id,
+                        petname: editor.petname,
+                        annotation: editor.annotation,
+                    });
+                }
+                if let Some(room) = actions.join_room {
+                    self.send_room_command(NetworkCommand::JoinRoom { room });
+                }
+                if let Some(room) = actions.leave_room {
+                    self.send_room_command(NetworkCommand::LeaveRoom { room });
+                }
+                if let Some(enabled) = actions.read_receipts {
+                    self.set_read_receipts(enabled);
                 }
             });
 
@@ -89,19 +495,118 @@ impl eframe::App for ChatApp {
             .resizable(true)
             .default_width(300.0)
             .show(ctx, |ui| {
-                debug_panel::render(ui, &self.state);
+                debug_panel::render(ui, &self.state, &self.previews);
             });
 
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("Rust P2P Chat");
+            match &self.state.active_conversation {
+                Conversation::Room(room) => ui.label(format!("# {room}")),
+                Conversation::Direct(peer_id) => ui.label(format!(
+                    "Chat riêng với {}",
+                    self.state.display_name(peer_id, peer_id.len())
+                )),
+            };
             ui.separator();
-            chat_area::render(ui, &self.state.messages);
+            let action = chat_area::render(ui, &self.state, &mut self.previews);
+            match action {
+                Some(MessageAction::Edit(message_id, content)) => {
+                    self.state.replying_to = None;
+                    self.state.editing = Some((self.state.active_conversation.clone(), message_id));
+                    self.state.input_text = content;
+                }
+                Some(MessageAction::Reply(message_id)) => {
+                    self.state.editing = None;
+                    self.state.replying_to =
+                        Some((self.state.active_conversation.clone(), message_id));
+                }
+                Some(MessageAction::Delete(message_id)) => self.delete_message(message_id),
+                Some(MessageAction::Download(peer_id, attachment)) => {
+                    self.download_file(peer_id, attachment)
+                }
+                Some(MessageAction::React(message_id, emoji)) => {
+                    self.send_reaction(message_id, emoji, true)
+                }
+                Some(MessageAction::RemoveReaction(message_id, emoji)) => {
+                    self.send_reaction(message_id, emoji, false)
+                }
+                None => {}
+            }
+
+            let typing = self.state.typing_in_active();
+            if !typing.is_empty() {
+                let names: Vec<&str> = typing
+                    .iter()
+                    .map(|peer_id| self.state.display_name(peer_id, 8))
+                    .collect();
+                ui.label(egui::RichText::new(format!("{} is typing…", names.join(", "))).weak());
+            }
 
             ui.separator();
+            // Đổi cuộc trò chuyện thì bỏ chế độ sửa / trả lời
+            let active = self.state.active_conversation.clone();
+            if self
+                .state
+                .editing
+                .as_ref()
+                .is_some_and(|(conversation, _)| *conversation != active)
+            {
+                self.state.editing = None;
+            }
+            if self
+                .state
+                .replying_to
+                .as_ref()
+                .is_some_and(|(conversation, _)| *conversation != active)
+            {
+                self.state.replying_to = None;
+            }
+            if let Some((_, message_id)) = &self.state.replying_to {
+                let preview = self
+                    .state
+                    .active_messages()
+                    .iter()
+                    .find(|message| &message.id == message_id)
+                    .map(|message| chat_area::quote_preview(message, &self.state.names))
+                    .unwrap_or_default();
+                ui.horizontal(|ui| {
+                    ui.label(egui::RichText::new(format!("Đang trả lời {preview}")).weak());
+                    if ui.small_button("Hủy").clicked() {
+                        self.state.replying_to = None;
+                    }
+                });
+            }
+            if self.state.editing.is_some() {
+                ui.horizontal(|ui| {
+                    ui.label(egui::RichText::new("Đang sửa tin nhắn").weak());
+                    if ui.small_button("Hủy").clicked() {
+                        self.state.editing = None;
+                        self.state.input_text.clear();
+                    }
+                });
+            }
             if let Some(content) = input_bar::render(ui, &mut self.state.input_text) {
-                self.send_command(content);
+                match self.state.editing.take() {
+                    Some((conversation, message_id)) => {
+                        self.send_edit(conversation, message_id, content)
+                    }
+                    None => {
+                        let reply_to = self.state.replying_to.take().map(|(_, id)| id);
+                        self.send_command(content, reply_to)
+                    }
+                }
+            } else if !self.state.input_text.is_empty() && self.state.should_send_typing() {
+                self.send_typing();
+            }
+            if let Some(path) = input_bar::render_file(ui, &mut self.state.file_path_input) {
+                self.send_file(path);
             }
         });
+        self.previews.show_viewer(ctx);
+
+        if ctx.input(|input| input.focused) {
+            self.mark_active_conversation_read();
+        }
 
         ctx.request_repaint();
     }
@@ -1,11 +1,276 @@
+use std::collections::HashMap;
+
 use eframe::egui;
 
-use crate::common::ChatMessage;
+use crate::common::{ChatMessage, DeliveryStatus, FileAttachment};
+use crate::ui::state::{AppState, FileTransfer, display_name};
+
+use super::image_preview::{self, ImagePreviews};
+
+/// Emoji có sẵn trong bảng chọn reaction
+const REACTION_CHOICES: [&str; 8] = ["👍", "❤", "😂", "😮", "😢", "🙏", "🎉", "👎"];
+/// Số ký tự tối đa của đoạn trích khi trả lời
+const QUOTE_PREVIEW_CHARS: usize = 60;
+/// Cạnh ảnh đại diện cạnh tin nhắn (điểm)
+const AVATAR_SIZE: f32 = 20.0;
+
+/// Thao tác người dùng chọn trên một tin nhắn
+pub enum MessageAction {
+    /// Sửa tin nhắn của mình (id, nội dung hiện tại)
+    Edit(String, String),
+    Delete(String),
+    /// Trả lời tin nhắn (id)
+    Reply(String),
+    /// Thả emoji (id, emoji)
+    React(String, String),
+    /// Gỡ emoji mình đã thả (id, emoji)
+    RemoveReaction(String, String),
+    /// Tải file đính kèm từ người gửi (peer_id, file)
+    Download(String, FileAttachment),
+}
 
-pub fn render(ui: &mut egui::Ui, messages: &[ChatMessage]) {
+/// Vẽ các tin nhắn của cuộc trò chuyện đang mở trong `state`, kèm ảnh đại
+/// diện và ảnh xem trước lấy từ `previews`. Trả về thao tác người dùng chọn.
+pub fn render(
+    ui: &mut egui::Ui,
+    state: &AppState,
+    previews: &mut ImagePreviews,
+) -> Option<MessageAction> {
+    let messages = state.active_messages();
+    let local_peer_id = state.local_peer_id.as_deref();
+    let AppState {
+        delivery,
+        reactions,
+        transfers,
+        names,
+        missing_parents,
+        ..
+    } = state;
+    let by_id: HashMap<&str, &ChatMessage> = messages
+        .iter()
+        .map(|message| (message.id.as_str(), message))
+        .collect();
+    let mut replies: HashMap<&str, Vec<&ChatMessage>> = HashMap::new();
+    for message in messages {
+        if let Some(parent_id) = &message.reply_to {
+            replies.entry(parent_id.as_str()).or_default().push(message);
+        }
+    }
+
+    let mut action = None;
     egui::ScrollArea::vertical().show(ui, |ui| {
         for message in messages {
-            ui.label(format!("{}: {}", message.sender, message.content));
+            if let Some(parent_id) = &message.reply_to {
+                let quote = match by_id.get(parent_id.as_str()) {
+                    Some(parent) => quote_preview(parent, names),
+                    None if missing_parents.contains(parent_id) => {
+                        "tin nhắn gốc (không tải được)".to_string()
+                    }
+                    None => "tin nhắn gốc (đang tải...)".to_string(),
+                };
+                ui.label(egui::RichText::new(format!("↪ {quote}")).small().weak());
+            }
+
+            let own = local_peer_id == Some(message.sender.as_str());
+            ui.horizontal(|ui| {
+                previews.show_avatar(ui, &message.sender, AVATAR_SIZE);
+                let response = message_label(ui, message, names);
+                if own && !message.deleted {
+                    response.context_menu(|ui| {
+                        if message.attachment.is_none() && ui.button("Sửa").clicked() {
+                            action = Some(MessageAction::Edit(
+                                message.id.clone(),
+                                message.content.clone(),
+                            ));
+                            ui.close();
+                        }
+                        if ui.button("Xóa").clicked() {
+                            action = Some(MessageAction::Delete(message.id.clone()));
+                            ui.close();
+                        }
+                    });
+                }
+                if own {
+                    let status = delivery
+                        .get(&message.id)
+                        .copied()
+                        .unwrap_or(DeliveryStatus::Sent);
+                    let (ticks, color) = match status {
+                        DeliveryStatus::Sent => ("✓", egui::Color32::GRAY),
+                        DeliveryStatus::Delivered => ("✓✓", egui::Color32::GRAY),
+                        DeliveryStatus::Read => ("✓✓", egui::Color32::LIGHT_BLUE),
+                    };
+                    ui.colored_label(color, ticks);
+                }
+                if !message.deleted {
+                    if ui.small_button("↩").on_hover_text("Trả lời").clicked() {
+                        action = Some(MessageAction::Reply(message.id.clone()));
+                    }
+                    ui.menu_button("☺", |ui| {
+                        ui.horizontal(|ui| {
+                            for emoji in REACTION_CHOICES {
+                                if ui.button(emoji).clicked() {
+                                    action = Some(MessageAction::React(
+                                        message.id.clone(),
+                                        emoji.to_string(),
+                                    ));
+                                    ui.close();
+                                }
+                            }
+                        });
+                    });
+                }
+            });
+
+            if let Some(attachment) = &message.attachment
+                && !message.deleted
+                && image_preview::is_image(&attachment.name)
+                && let Some(FileTransfer::Completed(path)) = transfers.get(&attachment.file_id)
+            {
+                previews.show_thumbnail(ui, &attachment.file_id, path);
+            }
+
+            if let Some(attachment) = &message.attachment
+                && !own
+                && !message.deleted
+            {
+                ui.horizontal(|ui| {
+                    let download = match transfers.get(&attachment.file_id) {
+                        None => ui.small_button("Tải về").clicked(),
+                        Some(FileTransfer::InProgress {
+                            received,
+                            total,
+                            sources,
+                        }) => {
+                            let fraction = if *total == 0 {
+                                1.0
+                            } else {
+                                *received as f32 / *total as f32
+                            };
+                            let mut bar = egui::ProgressBar::new(fraction)
+                                .desired_width(160.0)
+                                .show_percentage();
+                            if *sources > 1 {
+                                bar =
+                                    bar.text(format!("{:.0}% · {sources} nguồn", fraction * 100.0));
+                            }
+                            ui.add(bar);
+                            false
+                        }
+                        Some(FileTransfer::Completed(path)) => {
+                            ui.label(egui::RichText::new(format!("Đã lưu: {path}")).weak());
+                            false
+                        }
+                        Some(FileTransfer::Failed(reason)) => {
+                            ui.colored_label(egui::Color32::LIGHT_RED, reason);
+                            ui.small_button("Thử lại").clicked()
+                        }
+                    };
+                    if download {
+                        action = Some(MessageAction::Download(
+                            message.sender.clone(),
+                            attachment.clone(),
+                        ));
+                    }
+                });
+            }
+
+            if let Some(thread) = replies.get(message.id.as_str()) {
+                egui::CollapsingHeader::new(format!("{} trả lời", thread.len()))
+                    .id_salt(("thread", &message.id))
+                    .default_open(false)
+                    .show(ui, |ui| {
+                        for reply in thread {
+                            message_label(ui, reply, names);
+                        }
+                    });
+            }
+
+            if message.deleted {
+                continue;
+            }
+            let Some(reactions) = reactions.get(&message.id) else {
+                continue;
+            };
+            ui.horizontal(|ui| {
+                for (emoji, peers) in reactions {
+                    // Bấm vào emoji mình đã thả thì gỡ, chưa thả thì thả thêm
+                    let mine = local_peer_id.is_some_and(|local| peers.iter().any(|p| p == local));
+                    let chip = ui
+                        .selectable_label(mine, format!("{emoji} {}", peers.len()))
+                        .on_hover_text(
+                            peers
+                                .iter()
+                                .map(|peer_id| display_name(names, peer_id, 8))
+                                .collect::<Vec<_>>()
+                                .join(", "),
+                        );
+                    if chip.clicked() {
+                        let (id, emoji) = (message.id.clone(), emoji.clone());
+                        action = Some(if mine {
+                            MessageAction::RemoveReaction(id, emoji)
+                        } else {
+                            MessageAction::React(id, emoji)
+                        });
+                    }
+                }
+            });
         }
     });
+    action
+}
+
+/// Đoạn trích ngắn của một tin nhắn để hiển thị khi trả lời
+pub fn quote_preview(message: &ChatMessage, names: &HashMap<String, String>) -> String {
+    let sender = display_name(names, &message.sender, 8);
+    if message.deleted {
+        return format!("{sender}: tin nhắn đã bị xóa");
+    }
+    let mut snippet: String = message.content.chars().take(QUOTE_PREVIEW_CHARS).collect();
+    if message.content.chars().count() > QUOTE_PREVIEW_CHARS {
+        snippet.push('…');
+    }
+    format!("{sender}: {snippet}")
+}
+
+fn message_label(
+    ui: &mut egui::Ui,
+    message: &ChatMessage,
+    names: &HashMap<String, String>,
+) -> egui::Response {
+    let sender = display_name(names, &message.sender, message.sender.len());
+    if message.deleted {
+        return ui.label(
+            egui::RichText::new(format!("{sender}: tin nhắn đã bị xóa"))
+                .italics()
+                .weak(),
+        );
+    }
+    let response = match &message.attachment {
+        Some(attachment) => ui.label(format!(
+            "{sender}: 📎 {} ({})",
+            attachment.name,
+            format_size(attachment.size)
+        )),
+        None => ui.label(format!("{sender}: {}", message.content)),
+    };
+    if message.edited {
+        ui.label(egui::RichText::new("(đã sửa)").small().weak());
+    }
+    response
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
 }
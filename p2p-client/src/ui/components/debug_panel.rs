@@ -2,7 +2,12 @@ use eframe::egui;
 
 use crate::ui::state::AppState;
 
-pub fn render(ui: &mut egui::Ui, state: &AppState) {
+use super::image_preview::ImagePreviews;
+
+/// Cạnh ảnh đại diện cạnh mỗi peer (điểm)
+const AVATAR_SIZE: f32 = 14.0;
+
+pub fn render(ui: &mut egui::Ui, state: &AppState, previews: &ImagePreviews) {
     ui.heading("Debug Info");
     ui.separator();
 
@@ -21,7 +26,8 @@ pub fn render(ui: &mut egui::Ui, state: &AppState) {
             let now = chrono::Utc::now();
             let elapsed = now.signed_duration_since(*last_seen);
             ui.horizontal(|ui| {
-                ui.label(format!("✓ {}", &peer_id[..8]));
+                previews.show_avatar(ui, peer_id, AVATAR_SIZE);
+                ui.label(format!("✓ {}", state.display_name(peer_id, 8)));
                 ui.label(format!(
                     "Last seen: {:.1}s ago",
                     elapsed.num_milliseconds() as f64 / 1000.0
@@ -29,7 +35,8 @@ pub fn render(ui: &mut egui::Ui, state: &AppState) {
             });
         } else {
             ui.horizontal(|ui| {
-                ui.label(format!("✓ {}", &peer_id[..8]));
+                previews.show_avatar(ui, peer_id, AVATAR_SIZE);
+                ui.label(format!("✓ {}", state.display_name(peer_id, 8)));
                 ui.label("(connecting...)");
             });
         }
@@ -50,7 +57,8 @@ pub fn render(ui: &mut egui::Ui, state: &AppState) {
             let now = chrono::Utc::now();
             let elapsed = now.signed_duration_since(*last_seen);
             ui.horizontal(|ui| {
-                ui.label(format!("✗ {}", &peer_id[..8]));
+                previews.show_avatar(ui, peer_id, AVATAR_SIZE);
+                ui.label(format!("✗ {}", state.display_name(peer_id, 8)));
                 ui.label(format!(
                     "Offline: {:.1}s",
                     elapsed.num_milliseconds() as f64 / 1000.0
@@ -71,12 +79,18 @@ pub fn render(ui: &mut egui::Ui, state: &AppState) {
                     "PEER_CONNECTED" => egui::Color32::GREEN,
                     "PEER_DISCONNECTED" => egui::Color32::RED,
                     "PEER_REFRESHED" => egui::Color32::YELLOW,
+                    "E2E_UNDECRYPTABLE" => egui::Color32::ORANGE,
+                    "E2E_TAMPERED" => egui::Color32::LIGHT_RED,
+                    "MESSAGE_REJECTED" => egui::Color32::LIGHT_RED,
                     _ => egui::Color32::WHITE,
                 };
 
                 ui.horizontal(|ui| {
                     ui.colored_label(color, format!("[{}]", time_str));
-                    ui.label(&event.message);
+                    let label = ui.label(&event.message);
+                    if let Some(peer_id) = &event.peer_id {
+                        label.on_hover_text(peer_id);
+                    }
                 });
             }
         });
@@ -0,0 +1,376 @@
+//! Xem trước ảnh đính kèm (PNG/JPEG) trong khung chat, và ảnh đại diện trong hồ sơ.
+//!
+//! Ảnh được giải mã trên một luồng nền để việc cuộn lịch sử không bị giật.
+//! Thumbnail được lưu vào `data/thumbnails` theo mã file, nên lần sau chỉ cần
+//! đọc lại ảnh nhỏ thay vì giải mã ảnh gốc.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use eframe::egui;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+
+/// Thư mục lưu thumbnail đã tạo (`<file_id>.png`)
+const THUMBNAIL_DIR: &str = "data/thumbnails";
+
+/// Cạnh dài nhất của thumbnail (pixel)
+const THUMBNAIL_SIZE: u32 = 240;
+
+/// Cạnh dài nhất của ảnh trong cửa sổ phóng to
+const VIEWER_SIZE: u32 = 1600;
+
+/// Giới hạn khi giải mã để một file ảnh lạ không làm cạn bộ nhớ
+const MAX_IMAGE_DIMENSION: u32 = 16_384;
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// Ảnh đại diện do peer khác gửi chỉ là ảnh nhỏ
+const MAX_AVATAR_DIMENSION: u32 = 256;
+const MAX_AVATAR_ALLOC: u64 = 4 * 1024 * 1024;
+
+/// File đính kèm có phải ảnh xem trước được không (theo phần mở rộng)
+pub fn is_image(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    [".png", ".jpg", ".jpeg"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+enum Job {
+    Thumbnail { file_id: String, path: String },
+    Full { file_id: String, path: String },
+    Avatar { peer_id: String, png: Vec<u8> },
+}
+
+/// Ảnh vừa giải mã dùng vào đâu
+#[derive(Clone, Copy)]
+enum Slot {
+    Thumbnail,
+    Full,
+    Avatar,
+}
+
+struct Decoded {
+    /// Mã file, hoặc PeerId với ảnh đại diện
+    key: String,
+    slot: Slot,
+    image: Result<egui::ColorImage, String>,
+}
+
+enum Preview {
+    Loading,
+    Ready(egui::TextureHandle),
+    Failed,
+}
+
+/// Thumbnail của các ảnh đính kèm, ảnh đang được phóng to và ảnh đại diện.
+pub struct ImagePreviews {
+    thumbnails: HashMap<String, Preview>,
+    /// Ảnh đại diện theo PeerId
+    avatars: HashMap<String, Preview>,
+    /// Ảnh đang mở trong cửa sổ phóng to (file_id, ảnh)
+    viewer: Option<(String, Preview)>,
+    jobs: Sender<Job>,
+    results: Receiver<Decoded>,
+}
+
+impl ImagePreviews {
+    pub fn new() -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let (result_sender, results) = mpsc::channel();
+        let spawned = thread::Builder::new()
+            .name("image-decoder".to_string())
+            .spawn(move || {
+                for job in job_receiver {
+                    if result_sender.send(job.run()).is_err() {
+                        break;
+                    }
+                }
+            });
+        if let Err(err) = spawned {
+            log::warn!("Failed to start image decoder thread: {err}");
+        }
+
+        Self {
+            thumbnails: HashMap::new(),
+            avatars: HashMap::new(),
+            viewer: None,
+            jobs,
+            results,
+        }
+    }
+
+    /// Nhận các ảnh đã giải mã xong và tạo texture. Gọi mỗi frame.
+    pub fn poll(&mut self, ctx: &egui::Context) {
+        while let Ok(decoded) = self.results.try_recv() {
+            let preview = match decoded.image {
+                Ok(image) => {
+                    let name = match decoded.slot {
+                        Slot::Thumbnail => format!("image-thumb-{}", decoded.key),
+                        Slot::Full => format!("image-full-{}", decoded.key),
+                        Slot::Avatar => format!("avatar-{}", decoded.key),
+                    };
+                    Preview::Ready(ctx.load_texture(name, image, egui::TextureOptions::LINEAR))
+                }
+                Err(err) => {
+                    log::warn!("Failed to decode image {}: {err}", decoded.key);
+                    Preview::Failed
+                }
+            };
+            match decoded.slot {
+                Slot::Thumbnail => {
+                    self.thumbnails.insert(decoded.key, preview);
+                }
+                // Một ảnh mới hơn có thể đã thay ảnh này (giải mã theo thứ tự
+                // gửi nên kết quả sau cùng là ảnh mới nhất)
+                Slot::Avatar => {
+                    if self.avatars.contains_key(&decoded.key) {
+                        self.avatars.insert(decoded.key, preview);
+                    }
+                }
+                Slot::Full => {
+                    if let Some((file_id, slot)) = &mut self.viewer
+                        && *file_id == decoded.key
+                    {
+                        *slot = preview;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Thumbnail của ảnh đã có trên máy tại `path`; bấm vào để phóng to.
+    pub fn show_thumbnail(&mut self, ui: &mut egui::Ui, file_id: &str, path: &str) {
+        let jobs = &self.jobs;
+        let preview = self
+            .thumbnails
+            .entry(file_id.to_string())
+            .or_insert_with(|| {
+                let job = Job::Thumbnail {
+                    file_id: file_id.to_string(),
+                    path: path.to_string(),
+                };
+                match jobs.send(job) {
+                    Ok(()) => Preview::Loading,
+                    Err(_) => Preview::Failed,
+                }
+            });
+
+        let mut open = false;
+        match preview {
+            Preview::Loading => {
+                ui.spinner();
+            }
+            Preview::Ready(texture) => {
+                let response = ui
+                    .add(egui::Image::new(&*texture).sense(egui::Sense::click()))
+                    .on_hover_text("Bấm để phóng to");
+                open = response.clicked();
+            }
+            Preview::Failed => {
+                ui.label(egui::RichText::new("(không xem trước được ảnh)").weak());
+            }
+        }
+        if open {
+            self.open(file_id, path);
+        }
+    }
+
+    fn open(&mut self, file_id: &str, path: &str) {
+        let job = Job::Full {
+            file_id: file_id.to_string(),
+            path: path.to_string(),
+        };
+        let preview = match self.jobs.send(job) {
+            Ok(()) => Preview::Loading,
+            Err(_) => Preview::Failed,
+        };
+        self.viewer = Some((file_id.to_string(), preview));
+    }
+
+    /// Đặt (hoặc bỏ, khi `png` rỗng) ảnh đại diện của một peer
+    pub fn set_avatar(&mut self, peer_id: &str, png: &[u8]) {
+        if png.is_empty() {
+            self.avatars.remove(peer_id);
+            return;
+        }
+        let job = Job::Avatar {
+            peer_id: peer_id.to_string(),
+            png: png.to_vec(),
+        };
+        let preview = match self.jobs.send(job) {
+            Ok(()) => Preview::Loading,
+            Err(_) => Preview::Failed,
+        };
+        self.avatars.insert(peer_id.to_string(), preview);
+    }
+
+    /// Ảnh đại diện nhỏ của một peer (không vẽ gì nếu chưa có)
+    pub fn show_avatar(&self, ui: &mut egui::Ui, peer_id: &str, size: f32) {
+        if let Some(Preview::Ready(texture)) = self.avatars.get(peer_id) {
+            ui.add(egui::Image::new(texture).fit_to_exact_size(egui::vec2(size, size)));
+        }
+    }
+
+    /// Cửa sổ xem ảnh phóng to (nếu đang mở)
+    pub fn show_viewer(&mut self, ctx: &egui::Context) {
+        let Some((_, preview)) = &self.viewer else {
+            return;
+        };
+        let mut open = true;
+        egui::Window::new("Xem ảnh")
+            .id(egui::Id::new("image_viewer"))
+            .open(&mut open)
+            .collapsible(false)
+            .default_size([800.0, 600.0])
+            .show(ctx, |ui| match preview {
+                Preview::Loading => {
+                    ui.spinner();
+                }
+                Preview::Ready(texture) => {
+                    ui.add(egui::Image::new(texture).shrink_to_fit());
+                }
+                Preview::Failed => {
+                    ui.label("Không mở được ảnh");
+                }
+            });
+        if !open {
+            self.viewer = None;
+        }
+    }
+}
+
+impl Default for ImagePreviews {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Job {
+    fn run(self) -> Decoded {
+        match self {
+            Job::Thumbnail { file_id, path } => Decoded {
+                image: decode_thumbnail(&file_id, Path::new(&path)),
+                key: file_id,
+                slot: Slot::Thumbnail,
+            },
+            Job::Full { file_id, path } => Decoded {
+                image: decode_full(Path::new(&path)),
+                key: file_id,
+                slot: Slot::Full,
+            },
+            Job::Avatar { peer_id, png } => Decoded {
+                image: decode_avatar(&png),
+                key: peer_id,
+                slot: Slot::Avatar,
+            },
+        }
+    }
+}
+
+/// Đọc thumbnail đã lưu, hoặc tạo từ ảnh gốc rồi lưu lại.
+fn decode_thumbnail(file_id: &str, path: &Path) -> Result<egui::ColorImage, String> {
+    // Mã file luôn là hex; mã lạ không được dùng làm tên file
+    if !file_id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return open_image(path)
+            .map(|image| to_color_image(image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)));
+    }
+    let cached = thumbnail_path(file_id);
+    if cached.exists() {
+        match open_image(&cached) {
+            Ok(image) => return Ok(to_color_image(image)),
+            Err(err) => log::debug!("Ignoring unreadable thumbnail of {file_id}: {err}"),
+        }
+    }
+
+    let thumbnail = open_image(path)?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let saved = fs::create_dir_all(THUMBNAIL_DIR)
+        .map_err(|err| err.to_string())
+        .and_then(|_| {
+            thumbnail
+                .save_with_format(&cached, ImageFormat::Png)
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = saved {
+        log::warn!("Failed to cache thumbnail of {file_id}: {err}");
+    }
+    Ok(to_color_image(thumbnail))
+}
+
+fn decode_full(path: &Path) -> Result<egui::ColorImage, String> {
+    let image = open_image(path)?;
+    let image = if image.width() > VIEWER_SIZE || image.height() > VIEWER_SIZE {
+        image.resize(VIEWER_SIZE, VIEWER_SIZE, FilterType::Triangle)
+    } else {
+        image
+    };
+    Ok(to_color_image(image))
+}
+
+fn decode_avatar(png: &[u8]) -> Result<egui::ColorImage, String> {
+    let mut reader = ImageReader::with_format(Cursor::new(png), ImageFormat::Png);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_DIMENSION);
+    limits.max_alloc = Some(MAX_AVATAR_ALLOC);
+    reader.limits(limits);
+    reader
+        .decode()
+        .map(to_color_image)
+        .map_err(|err| err.to_string())
+}
+
+fn open_image(path: &Path) -> Result<DynamicImage, String> {
+    let mut reader = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|err| err.to_string())?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+    reader.decode().map_err(|err| err.to_string())
+}
+
+fn to_color_image(image: DynamicImage) -> egui::ColorImage {
+    let rgba = image.to_rgba8();
+    let size = [rgba.width() as usize, rgba.height() as usize];
+    egui::ColorImage::from_rgba_unmultiplied(size, rgba.as_raw())
+}
+
+fn thumbnail_path(file_id: &str) -> PathBuf {
+    Path::new(THUMBNAIL_DIR).join(format!("{file_id}.png"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[test]
+    fn only_png_and_jpeg_are_previewed() {
+        assert!(is_image("photo.JPG"));
+        assert!(is_image("scan.jpeg"));
+        assert!(is_image("diagram.png"));
+        assert!(!is_image("notes.txt"));
+        assert!(!is_image("png"));
+    }
+
+    #[test]
+    fn avatar_from_a_peer_must_be_small() {
+        assert!(decode_avatar(&png(64, 64)).is_ok());
+        assert!(decode_avatar(&png(MAX_AVATAR_DIMENSION + 1, 1)).is_err());
+        assert!(decode_avatar(b"not a png").is_err());
+    }
+}
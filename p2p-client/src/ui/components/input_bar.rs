@@ -21,3 +21,23 @@ pub fn render(ui: &mut egui::Ui, input_text: &mut String) -> Option<String> {
 
     None
 }
+
+/// Ô nhập đường dẫn file cần chia sẻ; trả về đường dẫn khi bấm gửi.
+pub fn render_file(ui: &mut egui::Ui, path_input: &mut String) -> Option<String> {
+    let mut send = false;
+    ui.horizontal(|ui| {
+        ui.label("📎");
+        ui.add(egui::TextEdit::singleline(path_input).hint_text("Đường dẫn file"));
+        if ui.button("Gửi file").clicked() {
+            send = true;
+        }
+    });
+
+    if send && !path_input.trim().is_empty() {
+        let path = path_input.trim().to_string();
+        path_input.clear();
+        return Some(path);
+    }
+
+    None
+}
@@ -1,4 +1,5 @@
 pub mod chat_area;
 pub mod debug_panel;
+pub mod image_preview;
 pub mod input_bar;
 pub mod sidebar;
@@ -1,44 +1,184 @@
-use crate::ui::state::AppState;
+use crate::common::{Conversation, DEFAULT_ROOM, normalize_room_name};
+use crate::ui::state::{AppState, FriendEditor};
 use eframe::egui;
 
+use super::image_preview::ImagePreviews;
+
+/// Cạnh ảnh đại diện trong danh sách (điểm)
+const AVATAR_SIZE: f32 = 16.0;
+
 #[derive(Default)]
 pub struct SidebarActions {
     pub connect_address: Option<String>,
-    pub friend_peer_id: Option<String>,
+    /// Gửi lời mời kết bạn (peer_id, lời nhắn)
+    pub friend_request: Option<(String, String)>,
+    pub accept_friend: Option<String>,
+    /// Từ chối lời mời nhận được hoặc hủy lời mời đã gửi
+    pub decline_friend: Option<String>,
+    /// Hủy kết bạn
+    pub remove_friend: Option<String>,
+    /// Lưu tên gợi nhớ và ghi chú mới của một người bạn
+    pub update_friend: Option<FriendEditor>,
+    pub block_peer: Option<String>,
+    pub unblock_peer: Option<String>,
+    /// Lưu hồ sơ của mình (tên hiển thị, trạng thái, đường dẫn ảnh đại diện mới)
+    pub set_profile: Option<(String, String, Option<String>)>,
+    pub join_room: Option<String>,
+    pub leave_room: Option<String>,
+    pub read_receipts: Option<bool>,
 }
 
-pub fn render(ui: &mut egui::Ui, state: &mut AppState) -> SidebarActions {
+pub fn render(ui: &mut egui::Ui, state: &mut AppState, previews: &ImagePreviews) -> SidebarActions {
     let mut actions = SidebarActions::default();
 
     ui.heading("Peers");
     ui.separator();
 
+    egui::CollapsingHeader::new("Hồ sơ của tôi")
+        .default_open(false)
+        .show(ui, |ui| {
+            if let Some(local_peer_id) = &state.local_peer_id {
+                previews.show_avatar(ui, local_peer_id, 48.0);
+            }
+            ui.add(
+                egui::TextEdit::singleline(&mut state.profile_name_input).hint_text("Tên hiển thị"),
+            );
+            ui.add(
+                egui::TextEdit::singleline(&mut state.profile_status_input)
+                    .hint_text("Trạng thái (vắng mặt, đang bận...)"),
+            );
+            ui.add(
+                egui::TextEdit::singleline(&mut state.profile_avatar_input)
+                    .hint_text("Đường dẫn ảnh đại diện (PNG/JPEG)"),
+            );
+            ui.horizontal(|ui| {
+                if ui.button("Lưu hồ sơ").clicked() {
+                    let avatar = state.profile_avatar_input.trim();
+                    actions.set_profile = Some((
+                        state.profile_name_input.clone(),
+                        state.profile_status_input.clone(),
+                        (!avatar.is_empty()).then(|| avatar.to_string()),
+                    ));
+                }
+                if ui.button("Bỏ ảnh").clicked() {
+                    actions.set_profile = Some((
+                        state.profile_name_input.clone(),
+                        state.profile_status_input.clone(),
+                        Some(String::new()),
+                    ));
+                }
+            });
+            if let Some(error) = &state.profile_error {
+                ui.colored_label(egui::Color32::LIGHT_RED, error);
+            }
+        });
+    ui.separator();
+
     // Manual connect section
     ui.label("Connect to Peer:");
     ui.text_edit_singleline(&mut state.peer_address_input);
-    if ui.button("Connect").clicked() {
-        if !state.peer_address_input.trim().is_empty() {
-            let address = state.peer_address_input.trim().to_string();
-            state.peer_address_input.clear();
-            actions.connect_address = Some(address);
+    if ui.button("Connect").clicked() && !state.peer_address_input.trim().is_empty() {
+        let address = state.peer_address_input.trim().to_string();
+        state.peer_address_input.clear();
+        actions.connect_address = Some(address);
+    }
+
+    if ui
+        .checkbox(&mut state.read_receipts, "Send read receipts")
+        .changed()
+    {
+        actions.read_receipts = Some(state.read_receipts);
+    }
+
+    ui.separator();
+    ui.label("Rooms:");
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(&mut state.room_input);
+        if ui.button("Join").clicked() {
+            // Tên phòng không hợp lệ thì giữ nguyên input để người dùng sửa
+            if let Some(room) = normalize_room_name(&state.room_input) {
+                actions.join_room = Some(room);
+                state.room_input.clear();
+            }
         }
+    });
+
+    // Phòng mặc định luôn đứng đầu danh sách
+    let mut rooms: Vec<String> = state.rooms.keys().cloned().collect();
+    rooms.sort_by_key(|room| room != DEFAULT_ROOM);
+    let mut selected_room = None;
+    for room in rooms {
+        ui.horizontal(|ui| {
+            let conversation = Conversation::Room(room.clone());
+            let selected = state.active_conversation == conversation;
+            if ui.selectable_label(selected, format!("# {room}")).clicked() {
+                selected_room = Some(conversation);
+            }
+            if room != DEFAULT_ROOM && ui.small_button("Leave").clicked() {
+                actions.leave_room = Some(room.clone());
+            }
+        });
+    }
+    if let Some(conversation) = selected_room {
+        state.active_conversation = conversation;
     }
 
     ui.separator();
     ui.label("Friends (Peer IDs):");
+    ui.text_edit_singleline(&mut state.friend_input);
     ui.horizontal(|ui| {
-        ui.text_edit_singleline(&mut state.friend_input);
-        if ui.button("Add").clicked() {
-            if !state.friend_input.trim().is_empty() {
-                actions.friend_peer_id = Some(state.friend_input.trim().to_string());
-                state.friend_input.clear();
-            }
+        ui.add(
+            egui::TextEdit::singleline(&mut state.friend_note_input)
+                .hint_text("Lời nhắn (tùy chọn)"),
+        );
+        if ui.button("Add").clicked() && !state.friend_input.trim().is_empty() {
+            actions.friend_request = Some((
+                state.friend_input.trim().to_string(),
+                state.friend_note_input.trim().to_string(),
+            ));
+            state.friend_input.clear();
+            state.friend_note_input.clear();
         }
     });
 
+    if !state.incoming_requests.is_empty() {
+        ui.label("Lời mời kết bạn:");
+        for (peer_id, note) in &state.incoming_requests {
+            ui.horizontal(|ui| {
+                previews.show_avatar(ui, peer_id, AVATAR_SIZE);
+                ui.label(state.display_name(peer_id, 16));
+                if ui.small_button("Đồng ý").clicked() {
+                    actions.accept_friend = Some(peer_id.clone());
+                }
+                if ui.small_button("Từ chối").clicked() {
+                    actions.decline_friend = Some(peer_id.clone());
+                }
+            });
+            if !note.is_empty() {
+                ui.label(egui::RichText::new(format!("“{note}”")).weak());
+            }
+        }
+    }
+    for peer_id in state.outgoing_requests.keys() {
+        ui.horizontal(|ui| {
+            ui.label(
+                egui::RichText::new(format!(
+                    "Đã mời {}, đang chờ trả lời",
+                    state.display_name(peer_id, 16)
+                ))
+                .weak(),
+            );
+            if ui.small_button("Hủy").clicked() {
+                actions.decline_friend = Some(peer_id.clone());
+            }
+        });
+    }
+
     if state.friends.is_empty() {
         ui.label("No friends added");
     } else {
+        let mut selected_friend = None;
+        let mut edit_friend = None;
         for status in state.friend_statuses() {
             ui.horizontal(|ui| {
                 let color = if status.online {
@@ -47,12 +187,110 @@ pub fn render(ui: &mut egui::Ui, state: &mut AppState) -> SidebarActions {
                     egui::Color32::GRAY
                 };
                 ui.colored_label(color, if status.online { "●" } else { "○" });
-                ui.label(&status.peer_id[..16.min(status.peer_id.len())]);
+                let conversation = Conversation::Direct(status.peer_id.clone());
+                let selected = state.active_conversation == conversation;
+                let peer_id = &status.peer_id;
+                previews.show_avatar(ui, peer_id, AVATAR_SIZE);
+                let mut hover = peer_id.clone();
+                let profile_status = state.profile_status(peer_id);
+                if !profile_status.is_empty() {
+                    hover.push_str(&format!("\n{profile_status}"));
+                }
+                if let Some(annotation) = state.friend_annotations.get(peer_id) {
+                    hover.push_str(&format!("\n{annotation}"));
+                }
+                // Click vào bạn bè để mở chat riêng, chuột phải để đổi tên / xóa
+                let response = ui
+                    .selectable_label(selected, state.display_name(peer_id, 16))
+                    .on_hover_text(hover);
+                if response.clicked() {
+                    selected_friend = Some(conversation);
+                }
+                response.context_menu(|ui| {
+                    if ui.button("Đổi tên / ghi chú…").clicked() {
+                        edit_friend = Some(peer_id.clone());
+                        ui.close();
+                    }
+                    if ui.button("Sao chép PeerId").clicked() {
+                        ui.ctx().copy_text(peer_id.clone());
+                        ui.close();
+                    }
+                    if ui.button("Xóa bạn").clicked() {
+                        actions.remove_friend = Some(peer_id.clone());
+                        ui.close();
+                    }
+                    if ui.button("Chặn").clicked() {
+                        actions.block_peer = Some(peer_id.clone());
+                        ui.close();
+                    }
+                });
                 ui.label(egui::RichText::new(status.message.clone()).weak());
             });
         }
+        if let Some(conversation) = selected_friend {
+            state.active_conversation = conversation;
+        }
+        if let Some(peer_id) = edit_friend {
+            state.editing_friend = Some(FriendEditor {
+                petname: state.petnames.get(&peer_id).cloned().unwrap_or_default(),
+                annotation: state
+                    .friend_annotations
+                    .get(&peer_id)
+                    .cloned()
+                    .unwrap_or_default(),
+                peer_id,
+            });
+        }
     }
 
+    let mut close_editor = false;
+    if let Some(editor) = &mut state.editing_friend {
+        ui.label(format!(
+            "Đổi tên {}:",
+            &editor.peer_id[..16.min(editor.peer_id.len())]
+        ));
+        ui.add(egui::TextEdit::singleline(&mut editor.petname).hint_text("Tên gợi nhớ"));
+        ui.add(
+            egui::TextEdit::multiline(&mut editor.annotation)
+                .hint_text("Ghi chú riêng")
+                .desired_rows(2),
+        );
+        ui.horizontal(|ui| {
+            if ui.button("Lưu").clicked() {
+                actions.update_friend = Some(editor.clone());
+                close_editor = true;
+            }
+            if ui.button("Hủy").clicked() {
+                close_editor = true;
+            }
+        });
+    }
+    if close_editor {
+        state.editing_friend = None;
+    }
+
+    ui.separator();
+    egui::CollapsingHeader::new(format!("Đã chặn ({})", state.blocked.len()))
+        .default_open(false)
+        .show(ui, |ui| {
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut state.block_input).hint_text("PeerId"));
+                if ui.button("Chặn").clicked() && !state.block_input.trim().is_empty() {
+                    actions.block_peer = Some(state.block_input.trim().to_string());
+                    state.block_input.clear();
+                }
+            });
+            for peer_id in &state.blocked {
+                ui.horizontal(|ui| {
+                    ui.label(state.display_name(peer_id, 16))
+                        .on_hover_text(peer_id);
+                    if ui.small_button("Bỏ chặn").clicked() {
+                        actions.unblock_peer = Some(peer_id.clone());
+                    }
+                });
+            }
+        });
+
     ui.separator();
     ui.label("Connected Peers:");
 
@@ -66,8 +304,20 @@ pub fn render(ui: &mut egui::Ui, state: &mut AppState) -> SidebarActions {
             // Hiển thị trạng thái online với màu xanh
             ui.colored_label(egui::Color32::GREEN, "●");
 
-            // Hiển thị peer ID (rút ngắn)
-            ui.label(&peer_id[..16.min(peer_id.len())]);
+            // Hiển thị ảnh đại diện, tên hoặc peer ID (rút ngắn)
+            previews.show_avatar(ui, peer_id, AVATAR_SIZE);
+            ui.label(state.display_name(peer_id, 16))
+                .on_hover_text(peer_id)
+                .context_menu(|ui| {
+                    if ui.button("Sao chép PeerId").clicked() {
+                        ui.ctx().copy_text(peer_id.clone());
+                        ui.close();
+                    }
+                    if ui.button("Chặn").clicked() {
+                        actions.block_peer = Some(peer_id.clone());
+                        ui.close();
+                    }
+                });
 
             // Hiển thị last seen nếu có
             if let Some(last_seen) = state.peer_last_seen.get(peer_id) {
@@ -1,6 +1,15 @@
-use crate::common::{ChatMessage, PeerStatus};
+use crate::common::{
+    ChatMessage, Conversation, DEFAULT_ROOM, DeliveryStatus, MessageOp, PeerProfile, PeerStatus,
+    Reactions,
+};
 use chrono::{DateTime, Utc};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Thời gian hiển thị "đang gõ" sau tín hiệu cuối cùng
+const TYPING_DISPLAY_DURATION: Duration = Duration::from_secs(5);
+/// Khoảng cách tối thiểu giữa hai lần gửi tín hiệu "đang gõ"
+const TYPING_SEND_INTERVAL: Duration = Duration::from_secs(3);
 
 /// Debug event để hiển thị thông tin mạng
 #[derive(Debug, Clone)]
@@ -11,9 +20,47 @@ pub struct DebugEvent {
     pub message: String,
 }
 
+/// Trạng thái tải một file được chia sẻ
+#[derive(Debug, Clone)]
+pub enum FileTransfer {
+    InProgress {
+        received: u64,
+        total: u64,
+        /// Số peer đang cung cấp file
+        sources: usize,
+    },
+    /// Có sẵn trên máy tại đường dẫn này (đã tải xong hoặc do mình chia sẻ)
+    Completed(String),
+    Failed(String),
+}
+
+/// Người bạn đang được đổi tên / ghi chú trong sidebar
+#[derive(Debug, Clone)]
+pub struct FriendEditor {
+    pub peer_id: String,
+    pub petname: String,
+    pub annotation: String,
+}
+
+/// Tên hiển thị của một peer: tên trong `names` (xem [`AppState::names`]),
+/// không có thì `len` ký tự đầu của PeerId
+pub fn display_name<'a>(
+    names: &'a HashMap<String, String>,
+    peer_id: &'a str,
+    len: usize,
+) -> &'a str {
+    match names.get(peer_id) {
+        Some(name) => name,
+        None => &peer_id[..len.min(peer_id.len())],
+    }
+}
+
 /// Trạng thái cục bộ của UI.
 pub struct AppState {
-    pub messages: Vec<ChatMessage>,
+    /// Tin nhắn theo từng phòng đã tham gia (tên phòng -> tin nhắn)
+    pub rooms: BTreeMap<String, Vec<ChatMessage>>,
+    /// Input tên phòng muốn tham gia
+    pub room_input: String,
     pub input_text: String,
     pub peer_address_input: String,
     pub peers: Vec<String>,
@@ -22,40 +69,349 @@ pub struct AppState {
     pub peer_last_seen: HashMap<String, DateTime<Utc>>,
     /// Input lưu peer_id bạn bè do người dùng nhập
     pub friend_input: String,
+    /// Lời nhắn gửi kèm lời mời kết bạn
+    pub friend_note_input: String,
     /// Danh sách bạn bè (theo peer_id) và trạng thái mới nhất
     pub friends: BTreeMap<String, PeerStatus>,
+    /// Lời mời kết bạn nhận được (peer_id -> lời nhắn)
+    pub incoming_requests: BTreeMap<String, String>,
+    /// Lời mời kết bạn đã gửi (peer_id -> lời nhắn)
+    pub outgoing_requests: BTreeMap<String, String>,
+    /// Tên gợi nhớ mình đặt cho bạn bè (peer_id -> tên), thay cho PeerId khi hiển thị
+    pub petnames: HashMap<String, String>,
+    /// Ghi chú riêng về bạn bè (peer_id -> ghi chú)
+    pub friend_annotations: HashMap<String, String>,
+    /// Người bạn đang được đổi tên / ghi chú
+    pub editing_friend: Option<FriendEditor>,
+    /// Hồ sơ đã biết của các peer, kể cả của mình
+    pub profiles: HashMap<String, PeerProfile>,
+    /// Tên hiển thị của từng peer: tên gợi nhớ, không có thì tên trong hồ sơ
+    pub names: HashMap<String, String>,
+    /// Ô nhập hồ sơ của mình
+    pub profile_name_input: String,
+    pub profile_status_input: String,
+    /// Đường dẫn ảnh đại diện mới (để trống thì giữ ảnh cũ)
+    pub profile_avatar_input: String,
+    /// Lỗi gần nhất khi đổi hồ sơ
+    pub profile_error: Option<String>,
+    /// Peer đã bị chặn
+    pub blocked: BTreeSet<String>,
+    /// Input PeerId muốn chặn
+    pub block_input: String,
+    /// Tin nhắn trong phòng của peer bị chặn, cất đi để hiện lại khi bỏ chặn
+    hidden_messages: HashMap<String, Vec<ChatMessage>>,
+    /// Tin nhắn riêng với peer bị chặn
+    hidden_direct: HashMap<String, Vec<ChatMessage>>,
+    /// Tin nhắn riêng theo từng người bạn (peer_id -> tin nhắn)
+    pub direct_messages: BTreeMap<String, Vec<ChatMessage>>,
+    /// Cuộc trò chuyện đang mở trong khung chat
+    pub active_conversation: Conversation,
+    /// PeerId của chính mình (biết sau khi tầng mạng khởi động)
+    pub local_peer_id: Option<String>,
+    /// Trạng thái gửi tốt nhất của từng tin nhắn do mình gửi
+    pub delivery: HashMap<String, DeliveryStatus>,
+    /// Có gửi read receipt hay không
+    pub read_receipts: bool,
+    /// Reaction trên từng tin nhắn (id -> emoji -> peer)
+    pub reactions: HashMap<String, Reactions>,
+    /// Tin nhắn của mình đang được sửa trong ô nhập (cuộc trò chuyện, id)
+    pub editing: Option<(Conversation, String)>,
+    /// Tin nhắn đang được trả lời (cuộc trò chuyện, id)
+    pub replying_to: Option<(Conversation, String)>,
+    /// Input đường dẫn file muốn chia sẻ
+    pub file_path_input: String,
+    /// Tiến độ tải các file (file_id -> trạng thái)
+    pub transfers: HashMap<String, FileTransfer>,
+    /// Tin nhắn gốc không tải được (id), để câu trả lời không báo "đang tải..." mãi
+    pub missing_parents: HashSet<String>,
+    /// Tin nhắn của người khác đã báo "đã đọc" xuống tầng mạng trong phiên này
+    marked_read: HashSet<String>,
+    /// Ai đang gõ ở đâu, và lần cuối nhận tín hiệu
+    typing: HashMap<(Conversation, String), Instant>,
+    /// Lần cuối mình gửi tín hiệu "đang gõ" (và cho cuộc trò chuyện nào)
+    last_typing_sent: Option<(Conversation, Instant)>,
 }
 
 impl AppState {
     pub fn new() -> Self {
         Self {
-            messages: Vec::new(),
+            rooms: BTreeMap::from([(DEFAULT_ROOM.to_string(), Vec::new())]),
+            room_input: String::new(),
             input_text: String::new(),
             peer_address_input: String::new(),
             peers: Vec::new(),
             debug_events: Vec::new(),
             peer_last_seen: HashMap::new(),
             friend_input: String::new(),
+            friend_note_input: String::new(),
             friends: BTreeMap::new(),
+            incoming_requests: BTreeMap::new(),
+            outgoing_requests: BTreeMap::new(),
+            petnames: HashMap::new(),
+            friend_annotations: HashMap::new(),
+            editing_friend: None,
+            profiles: HashMap::new(),
+            names: HashMap::new(),
+            profile_name_input: String::new(),
+            profile_status_input: String::new(),
+            profile_avatar_input: String::new(),
+            profile_error: None,
+            blocked: BTreeSet::new(),
+            block_input: String::new(),
+            hidden_messages: HashMap::new(),
+            hidden_direct: HashMap::new(),
+            direct_messages: BTreeMap::new(),
+            active_conversation: Conversation::default(),
+            local_peer_id: None,
+            delivery: HashMap::new(),
+            read_receipts: true,
+            reactions: HashMap::new(),
+            editing: None,
+            replying_to: None,
+            file_path_input: String::new(),
+            transfers: HashMap::new(),
+            missing_parents: HashSet::new(),
+            marked_read: HashSet::new(),
+            typing: HashMap::new(),
+            last_typing_sent: None,
         }
     }
 
     pub fn push_message(&mut self, message: ChatMessage) {
-        self.messages.push(message.clone());
+        if self.blocked.contains(&message.sender) {
+            return;
+        }
+        // Tin nhắn đến thì người gửi đã gõ xong
+        self.typing.remove(&(
+            Conversation::Room(message.room.clone()),
+            message.sender.clone(),
+        ));
+        // Tin nhắn của phòng vừa rời (còn trên đường truyền) bị bỏ qua
+        let Some(room) = self.rooms.get_mut(&message.room) else {
+            return;
+        };
+        if room.iter().any(|existing| existing.id == message.id) {
+            return;
+        }
+        room.push(message.clone());
+        let sender = self.display_name(&message.sender, 8).to_string();
         self.add_debug_event(
             "MESSAGE_RECEIVED".to_string(),
             Some(message.sender.clone()),
-            format!(
-                "Message from {}: {}",
-                &message.sender[..8],
-                &message.content
-            ),
+            format!("Message from {sender}: {}", &message.content),
+        );
+    }
+
+    /// Nạp danh sách phòng đã tham gia khi khởi động
+    pub fn load_rooms(&mut self, rooms: Vec<String>) {
+        for room in rooms {
+            self.rooms.entry(room).or_default();
+        }
+    }
+
+    pub fn join_room(&mut self, room: String) {
+        self.add_debug_event("ROOM_JOINED".to_string(), None, format!("Joined #{room}"));
+        self.rooms.entry(room.clone()).or_default();
+        self.active_conversation = Conversation::Room(room);
+    }
+
+    pub fn leave_room(&mut self, room: &str) {
+        if self.rooms.remove(room).is_none() {
+            return;
+        }
+        self.add_debug_event("ROOM_LEFT".to_string(), None, format!("Left #{room}"));
+        if self.active_conversation == Conversation::Room(room.to_string()) {
+            self.active_conversation = Conversation::default();
+        }
+    }
+
+    /// Nạp lịch sử đã lưu khi khởi động (không tạo debug event cho từng tin).
+    /// Tin của peer bị chặn được cất riêng, nên cần nạp danh sách chặn trước.
+    pub fn load_history(&mut self, history: Vec<ChatMessage>) {
+        if history.is_empty() {
+            return;
+        }
+        self.add_debug_event(
+            "HISTORY_LOADED".to_string(),
+            None,
+            format!("Loaded {} stored messages", history.len()),
         );
+        for message in history {
+            if self.blocked.contains(&message.sender) {
+                self.hidden_messages
+                    .entry(message.sender.clone())
+                    .or_default()
+                    .push(message);
+            } else if let Some(room) = self.rooms.get_mut(&message.room) {
+                room.push(message);
+            }
+        }
     }
 
-    pub fn push_history(&mut self, mut history: Vec<ChatMessage>) {
-        self.messages.append(&mut history);
-        self.messages.sort_by_key(|message| message.timestamp);
+    pub fn push_history(&mut self, history: Vec<ChatMessage>) {
+        let known: HashSet<String> = self
+            .rooms
+            .values()
+            .flatten()
+            .map(|m| m.id.clone())
+            .collect();
+        let fresh: Vec<ChatMessage> = history
+            .into_iter()
+            .filter(|message| {
+                !known.contains(&message.id)
+                    && self.rooms.contains_key(&message.room)
+                    && !self.blocked.contains(&message.sender)
+            })
+            .collect();
+        if fresh.is_empty() {
+            return;
+        }
+
+        self.add_debug_event(
+            "HISTORY_SYNCED".to_string(),
+            None,
+            format!("Synced {} messages from history", fresh.len()),
+        );
+        for message in fresh {
+            if let Some(room) = self.rooms.get_mut(&message.room) {
+                room.push(message);
+            }
+        }
+        for room in self.rooms.values_mut() {
+            room.sort_by_key(|message| message.timestamp);
+        }
+    }
+
+    pub fn push_direct_message(&mut self, peer_id: String, message: ChatMessage) {
+        if self.blocked.contains(&peer_id) {
+            return;
+        }
+        self.typing.remove(&(
+            Conversation::Direct(peer_id.clone()),
+            message.sender.clone(),
+        ));
+        if message.sender == peer_id {
+            let sender = self.display_name(&peer_id, 8).to_string();
+            self.add_debug_event(
+                "DIRECT_MESSAGE".to_string(),
+                Some(peer_id.clone()),
+                format!("Direct message from {sender}"),
+            );
+        }
+        let conversation = self.direct_messages.entry(peer_id).or_default();
+        if !conversation
+            .iter()
+            .any(|existing| existing.id == message.id)
+        {
+            conversation.push(message);
+        }
+    }
+
+    /// Nạp tin nhắn riêng đã lưu khi khởi động (cuộc trò chuyện với peer bị
+    /// chặn được cất riêng)
+    pub fn load_direct_history(&mut self, history: Vec<(String, ChatMessage)>) {
+        for (peer_id, message) in history {
+            if self.blocked.contains(&peer_id) {
+                self.hidden_direct.entry(peer_id).or_default().push(message);
+            } else {
+                self.direct_messages
+                    .entry(peer_id)
+                    .or_default()
+                    .push(message);
+            }
+        }
+    }
+
+    pub fn set_typing(&mut self, conversation: Conversation, peer_id: String) {
+        self.typing.insert((conversation, peer_id), Instant::now());
+    }
+
+    /// Những người đang gõ trong cuộc trò chuyện đang mở (bỏ các tín hiệu đã hết hạn)
+    pub fn typing_in_active(&mut self) -> Vec<String> {
+        self.typing
+            .retain(|_, seen| seen.elapsed() < TYPING_DISPLAY_DURATION);
+        let mut peers: Vec<String> = self
+            .typing
+            .keys()
+            .filter(|(conversation, _)| *conversation == self.active_conversation)
+            .map(|(_, peer_id)| peer_id.clone())
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    /// Có nên gửi tín hiệu "đang gõ" cho cuộc trò chuyện đang mở không (giới hạn tần suất)
+    pub fn should_send_typing(&mut self) -> bool {
+        let now = Instant::now();
+        if let Some((conversation, sent_at)) = &self.last_typing_sent
+            && *conversation == self.active_conversation
+            && now.duration_since(*sent_at) < TYPING_SEND_INTERVAL
+        {
+            return false;
+        }
+        self.last_typing_sent = Some((self.active_conversation.clone(), now));
+        true
+    }
+
+    /// Áp dụng thao tác sửa/xóa lên tin nhắn đang hiển thị (nếu có)
+    pub fn apply_op(&mut self, op: &MessageOp) {
+        let conversations = if op.room.is_empty() {
+            self.direct_messages.values_mut().collect::<Vec<_>>()
+        } else {
+            self.rooms.get_mut(&op.room).into_iter().collect()
+        };
+        for messages in conversations {
+            if let Some(message) = messages
+                .iter_mut()
+                .find(|message| message.id == op.target_id && message.sender == op.author)
+            {
+                message.apply_op(&op.action);
+                return;
+            }
+        }
+    }
+
+    pub fn update_reactions(&mut self, message_id: String, reactions: Reactions) {
+        if reactions.is_empty() {
+            self.reactions.remove(&message_id);
+        } else {
+            self.reactions.insert(message_id, reactions);
+        }
+    }
+
+    pub fn update_delivery(&mut self, message_id: String, status: DeliveryStatus) {
+        let current = self.delivery.entry(message_id).or_insert(status);
+        *current = (*current).max(status);
+    }
+
+    /// Id các tin nhắn của người khác trong cuộc trò chuyện đang mở chưa được
+    /// đánh dấu đã đọc; đánh dấu luôn để không gửi lại.
+    pub fn take_unread_in_active(&mut self) -> Vec<String> {
+        let Some(local_peer_id) = self.local_peer_id.clone() else {
+            return Vec::new();
+        };
+        let unread: Vec<String> = self
+            .active_messages()
+            .iter()
+            .filter(|message| {
+                message.sender != local_peer_id && !self.marked_read.contains(&message.id)
+            })
+            .map(|message| message.id.clone())
+            .collect();
+        self.marked_read.extend(unread.iter().cloned());
+        unread
+    }
+
+    /// Tin nhắn của cuộc trò chuyện đang mở
+    pub fn active_messages(&self) -> &[ChatMessage] {
+        match &self.active_conversation {
+            Conversation::Room(room) => self.rooms.get(room).map(Vec::as_slice).unwrap_or(&[]),
+            Conversation::Direct(peer_id) => self
+                .direct_messages
+                .get(peer_id)
+                .map(Vec::as_slice)
+                .unwrap_or(&[]),
+        }
     }
 
     pub fn add_peer(&mut self, peer_id: String) {
@@ -136,21 +492,6 @@ impl AppState {
         }
     }
 
-    /// Tính thời gian không thấy peer (seconds)
-    pub fn get_time_since_last_seen(&self, peer_id: &str) -> Option<f64> {
-        if !self.peers.contains(&peer_id.to_string()) {
-            // Peer đã disconnect, tính từ last_seen
-            self.peer_last_seen.get(peer_id).map(|last_seen| {
-                let now = Utc::now();
-                let duration = now.signed_duration_since(*last_seen);
-                duration.num_milliseconds() as f64 / 1000.0
-            })
-        } else {
-            // Peer đang online, return None hoặc 0
-            None
-        }
-    }
-
     pub fn upsert_friend_status(&mut self, status: PeerStatus) {
         if status.online {
             self.add_debug_event(
@@ -171,4 +512,252 @@ impl AppState {
     pub fn friend_statuses(&self) -> impl Iterator<Item = &PeerStatus> {
         self.friends.values()
     }
+
+    pub fn add_friend_request(&mut self, peer_id: String, note: String, incoming: bool) {
+        if incoming {
+            self.add_debug_event(
+                "FRIEND_REQUEST".to_string(),
+                Some(peer_id.clone()),
+                format!("Lời mời kết bạn: {note}"),
+            );
+            self.incoming_requests.insert(peer_id, note);
+        } else {
+            self.outgoing_requests.insert(peer_id, note);
+        }
+    }
+
+    pub fn close_friend_request(&mut self, peer_id: &str) {
+        self.incoming_requests.remove(peer_id);
+        self.outgoing_requests.remove(peer_id);
+    }
+
+    pub fn set_friend_info(&mut self, peer_id: String, petname: String, annotation: String) {
+        if petname.is_empty() {
+            self.petnames.remove(&peer_id);
+        } else {
+            self.petnames.insert(peer_id.clone(), petname);
+        }
+        if annotation.is_empty() {
+            self.friend_annotations.remove(&peer_id);
+        } else {
+            self.friend_annotations.insert(peer_id.clone(), annotation);
+        }
+        self.refresh_name(&peer_id);
+    }
+
+    /// Bỏ một người khỏi danh sách bạn bè (tin nhắn cũ vẫn được giữ)
+    pub fn remove_friend(&mut self, peer_id: &str) {
+        self.add_debug_event(
+            "FRIEND_REMOVED".to_string(),
+            Some(peer_id.to_string()),
+            format!("Không còn là bạn: {}", self.display_name(peer_id, 16)),
+        );
+        self.friends.remove(peer_id);
+        self.petnames.remove(peer_id);
+        self.friend_annotations.remove(peer_id);
+        self.refresh_name(peer_id);
+        if self
+            .editing_friend
+            .as_ref()
+            .is_some_and(|editor| editor.peer_id == peer_id)
+        {
+            self.editing_friend = None;
+        }
+        if self.active_conversation == Conversation::Direct(peer_id.to_string()) {
+            self.active_conversation = Conversation::default();
+        }
+    }
+
+    /// Nạp danh sách peer bị chặn khi khởi động (trước khi nạp lịch sử)
+    pub fn load_blocked(&mut self, peers: Vec<String>) {
+        for peer_id in peers {
+            self.hide_peer(peer_id);
+        }
+    }
+
+    pub fn block_peer(&mut self, peer_id: String) {
+        self.add_debug_event(
+            "PEER_BLOCKED".to_string(),
+            Some(peer_id.clone()),
+            format!("Đã chặn {}", self.display_name(&peer_id, 16)),
+        );
+        self.hide_peer(peer_id);
+    }
+
+    /// Bỏ chặn và hiện lại các tin nhắn cũ của peer
+    pub fn unblock_peer(&mut self, peer_id: &str) {
+        if !self.blocked.remove(peer_id) {
+            return;
+        }
+        self.add_debug_event(
+            "PEER_UNBLOCKED".to_string(),
+            Some(peer_id.to_string()),
+            format!("Đã bỏ chặn {}", self.display_name(peer_id, 16)),
+        );
+        for message in self.hidden_messages.remove(peer_id).unwrap_or_default() {
+            if let Some(room) = self.rooms.get_mut(&message.room) {
+                room.push(message);
+            }
+        }
+        for room in self.rooms.values_mut() {
+            room.sort_by_key(|message| message.timestamp);
+        }
+        if let Some(hidden) = self.hidden_direct.remove(peer_id) {
+            let conversation = self.direct_messages.entry(peer_id.to_string()).or_default();
+            conversation.extend(hidden);
+            conversation.sort_by_key(|message| message.timestamp);
+        }
+    }
+
+    /// Cất tin nhắn của một peer bị chặn khỏi mọi cuộc trò chuyện
+    fn hide_peer(&mut self, peer_id: String) {
+        let mut hidden = Vec::new();
+        for messages in self.rooms.values_mut() {
+            messages.retain(|message| {
+                if message.sender == peer_id {
+                    hidden.push(message.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.hidden_messages
+            .entry(peer_id.clone())
+            .or_default()
+            .extend(hidden);
+        if let Some(conversation) = self.direct_messages.remove(&peer_id) {
+            self.hidden_direct.insert(peer_id.clone(), conversation);
+        }
+        self.typing
+            .retain(|(_, typing_peer), _| *typing_peer != peer_id);
+        self.incoming_requests.remove(&peer_id);
+        if self.active_conversation == Conversation::Direct(peer_id.clone()) {
+            self.active_conversation = Conversation::default();
+        }
+        self.blocked.insert(peer_id);
+    }
+
+    /// Tên hiển thị của một peer (xem [`display_name`])
+    pub fn display_name<'a>(&'a self, peer_id: &'a str, len: usize) -> &'a str {
+        display_name(&self.names, peer_id, len)
+    }
+
+    /// Nạp các hồ sơ đã lưu khi khởi động
+    pub fn load_profiles(&mut self, profiles: Vec<PeerProfile>) {
+        for profile in profiles {
+            let peer_id = profile.peer_id.clone();
+            self.profiles.insert(peer_id.clone(), profile);
+            self.refresh_name(&peer_id);
+        }
+    }
+
+    pub fn set_profile(&mut self, profile: PeerProfile) {
+        let peer_id = profile.peer_id.clone();
+        let own = self.local_peer_id.as_ref() == Some(&peer_id);
+        if !own {
+            let previous = self.display_name(&peer_id, 8).to_string();
+            self.add_debug_event(
+                "PROFILE_UPDATED".to_string(),
+                Some(peer_id.clone()),
+                format!("Hồ sơ của {previous}: {}", profile.display_name),
+            );
+        }
+        self.profiles.insert(peer_id.clone(), profile);
+        self.refresh_name(&peer_id);
+        if own {
+            self.profile_error = None;
+            self.fill_profile_inputs();
+        }
+    }
+
+    /// Điền ô nhập hồ sơ bằng hồ sơ hiện tại của mình
+    pub fn fill_profile_inputs(&mut self) {
+        let Some(profile) = self
+            .local_peer_id
+            .as_ref()
+            .and_then(|local| self.profiles.get(local))
+        else {
+            return;
+        };
+        self.profile_name_input = profile.display_name.clone();
+        self.profile_status_input = profile.status.clone();
+        self.profile_avatar_input.clear();
+    }
+
+    /// Dòng trạng thái trong hồ sơ của một peer (rỗng nếu không có)
+    pub fn profile_status(&self, peer_id: &str) -> &str {
+        self.profiles
+            .get(peer_id)
+            .map_or("", |profile| profile.status.as_str())
+    }
+
+    fn refresh_name(&mut self, peer_id: &str) {
+        let name = self
+            .petnames
+            .get(peer_id)
+            .or_else(|| {
+                self.profiles
+                    .get(peer_id)
+                    .map(|profile| &profile.display_name)
+            })
+            .filter(|name| !name.is_empty())
+            .cloned();
+        match name {
+            Some(name) => self.names.insert(peer_id.to_string(), name),
+            None => self.names.remove(peer_id),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, sender: &str) -> ChatMessage {
+        ChatMessage {
+            id: id.to_string(),
+            sender: sender.to_string(),
+            content: "xin chào".to_string(),
+            timestamp: 0,
+            room: "general".to_string(),
+            edited: false,
+            deleted: false,
+            reply_to: None,
+            attachment: None,
+        }
+    }
+
+    #[test]
+    fn loaded_history_of_a_blocked_peer_stays_hidden() {
+        let mut state = AppState::new();
+        state.load_rooms(vec!["general".to_string()]);
+        state.load_blocked(vec!["blocked".to_string()]);
+        state.load_history(vec![message("1", "friend"), message("2", "blocked")]);
+        state.load_direct_history(vec![("blocked".to_string(), message("3", "blocked"))]);
+        assert_eq!(state.rooms["general"].len(), 1);
+        assert!(!state.direct_messages.contains_key("blocked"));
+
+        state.unblock_peer("blocked");
+        assert_eq!(state.rooms["general"].len(), 2);
+        assert_eq!(state.direct_messages["blocked"].len(), 1);
+    }
+
+    #[test]
+    fn typing_signal_expires_and_stays_in_its_conversation() {
+        let mut state = AppState::new();
+        let room = Conversation::Room("general".to_string());
+        state.active_conversation = room.clone();
+        state.set_typing(room.clone(), "fresh".to_string());
+        state.set_typing(
+            Conversation::Direct("friend".to_string()),
+            "friend".to_string(),
+        );
+        state.typing.insert(
+            (room, "stale".to_string()),
+            Instant::now() - TYPING_DISPLAY_DURATION,
+        );
+        assert_eq!(state.typing_in_active(), vec!["fresh".to_string()]);
+        assert_eq!(state.typing.len(), 2);
+    }
 }
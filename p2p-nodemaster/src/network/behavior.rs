@@ -6,9 +6,12 @@ use libp2p::identify;
 use libp2p::kad::{self, store::MemoryStore, Mode as KadMode};
 use libp2p::ping;
 use libp2p::relay;
+use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::swarm::NetworkBehaviour;
 use libp2p::{identity, PeerId};
 
+use super::mailbox::{build_mailbox_behaviour, MailboxBehaviour, MailboxEvent};
+
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "NodeBehaviorEvent")]
 pub struct NodeBehavior {
@@ -18,6 +21,8 @@ pub struct NodeBehavior {
     pub autonat: autonat::Behaviour,
     pub dcutr: dcutr::Behaviour,
     pub ping: ping::Behaviour,
+    /// Only present when the mailbox is enabled, so the protocol is not advertised otherwise
+    pub mailbox: Toggle<MailboxBehaviour>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -28,6 +33,7 @@ pub enum NodeBehaviorEvent {
     Autonat(autonat::Event),
     Dcutr(dcutr::Event),
     Ping(ping::Event),
+    Mailbox(MailboxEvent),
 }
 
 impl From<kad::Event> for NodeBehaviorEvent {
@@ -66,9 +72,16 @@ impl From<ping::Event> for NodeBehaviorEvent {
     }
 }
 
+impl From<MailboxEvent> for NodeBehaviorEvent {
+    fn from(event: MailboxEvent) -> Self {
+        NodeBehaviorEvent::Mailbox(event)
+    }
+}
+
 pub fn build_behavior(
     local_key: &identity::Keypair,
     local_peer_id: PeerId,
+    enable_mailbox: bool,
 ) -> Result<NodeBehavior, Box<dyn Error>> {
     // Configure Kademlia as server mode (bootstrap node)
     let store = MemoryStore::new(local_peer_id);
@@ -83,6 +96,7 @@ pub fn build_behavior(
     let autonat = autonat::Behaviour::new(local_peer_id, autonat::Config::default());
     let dcutr = dcutr::Behaviour::new(local_peer_id);
     let ping = ping::Behaviour::new(ping::Config::default());
+    let mailbox = Toggle::from(enable_mailbox.then(build_mailbox_behaviour));
 
     Ok(NodeBehavior {
         kad,
@@ -91,5 +105,6 @@ pub fn build_behavior(
         autonat,
         dcutr,
         ping,
+        mailbox,
     })
 }
@@ -0,0 +1,634 @@
+//! Store-and-forward mailbox for clients that are offline.
+//!
+//! Opt-in with `NODE_MAILBOX=1`. A client that cannot reach a friend leaves the
+//! end-to-end-encrypted message here, addressed to the friend's PeerId, and the
+//! friend fetches and acknowledges it on its next connection. The node only
+//! ever sees ciphertext. Every recipient and every sender has a quota (messages
+//! and bytes), the node caps the bytes it keeps overall, and messages expire
+//! after a TTL. Mailboxes are saved to `data/mailbox.bin` periodically so a
+//! restart does not lose them.
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::Utc;
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+/// Protocol clients use to leave and collect messages.
+pub const MAILBOX_PROTOCOL: StreamProtocol = StreamProtocol::new("/rust-p2p-chat/mailbox/1.0.0");
+
+const MAILBOX_PATH: &str = "data/mailbox.bin";
+
+/// Where mailboxes were saved, as JSON, before `MAILBOX_PATH`.
+const LEGACY_MAILBOX_PATH: &str = "data/mailbox.json";
+
+/// Messages kept per recipient unless `NODE_MAILBOX_QUOTA` says otherwise.
+const DEFAULT_QUOTA_MESSAGES: usize = 500;
+
+/// Hours a message is kept unless `NODE_MAILBOX_TTL_HOURS` says otherwise.
+const DEFAULT_TTL_HOURS: i64 = 7 * 24;
+
+/// Bytes kept per recipient.
+const QUOTA_BYTES: usize = 8 * 1024 * 1024;
+
+/// Messages one sender may have waiting, over all recipients, unless
+/// `NODE_MAILBOX_SENDER_QUOTA` says otherwise.
+const DEFAULT_SENDER_QUOTA_MESSAGES: usize = 200;
+
+/// Bytes one sender may have waiting, over all recipients.
+const SENDER_QUOTA_BYTES: usize = 4 * 1024 * 1024;
+
+/// Megabytes kept over all mailboxes unless `NODE_MAILBOX_MAX_MB` says otherwise.
+const DEFAULT_MAX_MB: usize = 256;
+
+/// Largest message accepted.
+const MAX_PAYLOAD_SIZE: usize = 256 * 1024;
+
+/// Messages and bytes returned by one fetch, well under the response size limit.
+const FETCH_MAX_ITEMS: usize = 100;
+const FETCH_MAX_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MailboxRequest {
+    /// Keep an end-to-end-encrypted message for an offline recipient
+    Deposit { recipient: String, payload: Vec<u8> },
+    /// Ask for the messages waiting for the requesting peer
+    Fetch,
+    /// Delete fetched messages once the recipient has them
+    Ack { ids: Vec<u64> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MailboxResponse {
+    Stored,
+    /// The recipient's mailbox, the sender's share or the node's storage is full
+    QuotaExceeded,
+    TooLarge,
+    InvalidRecipient,
+    Messages {
+        items: Vec<MailboxItem>,
+        /// More messages are waiting after these
+        more: bool,
+    },
+    Acked,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxItem {
+    pub id: u64,
+    /// PeerId of the client that left the message
+    pub sender: String,
+    pub payload: Vec<u8>,
+    pub deposited_at: i64,
+}
+
+pub type MailboxBehaviour = request_response::cbor::Behaviour<MailboxRequest, MailboxResponse>;
+pub type MailboxEvent = request_response::Event<MailboxRequest, MailboxResponse>;
+
+pub fn build_mailbox_behaviour() -> MailboxBehaviour {
+    request_response::cbor::Behaviour::new(
+        [(MAILBOX_PROTOCOL, ProtocolSupport::Inbound)],
+        request_response::Config::default(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct MailboxConfig {
+    pub quota_messages: usize,
+    pub sender_quota_messages: usize,
+    pub max_bytes: usize,
+    pub ttl_secs: i64,
+}
+
+impl MailboxConfig {
+    /// Read the mailbox settings from the environment; `None` when disabled.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let enabled = matches!(
+            env::var("NODE_MAILBOX").as_deref().map(str::trim),
+            Ok("1") | Ok("true")
+        );
+        if !enabled {
+            return Ok(None);
+        }
+        let quota_messages = parse_positive_env("NODE_MAILBOX_QUOTA", DEFAULT_QUOTA_MESSAGES)?;
+        let sender_quota_messages =
+            parse_positive_env("NODE_MAILBOX_SENDER_QUOTA", DEFAULT_SENDER_QUOTA_MESSAGES)?;
+        let max_mb: usize = parse_positive_env("NODE_MAILBOX_MAX_MB", DEFAULT_MAX_MB)?;
+        let ttl_hours: i64 = parse_positive_env("NODE_MAILBOX_TTL_HOURS", DEFAULT_TTL_HOURS)?;
+        Ok(Some(Self {
+            quota_messages,
+            sender_quota_messages,
+            max_bytes: max_mb
+                .checked_mul(1024 * 1024)
+                .ok_or("NODE_MAILBOX_MAX_MB is too large")?,
+            ttl_secs: ttl_hours
+                .checked_mul(3600)
+                .ok_or("NODE_MAILBOX_TTL_HOURS is too large")?,
+        }))
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Stored {
+    next_id: u64,
+    /// Recipient PeerId -> messages, oldest first
+    boxes: HashMap<String, VecDeque<MailboxItem>>,
+}
+
+/// Messages and bytes waiting in a mailbox or left by a sender.
+#[derive(Default, Clone, Copy)]
+struct Usage {
+    messages: usize,
+    bytes: usize,
+}
+
+impl Usage {
+    fn add(&mut self, item: &MailboxItem) {
+        self.messages += 1;
+        self.bytes += item.payload.len();
+    }
+
+    fn remove(&mut self, item: &MailboxItem) {
+        self.messages -= 1;
+        self.bytes -= item.payload.len();
+    }
+}
+
+pub struct Mailbox {
+    config: MailboxConfig,
+    stored: Stored,
+    /// Usage per recipient, per sender and overall, kept in step with `stored`
+    recipients: HashMap<String, Usage>,
+    senders: HashMap<String, Usage>,
+    total: Usage,
+    /// Changed since the last save
+    dirty: bool,
+    /// A save is being written in the background
+    saving: Arc<AtomicBool>,
+    /// The last save failed, so the changes it held are still unsaved
+    save_failed: Arc<AtomicBool>,
+}
+
+impl Mailbox {
+    /// Open the mailboxes saved by an earlier run.
+    pub fn load(config: MailboxConfig) -> Self {
+        let (stored, legacy) = match fs::read(MAILBOX_PATH) {
+            Ok(bytes) => (
+                postcard::from_bytes(&bytes).unwrap_or_else(|err| {
+                    log::warn!("Ignoring unreadable {MAILBOX_PATH}: {err}");
+                    Stored::default()
+                }),
+                false,
+            ),
+            Err(_) => match fs::read_to_string(LEGACY_MAILBOX_PATH) {
+                Ok(json) => (
+                    serde_json::from_str(&json).unwrap_or_else(|err| {
+                        log::warn!("Ignoring unreadable {LEGACY_MAILBOX_PATH}: {err}");
+                        Stored::default()
+                    }),
+                    true,
+                ),
+                Err(_) => (Stored::default(), false),
+            },
+        };
+        let mut mailbox = Self::new(config, stored);
+        // Rewritten in the current format on the next save
+        mailbox.dirty = legacy;
+        log::info!(
+            "Mailbox enabled: {} messages per recipient, {} per sender, {} MB in all, kept {}h ({} waiting)",
+            mailbox.config.quota_messages,
+            mailbox.config.sender_quota_messages,
+            mailbox.config.max_bytes / (1024 * 1024),
+            mailbox.config.ttl_secs / 3600,
+            mailbox.total.messages
+        );
+        mailbox
+    }
+
+    fn new(config: MailboxConfig, stored: Stored) -> Self {
+        let mut mailbox = Self {
+            config,
+            stored,
+            recipients: HashMap::new(),
+            senders: HashMap::new(),
+            total: Usage::default(),
+            dirty: false,
+            saving: Arc::default(),
+            save_failed: Arc::default(),
+        };
+        for (recipient, queue) in &mailbox.stored.boxes {
+            for item in queue {
+                mailbox
+                    .recipients
+                    .entry(recipient.clone())
+                    .or_default()
+                    .add(item);
+                mailbox
+                    .senders
+                    .entry(item.sender.clone())
+                    .or_default()
+                    .add(item);
+                mailbox.total.add(item);
+            }
+        }
+        mailbox
+    }
+
+    /// Expired messages are dropped by [`Mailbox::expire`] on a timer, not here,
+    /// so a request costs no more than the mailbox it touches.
+    pub fn handle_request(&mut self, from: &PeerId, request: MailboxRequest) -> MailboxResponse {
+        match request {
+            MailboxRequest::Deposit { recipient, payload } => {
+                self.deposit(from, &recipient, payload)
+            }
+            MailboxRequest::Fetch => self.fetch(from),
+            MailboxRequest::Ack { ids } => {
+                self.ack(from, &ids);
+                MailboxResponse::Acked
+            }
+        }
+    }
+
+    fn deposit(&mut self, from: &PeerId, recipient: &str, payload: Vec<u8>) -> MailboxResponse {
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return MailboxResponse::TooLarge;
+        }
+        let Ok(recipient) = PeerId::from_str(recipient) else {
+            return MailboxResponse::InvalidRecipient;
+        };
+        let key = recipient.to_string();
+        let sender = from.to_string();
+        let size = payload.len();
+        let for_recipient = self.recipients.get(&key).copied().unwrap_or_default();
+        if for_recipient.messages >= self.config.quota_messages
+            || for_recipient.bytes + size > QUOTA_BYTES
+        {
+            log::info!("Mailbox of {recipient} is full, refusing message from {from}");
+            return MailboxResponse::QuotaExceeded;
+        }
+        let by_sender = self.senders.get(&sender).copied().unwrap_or_default();
+        if by_sender.messages >= self.config.sender_quota_messages
+            || by_sender.bytes + size > SENDER_QUOTA_BYTES
+        {
+            log::info!("Mailbox: {from} left too many messages, refusing one for {recipient}");
+            return MailboxResponse::QuotaExceeded;
+        }
+        if self.total.bytes + size > self.config.max_bytes {
+            log::warn!("Mailbox storage is full, refusing message from {from}");
+            return MailboxResponse::QuotaExceeded;
+        }
+
+        let id = self.stored.next_id;
+        self.stored.next_id += 1;
+        let item = MailboxItem {
+            id,
+            sender,
+            payload,
+            deposited_at: Utc::now().timestamp(),
+        };
+        self.recipients.entry(key.clone()).or_default().add(&item);
+        self.senders
+            .entry(item.sender.clone())
+            .or_default()
+            .add(&item);
+        self.total.add(&item);
+        self.stored.boxes.entry(key).or_default().push_back(item);
+        self.dirty = true;
+        log::info!("Mailbox: stored message {id} from {from} for {recipient}");
+        MailboxResponse::Stored
+    }
+
+    /// Oldest messages for `recipient`; they stay until acknowledged. Messages
+    /// past the TTL but not yet expired are still handed out.
+    fn fetch(&self, recipient: &PeerId) -> MailboxResponse {
+        let Some(queue) = self.stored.boxes.get(&recipient.to_string()) else {
+            return MailboxResponse::Messages {
+                items: Vec::new(),
+                more: false,
+            };
+        };
+        let mut items = Vec::new();
+        let mut bytes = 0;
+        for item in queue {
+            if items.len() == FETCH_MAX_ITEMS
+                || (!items.is_empty() && bytes + item.payload.len() > FETCH_MAX_BYTES)
+            {
+                break;
+            }
+            bytes += item.payload.len();
+            items.push(item.clone());
+        }
+        let more = items.len() < queue.len();
+        MailboxResponse::Messages { items, more }
+    }
+
+    fn ack(&mut self, recipient: &PeerId, ids: &[u64]) {
+        let key = recipient.to_string();
+        let collected = self.remove_where(Some(&key), |item| ids.contains(&item.id));
+        if collected > 0 {
+            log::info!("Mailbox: {recipient} collected {collected} message(s)");
+        }
+    }
+
+    /// Drop messages older than the TTL; called periodically.
+    pub fn expire(&mut self) {
+        let oldest = Utc::now().timestamp() - self.config.ttl_secs;
+        let expired = self.remove_where(None, |item| item.deposited_at <= oldest);
+        if expired > 0 {
+            log::info!("Mailbox: {expired} message(s) expired");
+        }
+    }
+
+    /// Remove the messages matching `remove` from the mailbox of `recipient`
+    /// (every mailbox when `None`), keeping the usage in step. Returns how many
+    /// were removed.
+    fn remove_where(
+        &mut self,
+        recipient: Option<&str>,
+        remove: impl Fn(&MailboxItem) -> bool,
+    ) -> usize {
+        let mut removed = 0;
+        for (key, queue) in self.stored.boxes.iter_mut() {
+            if recipient.is_some_and(|recipient| recipient != key) {
+                continue;
+            }
+            queue.retain(|item| {
+                if !remove(item) {
+                    return true;
+                }
+                removed += 1;
+                forget(&mut self.recipients, key, item);
+                forget(&mut self.senders, &item.sender, item);
+                self.total.remove(item);
+                false
+            });
+        }
+        if removed > 0 {
+            self.stored.boxes.retain(|_, queue| !queue.is_empty());
+            self.dirty = true;
+        }
+        removed
+    }
+
+    /// Write the mailboxes to disk in the background if they changed.
+    pub fn save(&mut self) {
+        if self.save_failed.swap(false, Ordering::AcqRel) {
+            self.dirty = true;
+        }
+        // A save still being written is not overtaken; this one waits a tick
+        if !self.dirty || self.saving.load(Ordering::Acquire) {
+            return;
+        }
+        let bytes = match postcard::to_allocvec(&self.stored) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                log::warn!("Failed to encode mailboxes: {err}");
+                return;
+            }
+        };
+        self.dirty = false;
+        self.saving.store(true, Ordering::Release);
+        let saving = Arc::clone(&self.saving);
+        let save_failed = Arc::clone(&self.save_failed);
+        // Written off the swarm loop, which keeps serving requests meanwhile
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = write_atomically(Path::new(MAILBOX_PATH), &bytes) {
+                log::warn!("Failed to save {MAILBOX_PATH}: {err}");
+                save_failed.store(true, Ordering::Release);
+            }
+            saving.store(false, Ordering::Release);
+        });
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.total.messages
+    }
+}
+
+/// Take `item` off the usage of `key`, forgetting keys with nothing left.
+fn forget(usage: &mut HashMap<String, Usage>, key: &str, item: &MailboxItem) {
+    if let Some(entry) = usage.get_mut(key) {
+        entry.remove(item);
+        if entry.messages == 0 {
+            usage.remove(key);
+        }
+    }
+}
+
+/// Write `bytes` to a temporary file and move it over `path`, so a crash
+/// mid-write leaves the previous contents in place.
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = path.with_extension("tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+/// Like [`parse_env`], for settings that must be above zero.
+fn parse_positive_env<T>(name: &str, default: T) -> Result<T, Box<dyn Error>>
+where
+    T: FromStr + PartialOrd + Default,
+    T::Err: Display,
+{
+    let value = parse_env(name, default)?;
+    if value <= T::default() {
+        return Err(format!("{name} must be above zero").into());
+    }
+    Ok(value)
+}
+
+fn parse_env<T>(name: &str, default: T) -> Result<T, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|err| format!("Invalid {name} `{value}`: {err}").into()),
+        Err(env::VarError::NotPresent) => Ok(default),
+        Err(env::VarError::NotUnicode(_)) => {
+            Err(format!("{name} contains non-unicode characters").into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_quotas(
+        quota_messages: usize,
+        sender_quota_messages: usize,
+        max_bytes: usize,
+    ) -> Mailbox {
+        let config = MailboxConfig {
+            quota_messages,
+            sender_quota_messages,
+            max_bytes,
+            ttl_secs: 3600,
+        };
+        Mailbox::new(config, Stored::default())
+    }
+
+    fn deposit(mailbox: &mut Mailbox, from: &PeerId, to: &PeerId, size: usize) -> MailboxResponse {
+        let request = MailboxRequest::Deposit {
+            recipient: to.to_string(),
+            payload: vec![0; size],
+        };
+        mailbox.handle_request(from, request)
+    }
+
+    fn fetch(mailbox: &mut Mailbox, recipient: &PeerId) -> (Vec<u64>, bool) {
+        match mailbox.handle_request(recipient, MailboxRequest::Fetch) {
+            MailboxResponse::Messages { items, more } => {
+                (items.iter().map(|item| item.id).collect(), more)
+            }
+            other => panic!("unexpected response {other:?}"),
+        }
+    }
+
+    #[test]
+    fn deposits_past_a_quota_are_refused() {
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        // Per recipient
+        let mut mailbox = with_quotas(2, 10, usize::MAX);
+        for _ in 0..2 {
+            assert!(matches!(
+                deposit(&mut mailbox, &alice, &bob, 1),
+                MailboxResponse::Stored
+            ));
+        }
+        assert!(matches!(
+            deposit(&mut mailbox, &carol, &bob, 1),
+            MailboxResponse::QuotaExceeded
+        ));
+        assert!(matches!(
+            deposit(&mut mailbox, &alice, &carol, 1),
+            MailboxResponse::Stored
+        ));
+
+        // Per sender, whoever the recipient
+        let mut mailbox = with_quotas(10, 2, usize::MAX);
+        assert!(matches!(
+            deposit(&mut mailbox, &alice, &bob, 1),
+            MailboxResponse::Stored
+        ));
+        assert!(matches!(
+            deposit(&mut mailbox, &alice, &carol, 1),
+            MailboxResponse::Stored
+        ));
+        assert!(matches!(
+            deposit(&mut mailbox, &alice, &PeerId::random(), 1),
+            MailboxResponse::QuotaExceeded
+        ));
+        assert!(matches!(
+            deposit(&mut mailbox, &carol, &bob, 1),
+            MailboxResponse::Stored
+        ));
+
+        // Overall
+        let mut mailbox = with_quotas(10, 10, 100);
+        assert!(matches!(
+            deposit(&mut mailbox, &alice, &bob, 60),
+            MailboxResponse::Stored
+        ));
+        assert!(matches!(
+            deposit(&mut mailbox, &carol, &alice, 60),
+            MailboxResponse::QuotaExceeded
+        ));
+        assert!(matches!(
+            deposit(&mut mailbox, &carol, &alice, 40),
+            MailboxResponse::Stored
+        ));
+
+        assert!(matches!(
+            deposit(&mut mailbox, &alice, &bob, MAX_PAYLOAD_SIZE + 1),
+            MailboxResponse::TooLarge
+        ));
+    }
+
+    #[test]
+    fn fetch_pages_until_everything_is_acknowledged() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let mut mailbox = with_quotas(1000, 1000, usize::MAX);
+        for _ in 0..FETCH_MAX_ITEMS + 1 {
+            deposit(&mut mailbox, &alice, &bob, 1);
+        }
+
+        let (ids, more) = fetch(&mut mailbox, &bob);
+        assert_eq!(ids.len(), FETCH_MAX_ITEMS);
+        assert!(more);
+        // Nothing leaves before it is acknowledged
+        assert_eq!(fetch(&mut mailbox, &bob).0, ids);
+
+        mailbox.handle_request(&bob, MailboxRequest::Ack { ids });
+        let (ids, more) = fetch(&mut mailbox, &bob);
+        assert_eq!(ids.len(), 1);
+        assert!(!more);
+    }
+
+    #[test]
+    fn ack_only_removes_the_callers_messages() {
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut mailbox = with_quotas(10, 10, usize::MAX);
+        deposit(&mut mailbox, &alice, &bob, 1);
+        deposit(&mut mailbox, &alice, &carol, 1);
+        let (for_bob, _) = fetch(&mut mailbox, &bob);
+        let (for_carol, _) = fetch(&mut mailbox, &carol);
+
+        // Carol cannot delete what is waiting for Bob
+        mailbox.handle_request(
+            &carol,
+            MailboxRequest::Ack {
+                ids: for_bob.clone(),
+            },
+        );
+        assert_eq!(fetch(&mut mailbox, &bob).0, for_bob);
+
+        mailbox.handle_request(&carol, MailboxRequest::Ack { ids: for_carol });
+        assert!(fetch(&mut mailbox, &carol).0.is_empty());
+        assert_eq!(mailbox.pending_count(), 1);
+    }
+
+    #[test]
+    fn expiry_keeps_the_usage_in_step() {
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut mailbox = with_quotas(2, 2, usize::MAX);
+        deposit(&mut mailbox, &alice, &bob, 10);
+        deposit(&mut mailbox, &alice, &bob, 20);
+        deposit(&mut mailbox, &carol, &alice, 5);
+        for item in mailbox.stored.boxes.get_mut(&bob.to_string()).unwrap() {
+            item.deposited_at -= mailbox.config.ttl_secs + 1;
+        }
+
+        mailbox.expire();
+        assert_eq!(mailbox.pending_count(), 1);
+        assert_eq!(mailbox.total.bytes, 5);
+        assert!(!mailbox.recipients.contains_key(&bob.to_string()));
+        assert!(!mailbox.senders.contains_key(&alice.to_string()));
+        // The freed quota can be used again
+        for _ in 0..2 {
+            assert!(matches!(
+                deposit(&mut mailbox, &alice, &bob, 1),
+                MailboxResponse::Stored
+            ));
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod behavior;
+pub mod mailbox;
 pub mod transport;
 pub mod node;
 
@@ -8,11 +8,13 @@ use libp2p::futures::StreamExt;
 use libp2p::identify;
 use libp2p::kad;
 use libp2p::multiaddr::Protocol;
+use libp2p::request_response;
 use libp2p::swarm::{Config as SwarmConfig, SwarmEvent};
 use libp2p::{Multiaddr, PeerId, Swarm, identity};
 use tokio::time::{interval, Duration};
 
 use super::behavior::{NodeBehaviorEvent, build_behavior};
+use super::mailbox::{Mailbox, MailboxConfig, MailboxEvent};
 use super::transport::build_transport;
 
 const NODE_KEY_PATH: &str = "data/node_key.pk";
@@ -21,6 +23,8 @@ pub struct BootstrapNode {
     // In-memory storage of discovered peers and their addresses
     peers: HashMap<PeerId, HashSet<Multiaddr>>,
     local_peer_id: Option<PeerId>,
+    // Store-and-forward messages for offline clients (opt-in via NODE_MAILBOX)
+    mailbox: Option<Mailbox>,
 }
 
 impl BootstrapNode {
@@ -28,22 +32,24 @@ impl BootstrapNode {
         Ok(Self {
             peers: HashMap::new(),
             local_peer_id: None,
+            mailbox: None,
         })
     }
 
     pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
         let local_key = load_or_generate_key()?;
         let local_peer_id = PeerId::from(local_key.public());
-        self.local_peer_id = Some(local_peer_id.clone());
+        self.local_peer_id = Some(local_peer_id);
         log::info!("Bootstrap Node PeerID: {local_peer_id:?}");
 
         let transport = build_transport(&local_key)?;
-        let behavior = build_behavior(&local_key, local_peer_id.clone())?;
+        self.mailbox = MailboxConfig::from_env()?.map(Mailbox::load);
+        let behavior = build_behavior(&local_key, local_peer_id, self.mailbox.is_some())?;
 
         let mut swarm = Swarm::new(
             transport,
             behavior,
-            local_peer_id.clone(),
+            local_peer_id,
             SwarmConfig::with_tokio_executor(),
         );
 
@@ -51,7 +57,7 @@ impl BootstrapNode {
         if let Some(public_addr) = load_public_address_from_env()? {
             log::info!("Announcing public address: {}", public_addr);
             swarm.add_external_address(public_addr.clone());
-            if let Some(peer_id) = self.local_peer_id.clone() {
+            if let Some(peer_id) = self.local_peer_id {
                 let full_addr = public_addr.with(Protocol::P2p(peer_id));
                 log::info!("Clients can connect via public address: {}", full_addr);
             }
@@ -71,6 +77,11 @@ impl BootstrapNode {
                 }
                 _ = stats_interval.tick() => {
                     log::info!("Statistics: {} known peers", self.known_peers_count());
+                    if let Some(mailbox) = self.mailbox.as_mut() {
+                        mailbox.expire();
+                        mailbox.save();
+                        log::info!("Mailbox: {} message(s) waiting", mailbox.pending_count());
+                    }
                 }
             }
         }
@@ -100,8 +111,11 @@ impl BootstrapNode {
             SwarmEvent::Behaviour(NodeBehaviorEvent::Ping(event)) => {
                 log::trace!("Ping event: {:?}", event);
             }
+            SwarmEvent::Behaviour(NodeBehaviorEvent::Mailbox(event)) => {
+                self.handle_mailbox_event(event, swarm);
+            }
             SwarmEvent::NewListenAddr { address, .. } => {
-                if let Some(peer_id) = self.local_peer_id.clone() {
+                if let Some(peer_id) = self.local_peer_id {
                     let full_addr = address.clone().with(Protocol::P2p(peer_id));
                     log::info!("Bootstrap node listening on: {}", full_addr);
                     log::info!("Clients can connect to: {}", full_addr);
@@ -156,19 +170,45 @@ impl BootstrapNode {
     }
 
     fn handle_kad_event(&mut self, event: kad::Event) {
+        if let kad::Event::RoutingUpdated { peer, addresses, .. } = event {
+            // Merge addresses into in-memory map as well
+            let entry = self.peers.entry(peer).or_default();
+            for addr in addresses.iter() {
+                entry.insert(addr.clone());
+            }
+            log::debug!(
+                "Kademlia routing table updated for {} ({} addrs). Total peers: {}",
+                peer,
+                entry.len(),
+                self.known_peers_count()
+            );
+        }
+    }
+
+    fn handle_mailbox_event(
+        &mut self,
+        event: MailboxEvent,
+        swarm: &mut Swarm<super::behavior::NodeBehavior>,
+    ) {
         match event {
-            kad::Event::RoutingUpdated { peer, addresses, .. } => {
-                // Merge addresses into in-memory map as well
-                let entry = self.peers.entry(peer).or_default();
-                for addr in addresses.iter() {
-                    entry.insert(addr.clone());
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                let Some(mailbox) = self.mailbox.as_mut() else {
+                    return;
+                };
+                // Noise authenticated `peer`, so only the recipient can fetch its messages
+                let response = mailbox.handle_request(&peer, request);
+                if let Some(behaviour) = swarm.behaviour_mut().mailbox.as_mut()
+                    && behaviour.send_response(channel, response).is_err()
+                {
+                    log::debug!("Failed to answer mailbox request from {peer}");
                 }
-                log::debug!(
-                    "Kademlia routing table updated for {} ({} addrs). Total peers: {}",
-                    peer,
-                    entry.len(),
-                    self.known_peers_count()
-                );
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log::debug!("Mailbox request from {peer} failed: {error}");
             }
             _ => {}
         }